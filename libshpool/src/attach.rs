@@ -15,11 +15,11 @@
 use std::{env, fmt, io, path::PathBuf, thread, time};
 
 use anyhow::{anyhow, bail, Context};
-use tracing::{error, info, warn};
+use tracing::{info, warn};
 
 use super::{
-    config, duration, protocol,
-    protocol::{AttachHeader, ConnectHeader},
+    config, duration, predict, protocol,
+    protocol::{AttachHeader, ConnectHeader, FramedAttachHeader},
     test_hooks, tty,
 };
 
@@ -31,13 +31,21 @@ pub fn run(
     force: bool,
     ttl: Option<String>,
     cmd: Option<String>,
+    predict: Option<String>,
     socket: PathBuf,
 ) -> anyhow::Result<()> {
     info!("\n\n======================== STARTING ATTACH ============================\n\n");
     test_hooks::emit("attach-startup");
-    SignalHandler::new(name.clone(), socket.clone()).spawn()?;
 
-    let config = config::read_config(&config_file)?;
+    let mut config = config::read_config(&config_file)?;
+    if let Some(predict) = predict {
+        config.predictive_echo = Some(match predict.as_str() {
+            "never" => config::PredictiveEcho::Never,
+            "adaptive" => config::PredictiveEcho::Adaptive,
+            "always" => config::PredictiveEcho::Always,
+            _ => bail!("unknown predictive echo mode '{}'", predict),
+        });
+    }
 
     let ttl = match &ttl {
         Some(src) => match duration::parse(src.as_str()) {
@@ -116,6 +124,10 @@ fn do_attach(
             tty::Size { rows: 24, cols: 80 }
         }
     };
+    let predictor = match config.predictive_echo.clone().unwrap_or_default() {
+        config::PredictiveEcho::Never => None,
+        mode => Some(predict::Predictor::new(mode, &tty_size)),
+    };
 
     let mut local_env_keys = vec!["TERM", "DISPLAY", "LANG", "SSH_AUTH_SOCK"];
     if let Some(forward_env) = &config.forward_env {
@@ -125,24 +137,30 @@ fn do_attach(
     }
 
     client
-        .write_connect_header(ConnectHeader::Attach(AttachHeader {
-            name: String::from(name),
-            local_tty_size: tty_size,
-            local_env: local_env_keys
-                .into_iter()
-                .filter_map(|var| {
-                    let val = env::var(var).context("resolving var").ok()?;
-                    Some((String::from(var), val))
-                })
-                .collect::<Vec<_>>(),
-            ttl_secs: ttl.map(|d| d.as_secs()),
-            cmd: cmd.clone(),
+        .write_connect_header(ConnectHeader::FramedAttach(FramedAttachHeader {
+            attach: AttachHeader {
+                name: String::from(name),
+                local_tty_size: tty_size,
+                local_env: local_env_keys
+                    .into_iter()
+                    .filter_map(|var| {
+                        let val = env::var(var).context("resolving var").ok()?;
+                        Some((String::from(var), val))
+                    })
+                    .collect::<Vec<_>>(),
+                ttl_secs: ttl.map(|d| d.as_secs()),
+                cmd: cmd.clone(),
+            },
+            compression: config.compression.clone().unwrap_or_default(),
         }))
         .context("writing attach header")?;
 
-    let attach_resp: protocol::AttachReplyHeader =
-        client.read_reply().context("reading attach reply")?;
-    info!("attach_resp.status={:?}", attach_resp.status);
+    // A daemon too old to know about framed attaches hangs up on us
+    // rather than replying.
+    let attach_resp: protocol::FramedAttachReplyHeader = client
+        .read_reply()
+        .context("reading attach reply (the daemon may need restarting after an upgrade)")?;
+    info!("attach_resp.status={:?} compression={:?}", attach_resp.status, attach_resp.compression);
 
     {
         use protocol::AttachStatus::*;
@@ -172,7 +190,7 @@ fn do_attach(
         }
     }
 
-    match client.pipe_bytes() {
+    match client.pipe_bytes(attach_resp.compression, predictor) {
         Ok(exit_status) => std::process::exit(exit_status),
         Err(e) => Err(e),
     }
@@ -190,81 +208,3 @@ fn dial_client(socket: &PathBuf) -> anyhow::Result<protocol::Client> {
         }
     }
 }
-
-//
-// Signal Handling
-//
-
-struct SignalHandler {
-    session_name: String,
-    socket: PathBuf,
-}
-
-impl SignalHandler {
-    fn new(session_name: String, socket: PathBuf) -> Self {
-        SignalHandler { session_name, socket }
-    }
-
-    fn spawn(self) -> anyhow::Result<()> {
-        use signal_hook::{consts::*, iterator::*};
-
-        let sigs = vec![SIGWINCH];
-        let mut signals = Signals::new(sigs).context("creating signal iterator")?;
-
-        thread::spawn(move || {
-            for signal in &mut signals {
-                let res = match signal {
-                    SIGWINCH => self.handle_sigwinch(),
-                    sig => {
-                        error!("unknown signal: {}", sig);
-                        panic!("unknown signal: {}", sig);
-                    }
-                };
-                if let Err(e) = res {
-                    error!("signal handler error: {:?}", e);
-                }
-            }
-        });
-
-        Ok(())
-    }
-
-    fn handle_sigwinch(&self) -> anyhow::Result<()> {
-        info!("handle_sigwinch: enter");
-        let mut client = protocol::Client::new(&self.socket)?;
-
-        let tty_size = tty::Size::from_fd(0).context("getting tty size")?;
-        info!("handle_sigwinch: tty_size={:?}", tty_size);
-
-        // write the request on a new, seperate connection
-        client
-            .write_connect_header(protocol::ConnectHeader::SessionMessage(
-                protocol::SessionMessageRequest {
-                    session_name: self.session_name.clone(),
-                    payload: protocol::SessionMessageRequestPayload::Resize(
-                        protocol::ResizeRequest { tty_size: tty_size.clone() },
-                    ),
-                },
-            ))
-            .context("writing resize request")?;
-
-        let reply: protocol::SessionMessageReply =
-            client.read_reply().context("reading session message reply")?;
-        match reply {
-            protocol::SessionMessageReply::NotFound => {
-                warn!(
-                    "handle_sigwinch: sent resize for session '{}', but the daemon has no record of that session",
-                    self.session_name
-                );
-            }
-            protocol::SessionMessageReply::Resize(protocol::ResizeReply::Ok) => {
-                info!("handle_sigwinch: resized session '{}' to {:?}", self.session_name, tty_size);
-            }
-            reply => {
-                warn!("handle_sigwinch: unexpected resize reply: {:?}", reply);
-            }
-        }
-
-        Ok(())
-    }
-}
@@ -0,0 +1,55 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{io, path::Path};
+
+use anyhow::{anyhow, Context};
+
+use super::{
+    protocol,
+    protocol::{BroadcastMode, BroadcastReply, BroadcastRequest, ConnectHeader},
+};
+
+pub fn run<P>(message: String, pager: bool, socket: P) -> anyhow::Result<()>
+where
+    P: AsRef<Path>,
+{
+    let mut client = match protocol::Client::new(socket) {
+        Ok(c) => c,
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    let mode = if pager { BroadcastMode::Pager } else { BroadcastMode::Banner };
+    client
+        .write_connect_header(ConnectHeader::Broadcast(BroadcastRequest { message, mode }))
+        .context("writing broadcast request header")?;
+
+    let reply: BroadcastReply = client.read_reply().context("reading reply")?;
+
+    if reply.sessions.is_empty() {
+        eprintln!("no attached sessions to broadcast to");
+        return Err(anyhow!("no attached sessions to broadcast to"));
+    }
+    for session in reply.sessions.iter() {
+        println!("{}", session);
+    }
+
+    Ok(())
+}
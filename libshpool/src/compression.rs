@@ -0,0 +1,150 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Streaming compression for the output sent to attached clients.
+//!
+//! Both ends keep a single compression context for the whole
+//! connection rather than compressing each chunk on its own. Terminal
+//! output repeats itself a lot (prompts, escape sequences, redraws of
+//! the same screen), so being able to refer back to earlier chunks is
+//! what makes compression worth it. Every call flushes the stream so
+//! that the other end can decode everything it has been sent so far
+//! without waiting for more.
+
+use std::io::Write;
+
+use anyhow::Context;
+
+use super::protocol::Compression;
+
+/// The zstd compression level. Output has to go out as soon as the
+/// shell writes it, so we favor speed over ratio.
+const ZSTD_LEVEL: i32 = 3;
+
+/// The daemon side of a compressed connection.
+pub enum Compressor {
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Deflate(flate2::write::DeflateEncoder<Vec<u8>>),
+}
+
+impl Compressor {
+    pub fn new(algo: Compression) -> anyhow::Result<Self> {
+        Ok(match algo {
+            Compression::Zstd => Compressor::Zstd(
+                zstd::stream::write::Encoder::new(vec![], ZSTD_LEVEL)
+                    .context("creating zstd encoder")?,
+            ),
+            Compression::Deflate => Compressor::Deflate(flate2::write::DeflateEncoder::new(
+                vec![],
+                flate2::Compression::fast(),
+            )),
+        })
+    }
+
+    /// Compress buf, appending the result to out.
+    pub fn compress(&mut self, buf: &[u8], out: &mut Vec<u8>) -> anyhow::Result<()> {
+        match self {
+            Compressor::Zstd(e) => {
+                e.write_all(buf).context("zstd compressing")?;
+                e.flush().context("flushing zstd encoder")?;
+                out.append(e.get_mut());
+            }
+            Compressor::Deflate(e) => {
+                e.write_all(buf).context("deflate compressing")?;
+                e.flush().context("flushing deflate encoder")?;
+                out.append(e.get_mut());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The client side of a compressed connection.
+pub enum Decompressor {
+    Zstd(zstd::stream::write::Decoder<'static, Vec<u8>>),
+    Deflate(flate2::write::DeflateDecoder<Vec<u8>>),
+}
+
+impl Decompressor {
+    pub fn new(algo: Compression) -> anyhow::Result<Self> {
+        Ok(match algo {
+            Compression::Zstd => Decompressor::Zstd(
+                zstd::stream::write::Decoder::new(vec![]).context("creating zstd decoder")?,
+            ),
+            Compression::Deflate => {
+                Decompressor::Deflate(flate2::write::DeflateDecoder::new(vec![]))
+            }
+        })
+    }
+
+    /// Decompress buf, appending the result to out.
+    pub fn decompress(&mut self, buf: &[u8], out: &mut Vec<u8>) -> anyhow::Result<()> {
+        match self {
+            Decompressor::Zstd(d) => {
+                d.write_all(buf).context("zstd decompressing")?;
+                d.flush().context("flushing zstd decoder")?;
+                out.append(d.get_mut());
+            }
+            Decompressor::Deflate(d) => {
+                d.write_all(buf).context("deflate decompressing")?;
+                d.flush().context("flushing deflate decoder")?;
+                out.append(d.get_mut());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() -> anyhow::Result<()> {
+        let chunks: Vec<Vec<u8>> = vec![
+            b"$ ls\r\n".to_vec(),
+            b"\x1b[01;34mdir\x1b[0m  file.txt\r\n".to_vec(),
+            vec![],
+            b"$ ".repeat(1000),
+        ];
+        for algo in [Compression::Zstd, Compression::Deflate] {
+            let mut compressor = Compressor::new(algo)?;
+            let mut decompressor = Decompressor::new(algo)?;
+            for chunk in chunks.iter() {
+                // each chunk must be decodable as soon as it arrives
+                let mut compressed = vec![];
+                compressor.compress(chunk, &mut compressed)?;
+                let mut plain = vec![];
+                decompressor.decompress(&compressed, &mut plain)?;
+                assert_eq!(&plain, chunk, "algo={:?}", algo);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn shared_context() -> anyhow::Result<()> {
+        let line = b"\x1b[32muser@host\x1b[0m:\x1b[34m~/src/project\x1b[0m$ ";
+        for algo in [Compression::Zstd, Compression::Deflate] {
+            let mut compressor = Compressor::new(algo)?;
+            let mut first = vec![];
+            compressor.compress(line, &mut first)?;
+            let mut second = vec![];
+            compressor.compress(line, &mut second)?;
+            // the repeat should mostly be a back reference
+            assert!(second.len() < first.len() / 2, "algo={:?}", algo);
+        }
+        Ok(())
+    }
+}
@@ -20,28 +20,34 @@ use anyhow::Context;
 use serde_derive::Deserialize;
 use tracing::{info, instrument};
 
-use super::{daemon::keybindings, user};
+use super::{daemon::keybindings, protocol, user};
 
 #[instrument(skip_all)]
 pub fn read_config(config_file: &Option<String>) -> anyhow::Result<Config> {
-    let mut config = Config::default();
-    if let Some(config_path) = config_file {
-        info!("parsing explicitly passed in config ({})", config_path);
-        let config_str = fs::read_to_string(config_path).context("reading config toml (1)")?;
-        config = toml::from_str(&config_str).context("parsing config file (1)")?;
-    } else {
-        let user_info = user::info()?;
-        let mut config_path = PathBuf::from(user_info.home_dir);
-        config_path.push(".config");
-        config_path.push("shpool");
-        config_path.push("config.toml");
-        if config_path.exists() {
-            let config_str = fs::read_to_string(config_path).context("reading config toml (2)")?;
-            config = toml::from_str(&config_str).context("parsing config file (2)")?;
+    match config_path(config_file)? {
+        Some(config_path) => {
+            info!("parsing config ({})", config_path.display());
+            let config_str = fs::read_to_string(&config_path).context("reading config toml")?;
+            toml::from_str(&config_str).context("parsing config file")
         }
+        None => Ok(Config::default()),
+    }
+}
+
+/// The config file that read_config will load, which is the explicitly
+/// passed in one if there is one, otherwise ~/.config/shpool/config.toml
+/// if it exists.
+pub fn config_path(config_file: &Option<String>) -> anyhow::Result<Option<PathBuf>> {
+    if let Some(config_path) = config_file {
+        return Ok(Some(PathBuf::from(config_path)));
     }
 
-    Ok(config)
+    let user_info = user::info()?;
+    let mut config_path = PathBuf::from(user_info.home_dir);
+    config_path.push(".config");
+    config_path.push("shpool");
+    config_path.push("config.toml");
+    Ok(if config_path.exists() { Some(config_path) } else { None })
 }
 
 #[derive(Deserialize, Default, Debug, Clone)]
@@ -105,12 +111,80 @@ pub struct Config {
     /// The user supplied keybindings.
     pub keybinding: Option<Vec<Keybinding>>,
 
+    /// The pager program used to display a session's scrollback,
+    /// either in response to the `scrollback` keybinding action
+    /// or the `shpool scrollback` subcommand. The pager will be
+    /// invoked like `pager /tmp/scrollback.txt`. By default, `less`.
+    pub scrollback_pager: Option<String>,
+
     /// A prefix to inject into the prompt of freshly spawned shells.
-    /// The prefix will get included in the shell's prompt variable
-    /// verbatim except that the string '$SHPOOL_SESSION_NAME' will
-    /// get replaced with the actual name of the shpool session.
+    /// The prefix is a template where `{session_name}`, `{started_at}`,
+    /// `{ttl}` and `{hostname}` get filled in for the session,
+    /// `{if var}...{end}` sections are dropped when `var` is empty,
+    /// and `{red}`, `{bold}`, `{reset}` and friends color the prefix.
+    /// The legacy '$SHPOOL_SESSION_NAME' form also gets replaced with
+    /// the actual name of the shpool session.
     pub prompt_prefix: Option<String>,
 
+    /// Custom scripts to inject the prompt prefix with, keyed by the
+    /// basename of the shell binary (e.g. `bash` or `nu`). These take
+    /// precedence over the built in scripts, and allow shpool to inject
+    /// a prefix into shells it does not know about. The string
+    /// '$SHPOOL_PROMPT_PREFIX' in the script will get replaced with the
+    /// prompt prefix.
+    pub prompt_prefix_scripts: Option<HashMap<String, String>>,
+
+    /// Shell commands to run in response to session events.
+    pub hooks: Option<HooksConfig>,
+
+    /// Patterns to watch for in the output of shell sessions.
+    pub trigger: Option<Vec<Trigger>>,
+
+    /// How many seconds a detached session has to go without output
+    /// before new output marks it as active in `shpool list`.
+    /// By default, 10 seconds.
+    pub activity_idle_secs: Option<u64>,
+
+    /// How to tell a client attaching to a session about any bells or
+    /// activity that happened while the session was detached. By
+    /// default, the client is not told.
+    pub reattach_notify: Option<ReattachNotify>,
+
+    /// How to get the prompt prefix script into the shell. By default,
+    /// bash, zsh and fish source it from a generated rc file, while
+    /// other shells have it typed in at their first prompt.
+    pub prompt_prefix_injection: Option<PromptPrefixInjection>,
+
+    /// Extra unix sockets for the daemon to listen on, besides the
+    /// one given with --socket. Ignored when systemd passes the daemon
+    /// its sockets, since those are configured in the socket unit.
+    pub listen: Option<Vec<ListenConfig>>,
+
+    /// Serve counters and gauges about the daemon and its sessions
+    /// in the Prometheus text format. Off by default.
+    pub metrics: Option<MetricsConfig>,
+
+    /// Run each session in a cgroup of its own, optionally with memory
+    /// and cpu limits. Off by default, in which case every shell shares
+    /// the daemon's cgroup.
+    pub session_cgroup: Option<SessionCgroupConfig>,
+
+    /// What to do with the shell's output when the client can't keep
+    /// up with it. By default, the shell is made to wait.
+    pub output_flow_control: Option<OutputFlowControl>,
+
+    /// Whether `shpool attach` should guess how the shell is going to
+    /// echo what you type and show it right away, underlined until the
+    /// shell confirms it. Can be overridden with `shpool attach --predict`.
+    /// By default, it never does.
+    pub predictive_echo: Option<PredictiveEcho>,
+
+    /// The algorithms `shpool attach` offers to the daemon for
+    /// compressing the shell's output, in order of preference. Off
+    /// by default. Compression only pays for itself when the socket
+    /// is reached over a slow link, like an ssh forwarded socket.
+    pub compression: Option<Vec<protocol::Compression>>,
+
     /// Control when and how shpool will display the message of the day.
     pub motd: Option<MotdDisplayMode>,
 
@@ -127,6 +201,127 @@ pub struct Config {
     pub motd_args: Option<Vec<String>>,
 }
 
+/// Shell commands to run when session events happen. Each command
+/// gets run with `/bin/sh -c` in the background with the name of
+/// the event in $SHPOOL_HOOK_EVENT, the name of the session in
+/// $SHPOOL_SESSION_NAME, and whatever else is known about the event
+/// in other $SHPOOL_* variables.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct HooksConfig {
+    /// Run when a fresh session is created.
+    pub on_new_session: Option<String>,
+    /// Run when a user connects to an existing session.
+    pub on_reattach: Option<String>,
+    /// Run when a user tries to connect to a session but can't
+    /// because there is already a connected client.
+    pub on_busy: Option<String>,
+    /// Run when the `shpool attach` process hangs up.
+    pub on_client_disconnect: Option<String>,
+    /// Run when a session closes due to some event on the daemon
+    /// such as the shell exiting.
+    pub on_shell_disconnect: Option<String>,
+    /// Run when a session is killed with `shpool kill`.
+    pub on_session_killed: Option<String>,
+    /// Run when a session is reaped because its ttl ran out.
+    pub on_ttl_expired: Option<String>,
+    /// Run when an attached client reports a new tty size.
+    pub on_resize: Option<String>,
+    /// Run when a keybinding fires, with the action in
+    /// $SHPOOL_KEYBINDING_ACTION.
+    pub on_keybinding: Option<String>,
+    /// Run when the daemon starts listening for connections.
+    pub on_daemon_start: Option<String>,
+    /// Run when the daemon shuts down due to a signal. Unlike
+    /// the other hooks, the daemon waits for this one to finish.
+    pub on_daemon_stop: Option<String>,
+    /// Run when a shell prints a line matching a `[[trigger]]`
+    /// pattern, with the pattern in $SHPOOL_TRIGGER_PATTERN and the
+    /// matching line in $SHPOOL_TRIGGER_MATCH.
+    pub on_output_trigger: Option<String>,
+    /// How long a hook command may run before it gets killed.
+    /// By default, 30 seconds.
+    pub timeout_secs: Option<u64>,
+}
+
+/// A regex to watch for in shell output, along with what to do when
+/// a line of output matches it.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Trigger {
+    /// The regex to match against each line of output, with any
+    /// terminal escape codes stripped out.
+    pub pattern: String,
+    /// The sessions to watch. By default, all sessions.
+    pub sessions: Option<Vec<String>>,
+    /// A shell command to run when the trigger fires. It gets run
+    /// just like a hook command for the `on_output_trigger` event.
+    pub command: Option<String>,
+    /// If true, send a desktop notification to the attached client
+    /// when the trigger fires, or to the next client to attach if
+    /// no one is attached at the time.
+    pub notify: Option<bool>,
+    /// The minimum number of seconds between firings of this trigger
+    /// for any one session. By default, 30.
+    pub min_interval_secs: Option<u64>,
+}
+
+/// An extra socket for the daemon to listen on.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ListenConfig {
+    /// The path of the unix socket.
+    pub path: String,
+    /// If true, the socket only accepts commands that don't change
+    /// anything, like `shpool list` and `shpool status`.
+    pub read_only: Option<bool>,
+}
+
+/// Where to serve metrics from. The metrics get served over HTTP, and
+/// every request gets the metrics regardless of the path.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct MetricsConfig {
+    /// The path of a unix socket to listen on.
+    pub socket: Option<String>,
+    /// A TCP port to listen on. Only the loopback interface is used.
+    pub port: Option<u16>,
+}
+
+/// How to put sessions in cgroups of their own, and the resource
+/// limits to apply to them.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct SessionCgroupConfig {
+    /// How to create the cgroups. By default, a systemd scope.
+    pub mode: Option<CgroupMode>,
+    /// The memory limit for each session, as a size like 4G. Past this
+    /// limit, the OOM killer only picks from processes in the session.
+    /// By default, unlimited.
+    pub memory_max: Option<String>,
+    /// The cpu limit for each session, as a percentage of one cpu
+    /// (e.g. 150% for one and a half cpus). By default, unlimited.
+    pub cpu_max: Option<String>,
+    /// Limits for particular sessions, keyed by session name. These
+    /// override the limits above.
+    pub sessions: Option<HashMap<String, CgroupLimits>>,
+}
+
+/// Resource limits for one session's cgroup.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct CgroupLimits {
+    pub memory_max: Option<String>,
+    pub cpu_max: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CgroupMode {
+    /// Ask systemd to start a transient scope unit for each session,
+    /// in the user manager unless the daemon is running as root.
+    #[default]
+    Scope,
+    /// Create the cgroups directly under the daemon's own cgroup,
+    /// which must be delegated to it (e.g. with `Delegate=yes` in
+    /// the systemd unit).
+    Delegate,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Keybinding {
     /// The keybinding to map to an action. The syntax for these keybindings
@@ -136,6 +331,62 @@ pub struct Keybinding {
     pub action: keybindings::Action,
 }
 
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PromptPrefixInjection {
+    /// Hand the script to the shell in a generated rc file where the
+    /// shell supports it, so that it never shows up on the screen or in
+    /// the shell history. Other shells fall back on `Typed`.
+    #[default]
+    Rcfile,
+    /// Type the script into the shell at its first prompt and then
+    /// clear the screen.
+    Typed,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ReattachNotify {
+    /// Don't send anything.
+    #[default]
+    None,
+    /// Ring the bell if the session rang the bell while detached.
+    Bell,
+    /// Send an OSC 9 desktop notification.
+    Osc9,
+    /// Send an OSC 777 desktop notification.
+    Osc777,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFlowControl {
+    /// Stop reading the shell's output until the client catches up,
+    /// so that the shell blocks writing to its terminal. Every byte
+    /// makes it to the client, however long that takes.
+    #[default]
+    Block,
+    /// Keep reading the shell's output, but stop forwarding it to the
+    /// client, then send a fresh render of the screen from the output
+    /// spool once the client catches up. Falls back to `Block` with the
+    /// `simple` session restore mode, since there is no spool to render
+    /// the screen from.
+    Skip,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PredictiveEcho {
+    /// Only ever show what the shell has echoed.
+    #[default]
+    Never,
+    /// Show guesses while the round trip time to the daemon is long
+    /// enough for typing to feel sluggish.
+    Adaptive,
+    /// Always show guesses.
+    Always,
+}
+
 #[derive(Deserialize, Debug, Clone, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum SessionRestoreMode {
@@ -153,6 +404,12 @@ pub enum SessionRestoreMode {
     /// Emit enough output data to restore the last n lines of
     /// history from the output spool.
     Lines(u16),
+    /// Restore the screen as it would look if it had been cleared
+    /// right before the most recent command started, showing that
+    /// command's output followed by whatever came after it. This relies
+    /// on the shell reporting command boundaries with semantic prompt
+    /// marks, so it falls back to `Screen` until the first command runs.
+    LastCommand,
 }
 
 #[derive(Deserialize, Debug, Clone, Default)]
@@ -199,10 +456,64 @@ mod test {
             session_restore_mode = "screen"
             "#,
             r#"
+            session_restore_mode = "lastcommand"
+            "#,
+            r#"
+            prompt_prefix = "[$SHPOOL_SESSION_NAME] "
+            prompt_prefix_injection = "typed"
+
+            [prompt_prefix_scripts]
+            elvish = "var old = $edit:prompt; set edit:prompt = { print '$SHPOOL_PROMPT_PREFIX'; $old }"
+            "#,
+            r#"
+            [hooks]
+            on_new_session = "notify-send \"new session $SHPOOL_SESSION_NAME\""
+            on_shell_disconnect = "rm -rf /tmp/scratch/$SHPOOL_SESSION_NAME"
+            on_keybinding = "echo $SHPOOL_KEYBINDING_ACTION >> /tmp/keys"
+            on_daemon_stop = "rm -rf /tmp/scratch"
+            timeout_secs = 5
+            "#,
+            r#"
+            [[trigger]]
+            pattern = "BUILD FAILED"
+            sessions = ["build"]
+            command = "notify-send \"$SHPOOL_TRIGGER_MATCH\""
+
+            [[trigger]]
+            pattern = "[Pp]assword:"
+            notify = true
+            min_interval_secs = 5
+            "#,
+            r#"
+            activity_idle_secs = 30
+            reattach_notify = "osc777"
+            "#,
+            r#"
+            [[listen]]
+            path = "/run/user/1000/shpool/readonly.socket"
+            read_only = true
+            "#,
+            r#"
+            [session_cgroup]
+            mode = "delegate"
+            memory_max = "4G"
+            cpu_max = "200%"
+
+            [session_cgroup.sessions.build]
+            memory_max = "16G"
+            "#,
+            r#"
             [[keybinding]]
             binding = "Ctrl-q a"
             action = "detach"
             "#,
+            r#"
+            scrollback_pager = "most"
+
+            [[keybinding]]
+            binding = "Ctrl-q s"
+            action = "scrollback"
+            "#,
         ];
 
         for case in cases.into_iter() {
@@ -0,0 +1,228 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Activity monitoring notices when a detached session rings the bell
+//! or starts producing output again after going quiet, so that `shpool
+//! list` can point out the sessions that want attention. The flags get
+//! cleared as soon as a client attaches to the session.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tracing::info;
+
+use crate::daemon::config;
+
+const BEL: u8 = 0x07;
+const ESC: u8 = 0x1b;
+
+/// The things that have happened in a session since a client was
+/// last attached to it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Flags {
+    /// The shell rang the bell.
+    pub bell: bool,
+    /// The shell produced output after being idle.
+    pub activity: bool,
+}
+
+impl Flags {
+    /// A description of the flags like "bell, activity", or None
+    /// if no flags are set.
+    pub fn describe(&self) -> Option<String> {
+        let mut parts = vec![];
+        if self.bell {
+            parts.push("bell");
+        }
+        if self.activity {
+            parts.push("activity");
+        }
+        if parts.is_empty() { None } else { Some(parts.join(", ")) }
+    }
+}
+
+/// Where we are in the escape code grammar. We only care about
+/// telling a real bell apart from a BEL which ends an OSC string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ScanState {
+    Ground,
+    Escape,
+    String,
+    StringEscape,
+}
+
+/// Watches the output of a session from within the reader
+/// and keeps the session's shared flags up to date.
+pub struct Monitor {
+    flags: Arc<Mutex<Flags>>,
+    idle_threshold: Duration,
+    last_output: Option<Instant>,
+    state: ScanState,
+}
+
+impl Monitor {
+    pub fn new(flags: Arc<Mutex<Flags>>, idle_threshold: Duration) -> Self {
+        Monitor { flags, idle_threshold, last_output: None, state: ScanState::Ground }
+    }
+
+    /// Record a chunk of output from the shell.
+    pub fn output(&mut self, buf: &[u8], attached: bool) {
+        self.output_at(buf, attached, Instant::now())
+    }
+
+    fn output_at(&mut self, buf: &[u8], attached: bool, now: Instant) {
+        let mut bell = false;
+        for byte in buf.iter() {
+            self.state = match (self.state, *byte) {
+                (ScanState::Ground, BEL) => {
+                    bell = true;
+                    ScanState::Ground
+                }
+                (ScanState::Ground, ESC) => ScanState::Escape,
+                (ScanState::Ground, _) => ScanState::Ground,
+                // OSC, DCS, SOS, PM and APC all introduce a string
+                // which may be terminated by a BEL.
+                (ScanState::Escape, b']' | b'P' | b'X' | b'^' | b'_') => ScanState::String,
+                (ScanState::Escape, ESC) => ScanState::Escape,
+                (ScanState::Escape, _) => ScanState::Ground,
+                (ScanState::String, BEL) => ScanState::Ground,
+                (ScanState::String, ESC) => ScanState::StringEscape,
+                (ScanState::String, _) => ScanState::String,
+                (ScanState::StringEscape, b'\\') => ScanState::Ground,
+                (ScanState::StringEscape, ESC) => ScanState::StringEscape,
+                (ScanState::StringEscape, _) => ScanState::String,
+            };
+        }
+
+        let was_idle = self
+            .last_output
+            .map(|last| now.saturating_duration_since(last) >= self.idle_threshold)
+            .unwrap_or(true);
+        self.last_output = Some(now);
+        if attached || buf.is_empty() {
+            return;
+        }
+
+        let mut flags = self.flags.lock().unwrap();
+        if bell && !flags.bell {
+            info!("detached session rang the bell");
+            flags.bell = true;
+        }
+        if was_idle && !flags.activity {
+            info!("detached session became active");
+            flags.activity = true;
+        }
+    }
+
+    /// Clear the flags because a client has attached, returning the
+    /// codes to send the client to tell it about them, if any.
+    pub fn attached(&mut self, session_name: &str, mode: &config::ReattachNotify) -> Vec<u8> {
+        let flags = std::mem::take(&mut *self.flags.lock().unwrap());
+        let what = match flags.describe() {
+            Some(what) => what,
+            None => return vec![],
+        };
+
+        match mode {
+            config::ReattachNotify::None => vec![],
+            config::ReattachNotify::Bell if flags.bell => vec![BEL],
+            config::ReattachNotify::Bell => vec![],
+            config::ReattachNotify::Osc9 => {
+                format!("\x1b]9;shpool({}): {}\x07", session_name, what).into_bytes()
+            }
+            config::ReattachNotify::Osc777 => {
+                format!("\x1b]777;notify;shpool;{}: {}\x07", session_name, what).into_bytes()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn monitor(idle_secs: u64) -> (Monitor, Arc<Mutex<Flags>>) {
+        let flags = Arc::new(Mutex::new(Flags::default()));
+        (Monitor::new(Arc::clone(&flags), Duration::from_secs(idle_secs)), flags)
+    }
+
+    #[test]
+    fn bell() {
+        let cases: Vec<(&[&[u8]], bool)> = vec![
+            (&[b"foo"], false),
+            (&[b"foo\x07"], true),
+            // a BEL terminating an OSC is not a bell
+            (&[b"\x1b]0;title\x07foo"], false),
+            (&[b"\x1b]0;ti", b"tle\x07"], false),
+            (&[b"\x1b]0;title\x1b\\\x07"], true),
+            (&[b"\x1bP1$r\x1b\\"], false),
+            (&[b"\x1b[1m\x07"], true),
+            (&[b"\x1b]0;title\x07", b"\x07"], true),
+        ];
+        for (chunks, want_bell) in cases.into_iter() {
+            let (mut monitor, flags) = monitor(0);
+            for chunk in chunks.iter() {
+                monitor.output(chunk, false);
+            }
+            assert_eq!(flags.lock().unwrap().bell, want_bell, "chunks={:?}", chunks);
+        }
+    }
+
+    #[test]
+    fn activity() {
+        let (mut monitor, flags) = monitor(10);
+        let start = Instant::now();
+        monitor.output_at(b"prompt> ", true, start);
+        monitor.output_at(b"foo", false, start + Duration::from_secs(1));
+        assert_eq!(*flags.lock().unwrap(), Flags::default());
+
+        monitor.output_at(b"foo", false, start + Duration::from_secs(20));
+        assert_eq!(*flags.lock().unwrap(), Flags { bell: false, activity: true });
+    }
+
+    #[test]
+    fn attached_output_ignored() {
+        let (mut monitor, flags) = monitor(0);
+        monitor.output(b"\x07", true);
+        assert_eq!(*flags.lock().unwrap(), Flags::default());
+    }
+
+    #[test]
+    fn attach_notifications() {
+        let cases = vec![
+            (config::ReattachNotify::None, Flags { bell: true, activity: true }, ""),
+            (config::ReattachNotify::Bell, Flags { bell: true, activity: true }, "\x07"),
+            (config::ReattachNotify::Bell, Flags { bell: false, activity: true }, ""),
+            (
+                config::ReattachNotify::Osc9,
+                Flags { bell: true, activity: true },
+                "\x1b]9;shpool(main): bell, activity\x07",
+            ),
+            (
+                config::ReattachNotify::Osc777,
+                Flags { bell: false, activity: true },
+                "\x1b]777;notify;shpool;main: activity\x07",
+            ),
+            (config::ReattachNotify::Osc9, Flags::default(), ""),
+        ];
+        for (mode, set_flags, want) in cases.into_iter() {
+            let (mut monitor, flags) = monitor(0);
+            *flags.lock().unwrap() = set_flags;
+            assert_eq!(monitor.attached("main", &mode), want.as_bytes(), "mode={:?}", mode);
+            assert_eq!(*flags.lock().unwrap(), Flags::default());
+        }
+    }
+}
@@ -0,0 +1,564 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Session cgroups put the process tree of each session in a cgroup
+  of its own, so that a runaway build in one session gets limited on
+  its own rather than having its memory use pinned on the daemon.
+
+  There are two ways to get the cgroups. In `scope` mode, we ask
+  systemd to start a transient scope unit for each shell, which works
+  anywhere systemd manages the user's processes. In `delegate` mode, we
+  manage a cgroup v2 subtree ourselves, which needs the daemon's cgroup
+  to be delegated to it. Since cgroup v2 does not allow processes in a
+  cgroup that hands out controllers to child cgroups, the daemon moves
+  itself into a `daemon` leaf cgroup next to the session cgroups.
+
+  Either way, the shell gets held back from exec'ing until it has been
+  placed, so that everything it runs lands in the session cgroup. The
+  placing happens on a thread of its own so that a slow systemd holds
+  up the shell rather than the attach. If the shell can't be placed,
+  including when systemd does not get it into its scope within
+  `SCOPE_TIMEOUT`, it gets let go anyway and runs unconfined in the
+  daemon's cgroup.
+*/
+
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::{self, Read, Write},
+    os::{fd::OwnedFd, unix::fs::PermissionsExt},
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+    thread, time,
+};
+
+use anyhow::{anyhow, bail, Context};
+use tracing::{debug, info, instrument, warn};
+
+use crate::{config, size};
+
+// The leaf cgroup the daemon moves into in delegate mode.
+const DAEMON_LEAF: &str = "daemon";
+// The prefix for session cgroups in delegate mode.
+const SESSION_PREFIX: &str = "session-";
+// The prefix for session scope units in scope mode.
+const SCOPE_PREFIX: &str = "shpool-";
+// How long to wait for systemd to move a shell into its scope.
+const SCOPE_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+// The period to use for cpu.max, the same as the kernel default.
+const CPU_PERIOD_USEC: u64 = 100_000;
+// Keep cgroup and unit names to a sane length.
+const MAX_NAME_LEN: usize = 64;
+
+pub struct Manager {
+    mode: config::CgroupMode,
+    limits: Limits,
+    session_limits: HashMap<String, Limits>,
+    /// Where the cgroup v2 hierarchy is mounted.
+    mount: PathBuf,
+    /// The cgroup the daemon was started in. In delegate mode, session
+    /// cgroups get created directly under it.
+    root: PathBuf,
+    /// Whether to talk to the user's systemd instance rather than the
+    /// system one in scope mode.
+    user_manager: bool,
+    /// The busctl binary to start scopes with. Only set in scope mode.
+    busctl: Option<PathBuf>,
+}
+
+/// The resource limits for a single session.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Limits {
+    memory_max: Option<u64>,
+    cpu_max_percent: Option<u64>,
+}
+
+/// How much a session's cgroup has used so far.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Usage {
+    pub memory_bytes: Option<u64>,
+    pub cpu_usage_usec: Option<u64>,
+}
+
+impl Manager {
+    /// Set up to put sessions in cgroups according to the given config.
+    /// In delegate mode, this moves the daemon into its leaf cgroup.
+    #[instrument(skip_all)]
+    pub fn new(config: &config::SessionCgroupConfig) -> anyhow::Result<Self> {
+        let limits = Limits::parse(config.memory_max.as_deref(), config.cpu_max.as_deref())
+            .context("parsing session_cgroup limits")?;
+        let mut session_limits = HashMap::new();
+        for (session, session_config) in config.sessions.iter().flatten() {
+            let overrides = Limits::parse(
+                session_config.memory_max.as_deref(),
+                session_config.cpu_max.as_deref(),
+            )
+            .with_context(|| format!("parsing session_cgroup limits for '{}'", session))?;
+            session_limits.insert(session.clone(), overrides.or(limits));
+        }
+
+        let mode = config.mode.unwrap_or_default();
+        let busctl = match mode {
+            config::CgroupMode::Scope => Some(find_in_path("busctl").ok_or(anyhow!(
+                "scope mode needs busctl to talk to systemd, but it is not in PATH \
+                 (mode = \"delegate\" does not need it)"
+            ))?),
+            config::CgroupMode::Delegate => None,
+        };
+
+        let mountinfo = fs::read_to_string("/proc/self/mountinfo").context("reading mountinfo")?;
+        let mount = cgroup2_mount(&mountinfo).ok_or(anyhow!("no cgroup v2 hierarchy mounted"))?;
+        let own_cgroup = cgroup_of(process::id() as libc::pid_t)
+            .ok_or(anyhow!("could not find the daemon's cgroup"))?;
+        let manager = Manager {
+            mode,
+            limits,
+            session_limits,
+            root: mount.join(own_cgroup),
+            mount,
+            user_manager: !nix::unistd::geteuid().is_root(),
+            busctl,
+        };
+        info!("mode={:?} root={:?}", manager.mode, manager.root);
+
+        if manager.mode == config::CgroupMode::Delegate {
+            manager.delegate().context("setting up delegated cgroup")?;
+        }
+
+        Ok(manager)
+    }
+
+    /// Move the daemon out of the way and enable the controllers we
+    /// need for the session cgroups.
+    fn delegate(&self) -> anyhow::Result<()> {
+        let leaf = self.root.join(DAEMON_LEAF);
+        create_dir_if_missing(&leaf)?;
+        fs::write(leaf.join("cgroup.procs"), process::id().to_string())
+            .context("moving daemon into its leaf cgroup")?;
+
+        let available = fs::read_to_string(self.root.join("cgroup.controllers"))
+            .context("reading available controllers")?;
+        let enable = ["memory", "cpu"]
+            .iter()
+            .filter(|c| available.split_whitespace().any(|a| a == **c))
+            .map(|c| format!("+{}", c))
+            .collect::<Vec<_>>();
+        if enable.len() < 2 {
+            warn!("only got controllers {:?}, some limits will not apply", enable);
+        }
+        if !enable.is_empty() {
+            fs::write(self.root.join("cgroup.subtree_control"), enable.join(" "))
+                .context("enabling controllers")?;
+        }
+
+        // clean up after sessions from an earlier daemon, any that still
+        // have processes in them will just stay put
+        for entry in fs::read_dir(&self.root).context("listing cgroups")? {
+            let entry = entry.context("listing cgroups")?;
+            if entry.file_name().to_string_lossy().starts_with(SESSION_PREFIX) {
+                let _ = fs::remove_dir(entry.path());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Put the given process, which is waiting on the other end of the
+    /// gate, into a cgroup for the named session on a thread of its own,
+    /// then let it go ahead and exec. It gets let go whether or not it
+    /// could be placed.
+    pub fn place_and_release(
+        self: &Arc<Self>,
+        session: String,
+        pid: libc::pid_t,
+        gate: OwnedFd,
+    ) -> anyhow::Result<()> {
+        let manager = Arc::clone(self);
+        thread::Builder::new()
+            .name(format!("cgroup({})", session))
+            .spawn(move || {
+                if let Err(err) = manager.place(&session, pid) {
+                    warn!("running shell outside of a session cgroup: {:?}", err);
+                }
+                release(gate);
+            })
+            .context("spawning cgroup placement thread")?;
+        Ok(())
+    }
+
+    /// Put the given process, which must not have exec'd yet, into a
+    /// cgroup for the named session.
+    #[instrument(skip_all, fields(s = session, pid = pid))]
+    fn place(&self, session: &str, pid: libc::pid_t) -> anyhow::Result<()> {
+        let limits = self.session_limits.get(session).copied().unwrap_or(self.limits);
+        info!("limits={:?}", limits);
+        match self.mode {
+            config::CgroupMode::Scope => self.start_scope(session, pid, limits),
+            config::CgroupMode::Delegate => self.create_cgroup(session, pid, limits),
+        }
+    }
+
+    fn start_scope(&self, session: &str, pid: libc::pid_t, limits: Limits) -> anyhow::Result<()> {
+        let unit = format!("{}{}-{}.scope", SCOPE_PREFIX, escape(session), pid);
+        // each property is its name, its type signature and then its value,
+        // which for an array is the length followed by the elements
+        let mut props: Vec<Vec<String>> = vec![
+            vec![String::from("PIDs"), String::from("au"), String::from("1"), pid.to_string()],
+            vec![
+                String::from("Description"),
+                String::from("s"),
+                format!("shpool session {}", session),
+            ],
+            vec![
+                String::from("CollectMode"),
+                String::from("s"),
+                String::from("inactive-or-failed"),
+            ],
+        ];
+        if let Some(bytes) = limits.memory_max {
+            props.push(vec![String::from("MemoryMax"), String::from("t"), bytes.to_string()]);
+        }
+        if let Some(percent) = limits.cpu_max_percent {
+            // systemd wants cpu time per second, so 100% is 1s
+            props.push(vec![
+                String::from("CPUQuotaPerSecUSec"),
+                String::from("t"),
+                (percent * 10_000).to_string(),
+            ]);
+        }
+
+        let busctl = self.busctl.as_ref().ok_or(anyhow!("no busctl to start scopes with"))?;
+        let mut cmd = process::Command::new(busctl);
+        if self.user_manager {
+            cmd.arg("--user");
+        }
+        cmd.args([
+            "call",
+            "org.freedesktop.systemd1",
+            "/org/freedesktop/systemd1",
+            "org.freedesktop.systemd1.Manager",
+            "StartTransientUnit",
+            "ssa(sv)a(sa(sv))",
+            &unit,
+            "fail",
+        ])
+        .arg(props.len().to_string());
+        for prop in props.iter() {
+            cmd.args(prop);
+        }
+        cmd.arg("0");
+        let out = cmd.output().context("running busctl")?;
+        if !out.status.success() {
+            bail!("starting {}: {}", unit, String::from_utf8_lossy(&out.stderr).trim());
+        }
+
+        // The reply only means that the start job got queued, so give
+        // systemd a moment to actually move the process.
+        let deadline = time::Instant::now() + SCOPE_TIMEOUT;
+        while time::Instant::now() < deadline {
+            if cgroup_of(pid).map(|cg| cg.ends_with(&unit)).unwrap_or(false) {
+                info!("placed in {}", unit);
+                return Ok(());
+            }
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        Err(anyhow!("timed out waiting for systemd to start {}", unit))
+    }
+
+    fn create_cgroup(&self, session: &str, pid: libc::pid_t, limits: Limits) -> anyhow::Result<()> {
+        let dir = self.session_cgroup(session, pid);
+        create_dir_if_missing(&dir)?;
+        if let Some(bytes) = limits.memory_max {
+            fs::write(dir.join("memory.max"), bytes.to_string()).context("setting memory.max")?;
+        }
+        if let Some(percent) = limits.cpu_max_percent {
+            let quota = percent * CPU_PERIOD_USEC / 100;
+            fs::write(dir.join("cpu.max"), format!("{} {}", quota, CPU_PERIOD_USEC))
+                .context("setting cpu.max")?;
+        }
+        fs::write(dir.join("cgroup.procs"), pid.to_string()).context("moving shell")?;
+        info!("placed in {:?}", dir);
+        Ok(())
+    }
+
+    /// Clean up after a session whose shell has exited. systemd collects
+    /// scopes by itself once they are empty, so this only does anything
+    /// in delegate mode.
+    pub fn remove(&self, session: &str, pid: libc::pid_t) {
+        if self.mode != config::CgroupMode::Delegate {
+            return;
+        }
+        let dir = self.session_cgroup(session, pid);
+        if let Err(e) = fs::remove_dir(&dir) {
+            // most likely a background job outlived the shell
+            debug!("not removing {:?}: {:?}", dir, e);
+        }
+    }
+
+    fn session_cgroup(&self, session: &str, pid: libc::pid_t) -> PathBuf {
+        self.root.join(format!("{}{}-{}", SESSION_PREFIX, escape(session), pid))
+    }
+
+    /// Report on how much the session cgroup that the given shell is in
+    /// has used, or None if the shell never made it into one.
+    pub fn usage(&self, pid: libc::pid_t) -> Option<Usage> {
+        let cgroup = cgroup_of(pid)?;
+        let name = cgroup.file_name()?.to_string_lossy().into_owned();
+        let is_session = match self.mode {
+            config::CgroupMode::Scope => name.starts_with(SCOPE_PREFIX) && name.ends_with(".scope"),
+            config::CgroupMode::Delegate => name.starts_with(SESSION_PREFIX),
+        };
+        if !is_session {
+            return None;
+        }
+        Some(read_usage(&self.mount.join(cgroup)))
+    }
+}
+
+impl Limits {
+    fn parse(memory_max: Option<&str>, cpu_max: Option<&str>) -> anyhow::Result<Self> {
+        Ok(Limits {
+            memory_max: memory_max.map(size::parse).transpose().context("parsing memory_max")?,
+            cpu_max_percent: cpu_max.map(parse_percent).transpose().context("parsing cpu_max")?,
+        })
+    }
+
+    /// Fill in any limits not set here from the fallback.
+    fn or(self, fallback: Limits) -> Limits {
+        Limits {
+            memory_max: self.memory_max.or(fallback.memory_max),
+            cpu_max_percent: self.cpu_max_percent.or(fallback.cpu_max_percent),
+        }
+    }
+}
+
+/// Parse a cpu limit like 150%.
+fn parse_percent(src: &str) -> anyhow::Result<u64> {
+    let digits = src
+        .trim()
+        .strip_suffix('%')
+        .ok_or(anyhow!("cpu limit '{}' must be a percentage like 150%", src))?;
+    let percent = digits.parse::<u64>().context(format!("could not parse '{}'", src))?;
+    if percent == 0 {
+        bail!("cpu limit must be more than 0%");
+    }
+    Ok(percent)
+}
+
+/// Block until the daemon has placed us in our cgroup. This runs in the
+/// forked shell process before it execs.
+pub fn wait_for_placement(gate: OwnedFd) {
+    let mut gate = fs::File::from(gate);
+    let mut buf = [0; 1];
+    loop {
+        match gate.read(&mut buf) {
+            // a byte means we have been placed, EOF means the daemon
+            // gave up on placing us, either way we go on with the exec
+            Ok(_) => return,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => return,
+        }
+    }
+}
+
+/// Let the forked shell go ahead and exec.
+pub fn release(gate: OwnedFd) {
+    let mut gate = fs::File::from(gate);
+    if let Err(e) = gate.write_all(&[1]) {
+        // dropping the gate will still release the shell
+        warn!("releasing shell: {:?}", e);
+    }
+}
+
+/// Find the named executable in PATH.
+fn find_in_path(bin: &str) -> Option<PathBuf> {
+    env::split_paths(&env::var_os("PATH")?).map(|dir| dir.join(bin)).find(|path| {
+        fs::metadata(path)
+            .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    })
+}
+
+/// Make a session name safe to use in a cgroup or unit name.
+fn escape(session: &str) -> String {
+    session
+        .chars()
+        .take(MAX_NAME_LEN)
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '.' { c } else { '_' })
+        .collect()
+}
+
+fn create_dir_if_missing(dir: &Path) -> anyhow::Result<()> {
+    match fs::create_dir(dir) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => {
+            Err(e).context(format!("creating cgroup {:?}", dir))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Find where the cgroup v2 hierarchy is mounted from the contents
+/// of /proc/self/mountinfo.
+fn cgroup2_mount(mountinfo: &str) -> Option<PathBuf> {
+    mountinfo.lines().find_map(|line| {
+        let (mount, fs) = line.split_once(" - ")?;
+        if fs.split_whitespace().next()? != "cgroup2" {
+            return None;
+        }
+        mount.split_whitespace().nth(4).map(PathBuf::from)
+    })
+}
+
+/// The cgroup v2 path of the given process, relative to the mount.
+fn cgroup_of(pid: libc::pid_t) -> Option<PathBuf> {
+    let cgroups = fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?;
+    parse_cgroup(&cgroups)
+}
+
+fn parse_cgroup(cgroups: &str) -> Option<PathBuf> {
+    cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(|path| PathBuf::from(path.trim_start_matches('/')))
+}
+
+fn read_usage(dir: &Path) -> Usage {
+    let memory_bytes = fs::read_to_string(dir.join("memory.current"))
+        .ok()
+        .and_then(|current| current.trim().parse().ok());
+    let cpu_usage_usec = fs::read_to_string(dir.join("cpu.stat")).ok().and_then(|stat| {
+        stat.lines().find_map(|line| line.strip_prefix("usage_usec ")?.trim().parse().ok())
+    });
+    Usage { memory_bytes, cpu_usage_usec }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_manager(root: &Path, limits: Limits) -> Manager {
+        Manager {
+            mode: config::CgroupMode::Delegate,
+            limits,
+            session_limits: HashMap::new(),
+            mount: PathBuf::from("/sys/fs/cgroup"),
+            root: root.to_path_buf(),
+            user_manager: true,
+            busctl: None,
+        }
+    }
+
+    #[test]
+    fn limits() -> anyhow::Result<()> {
+        let config: config::SessionCgroupConfig = toml::from_str(
+            r#"
+            memory_max = "4G"
+            cpu_max = "150%"
+
+            [sessions.build]
+            memory_max = "16G"
+            "#,
+        )?;
+        let limits = Limits::parse(config.memory_max.as_deref(), config.cpu_max.as_deref())?;
+        assert_eq!(limits, Limits { memory_max: Some(4 << 30), cpu_max_percent: Some(150) });
+
+        let build = &config.sessions.as_ref().unwrap()["build"];
+        let build = Limits::parse(build.memory_max.as_deref(), build.cpu_max.as_deref())?;
+        assert_eq!(
+            build.or(limits),
+            Limits { memory_max: Some(16 << 30), cpu_max_percent: Some(150) }
+        );
+
+        assert!(Limits::parse(None, Some("2")).is_err());
+        assert!(Limits::parse(None, Some("0%")).is_err());
+        assert!(Limits::parse(Some("lots"), None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parsing() {
+        let mountinfo = "\
+            30 24 0:26 / /sys/fs/cgroup/memory rw,relatime - cgroup cgroup rw,memory\n\
+            35 24 0:31 / /sys/fs/cgroup/unified rw,relatime - cgroup2 cgroup2 rw\n";
+        assert_eq!(cgroup2_mount(mountinfo), Some(PathBuf::from("/sys/fs/cgroup/unified")));
+        assert_eq!(cgroup2_mount("30 24 0:26 / /proc rw - proc proc rw\n"), None);
+
+        let cgroups = "4:memory:/foo\n0::/user.slice/user-1000.slice/shpool.service\n";
+        assert_eq!(
+            parse_cgroup(cgroups),
+            Some(PathBuf::from("user.slice/user-1000.slice/shpool.service"))
+        );
+        assert_eq!(parse_cgroup("4:memory:/foo\n"), None);
+
+        assert_eq!(escape("main"), "main");
+        assert_eq!(escape("my session/2"), "my_session_2");
+    }
+
+    #[test]
+    fn delegated_session() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let manager = test_manager(
+            tmp.path(),
+            Limits { memory_max: Some(1 << 30), cpu_max_percent: Some(50) },
+        );
+
+        manager.place("a b", 1234)?;
+        let dir = tmp.path().join("session-a_b-1234");
+        assert_eq!(fs::read_to_string(dir.join("memory.max"))?, "1073741824");
+        assert_eq!(fs::read_to_string(dir.join("cpu.max"))?, "50000 100000");
+        assert_eq!(fs::read_to_string(dir.join("cgroup.procs"))?, "1234");
+
+        fs::write(dir.join("memory.current"), "4096\n")?;
+        fs::write(dir.join("cpu.stat"), "usage_usec 2500000\nuser_usec 2000000\n")?;
+        assert_eq!(
+            read_usage(&dir),
+            Usage { memory_bytes: Some(4096), cpu_usage_usec: Some(2_500_000) }
+        );
+        assert_eq!(read_usage(tmp.path()), Usage::default());
+
+        Ok(())
+    }
+
+    #[test]
+    fn scope_timeout() -> anyhow::Result<()> {
+        // A busctl that claims to have started the scope without systemd
+        // ever moving the shell into it.
+        let tmp = tempfile::tempdir()?;
+        let manager = Arc::new(Manager {
+            mode: config::CgroupMode::Scope,
+            busctl: Some(find_in_path("true").context("finding true")?),
+            ..test_manager(tmp.path(), Limits::default())
+        });
+        let mut shell = process::Command::new("sleep").arg("30").spawn()?;
+        let pid = shell.id() as libc::pid_t;
+        let own_cgroup = cgroup_of(pid);
+
+        let (gate_rx, gate_tx) = nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC)?;
+        let start = time::Instant::now();
+        manager.place_and_release(String::from("slow"), pid, gate_tx)?;
+        // placing happens in the background
+        assert!(start.elapsed() < SCOPE_TIMEOUT);
+
+        // the shell gets let go once systemd times out, still unconfined
+        wait_for_placement(gate_rx);
+        assert!(start.elapsed() >= SCOPE_TIMEOUT);
+        assert_eq!(cgroup_of(pid), own_cgroup);
+        assert_eq!(manager.usage(pid), None);
+
+        shell.kill()?;
+        shell.wait()?;
+        Ok(())
+    }
+}
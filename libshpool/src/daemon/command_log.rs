@@ -0,0 +1,181 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The command log keeps track of the commands run in a shell session
+//! based on the OSC 133 semantic prompt marks which the prompt prefix
+//! injection code teaches the shell to emit. Shells that don't emit
+//! the marks just never have any commands recorded.
+
+use std::time;
+
+use tracing::info;
+
+// The most output we will hang on to for the last command. Past this
+// we drop the oldest output.
+const MAX_LAST_COMMAND_OUTPUT_BYTES: usize = 1024 * 1024;
+
+/// A single command run at the shell prompt.
+#[derive(Debug, Clone)]
+pub struct Command {
+    /// The command line, if the shell reported it.
+    pub cmdline: Option<String>,
+    pub started_at: time::SystemTime,
+}
+
+/// The running command and the outcome of the most recently finished
+/// command for a session. Shared between the reader, which
+/// updates it, and the server, which reports on it.
+#[derive(Debug, Default)]
+pub struct CommandLog {
+    running: Option<Command>,
+    last_exit_status: Option<i32>,
+}
+
+impl CommandLog {
+    /// Record that the shell has started executing a command.
+    pub fn executed(&mut self, cmdline: Option<String>) {
+        if let Some(cmd) = self.running.as_mut() {
+            // Some shells emit their own marks in addition to the ones
+            // we inject, so treat a repeated mark as more info about
+            // the same command.
+            if cmd.cmdline.is_none() {
+                cmd.cmdline = cmdline;
+            }
+            return;
+        }
+
+        self.running = Some(Command { cmdline, started_at: time::SystemTime::now() });
+    }
+
+    /// Record that the running command has finished. The shell reports
+    /// a finish before the very first prompt, so a finish without a
+    /// running command is ignored.
+    pub fn finished(&mut self, exit_status: Option<i32>) {
+        if let Some(command) = self.running.take() {
+            info!(
+                "command finished (exit_status={:?}, duration={:?})",
+                exit_status,
+                command.started_at.elapsed().unwrap_or_default()
+            );
+            self.last_exit_status = exit_status;
+        }
+    }
+
+    /// The currently running command, if any.
+    pub fn running(&self) -> Option<&Command> {
+        self.running.as_ref()
+    }
+
+    /// The exit status of the most recently finished command, if the
+    /// shell reported it.
+    pub fn last_exit_status(&self) -> Option<i32> {
+        self.last_exit_status
+    }
+}
+
+/// The raw shell output starting with the output of the most recent
+/// command. This is owned by the reader and used to restore or
+/// capture just the last command's output.
+#[derive(Debug, Default)]
+pub struct LastCommandOutput {
+    /// Everything the shell has output since the last command started
+    /// executing, or None if no command has been seen yet.
+    buf: Option<Vec<u8>>,
+    /// The length of the prefix of buf which is the actual output of
+    /// the command, or None if it is still running.
+    output_len: Option<usize>,
+}
+
+impl LastCommandOutput {
+    /// Mark the start of a fresh command's output.
+    pub fn start(&mut self) {
+        self.buf = Some(vec![]);
+        self.output_len = None;
+    }
+
+    /// Mark the end of the current command's output.
+    pub fn finish(&mut self) {
+        if let (Some(buf), None) = (self.buf.as_ref(), self.output_len) {
+            self.output_len = Some(buf.len());
+        }
+    }
+
+    /// Record some output from the shell.
+    pub fn push(&mut self, bytes: &[u8]) {
+        let buf = match self.buf.as_mut() {
+            Some(buf) => buf,
+            None => return,
+        };
+        buf.extend_from_slice(bytes);
+        if buf.len() > MAX_LAST_COMMAND_OUTPUT_BYTES {
+            let excess = buf.len() - MAX_LAST_COMMAND_OUTPUT_BYTES;
+            buf.drain(..excess);
+            self.output_len = self.output_len.map(|l| l.saturating_sub(excess));
+        }
+    }
+
+    /// The output of the last command, or whatever output it has
+    /// produced so far if it is still running.
+    pub fn output(&self) -> Option<&[u8]> {
+        let buf = self.buf.as_ref()?;
+        Some(&buf[..self.output_len.unwrap_or(buf.len())])
+    }
+
+    /// The output of the last command along with everything that the
+    /// shell has output since (usually the next prompt).
+    pub fn since_start(&self) -> Option<&[u8]> {
+        self.buf.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn command_lifecycle() {
+        let mut log = CommandLog::default();
+        log.finished(Some(0)); // the initial prompt
+        assert!(log.running().is_none());
+        assert_eq!(log.last_exit_status(), None);
+
+        log.executed(Some(String::from("sleep 10")));
+        log.executed(None);
+        assert_eq!(log.running().and_then(|c| c.cmdline.as_deref()), Some("sleep 10"));
+
+        log.finished(Some(3));
+        assert!(log.running().is_none());
+        assert_eq!(log.last_exit_status(), Some(3));
+    }
+
+    #[test]
+    fn last_command_output() {
+        let mut out = LastCommandOutput::default();
+        out.push(b"prompt> ");
+        assert_eq!(out.output(), None);
+
+        out.start();
+        out.push(b"foo\n");
+        assert_eq!(out.output(), Some(&b"foo\n"[..]));
+        out.finish();
+        out.push(b"prompt> ");
+        assert_eq!(out.output(), Some(&b"foo\n"[..]));
+        assert_eq!(out.since_start(), Some(&b"foo\nprompt> "[..]));
+
+        out.start();
+        out.push(&vec![b'x'; MAX_LAST_COMMAND_OUTPUT_BYTES + 10]);
+        out.finish();
+        assert_eq!(out.output().map(|o| o.len()), Some(MAX_LAST_COMMAND_OUTPUT_BYTES));
+    }
+}
@@ -14,70 +14,284 @@
 
 //! The escape codes module provides an online (trie based) matcher
 //! to scan for escape codes we are interested in in the output of
-//! the subshell. For the moment, we just use this to scan for
-//! the ClearScreen code emitted by the prompt prefix injection shell
-//! code. We need to scan for this to avoid a race that can lead to
-//! the motd getting clobbered when in dump mode.
+//! the subshell. We scan for the ClearScreen code emitted by the
+//! prompt prefix injection shell code to avoid a race that can lead
+//! to the motd getting clobbered when in dump mode, and for the
+//! OSC 133 semantic prompt marks that the same shell code teaches
+//! the shell to emit so that we can keep track of command boundaries.
+//! We also keep track of whether the shell wants focus events, since
+//! the client reports those to us in-band.
 
 use anyhow::{anyhow, Context};
 
 use super::trie::{Trie, TrieCursor};
 
-#[derive(Debug, Clone, Copy)]
+// The longest OSC 133 body we are willing to buffer. Anything longer is
+// almost certainly not a well formed mark, and we don't want some binary
+// garbage in the output stream to make us buffer unboundedly.
+const MAX_OSC_BODY_LEN: usize = 1024 * 4;
+
+const BEL: u8 = 0x07;
+const ESC: u8 = 0x1b;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Code {
     ClearScreen,
+    /// A semantic prompt mark (OSC 133).
+    SemanticPrompt(SemanticPromptMark),
+    /// Focus event reporting (DEC private mode 1004) was turned
+    /// on or off.
+    FocusReporting(bool),
+}
+
+/// The OSC 133 semantic prompt marks, as documented in
+/// https://gitlab.freedesktop.org/Per_Bothner/specifications/blob/master/proposals/semantic-prompts.md
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SemanticPromptMark {
+    /// `OSC 133;A`: the shell is about to draw the prompt.
+    PromptStart,
+    /// `OSC 133;B`: the prompt is done, user input follows.
+    CommandStart,
+    /// `OSC 133;C`: the user has submitted a command, and its output
+    /// follows. Like kitty, we accept an optional `cmdline=<command>`
+    /// or percent encoded `cmdline_url=<command>` parameter.
+    CommandExecuted { cmdline: Option<String> },
+    /// `OSC 133;D[;<exit status>]`: the command has finished.
+    CommandFinished { exit_status: Option<i32> },
+}
+
+/// The sequences that we look for with the trie. Osc133 is just the
+/// prefix of a mark, we parse the rest of it by hand.
+#[derive(Debug, Clone, Copy)]
+enum Pattern {
+    ClearScreen,
+    Osc133,
+    FocusReportingOn,
+    FocusReportingOff,
 }
 
 #[derive(Debug)]
 pub struct Matcher {
-    codes: Trie<u8, Code, Vec<Option<usize>>>,
+    codes: Trie<u8, Pattern, Vec<Option<usize>>>,
     codes_cursor: TrieCursor,
+    /// The body of the OSC 133 mark we are in the middle of, if any.
+    osc_body: Option<Vec<u8>>,
 }
 
 impl Matcher {
     pub fn new(term_db: &termini::TermInfo) -> anyhow::Result<Self> {
-        let clear_code_bytes = match term_db.raw_string_cap(termini::StringCapability::ClearScreen)
-        {
-            Some(code) => Vec::from(code),
-            None => {
-                // If we somehow have a wacky terminfo db with no clear code, we fall
-                // back on xterm clear since we still need something to scan for.
-                let xterm_db =
-                    termini::TermInfo::from_name("xterm").context("building fallback xterm db")?;
-                let code = xterm_db
-                    .raw_string_cap(termini::StringCapability::ClearScreen)
-                    .ok_or(anyhow!("no fallback clear screen code"))?;
-                Vec::from(code)
-            }
-        };
+        let clear_code_bytes = clear_code(term_db)?;
 
         let raw_bindings = vec![
             // We need to scan for the clear code that gets emitted by the prompt prefix
             // shell injection code so that we can make sure that the message of the day
             // won't get clobbered immediately.
-            (clear_code_bytes, Code::ClearScreen),
+            (clear_code_bytes, Pattern::ClearScreen),
+            (Vec::from(&b"\x1b]133;"[..]), Pattern::Osc133),
+            (Vec::from(&b"\x1b[?1004h"[..]), Pattern::FocusReportingOn),
+            (Vec::from(&b"\x1b[?1004l"[..]), Pattern::FocusReportingOff),
         ];
         let mut codes = Trie::new();
         for (raw_bytes, code) in raw_bindings.into_iter() {
             codes.insert(raw_bytes.into_iter(), code);
         }
 
-        Ok(Matcher { codes, codes_cursor: TrieCursor::Start })
+        Ok(Matcher { codes, codes_cursor: TrieCursor::Start, osc_body: None })
     }
 
     pub fn transition(&mut self, byte: u8) -> Option<Code> {
+        if let Some(body) = self.osc_body.as_mut() {
+            // The body is terminated by either BEL or ST (ESC \).
+            let terminated = match (body.last(), byte) {
+                (_, BEL) => true,
+                (Some(&ESC), b'\\') => {
+                    body.pop();
+                    true
+                }
+                _ => false,
+            };
+            if terminated {
+                let body = self.osc_body.take().unwrap_or_default();
+                return parse_semantic_prompt_mark(&body).map(Code::SemanticPrompt);
+            }
+
+            body.push(byte);
+            if body.len() > MAX_OSC_BODY_LEN || (body.len() > 1 && body[body.len() - 2] == ESC) {
+                // Either garbage or a fresh escape sequence that got
+                // cut into the middle of our mark. Give up on it.
+                let overflowed = body.len() > MAX_OSC_BODY_LEN;
+                self.osc_body = None;
+                self.codes_cursor = TrieCursor::Start;
+                if !overflowed {
+                    self.transition(ESC);
+                    return self.transition(byte);
+                }
+            }
+            return None;
+        }
+
         self.codes_cursor = self.codes.advance(self.codes_cursor, byte);
+        if let TrieCursor::NoMatch = self.codes_cursor {
+            // The byte which broke the match might be the start of
+            // a fresh code.
+            self.codes_cursor = self.codes.advance(TrieCursor::Start, byte);
+        }
         match self.codes_cursor {
             TrieCursor::NoMatch => {
                 self.codes_cursor = TrieCursor::Start;
                 None
             }
             TrieCursor::Match { is_partial, .. } if !is_partial => {
-                let code = self.codes.get(self.codes_cursor).copied();
+                let pattern = self.codes.get(self.codes_cursor).copied();
                 self.codes_cursor = TrieCursor::Start;
-                code
+                match pattern {
+                    Some(Pattern::ClearScreen) => Some(Code::ClearScreen),
+                    Some(Pattern::Osc133) => {
+                        self.osc_body = Some(vec![]);
+                        None
+                    }
+                    Some(Pattern::FocusReportingOn) => Some(Code::FocusReporting(true)),
+                    Some(Pattern::FocusReportingOff) => Some(Code::FocusReporting(false)),
+                    None => None,
+                }
             }
             _ => None,
         }
     }
 }
+
+/// Parse the part of an OSC 133 mark after the `133;` prefix
+/// and before the terminator. Unknown marks are ignored.
+fn parse_semantic_prompt_mark(body: &[u8]) -> Option<SemanticPromptMark> {
+    let body = String::from_utf8_lossy(body);
+    let mut parts = body.split(';');
+    let kind = parts.next()?;
+    match kind {
+        "A" => Some(SemanticPromptMark::PromptStart),
+        "B" => Some(SemanticPromptMark::CommandStart),
+        "C" => {
+            // The cmdline may itself contain ';', so it must be the
+            // last parameter.
+            let params = body.split_once(';').map(|(_, params)| params).unwrap_or("");
+            let cmdline = if let Some(raw) = params.strip_prefix("cmdline=") {
+                Some(String::from(raw))
+            } else {
+                params.strip_prefix("cmdline_url=").map(percent_decode)
+            };
+            Some(SemanticPromptMark::CommandExecuted { cmdline })
+        }
+        "D" => Some(SemanticPromptMark::CommandFinished {
+            exit_status: parts.next().and_then(|s| s.trim().parse().ok()),
+        }),
+        _ => None,
+    }
+}
+
+fn percent_decode(encoded: &str) -> String {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if let Some(b) = encoded.get(i + 1..i + 3).and_then(|h| u8::from_str_radix(h, 16).ok())
+            {
+                decoded.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// The clear screen code for the given terminfo db. If we somehow have a
+/// wacky terminfo db with no clear code, we fall back on xterm clear since
+/// we still need something to scan for.
+pub fn clear_code(term_db: &termini::TermInfo) -> anyhow::Result<Vec<u8>> {
+    if let Some(code) = term_db.raw_string_cap(termini::StringCapability::ClearScreen) {
+        return Ok(Vec::from(code));
+    }
+
+    let xterm_db = termini::TermInfo::from_name("xterm").context("building fallback xterm db")?;
+    let code = xterm_db
+        .raw_string_cap(termini::StringCapability::ClearScreen)
+        .ok_or(anyhow!("no fallback clear screen code"))?;
+    Ok(Vec::from(code))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn scan(input: &[u8]) -> anyhow::Result<Vec<Code>> {
+        let term_db = termini::TermInfo::from_name("xterm").context("building xterm db")?;
+        let mut matcher = Matcher::new(&term_db)?;
+        Ok(input.iter().filter_map(|b| matcher.transition(*b)).collect())
+    }
+
+    #[test]
+    fn semantic_prompt_marks() -> anyhow::Result<()> {
+        use SemanticPromptMark::*;
+        let cases = vec![
+            (&b"\x1b]133;A\x07"[..], vec![Code::SemanticPrompt(PromptStart)]),
+            (&b"foo\x1b]133;B\x1b\\bar"[..], vec![Code::SemanticPrompt(CommandStart)]),
+            (&b"\x1b]133;C\x07"[..], vec![Code::SemanticPrompt(CommandExecuted { cmdline: None })]),
+            (
+                &b"\x1b]133;C;cmdline=echo a;b\x07"[..],
+                vec![Code::SemanticPrompt(CommandExecuted {
+                    cmdline: Some(String::from("echo a;b")),
+                })],
+            ),
+            (
+                &b"\x1b]133;C;cmdline_url=echo%20hi\x07"[..],
+                vec![Code::SemanticPrompt(CommandExecuted {
+                    cmdline: Some(String::from("echo hi")),
+                })],
+            ),
+            (
+                &b"\x1b]133;D;127\x07"[..],
+                vec![Code::SemanticPrompt(CommandFinished { exit_status: Some(127) })],
+            ),
+            (
+                &b"\x1b]133;D\x07"[..],
+                vec![Code::SemanticPrompt(CommandFinished { exit_status: None })],
+            ),
+            // a mark immediately after a broken escape sequence
+            (&b"\x1b]13\x1b]133;A\x07"[..], vec![Code::SemanticPrompt(PromptStart)]),
+            // a mark cut off by some other escape sequence
+            (&b"\x1b]133;C\x1b[H\x1b[2J"[..], vec![Code::ClearScreen]),
+            // unknown marks are ignored
+            (&b"\x1b]133;Z\x07\x1b]133;A\x07"[..], vec![Code::SemanticPrompt(PromptStart)]),
+        ];
+
+        for (input, want) in cases.into_iter() {
+            assert_eq!(scan(input)?, want, "input={:?}", String::from_utf8_lossy(input));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn focus_reporting() -> anyhow::Result<()> {
+        assert_eq!(
+            scan(&b"vim\x1b[?1004hstuff\x1b[?1004l"[..])?,
+            vec![Code::FocusReporting(true), Code::FocusReporting(false)]
+        );
+        // other private modes are not focus reporting
+        assert_eq!(scan(&b"\x1b[?1049h\x1b[?100h"[..])?, vec![]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn unterminated_mark() -> anyhow::Result<()> {
+        let mut input = Vec::from(&b"\x1b]133;C;cmdline="[..]);
+        input.extend(std::iter::repeat(b'x').take(MAX_OSC_BODY_LEN * 2));
+        input.extend(&b"\x07\x1b]133;B\x07"[..]);
+        assert_eq!(scan(&input)?, vec![Code::SemanticPrompt(SemanticPromptMark::CommandStart)]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,613 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! The event loops drive sessions from a small, fixed pool of threads.
+
+  Each loop waits in epoll until one of the fds its drivers are
+  watching becomes ready, another thread wakes one of its drivers up,
+  or one of its drivers reaches a deadline it asked for. That way an
+  idle session costs a few fds rather than a handful of threads that
+  wake up every so often to check whether anything has happened, and
+  nothing has to wait for the next poll to come around before getting
+  handled.
+
+  Drivers must never block, since that would hold up every other
+  driver on the same loop.
+*/
+
+use std::{
+    collections::HashMap,
+    fmt,
+    os::fd::{AsRawFd, BorrowedFd, RawFd},
+    panic,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::Instant,
+};
+
+use anyhow::{anyhow, Context};
+use nix::{
+    errno::Errno,
+    sys::{
+        epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags, EpollTimeout},
+        eventfd::{EfdFlags, EventFd},
+    },
+};
+use tracing::{error, instrument, span, warn, Level};
+
+// The most events to pick up from a single epoll_wait.
+const MAX_EVENTS: usize = 64;
+
+// The epoll data for the eventfd that wakes a loop up. Driver ids
+// start at 1 so that they never collide with it.
+const WAKE_DATA: u64 = 0;
+
+/// Tells apart the fds that a single driver is watching.
+pub type Token = u8;
+
+/// What a driver wants to hear about an fd.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Interest {
+    pub read: bool,
+    pub write: bool,
+}
+
+impl Interest {
+    fn flags(&self) -> EpollFlags {
+        let mut flags = EpollFlags::empty();
+        if self.read {
+            flags |= EpollFlags::EPOLLIN | EpollFlags::EPOLLRDHUP;
+        }
+        if self.write {
+            flags |= EpollFlags::EPOLLOUT;
+        }
+        flags
+    }
+}
+
+/// Something that happened to one of the fds a driver is watching.
+#[derive(Debug, Clone, Copy)]
+pub struct Ready {
+    pub token: Token,
+    pub readable: bool,
+    pub writable: bool,
+    pub hangup: bool,
+}
+
+/// What a driver wants to happen once it has run.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Next {
+    /// Run again once something happens, or at the given time if
+    /// nothing happens before then.
+    Wait(Option<Instant>),
+    /// The driver is finished. It stops watching all of its fds
+    /// and gets dropped.
+    Done,
+}
+
+/// A state machine that runs on an event loop.
+pub trait Driver: Send {
+    /// Respond to whatever has happened since the last run, which is
+    /// some combination of the fds in `ready` becoming ready, getting
+    /// woken up by a `Waker` and reaching the deadline. The first run
+    /// happens as soon as the driver is started, with nothing ready,
+    /// which is where it should start watching its fds.
+    ///
+    /// An fd must stay open for as long as it is being watched.
+    fn drive(&mut self, fds: &mut Fds, ready: &[Ready]) -> anyhow::Result<Next>;
+}
+
+/// The fds that a driver is watching.
+pub struct Fds<'loop_> {
+    epoll: &'loop_ Epoll,
+    id: u64,
+    watched: &'loop_ mut HashMap<Token, Watch>,
+}
+
+struct Watch {
+    fd: RawFd,
+    flags: EpollFlags,
+}
+
+impl Fds<'_> {
+    /// Watch an fd, replacing whatever fd was being watched under the
+    /// same token. An empty interest stops watching it altogether.
+    pub fn watch(
+        &mut self,
+        token: Token,
+        fd: BorrowedFd,
+        interest: Interest,
+    ) -> anyhow::Result<()> {
+        let flags = interest.flags();
+        if flags.is_empty() {
+            return self.unwatch(token);
+        }
+
+        let mut event = EpollEvent::new(flags, self.id << 8 | token as u64);
+        match self.watched.get_mut(&token) {
+            Some(w) if w.fd == fd.as_raw_fd() => {
+                if w.flags != flags {
+                    self.epoll.modify(fd, &mut event).context("modifying epoll interest")?;
+                    w.flags = flags;
+                }
+                return Ok(());
+            }
+            Some(_) => self.unwatch(token)?,
+            None => {}
+        }
+        self.epoll.add(fd, event).context("adding fd to epoll")?;
+        self.watched.insert(token, Watch { fd: fd.as_raw_fd(), flags });
+        Ok(())
+    }
+
+    /// Stop watching whatever fd is being watched under the token.
+    pub fn unwatch(&mut self, token: Token) -> anyhow::Result<()> {
+        if let Some(w) = self.watched.remove(&token) {
+            // Safety: drivers keep their fds open for as long as
+            // they are being watched.
+            let fd = unsafe { BorrowedFd::borrow_raw(w.fd) };
+            self.epoll.delete(fd).context("removing fd from epoll")?;
+        }
+        Ok(())
+    }
+
+    fn unwatch_all(&mut self) {
+        let tokens: Vec<Token> = self.watched.keys().copied().collect();
+        for token in tokens {
+            if let Err(e) = self.unwatch(token) {
+                warn!("unwatching fd for finished driver: {:?}", e);
+            }
+        }
+    }
+}
+
+/// A fixed set of event loops, each running on its own thread,
+/// which drivers get spread across.
+pub struct Pool {
+    loops: Vec<Arc<Shared>>,
+    next_id: AtomicU64,
+}
+
+impl Pool {
+    #[instrument(skip_all)]
+    pub fn new(size: usize) -> anyhow::Result<Self> {
+        let mut loops = Vec::with_capacity(size);
+        for i in 0..size.max(1) {
+            let shared = Arc::new(Shared::new().context("creating event loop")?);
+            let loop_shared = Arc::clone(&shared);
+            thread::Builder::new()
+                .name(format!("event_loop({})", i))
+                .spawn(move || run(i, loop_shared))
+                .context("spawning event loop thread")?;
+            loops.push(shared);
+        }
+        Ok(Pool { loops, next_id: AtomicU64::new(WAKE_DATA + 1) })
+    }
+
+    /// Get a waker for a new driver, which lives on whichever loop
+    /// currently has the fewest drivers. The driver itself gets
+    /// started with `Waker::start`, which makes it possible to hand
+    /// out wakers to whoever needs them before it starts.
+    pub fn waker(&self) -> Waker {
+        let shared = self
+            .loops
+            .iter()
+            .min_by_key(|l| l.drivers.load(Ordering::Relaxed))
+            .expect("pools always have at least one loop");
+        Waker { shared: Arc::clone(shared), id: self.next_id.fetch_add(1, Ordering::Relaxed) }
+    }
+}
+
+/// A handle for waking a driver up from another thread.
+#[derive(Clone)]
+pub struct Waker {
+    shared: Arc<Shared>,
+    id: u64,
+}
+
+impl Waker {
+    /// Make the driver run as soon as its loop gets around to it.
+    pub fn wake(&self) {
+        self.shared.inbox.lock().unwrap().woken.push(self.id);
+        if let Err(e) = self.shared.wake.arm() {
+            error!("waking event loop: {:?}", e);
+        }
+    }
+
+    /// Start running a driver on the loop that this waker belongs
+    /// to. The waker can be used to wake the driver up once it has
+    /// started.
+    pub fn start(&self, driver: Box<dyn Driver>) -> anyhow::Result<JoinHandle> {
+        let handle = JoinHandle { finish: Arc::new(Finish::default()) };
+        self.shared.drivers.fetch_add(1, Ordering::Relaxed);
+        self.shared.inbox.lock().unwrap().started.push(Started {
+            id: self.id,
+            driver,
+            finish: Arc::clone(&handle.finish),
+        });
+        self.shared.wake.arm().context("waking event loop")?;
+        Ok(handle)
+    }
+}
+
+impl fmt::Debug for Waker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Waker").field("id", &self.id).finish()
+    }
+}
+
+/// A channel to a driver which wakes the driver up whenever a
+/// message gets sent, or once the sender goes away.
+pub fn channel<T>(waker: &Waker) -> (Sender<T>, crossbeam_channel::Receiver<T>) {
+    // There is room for one message so that senders never have to
+    // wait on the driver to get around to receiving it, though since
+    // they usually wait on an ack anyway, more than that is a waste.
+    let (tx, rx) = crossbeam_channel::bounded(1);
+    (Sender { tx: Some(tx), waker: waker.clone() }, rx)
+}
+
+pub struct Sender<T> {
+    // Only ever None while getting dropped.
+    tx: Option<crossbeam_channel::Sender<T>>,
+    waker: Waker,
+}
+
+impl<T> Sender<T> {
+    pub fn send(&self, msg: T) -> Result<(), crossbeam_channel::SendError<T>> {
+        self.tx.as_ref().expect("sender to be live").send(msg)?;
+        self.waker.wake();
+        Ok(())
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sender").field("waker", &self.waker).finish_non_exhaustive()
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // Drop the sender before waking the driver so that it finds
+        // the channel disconnected.
+        self.tx.take();
+        self.waker.wake();
+    }
+}
+
+/// Waits for a driver to finish, much like a `thread::JoinHandle`.
+pub struct JoinHandle {
+    finish: Arc<Finish>,
+}
+
+#[derive(Default)]
+struct Finish {
+    result: Mutex<Option<anyhow::Result<()>>>,
+    cond: Condvar,
+}
+
+impl JoinHandle {
+    pub fn is_finished(&self) -> bool {
+        self.finish.result.lock().unwrap().is_some()
+    }
+
+    /// Wait for the driver to finish, returning the error it failed
+    /// with, if any. Panics in the driver are turned into errors.
+    pub fn join(self) -> anyhow::Result<()> {
+        let result = self.finish.result.lock().unwrap();
+        let mut result = self.finish.cond.wait_while(result, |r| r.is_none()).unwrap();
+        result.take().expect("finished driver to have a result")
+    }
+}
+
+impl fmt::Debug for JoinHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JoinHandle").field("finished", &self.is_finished()).finish()
+    }
+}
+
+/// The part of a loop that other threads get to poke at.
+struct Shared {
+    epoll: Epoll,
+    wake: EventFd,
+    inbox: Mutex<Inbox>,
+    drivers: AtomicUsize,
+}
+
+#[derive(Default)]
+struct Inbox {
+    woken: Vec<u64>,
+    started: Vec<Started>,
+}
+
+struct Started {
+    id: u64,
+    driver: Box<dyn Driver>,
+    finish: Arc<Finish>,
+}
+
+impl Shared {
+    fn new() -> anyhow::Result<Self> {
+        let epoll = Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC).context("creating epoll")?;
+        let wake = EventFd::from_value_and_flags(0, EfdFlags::EFD_CLOEXEC | EfdFlags::EFD_NONBLOCK)
+            .context("creating eventfd")?;
+        epoll
+            .add(&wake, EpollEvent::new(EpollFlags::EPOLLIN, WAKE_DATA))
+            .context("adding eventfd to epoll")?;
+        Ok(Shared {
+            epoll,
+            wake,
+            inbox: Mutex::new(Inbox::default()),
+            drivers: AtomicUsize::new(0),
+        })
+    }
+}
+
+/// A driver along with the loop's bookkeeping for it.
+struct Slot {
+    driver: Box<dyn Driver>,
+    finish: Arc<Finish>,
+    watched: HashMap<Token, Watch>,
+    deadline: Option<Instant>,
+}
+
+fn run(i: usize, shared: Arc<Shared>) {
+    let _s = span!(Level::INFO, "event_loop", i).entered();
+
+    let mut slots: HashMap<u64, Slot> = HashMap::new();
+    let mut events = vec![EpollEvent::empty(); MAX_EVENTS];
+    let mut ready: HashMap<u64, Vec<Ready>> = HashMap::new();
+    loop {
+        let timeout = match slots.values().filter_map(|s| s.deadline).min() {
+            Some(deadline) => {
+                let wait = deadline.saturating_duration_since(Instant::now());
+                // Round up so that we don't wake up just before the
+                // deadline and then have to spin until it arrives.
+                let ms = wait.as_micros().div_ceil(1000);
+                EpollTimeout::try_from(ms).unwrap_or(EpollTimeout::MAX)
+            }
+            None => EpollTimeout::NONE,
+        };
+        let nevents = match shared.epoll.wait(&mut events, timeout) {
+            Ok(n) => n,
+            Err(Errno::EINTR) => 0,
+            Err(e) => {
+                error!("waiting on epoll: {:?}", e);
+                0
+            }
+        };
+
+        for event in &events[..nevents] {
+            let data = event.data();
+            if data == WAKE_DATA {
+                // Reset the eventfd. The inbox tells us what to do.
+                let _ = shared.wake.read();
+                continue;
+            }
+            let flags = event.events();
+            ready.entry(data >> 8).or_default().push(Ready {
+                token: (data & 0xff) as Token,
+                readable: flags.contains(EpollFlags::EPOLLIN),
+                writable: flags.contains(EpollFlags::EPOLLOUT),
+                hangup: flags.intersects(
+                    EpollFlags::EPOLLHUP | EpollFlags::EPOLLERR | EpollFlags::EPOLLRDHUP,
+                ),
+            });
+        }
+
+        let inbox = {
+            let mut inbox = shared.inbox.lock().unwrap();
+            std::mem::take(&mut *inbox)
+        };
+        let mut to_run: Vec<u64> = inbox.woken;
+        for started in inbox.started {
+            to_run.push(started.id);
+            slots.insert(
+                started.id,
+                Slot {
+                    driver: started.driver,
+                    finish: started.finish,
+                    watched: HashMap::new(),
+                    deadline: None,
+                },
+            );
+        }
+        to_run.extend(ready.keys().copied());
+        let now = Instant::now();
+        to_run.extend(
+            slots
+                .iter()
+                .filter(|(_, s)| s.deadline.map(|d| d <= now).unwrap_or(false))
+                .map(|(id, _)| *id),
+        );
+        to_run.sort_unstable();
+        to_run.dedup();
+
+        for id in to_run {
+            let Some(slot) = slots.get_mut(&id) else {
+                // Woken after finishing, or events from just before
+                // it stopped watching an fd.
+                continue;
+            };
+            let ready = ready.remove(&id).unwrap_or_default();
+            let mut fds = Fds { epoll: &shared.epoll, id, watched: &mut slot.watched };
+            let res = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                slot.driver.drive(&mut fds, &ready)
+            }))
+            .unwrap_or_else(|payload| {
+                let msg = payload
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| String::from("<non-string panic>"));
+                Err(anyhow!("driver panicked: {}", msg))
+            });
+
+            let result = match res {
+                Ok(Next::Wait(deadline)) => {
+                    slot.deadline = deadline;
+                    continue;
+                }
+                Ok(Next::Done) => Ok(()),
+                Err(e) => Err(e),
+            };
+            // Stop watching the fds while the driver is still around
+            // to keep them open.
+            fds.unwatch_all();
+            let slot = slots.remove(&id).expect("slot to be there");
+            drop(slot.driver);
+            shared.drivers.fetch_sub(1, Ordering::Relaxed);
+            *slot.finish.result.lock().unwrap() = Some(result);
+            slot.finish.cond.notify_all();
+        }
+        ready.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::{
+        io::{Read, Write},
+        os::{fd::AsFd, unix::net::UnixStream},
+        time::Duration,
+    };
+
+    struct Ticker {
+        ticks: usize,
+    }
+
+    impl Driver for Ticker {
+        fn drive(&mut self, _fds: &mut Fds, _ready: &[Ready]) -> anyhow::Result<Next> {
+            if self.ticks == 0 {
+                return Ok(Next::Done);
+            }
+            self.ticks -= 1;
+            Ok(Next::Wait(Some(Instant::now() + Duration::from_millis(20))))
+        }
+    }
+
+    #[test]
+    fn deadlines() -> anyhow::Result<()> {
+        let pool = Pool::new(1)?;
+        let start = Instant::now();
+        let handles = (0..3)
+            .map(|_| pool.waker().start(Box::new(Ticker { ticks: 3 })))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        for h in handles {
+            h.join()?;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(60));
+        Ok(())
+    }
+
+    struct Inbox {
+        rx: crossbeam_channel::Receiver<&'static str>,
+        got: Vec<&'static str>,
+    }
+
+    impl Driver for Inbox {
+        fn drive(&mut self, _fds: &mut Fds, _ready: &[Ready]) -> anyhow::Result<Next> {
+            loop {
+                match self.rx.try_recv() {
+                    Ok(msg) => self.got.push(msg),
+                    Err(crossbeam_channel::TryRecvError::Empty) => return Ok(Next::Wait(None)),
+                    Err(crossbeam_channel::TryRecvError::Disconnected) => {
+                        if self.got == vec!["a", "b"] {
+                            return Ok(Next::Done);
+                        }
+                        return Err(anyhow!("got {:?}", self.got));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn channel_wakes() -> anyhow::Result<()> {
+        let pool = Pool::new(2)?;
+        let waker = pool.waker();
+        let (tx, rx) = channel(&waker);
+        let h = waker.start(Box::new(Inbox { rx, got: vec![] }))?;
+        tx.send("a")?;
+        thread::sleep(Duration::from_millis(10));
+        tx.send("b")?;
+        assert!(!h.is_finished());
+        drop(tx);
+        h.join()
+    }
+
+    struct Echo {
+        stream: UnixStream,
+    }
+
+    impl Driver for Echo {
+        fn drive(&mut self, fds: &mut Fds, ready: &[Ready]) -> anyhow::Result<Next> {
+            fds.watch(0, self.stream.as_fd(), Interest { read: true, write: false })?;
+            for _ in ready {
+                let mut buf = [0; 64];
+                let len = self.stream.read(&mut buf)?;
+                if len == 0 {
+                    return Ok(Next::Done);
+                }
+                self.stream.write_all(&buf[..len])?;
+            }
+            Ok(Next::Wait(None))
+        }
+    }
+
+    #[test]
+    fn fds() -> anyhow::Result<()> {
+        let pool = Pool::new(1)?;
+        let (mut client, server) = UnixStream::pair()?;
+        let h = pool.waker().start(Box::new(Echo { stream: server }))?;
+        for msg in ["hello", "world"] {
+            client.write_all(msg.as_bytes())?;
+            let mut buf = [0; 5];
+            client.read_exact(&mut buf)?;
+            assert_eq!(&buf, msg.as_bytes());
+        }
+        client.shutdown(std::net::Shutdown::Write)?;
+        h.join()
+    }
+
+    struct Failer {
+        panic: bool,
+    }
+
+    impl Driver for Failer {
+        fn drive(&mut self, _fds: &mut Fds, _ready: &[Ready]) -> anyhow::Result<Next> {
+            if self.panic {
+                panic!("boom");
+            }
+            Err(anyhow!("bang"))
+        }
+    }
+
+    #[test]
+    fn failures() -> anyhow::Result<()> {
+        let pool = Pool::new(1)?;
+        let err = pool.waker().start(Box::new(Failer { panic: false }))?.join().unwrap_err();
+        assert_eq!(format!("{}", err), "bang");
+        let err = pool.waker().start(Box::new(Failer { panic: true }))?.join().unwrap_err();
+        assert!(format!("{}", err).contains("boom"));
+
+        // the loop keeps going
+        pool.waker().start(Box::new(Ticker { ticks: 1 }))?.join()
+    }
+}
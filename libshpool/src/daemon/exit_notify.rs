@@ -13,10 +13,23 @@
 // limitations under the License.
 
 use std::{
-    sync::{Condvar, Mutex},
+    io,
+    os::fd::{AsFd, FromRawFd, OwnedFd},
+    sync::{Arc, Condvar, Mutex},
+    thread,
     time::Duration,
 };
 
+use anyhow::Context;
+use nix::{
+    errno::Errno,
+    sys::wait::{waitpid, WaitPidFlag, WaitStatus},
+    unistd::Pid,
+};
+use tracing::{info, span, warn, Level};
+
+use super::event_loop;
+
 #[derive(Debug)]
 pub struct ExitNotifier {
     slot: Mutex<Option<i32>>,
@@ -60,3 +73,128 @@ impl ExitNotifier {
         }
     }
 }
+
+/// Reap the child once it exits, then notify about the exit and call
+/// `on_exit`. Uses a pidfd on an event loop where the kernel supports
+/// it, falling back to a thread blocked in waitpid where it does not.
+pub fn watch_child(
+    pool: &event_loop::Pool,
+    name: String,
+    conn_id: usize,
+    pid: libc::pid_t,
+    notifier: Arc<ExitNotifier>,
+    on_exit: Box<dyn FnOnce() + Send>,
+) -> anyhow::Result<()> {
+    let pidfd = match pidfd_open(pid) {
+        Ok(pidfd) => pidfd,
+        Err(e) => {
+            warn!("could not open pidfd, watching child from a thread: {:?}", e);
+            thread::spawn(move || {
+                let _s = span!(Level::INFO, "child_watcher", s = name, cid = conn_id).entered();
+                let status = loop {
+                    match waitpid(Pid::from_raw(pid), None) {
+                        Ok(status) => {
+                            if let Some(status) = exit_status(status) {
+                                break status;
+                            }
+                        }
+                        Err(Errno::EINTR) => {}
+                        Err(e) => {
+                            info!("error waiting on child, using exit status 1: {:?}", e);
+                            break 1;
+                        }
+                    }
+                };
+                reaped(pid, status, &notifier, on_exit);
+            });
+            return Ok(());
+        }
+    };
+
+    pool.waker()
+        .start(Box::new(ChildWatcher {
+            name,
+            conn_id,
+            pid,
+            pidfd,
+            notifier,
+            on_exit: Some(on_exit),
+        }))
+        .context("starting child watcher")?;
+    Ok(())
+}
+
+/// Waits for the pidfd of a child to become readable, which happens
+/// once the child exits.
+struct ChildWatcher {
+    name: String,
+    conn_id: usize,
+    pid: libc::pid_t,
+    pidfd: OwnedFd,
+    notifier: Arc<ExitNotifier>,
+    on_exit: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl event_loop::Driver for ChildWatcher {
+    fn drive(
+        &mut self,
+        fds: &mut event_loop::Fds,
+        ready: &[event_loop::Ready],
+    ) -> anyhow::Result<event_loop::Next> {
+        let _s = span!(Level::INFO, "child_watcher", s = self.name, cid = self.conn_id).entered();
+
+        if ready.is_empty() {
+            fds.watch(0, self.pidfd.as_fd(), event_loop::Interest { read: true, write: false })?;
+            return Ok(event_loop::Next::Wait(None));
+        }
+
+        let status = match waitpid(Pid::from_raw(self.pid), Some(WaitPidFlag::WNOHANG)) {
+            Ok(status) => match exit_status(status) {
+                Some(status) => status,
+                None => return Ok(event_loop::Next::Wait(None)),
+            },
+            Err(e) => {
+                info!("error waiting on child, using exit status 1: {:?}", e);
+                1
+            }
+        };
+        if let Some(on_exit) = self.on_exit.take() {
+            reaped(self.pid, status, &self.notifier, on_exit);
+        }
+        Ok(event_loop::Next::Done)
+    }
+}
+
+fn pidfd_open(pid: libc::pid_t) -> io::Result<OwnedFd> {
+    // Safety: pidfd_open just takes a pid and flags, and hands back
+    // a fresh fd that nothing else owns.
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+/// The exit status of a child that has exited, or None if it is still
+/// around.
+fn exit_status(status: WaitStatus) -> Option<i32> {
+    match status {
+        WaitStatus::Exited(_, status) => Some(status),
+        WaitStatus::Signaled(_, signal, _) => {
+            info!("child killed by {}, using exit status 1", signal);
+            Some(1)
+        }
+        _ => None,
+    }
+}
+
+fn reaped(
+    pid: libc::pid_t,
+    status: i32,
+    notifier: &ExitNotifier,
+    on_exit: Box<dyn FnOnce() + Send>,
+) {
+    info!("reaped child shell {} with exit status {}", pid, status);
+    notifier.notify_exit(status);
+    on_exit();
+}
@@ -0,0 +1,390 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Hook commands let users run shell commands in response to session
+  events straight from their config file, without having to write a
+  wrapper binary around libshpool. The hooks just enqueue the event
+  for a worker thread which runs the commands one at a time, so a slow
+  or wedged command can never block the daemon. Output triggers share
+  the same worker for their commands.
+*/
+
+use std::{
+    os::unix::process::CommandExt,
+    process, thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use nix::{
+    sys::signal::{self, Signal},
+    unistd::Pid,
+};
+use tracing::{info, span, warn, Level};
+
+use crate::{config, hooks};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+// The most events we will buffer up while waiting for earlier hook
+// commands to finish. Past this, new events get dropped.
+const QUEUE_DEPTH: usize = 64;
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A single hook command waiting to be run.
+#[derive(Debug)]
+struct Invocation {
+    event: &'static str,
+    cmd: String,
+    /// The SHPOOL_* variables describing the event.
+    env: Vec<(&'static str, String)>,
+}
+
+/// Work for the worker thread.
+enum Job {
+    Run(Invocation),
+    /// Ack once every job queued before this one is done.
+    Flush(crossbeam_channel::Sender<()>),
+}
+
+/// A handle to the worker thread which runs hook commands.
+#[derive(Clone)]
+pub struct Runner {
+    queue: crossbeam_channel::Sender<Job>,
+    timeout: Duration,
+}
+
+impl Runner {
+    /// Spawn the worker thread, killing any command which runs for
+    /// longer than `timeout_secs` (30 seconds by default).
+    pub fn new(timeout_secs: Option<u64>) -> anyhow::Result<Self> {
+        let timeout = timeout_secs.map(Duration::from_secs).unwrap_or(DEFAULT_TIMEOUT);
+        let (queue_tx, queue_rx) = crossbeam_channel::bounded(QUEUE_DEPTH);
+        thread::Builder::new()
+            .name(String::from("hook_commands"))
+            .spawn(move || {
+                let _s = span!(Level::INFO, "hook_commands").entered();
+                for job in queue_rx.iter() {
+                    match job {
+                        Job::Run(invocation) => {
+                            if let Err(err) = run(&invocation, timeout) {
+                                warn!("running {} hook: {:?}", invocation.event, err);
+                            }
+                        }
+                        Job::Flush(done) => {
+                            let _ = done.send(());
+                        }
+                    }
+                }
+            })
+            .context("spawning hook command worker")?;
+
+        Ok(Runner { queue: queue_tx, timeout })
+    }
+
+    /// Queue up a command to be run on the worker thread.
+    pub fn enqueue(&self, event: &'static str, cmd: &str, env: Vec<(&'static str, String)>) {
+        let invocation = Invocation { event, cmd: String::from(cmd), env };
+        if let Err(err) = self.queue.try_send(Job::Run(invocation)) {
+            warn!("dropping {} hook: {:?}", event, err);
+        }
+    }
+
+    /// Wait for the commands which have already been queued up to
+    /// finish, giving up after `timeout`. Used when the daemon is about
+    /// to exit so that we don't drop events on the floor.
+    pub fn flush(&self, timeout: Duration) {
+        let (done_tx, done_rx) = crossbeam_channel::bounded(1);
+        let deadline = Instant::now() + timeout;
+        if let Err(err) = self.queue.send_deadline(Job::Flush(done_tx), deadline) {
+            warn!("flushing hook commands: {:?}", err);
+            return;
+        }
+        if done_rx.recv_deadline(deadline).is_err() {
+            warn!("timed out waiting for hook commands to finish");
+        }
+    }
+
+    /// Run a command right away on the current thread.
+    fn run_now(&self, event: &'static str, cmd: &str, env: Vec<(&'static str, String)>) {
+        let invocation = Invocation { event, cmd: String::from(cmd), env };
+        if let Err(err) = run(&invocation, self.timeout) {
+            warn!("running {} hook: {:?}", event, err);
+        }
+    }
+}
+
+/// Hooks which run the commands from the `[hooks]` config section
+/// after first calling through to the wrapped hooks.
+pub struct CommandHooks {
+    inner: Box<dyn hooks::Hooks + Send + Sync>,
+    config: config::HooksConfig,
+    runner: Runner,
+}
+
+impl CommandHooks {
+    /// Wrap the given hooks, running the commands on the given runner.
+    pub fn new(
+        inner: Box<dyn hooks::Hooks + Send + Sync>,
+        config: config::HooksConfig,
+        runner: Runner,
+    ) -> Self {
+        CommandHooks { inner, config, runner }
+    }
+
+    fn enqueue(&self, event: &'static str, cmd: &Option<String>, ctx: &hooks::HookContext) {
+        self.enqueue_with_env(event, cmd, context_env(ctx));
+    }
+
+    fn enqueue_with_env(
+        &self,
+        event: &'static str,
+        cmd: &Option<String>,
+        env: Vec<(&'static str, String)>,
+    ) {
+        if let Some(cmd) = cmd {
+            self.runner.enqueue(event, cmd, env);
+        }
+    }
+}
+
+impl hooks::Hooks for CommandHooks {
+    fn on_new_session_with_context(&self, ctx: &hooks::HookContext) -> anyhow::Result<()> {
+        self.enqueue("on_new_session", &self.config.on_new_session, ctx);
+        self.inner.on_new_session_with_context(ctx)
+    }
+
+    fn on_reattach_with_context(&self, ctx: &hooks::HookContext) -> anyhow::Result<()> {
+        self.enqueue("on_reattach", &self.config.on_reattach, ctx);
+        self.inner.on_reattach_with_context(ctx)
+    }
+
+    fn on_busy_with_context(&self, ctx: &hooks::HookContext) -> anyhow::Result<()> {
+        self.enqueue("on_busy", &self.config.on_busy, ctx);
+        self.inner.on_busy_with_context(ctx)
+    }
+
+    fn on_client_disconnect_with_context(&self, ctx: &hooks::HookContext) -> anyhow::Result<()> {
+        self.enqueue("on_client_disconnect", &self.config.on_client_disconnect, ctx);
+        self.inner.on_client_disconnect_with_context(ctx)
+    }
+
+    fn on_shell_disconnect_with_context(&self, ctx: &hooks::HookContext) -> anyhow::Result<()> {
+        self.enqueue("on_shell_disconnect", &self.config.on_shell_disconnect, ctx);
+        self.inner.on_shell_disconnect_with_context(ctx)
+    }
+
+    fn on_session_killed(&self, ctx: &hooks::HookContext) -> anyhow::Result<()> {
+        self.enqueue("on_session_killed", &self.config.on_session_killed, ctx);
+        self.inner.on_session_killed(ctx)
+    }
+
+    fn on_ttl_expired(&self, ctx: &hooks::HookContext) -> anyhow::Result<()> {
+        self.enqueue("on_ttl_expired", &self.config.on_ttl_expired, ctx);
+        self.inner.on_ttl_expired(ctx)
+    }
+
+    fn on_resize(&self, ctx: &hooks::HookContext) -> anyhow::Result<()> {
+        self.enqueue("on_resize", &self.config.on_resize, ctx);
+        self.inner.on_resize(ctx)
+    }
+
+    fn on_keybinding(&self, ctx: &hooks::HookContext, action: &str) -> anyhow::Result<()> {
+        let mut env = context_env(ctx);
+        env.push(("SHPOOL_KEYBINDING_ACTION", String::from(action)));
+        self.enqueue_with_env("on_keybinding", &self.config.on_keybinding, env);
+        self.inner.on_keybinding(ctx, action)
+    }
+
+    fn on_output_trigger(
+        &self,
+        ctx: &hooks::HookContext,
+        pattern: &str,
+        line: &str,
+    ) -> anyhow::Result<()> {
+        self.enqueue_with_env(
+            "on_output_trigger",
+            &self.config.on_output_trigger,
+            trigger_env(ctx, pattern, line),
+        );
+        self.inner.on_output_trigger(ctx, pattern, line)
+    }
+
+    fn on_daemon_start(&self) -> anyhow::Result<()> {
+        self.enqueue_with_env("on_daemon_start", &self.config.on_daemon_start, daemon_env());
+        self.inner.on_daemon_start()
+    }
+
+    fn on_daemon_stop(&self) -> anyhow::Result<()> {
+        // The daemon exits as soon as this returns, so the worker
+        // thread would never get around to running the command.
+        if let Some(cmd) = &self.config.on_daemon_stop {
+            self.runner.run_now("on_daemon_stop", cmd, daemon_env());
+        }
+        self.inner.on_daemon_stop()
+    }
+}
+
+/// The environment for events which don't concern any one session.
+fn daemon_env() -> Vec<(&'static str, String)> {
+    vec![("SHPOOL_HOOK_TIME", chrono::Local::now().to_rfc3339())]
+}
+
+/// The environment for an output trigger firing.
+pub fn trigger_env(
+    ctx: &hooks::HookContext,
+    pattern: &str,
+    line: &str,
+) -> Vec<(&'static str, String)> {
+    let mut env = context_env(ctx);
+    env.push(("SHPOOL_TRIGGER_PATTERN", String::from(pattern)));
+    env.push(("SHPOOL_TRIGGER_MATCH", String::from(line)));
+    env
+}
+
+/// The environment describing a session event, leaving out anything
+/// that is unknown for the event.
+fn context_env(ctx: &hooks::HookContext) -> Vec<(&'static str, String)> {
+    let mut env = vec![
+        ("SHPOOL_SESSION_NAME", ctx.session_name.clone()),
+        ("SHPOOL_HOOK_TIME", chrono::DateTime::<chrono::Local>::from(ctx.timestamp).to_rfc3339()),
+    ];
+    if let Some(pid) = ctx.child_pid {
+        env.push(("SHPOOL_CHILD_PID", pid.to_string()));
+    }
+    if let Some(status) = ctx.exit_status {
+        env.push(("SHPOOL_EXIT_STATUS", status.to_string()));
+    }
+    if let Some(pid) = ctx.client_pid {
+        env.push(("SHPOOL_CLIENT_PID", pid.to_string()));
+    }
+    if let Some(exe) = &ctx.client_exe {
+        env.push(("SHPOOL_CLIENT_EXE", exe.to_string_lossy().into_owned()));
+    }
+    if let Some(size) = ctx.tty_size {
+        env.push(("SHPOOL_TTY_ROWS", size.rows.to_string()));
+        env.push(("SHPOOL_TTY_COLS", size.cols.to_string()));
+    }
+    if let Some(started_at) = ctx.session_started_at {
+        env.push((
+            "SHPOOL_SESSION_STARTED_AT",
+            chrono::DateTime::<chrono::Local>::from(started_at).to_rfc3339(),
+        ));
+    }
+    if let Some(ttl) = ctx.ttl {
+        env.push(("SHPOOL_TTL_SECS", ttl.as_secs().to_string()));
+    }
+    env
+}
+
+/// Run a single hook command with `sh -c`, killing it if it runs
+/// for longer than the timeout.
+fn run(invocation: &Invocation, timeout: Duration) -> anyhow::Result<()> {
+    info!("running {} hook", invocation.event);
+    let mut child = process::Command::new("/bin/sh")
+        .arg("-c")
+        .arg(&invocation.cmd)
+        .env("SHPOOL_HOOK_EVENT", invocation.event)
+        .envs(invocation.env.iter().map(|(k, v)| (k, v)))
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        // put the hook in its own process group so that we can take
+        // out anything it spawned if it times out
+        .process_group(0)
+        .spawn()
+        .context("spawning hook command")?;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait().context("waiting on hook command")? {
+            if !status.success() {
+                warn!("{} hook exited with {}", invocation.event, status);
+            }
+            return Ok(());
+        }
+        if Instant::now() > deadline {
+            break;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    warn!("{} hook timed out after {:?}, killing it", invocation.event, timeout);
+    signal::killpg(Pid::from_raw(child.id() as i32), Signal::SIGKILL)
+        .context("killing hook command")?;
+    child.wait().context("reaping hook command")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs;
+
+    use ntest::timeout;
+
+    #[test]
+    fn env() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let out = tmp_dir.path().join("out");
+        let mut ctx = hooks::HookContext::new("main");
+        ctx.exit_status = Some(3);
+        ctx.tty_size = Some(hooks::TtySize { rows: 24, cols: 80 });
+        let invocation = Invocation {
+            event: "on_reattach",
+            cmd: format!(
+                r#"echo "$SHPOOL_HOOK_EVENT $SHPOOL_SESSION_NAME $SHPOOL_EXIT_STATUS ${{SHPOOL_CLIENT_PID-unset}} $SHPOOL_TTY_ROWS $SHPOOL_TTY_COLS" > {}"#,
+                out.to_str().unwrap()
+            ),
+            env: context_env(&ctx),
+        };
+        run(&invocation, DEFAULT_TIMEOUT)?;
+        assert_eq!(fs::read_to_string(&out)?, "on_reattach main 3 unset 24 80\n");
+
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(10000)]
+    fn kills_on_timeout() -> anyhow::Result<()> {
+        let invocation = Invocation {
+            event: "on_busy",
+            cmd: String::from("sleep 1000 & sleep 1000"),
+            env: vec![],
+        };
+        let start = Instant::now();
+        run(&invocation, Duration::from_millis(100))?;
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(10000)]
+    fn flush() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let out = tmp_dir.path().join("out");
+        let runner = Runner::new(None)?;
+        runner.enqueue(
+            "on_daemon_stop",
+            &format!("sleep 0.2; echo done > {}", out.to_str().unwrap()),
+            vec![],
+        );
+        runner.flush(Duration::from_secs(5));
+        assert_eq!(fs::read_to_string(&out)?, "done\n");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! The hook worker calls hooks on behalf of the session readers.
+
+  Readers are drivers on a shared event loop, so they must never block,
+  but hooks are arbitrary embedder code that may well take a while. A
+  reader hands its hook calls to the worker instead, so a slow or wedged
+  hook holds up other hook calls rather than every session on the loop.
+*/
+
+use std::{
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use tracing::{span, warn, Level};
+
+use crate::hooks;
+
+// The most hook calls we will buffer up while waiting for earlier ones
+// to return. Past this, new calls get dropped.
+const QUEUE_DEPTH: usize = 256;
+
+type Call = Box<dyn FnOnce(&(dyn hooks::Hooks + Send + Sync)) -> anyhow::Result<()> + Send>;
+
+/// Work for the worker thread.
+enum Job {
+    Call(&'static str, Call),
+    /// Ack once every job queued before this one is done.
+    Flush(crossbeam_channel::Sender<()>),
+}
+
+/// A handle to the thread which calls hooks for the readers.
+#[derive(Clone)]
+pub struct HookWorker {
+    queue: crossbeam_channel::Sender<Job>,
+}
+
+impl HookWorker {
+    /// Spawn the worker thread, which calls into the given hooks.
+    pub fn new(hooks: Arc<dyn hooks::Hooks + Send + Sync>) -> anyhow::Result<Self> {
+        let (queue_tx, queue_rx) = crossbeam_channel::bounded(QUEUE_DEPTH);
+        thread::Builder::new()
+            .name(String::from("hook_worker"))
+            .spawn(move || {
+                let _s = span!(Level::INFO, "hook_worker").entered();
+                for job in queue_rx.iter() {
+                    match job {
+                        Job::Call(event, call) => {
+                            if let Err(err) = call(&*hooks) {
+                                warn!("{} hook: {:?}", event, err);
+                            }
+                        }
+                        Job::Flush(done) => {
+                            let _ = done.send(());
+                        }
+                    }
+                }
+            })
+            .context("spawning hook worker")?;
+
+        Ok(HookWorker { queue: queue_tx })
+    }
+
+    /// Queue up a hook call without waiting for it to run.
+    pub fn call<F>(&self, event: &'static str, call: F)
+    where
+        F: FnOnce(&(dyn hooks::Hooks + Send + Sync)) -> anyhow::Result<()> + Send + 'static,
+    {
+        if let Err(err) = self.queue.try_send(Job::Call(event, Box::new(call))) {
+            warn!("dropping {} hook: {:?}", event, err);
+        }
+    }
+
+    /// Wait for the hook calls which have already been queued up to
+    /// return, giving up after `timeout`.
+    pub fn flush(&self, timeout: Duration) {
+        let (done_tx, done_rx) = crossbeam_channel::bounded(1);
+        let deadline = Instant::now() + timeout;
+        if let Err(err) = self.queue.send_deadline(Job::Flush(done_tx), deadline) {
+            warn!("flushing hook calls: {:?}", err);
+            return;
+        }
+        if done_rx.recv_deadline(deadline).is_err() {
+            warn!("timed out waiting for hook calls to return");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Mutex, MutexGuard};
+
+    use super::*;
+
+    /// Hooks that hold up resize calls until the test lets go of `gate`.
+    struct SlowHooks {
+        gate: Mutex<()>,
+        keybindings: crossbeam_channel::Sender<String>,
+    }
+
+    impl hooks::Hooks for SlowHooks {
+        fn on_resize(&self, _ctx: &hooks::HookContext) -> anyhow::Result<()> {
+            let _gate = self.gate.lock().unwrap();
+            Ok(())
+        }
+
+        fn on_keybinding(&self, _ctx: &hooks::HookContext, action: &str) -> anyhow::Result<()> {
+            self.keybindings.send(String::from(action))?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn calls_do_not_block() -> anyhow::Result<()> {
+        let (keybindings_tx, keybindings_rx) = crossbeam_channel::unbounded();
+        let slow = Arc::new(SlowHooks { gate: Mutex::new(()), keybindings: keybindings_tx });
+        let gate: MutexGuard<()> = slow.gate.lock().unwrap();
+        let worker = HookWorker::new(Arc::clone(&slow) as Arc<dyn hooks::Hooks + Send + Sync>)?;
+
+        let ctx = hooks::HookContext::new("sh1");
+        // the worker gets stuck on this one, but we don't
+        worker.call("on_resize", move |h| h.on_resize(&ctx));
+        let ctx = hooks::HookContext::new("sh1");
+        worker.call("on_keybinding", move |h| h.on_keybinding(&ctx, "detach"));
+        assert!(keybindings_rx.recv_timeout(Duration::from_millis(50)).is_err());
+
+        drop(gate);
+        assert_eq!(keybindings_rx.recv_timeout(Duration::from_secs(5))?, "detach");
+        Ok(())
+    }
+}
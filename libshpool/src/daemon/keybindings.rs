@@ -202,10 +202,23 @@ impl Bindings {
 pub enum Action {
     /// detaches the current shpool session
     Detach,
+    /// opens the session's scrollback in the configured pager
+    Scrollback,
     /// does nothing, useful for testing the keybinding engine and not much else
     NoOp,
 }
 
+impl Action {
+    /// The name of the action as it is spelled in the config file.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Detach => "detach",
+            Action::Scrollback => "scrollback",
+            Action::NoOp => "noop",
+        }
+    }
+}
+
 //
 // Parser
 //
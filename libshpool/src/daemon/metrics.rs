@@ -0,0 +1,373 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Metrics count the interesting things that happen in the daemon so
+  that they can be scraped by Prometheus, or anything else that speaks
+  its text exposition format. The counters are bumped from the same
+  places that log the events, and they get rendered on demand when
+  a request comes in on one of the listeners configured in the
+  `[metrics]` section of the config.
+
+  We speak just enough HTTP to satisfy a scraper: every request gets
+  the metrics in response, regardless of the method or path.
+*/
+
+use std::{
+    io::{Read, Write},
+    net::{Ipv4Addr, TcpListener},
+    os::unix::net::UnixListener,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread, time,
+};
+
+use anyhow::Context;
+use tracing::{info, warn};
+
+use crate::config;
+
+const REQUEST_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+// The most of a request we will read before responding anyway.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+/// A number which only goes up.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A number which can go up and down.
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub fn set(&self, n: u64) {
+        self.0.store(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Metrics for the daemon as a whole.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub sessions_created: Counter,
+    /// Sessions killed with `shpool kill`.
+    pub sessions_killed: Counter,
+    /// Sessions killed because their ttl ran out.
+    pub sessions_ttl_expired: Counter,
+    pub attaches: Counter,
+    /// Attaches turned away because someone else was attached.
+    pub busy_rejections: Counter,
+    /// Heartbeats which could not be written to the client, which is
+    /// usually how we notice that a client has gone away.
+    pub heartbeat_failures: Counter,
+    /// Readers which exited with an error.
+    pub reader_errors: Counter,
+}
+
+/// Metrics for a single session. They live as long as the session.
+#[derive(Debug, Default)]
+pub struct SessionMetrics {
+    /// Input written to the shell.
+    pub bytes_in: Counter,
+    /// Output read from the shell.
+    pub bytes_out: Counter,
+    /// Output dropped instead of being sent to a client that fell
+    /// behind.
+    pub bytes_skipped: Counter,
+    /// A rough estimate of the memory used by the output spool.
+    pub spool_bytes: Gauge,
+}
+
+/// A session as of when the metrics are being rendered.
+pub struct SessionSample<'a> {
+    pub name: &'a str,
+    pub attached: bool,
+    pub metrics: &'a SessionMetrics,
+}
+
+impl Metrics {
+    /// Render the metrics in the Prometheus text exposition format.
+    pub fn render(&self, sessions: &[SessionSample]) -> String {
+        let mut out = String::new();
+
+        for (name, help, counter) in [
+            ("shpool_sessions_created_total", "Sessions created.", &self.sessions_created),
+            ("shpool_attaches_total", "Clients attached to a session.", &self.attaches),
+            (
+                "shpool_busy_rejections_total",
+                "Attaches rejected because the session already had a client.",
+                &self.busy_rejections,
+            ),
+            (
+                "shpool_heartbeat_failures_total",
+                "Heartbeats that could not be written to a client.",
+                &self.heartbeat_failures,
+            ),
+            (
+                "shpool_reader_errors_total",
+                "Session readers that exited with an error.",
+                &self.reader_errors,
+            ),
+        ] {
+            header(&mut out, name, help, "counter");
+            out.push_str(&format!("{} {}\n", name, counter.get()));
+        }
+
+        header(&mut out, "shpool_sessions_killed_total", "Sessions killed.", "counter");
+        for (reason, counter) in
+            [("kill", &self.sessions_killed), ("ttl", &self.sessions_ttl_expired)]
+        {
+            out.push_str(&format!(
+                "shpool_sessions_killed_total{{reason=\"{}\"}} {}\n",
+                reason,
+                counter.get()
+            ));
+        }
+
+        header(&mut out, "shpool_sessions", "Sessions which currently exist.", "gauge");
+        let attached = sessions.iter().filter(|s| s.attached).count();
+        out.push_str(&format!("shpool_sessions{{status=\"attached\"}} {}\n", attached));
+        out.push_str(&format!(
+            "shpool_sessions{{status=\"disconnected\"}} {}\n",
+            sessions.len() - attached
+        ));
+
+        per_session(
+            &mut out,
+            sessions,
+            "shpool_session_bytes_in_total",
+            "Bytes of input written to the session's shell.",
+            "counter",
+            |m| m.bytes_in.get(),
+        );
+        per_session(
+            &mut out,
+            sessions,
+            "shpool_session_bytes_out_total",
+            "Bytes of output read from the session's shell.",
+            "counter",
+            |m| m.bytes_out.get(),
+        );
+        per_session(
+            &mut out,
+            sessions,
+            "shpool_session_bytes_skipped_total",
+            "Bytes of output not sent to a client that fell behind.",
+            "counter",
+            |m| m.bytes_skipped.get(),
+        );
+        per_session(
+            &mut out,
+            sessions,
+            "shpool_session_spool_bytes",
+            "Estimated memory used by the session's output spool.",
+            "gauge",
+            |m| m.spool_bytes.get(),
+        );
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+}
+
+/// Render a metric with a series for each session.
+fn per_session<F>(
+    out: &mut String,
+    sessions: &[SessionSample],
+    name: &str,
+    help: &str,
+    kind: &str,
+    value: F,
+) where
+    F: Fn(&SessionMetrics) -> u64,
+{
+    header(out, name, help, kind);
+    for session in sessions.iter() {
+        out.push_str(&format!(
+            "{}{{session=\"{}\"}} {}\n",
+            name,
+            escape_label(session.name),
+            value(session.metrics)
+        ));
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Start serving metrics on the given listeners and on the port in the
+/// config, calling `render` to get the metrics for each request.
+pub fn serve<F>(
+    config: &config::MetricsConfig,
+    listeners: Vec<UnixListener>,
+    render: F,
+) -> anyhow::Result<()>
+where
+    F: Fn() -> String + Send + Sync + 'static,
+{
+    let render = Arc::new(render);
+
+    // sockets handed to us by systemd, along with the one from the
+    // config, which the caller binds so that it can clean it up
+    for listener in listeners.into_iter() {
+        info!("serving metrics on {:?}", listener.local_addr().ok());
+        serve_unix(listener, Arc::clone(&render))?;
+    }
+
+    if let Some(port) = config.port {
+        let listener =
+            TcpListener::bind((Ipv4Addr::LOCALHOST, port)).context("binding metrics port")?;
+        info!("serving metrics on port {}", port);
+        thread::Builder::new().name(String::from("metrics(tcp)")).spawn(move || {
+            for stream in listener.incoming() {
+                let res = stream.context("accepting metrics conn").and_then(|s| {
+                    s.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+                    respond(s, &*render)
+                });
+                if let Err(e) = res {
+                    warn!("serving metrics: {:?}", e);
+                }
+            }
+        })?;
+    }
+
+    Ok(())
+}
+
+fn serve_unix<F>(listener: UnixListener, render: Arc<F>) -> anyhow::Result<()>
+where
+    F: Fn() -> String + Send + Sync + 'static,
+{
+    thread::Builder::new().name(String::from("metrics(unix)")).spawn(move || {
+        for stream in listener.incoming() {
+            let res = stream.context("accepting metrics conn").and_then(|s| {
+                s.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+                respond(s, &*render)
+            });
+            if let Err(e) = res {
+                warn!("serving metrics: {:?}", e);
+            }
+        }
+    })?;
+    Ok(())
+}
+
+/// Wait for the end of the request headers, then respond with the metrics.
+fn respond<S, F>(mut stream: S, render: &F) -> anyhow::Result<()>
+where
+    S: Read + Write,
+    F: Fn() -> String,
+{
+    let mut request = vec![];
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_LEN {
+        let len = stream.read(&mut buf).context("reading metrics request")?;
+        if len == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..len]);
+    }
+
+    let body = render();
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )
+    .context("writing metrics response")?;
+    stream.flush().context("flushing metrics response")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::default();
+        metrics.sessions_created.add(3);
+        metrics.sessions_killed.inc();
+        metrics.busy_rejections.inc();
+
+        let main = SessionMetrics::default();
+        main.bytes_in.add(10);
+        main.bytes_out.add(200);
+        main.bytes_skipped.add(50);
+        main.spool_bytes.set(4096);
+        let odd = SessionMetrics::default();
+
+        let out = metrics.render(&[
+            SessionSample { name: "main", attached: true, metrics: &main },
+            SessionSample { name: "a \"b\"\\", attached: false, metrics: &odd },
+        ]);
+
+        for want in [
+            "# TYPE shpool_sessions_created_total counter\nshpool_sessions_created_total 3\n",
+            "shpool_busy_rejections_total 1\n",
+            "shpool_attaches_total 0\n",
+            "shpool_sessions_killed_total{reason=\"kill\"} 1\n",
+            "shpool_sessions_killed_total{reason=\"ttl\"} 0\n",
+            "shpool_sessions{status=\"attached\"} 1\n",
+            "shpool_sessions{status=\"disconnected\"} 1\n",
+            "shpool_session_bytes_in_total{session=\"main\"} 10\n",
+            "shpool_session_bytes_out_total{session=\"main\"} 200\n",
+            "shpool_session_bytes_skipped_total{session=\"main\"} 50\n",
+            "# TYPE shpool_session_spool_bytes gauge\n",
+            "shpool_session_spool_bytes{session=\"main\"} 4096\n",
+            "shpool_session_bytes_in_total{session=\"a \\\"b\\\"\\\\\"} 0\n",
+        ] {
+            assert!(out.contains(want), "missing {:?} in:\n{}", want, out);
+        }
+    }
+
+    #[test]
+    fn respond_to_request() -> anyhow::Result<()> {
+        let (mut client, server) = std::os::unix::net::UnixStream::pair()?;
+        client.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+        respond(server, &|| String::from("foo 1\n"))?;
+
+        let mut response = String::new();
+        client.read_to_string(&mut response)?;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Length: 6\r\n"));
+        assert!(response.ends_with("\r\n\r\nfoo 1\n"));
+
+        Ok(())
+    }
+}
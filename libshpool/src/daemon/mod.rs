@@ -12,17 +12,33 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{os::unix::net::UnixListener, path::PathBuf};
+use std::{
+    fs, io,
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::Arc,
+    time,
+};
 
-use anyhow::Context;
-use tracing::{info, instrument};
+use anyhow::{anyhow, Context};
+use tracing::{info, instrument, warn};
 
 use super::{config, hooks};
 
+mod activity;
+mod cgroup;
+mod command_log;
 mod control_codes;
 mod etc_environment;
+mod event_loop;
 mod exit_notify;
+mod hook_commands;
+mod hook_worker;
 pub mod keybindings;
+mod metrics;
 mod pager;
 mod prompt;
 mod server;
@@ -31,8 +47,16 @@ mod show_motd;
 mod signals;
 mod systemd;
 mod trie;
+mod triggers;
 mod ttl_reaper;
 
+// The FileDescriptorName= for an activation socket that should
+// serve metrics rather than the control protocol.
+const METRICS_FD_NAME: &str = "metrics";
+// The FileDescriptorName= for an activation socket that should
+// only allow commands that don't change anything.
+const READ_ONLY_FD_NAME: &str = "readonly";
+
 #[instrument(skip_all)]
 pub fn run(
     config_file: Option<String>,
@@ -41,30 +65,191 @@ pub fn run(
     socket: PathBuf,
 ) -> anyhow::Result<()> {
     info!("\n\n======================== STARTING DAEMON ============================\n\n");
+    let started_at = time::SystemTime::now();
+    // grab the notify socket before we spawn any threads or processes
+    // which could see it in the environment
+    let notifier = systemd::Notifier::from_env()?.map(Arc::new);
 
+    let config_path = config::config_path(&config_file)?;
     let config = config::read_config(&config_file)?;
-    let server = server::Server::new(config, hooks, runtime_dir)?;
+    let config_loaded_at = time::SystemTime::now();
+    let metrics_config = config.metrics.clone();
+    let needs_runner = config.hooks.is_some()
+        || config.trigger.iter().flatten().any(|trigger| trigger.command.is_some());
+    let runner = if needs_runner {
+        Some(hook_commands::Runner::new(config.hooks.as_ref().and_then(|h| h.timeout_secs))?)
+    } else {
+        None
+    };
+    let hooks: Arc<dyn hooks::Hooks + Send + Sync> = match (config.hooks.clone(), runner.clone()) {
+        (Some(hooks_config), Some(runner)) => {
+            Arc::new(hook_commands::CommandHooks::new(hooks, hooks_config, runner))
+        }
+        _ => Arc::from(hooks),
+    };
 
-    let (cleanup_socket, listener) = match systemd::activation_socket() {
-        Ok(l) => {
-            info!("using systemd activation socket");
-            (None, l)
+    let mut metrics_listeners = vec![];
+    let (systemd_activated, mut cleanup_sockets, listeners) = match systemd::activation_sockets() {
+        Ok(socks) => {
+            info!(
+                "using systemd activation sockets: {:?}",
+                socks.iter().map(|s| &s.name).collect::<Vec<_>>()
+            );
+            let mut listeners = vec![];
+            for sock in socks.into_iter() {
+                match sock.name.as_str() {
+                    METRICS_FD_NAME => metrics_listeners.push(sock.listener),
+                    name => listeners.push(server::Listener {
+                        listener: sock.listener,
+                        read_only: name == READ_ONLY_FD_NAME,
+                    }),
+                }
+            }
+            (true, vec![], listeners)
         }
         Err(e) => {
             info!("no systemd activation socket: {:?}", e);
-            (Some(socket.clone()), UnixListener::bind(&socket).context("binding to socket")?)
+            let (cleanup_sockets, listeners) = bind_sockets(socket.clone(), &config)?;
+            (false, cleanup_sockets, listeners)
         }
     };
+    if let Some(path) = metrics_config.as_ref().and_then(|c| c.socket.as_ref()) {
+        let path = PathBuf::from(path);
+        match bind_replacing(&path) {
+            Ok(listener) => {
+                metrics_listeners.push(listener);
+                cleanup_sockets.push(path);
+            }
+            Err(err) => warn!("not serving metrics on {:?}: {:?}", path, err),
+        }
+    }
     // spawn the signal handler thread in the background
-    signals::Handler::new(cleanup_socket.clone()).spawn()?;
+    signals::Handler::new(cleanup_sockets.clone(), Arc::clone(&hooks), notifier.clone()).spawn()?;
+
+    let main_listener = listeners
+        .iter()
+        .find(|l| !l.read_only)
+        .ok_or(anyhow!("none of the activation sockets is a read-write control socket"))?;
+    let info = server::DaemonInfo {
+        started_at,
+        config_file: config_path,
+        config_loaded_at,
+        // systemd may have handed us a socket somewhere other than
+        // where we would have put it
+        socket: main_listener
+            .listener
+            .local_addr()
+            .ok()
+            .and_then(|addr| addr.as_pathname().map(PathBuf::from))
+            .unwrap_or(socket),
+        sockets: listeners
+            .iter()
+            .filter_map(|l| l.listener.local_addr().ok()?.as_pathname().map(PathBuf::from))
+            .collect(),
+        systemd_activated,
+        cleanup_sockets: cleanup_sockets.clone(),
+    };
+    let server = match server::Server::new(
+        config,
+        Arc::clone(&hooks),
+        runner,
+        runtime_dir,
+        info,
+        notifier,
+    ) {
+        Ok(server) => server,
+        Err(err) => {
+            // don't leave sockets around that nobody is listening on
+            for sock in cleanup_sockets.iter() {
+                let _ = fs::remove_file(sock);
+            }
+            return Err(err);
+        }
+    };
+
+    if metrics_config.is_some() || !metrics_listeners.is_empty() {
+        let metrics_server = Arc::clone(&server);
+        let render = move || metrics_server.render_metrics();
+        let metrics_config = metrics_config.unwrap_or_default();
+        if let Err(err) = metrics::serve(&metrics_config, metrics_listeners, render) {
+            warn!("not serving metrics: {:?}", err);
+        }
+    }
 
-    server::Server::serve(server, listener)?;
+    if let Err(err) = hooks.on_daemon_start() {
+        warn!("daemon_start hook: {:?}", err);
+    }
 
-    if let Some(sock) = cleanup_socket {
-        std::fs::remove_file(sock).context("cleaning up socket on exit")?;
-    } else {
-        info!("systemd manages the socket, so not cleaning it up");
+    server::Server::serve(server, listeners)?;
+
+    if systemd_activated {
+        info!("systemd manages the sockets, so not cleaning them up");
+    }
+    for sock in cleanup_sockets.iter() {
+        fs::remove_file(sock).context("cleaning up socket on exit")?;
     }
 
     Ok(())
 }
+
+/// Bind the main socket along with any extra sockets from the config,
+/// returning the paths to clean up on exit along with the listeners.
+fn bind_sockets(
+    socket: PathBuf,
+    config: &config::Config,
+) -> anyhow::Result<(Vec<PathBuf>, Vec<server::Listener>)> {
+    let mut listeners = vec![server::Listener {
+        listener: UnixListener::bind(&socket).context("binding to socket")?,
+        read_only: false,
+    }];
+    let mut cleanup_sockets = vec![socket];
+
+    for listen in config.listen.iter().flatten() {
+        let path = PathBuf::from(&listen.path);
+        match bind_replacing(&path) {
+            Ok(listener) => {
+                info!("also listening on {:?}", path);
+                listeners.push(server::Listener {
+                    listener,
+                    read_only: listen.read_only.unwrap_or(false),
+                });
+                cleanup_sockets.push(path);
+            }
+            Err(err) => {
+                // don't leave sockets around that nobody is listening on
+                for sock in cleanup_sockets.iter() {
+                    let _ = fs::remove_file(sock);
+                }
+                return Err(err);
+            }
+        }
+    }
+
+    Ok((cleanup_sockets, listeners))
+}
+
+/// Bind to the given path, clearing out any socket left behind by a
+/// previous daemon.
+fn bind_replacing(path: &Path) -> anyhow::Result<UnixListener> {
+    remove_stale_socket(path)?;
+    UnixListener::bind(path).context(format!("binding to socket {:?}", path))
+}
+
+/// Remove the socket at the given path if nothing is listening on it
+/// anymore. Anything other than a dead socket is left alone and makes
+/// this fail, so that a typo in the config can't cost the user a file
+/// or pull the rug out from under another process.
+fn remove_stale_socket(path: &Path) -> anyhow::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).context(format!("checking for old socket {:?}", path)),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(anyhow!("{:?} already exists and is not a socket", path));
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(anyhow!("{:?} is already in use", path));
+    }
+    fs::remove_file(path).context(format!("removing old socket {:?}", path))
+}
@@ -45,8 +45,12 @@ use std::{
 };
 
 use anyhow::{anyhow, Context};
-use nix::{poll, sys::signal, unistd};
-use tracing::{error, info, instrument, span, trace, warn, Level};
+use nix::{
+    poll,
+    sys::{signal, wait},
+    unistd,
+};
+use tracing::{debug, error, info, instrument, span, trace, warn, Level};
 
 use crate::{consts, protocol, tty};
 
@@ -97,6 +101,9 @@ impl Pager {
         &self,
         // The client connection on which to display the pager.
         client_stream: &mut UnixStream,
+        // Whatever the client has sent which has not been handled yet.
+        // Anything the pager does not get to is left in here.
+        input: &mut protocol::ChunkDecoder,
         // The slot to install the control handle in
         ctl_slot: Arc<Mutex<Option<PagerCtl>>>,
         // The size of the tty to start off with
@@ -133,7 +140,12 @@ impl Pager {
         // and execing the pty wrapped pager in the child.
         info!("forking pager pty proc");
         let fork = shpool_pty::fork::Fork::from_ptmx().context("forking pty")?;
-        if fork.is_child().is_ok() {
+        if let Ok(slave) = fork.is_child() {
+            if let Some(fd) = slave.borrow_fd() {
+                if let Err(err) = tty::make_controlling_terminal(fd) {
+                    eprintln!("shpool: warn: no job control: {:?}", err);
+                }
+            }
             for fd in consts::STDERR_FD + 1..(nix::unistd::SysconfVar::OPEN_MAX as i32) {
                 let _ = nix::unistd::close(fd);
             }
@@ -146,16 +158,19 @@ impl Pager {
             PagerProcGuard { pager_proc: &fork, pager_exited: Arc::clone(&pager_exited) };
 
         let pager_exited_ref = Arc::clone(&pager_exited);
-        let waitable_child = fork.clone();
+        // N.B. we wait on the raw pid rather than a clone of the fork handle
+        // because dropping a fork handle closes the pty master fd out from
+        // under us while we might still be draining the pager's output.
+        let pager_pid = unistd::Pid::from_raw(fork.child_pid().ok_or(anyhow!("no pager pid"))?);
         thread::spawn(move || {
             let _s = span!(Level::INFO, "pager_exit_monitor").entered();
-            match waitable_child.wait_for_exit() {
-                Ok((_, Some(exit_status))) => {
+            match wait::waitpid(pager_pid, None) {
+                Ok(wait::WaitStatus::Exited(_, exit_status)) => {
                     info!("child pager exited with status {}", exit_status);
                     pager_exited_ref.store(true, Ordering::Relaxed);
                 }
-                Ok((_, None)) => {
-                    info!("child pager exited without status");
+                Ok(status) => {
+                    info!("child pager exited without status ({:?})", status);
                     pager_exited_ref.store(true, Ordering::Relaxed);
                 }
                 Err(e) => {
@@ -163,7 +178,7 @@ impl Pager {
                     pager_exited_ref.store(true, Ordering::Relaxed);
                 }
             }
-            info!("reaped child pager: {:?}", waitable_child);
+            info!("reaped child pager: {}", pager_pid);
         });
 
         let mut pty_master = fork.is_parent().context("getting pty_master handle")?;
@@ -215,8 +230,13 @@ impl Pager {
                 ),
                 poll::PollFd::new(watchable_client_stream.as_fd(), poll::PollFlags::POLLIN),
             ];
+            // N.B. we check for exit before polling so that if the poll times
+            // out we know we have forwarded all the output the pager wrote
+            // before it exited, since the pty can take a moment to pass
+            // output along.
+            let exited = pager_exited.load(Ordering::Relaxed);
             let nready = poll::poll(&mut poll_fds, POLL_MS).context("polling both streams")?;
-            if pager_exited.load(Ordering::Relaxed) {
+            if exited && nready == 0 {
                 let tty_size = tty_size.lock().unwrap();
                 return Ok(tty_size.clone());
             }
@@ -256,6 +276,16 @@ impl Pager {
                 if pty_master_poll_fd.any().unwrap_or(false) {
                     // the pager process has some data for us
                     let len = pty_master.read(&mut buf).context("reading chunk from pty master")?;
+                    if len == 0 {
+                        // shpool_pty reports read errors as empty reads, which
+                        // includes the EIO we get once the pager has exited and
+                        // we have drained the pty.
+                        if exited {
+                            let tty_size = tty_size.lock().unwrap();
+                            return Ok(tty_size.clone());
+                        }
+                        continue;
+                    }
                     let chunk =
                         protocol::Chunk { kind: protocol::ChunkKind::Data, buf: &buf[..len] };
                     match chunk.write_to(client_stream).and_then(|_| client_stream.flush()) {
@@ -273,24 +303,54 @@ impl Pager {
                 if client_stream_poll_fd.any().unwrap_or(false) {
                     let len = client_stream.read(&mut buf).context("reading client chunk")?;
                     if len == 0 {
-                        continue;
-                    }
-
-                    trace!("user input: {}", String::from_utf8_lossy(&buf[..len]));
-
-                    if let Err(e) = pty_master.write_all(&buf[0..len]) {
-                        info!("Error writing to pager pty, nbd though: {:?}", e);
-                        // assume the pager proc just quit normally and the
-                        // timing was such that we didn't pick it up with our
-                        // exit watcher thread.
-                        let tty_size = tty_size.lock().unwrap();
-                        return Ok(tty_size.clone());
+                        // EOF, so the client is gone
+                        trace!("client hangup reading input");
+                        return Err(PagerError::ClientHangup)?;
                     }
-                    if let Err(e) = pty_master.flush() {
-                        info!("Error flushing pager pty, nbd though: {:?}", e);
-                        // same logic as above
-                        let tty_size = tty_size.lock().unwrap();
-                        return Ok(tty_size.clone());
+                    input.feed(&buf[..len]);
+
+                    while let Some(chunk) = input.next_chunk().context("decoding client chunk")? {
+                        match chunk.kind {
+                            protocol::ChunkKind::Data => {
+                                trace!("user input: {}", String::from_utf8_lossy(chunk.buf));
+
+                                if let Err(e) =
+                                    pty_master.write_all(chunk.buf).and_then(|_| pty_master.flush())
+                                {
+                                    info!("Error writing to pager pty, nbd though: {:?}", e);
+                                    // assume the pager proc just quit normally and the
+                                    // timing was such that we didn't pick it up with our
+                                    // exit watcher thread.
+                                    let tty_size = tty_size.lock().unwrap();
+                                    return Ok(tty_size.clone());
+                                }
+                            }
+                            protocol::ChunkKind::Resize => {
+                                let size = protocol::decode_size(chunk.buf)?;
+                                info!("resizing pager (rows={}, cols={})", size.rows, size.cols);
+                                if let Err(e) = size.set_fd(pty_master_fd) {
+                                    warn!("setting pager size: {:?}", e);
+                                }
+                                *tty_size.lock().unwrap() = size;
+                            }
+                            protocol::ChunkKind::Ping => {
+                                let pong = protocol::Chunk {
+                                    kind: protocol::ChunkKind::Pong,
+                                    buf: chunk.buf,
+                                };
+                                if let Err(e) =
+                                    pong.write_to(client_stream).and_then(|_| client_stream.flush())
+                                {
+                                    trace!("client hangup writing pong: {:?}", e);
+                                    return Err(PagerError::ClientHangup)?;
+                                }
+                            }
+                            kind => {
+                                // Pasting or focus events don't mean much
+                                // to a pager.
+                                debug!("pager ignoring {:?} chunk", kind);
+                            }
+                        }
                     }
                 }
             }
@@ -13,37 +13,409 @@
 // limitations under the License.
 
 // This file contains the logic for injecting the `prompt_annotation`
-// config option into a user's prompt for known shells.
+// config option into a user's prompt for known shells. Along with the
+// prefix, we teach the shell to emit OSC 133 semantic prompt marks
+// around its prompt and commands so that the daemon can keep track of
+// command boundaries.
+//
+// Where we can, we hand the script to the shell in a generated rc file
+// so that it never shows up on screen or in the shell history. For
+// other shells, we fall back on typing the script into the pty once
+// the shell starts up and then clearing the screen.
 
-use std::io::Write;
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process, time,
+};
 
 use anyhow::{anyhow, Context};
-use tracing::instrument;
+use tracing::{info, instrument, warn};
+
+use crate::duration;
+
+/// The per-session values which may be referenced from the prompt
+/// prefix template. These are resolved once when the shell is spawned.
+#[derive(Debug, Clone)]
+pub struct PrefixVars<'a> {
+    pub session_name: &'a str,
+    pub started_at: chrono::DateTime<chrono::Local>,
+    pub ttl: Option<time::Duration>,
+    pub hostname: Option<String>,
+}
+
+impl PrefixVars<'_> {
+    /// The value of the named variable, or None if there is no
+    /// such variable. Variables with no value for this session
+    /// expand to the empty string.
+    fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "session_name" => Some(String::from(self.session_name)),
+            "started_at" => Some(self.started_at.format("%H:%M").to_string()),
+            "ttl" => Some(self.ttl.map(duration::format).unwrap_or_default()),
+            "hostname" => Some(self.hostname.clone().unwrap_or_default()),
+            _ => None,
+        }
+    }
+}
+
+/// How the prompt prefix script makes its way into the shell.
+#[derive(Debug)]
+pub enum Injection {
+    /// The shell will source the script from a generated rc file
+    /// as it starts up. If the rc file might not get sourced after all,
+    /// `fallback` should be typed into the shell in case the clear at
+    /// the end of the rc file never shows up.
+    Rcfile { fallback: Option<String> },
+    /// The script needs to be typed into the shell once it has
+    /// started up.
+    Typed(String),
+}
+
+/// Everything needed to work out how to inject the prompt prefix
+/// into a fresh shell.
+pub struct InjectArgs<'a> {
+    /// The basename of the shell binary.
+    pub shell: &'a str,
+    pub prompt_prefix: &'a str,
+    pub vars: PrefixVars<'a>,
+    /// A user supplied script to use in place of the built in one.
+    pub script_template: Option<&'a str>,
+    pub needs_default_term: bool,
+    /// The directory to write rc files into, or None if the script
+    /// should always be typed into the shell.
+    pub rc_dir: Option<PathBuf>,
+    /// Whether the user has asked for the shell to skip their rc files.
+    pub norc: bool,
+    /// The clear screen code that the reader scans for.
+    pub clear_code: Vec<u8>,
+}
+
+/// Set up the shell command so that it will inject the prompt prefix,
+/// using the shell basename to decide the right way to go about it.
+/// This must be called before the shell gets spawned. If the shell can't
+/// be set up to source an rc file, the returned script should be typed
+/// into the shell with `type_script` once it has been spawned.
+#[instrument(skip_all)]
+pub fn prepare_injection(
+    cmd: &mut process::Command,
+    args: &InjectArgs,
+) -> anyhow::Result<Injection> {
+    // Colors need to be quoted differently depending on how the prefix gets
+    // embedded in the script, so we only know how to do it for our own scripts.
+    let use_color = args.script_template.is_none() && !args.needs_default_term;
+    let prompt_prefix = expand_prefix(args.prompt_prefix, args.shell, &args.vars, use_color);
+    let script = prefix_script(args.shell, &prompt_prefix, args.script_template)?;
+
+    if let Some(rc_dir) = &args.rc_dir {
+        match write_rcfile(cmd, args, rc_dir, &script) {
+            Ok(true) => {
+                info!("injecting prompt prefix via rc file in {:?}", rc_dir);
+                // bash only gets to the rc file if the user's startup files
+                // leave PROMPT_COMMAND alone.
+                let fallback = if args.shell.ends_with("bash") {
+                    Some(bash_fallback(&args.clear_code))
+                } else {
+                    None
+                };
+                return Ok(Injection::Rcfile { fallback });
+            }
+            Ok(false) => {}
+            Err(err) => warn!("setting up rc file, falling back on typing: {:?}", err),
+        }
+    }
 
-/// Inject the given prefix into the given shell subprocess, using
-/// the shell path in `shell` to decide the right way to go about
-/// injecting the prefix.
+    let mut script = script;
+    script.push_str(clear_command(args.shell, args.needs_default_term));
+    Ok(Injection::Typed(script))
+}
+
+/// Type the given script into the shell subprocess.
 #[instrument(skip_all)]
-pub fn inject_prefix(
-    pty_master: &mut shpool_pty::fork::Fork,
+pub fn type_script(pty_master: &mut shpool_pty::fork::Fork, script: &str) -> anyhow::Result<()> {
+    let mut pty_master = pty_master.is_parent().context("expected parent")?;
+    pty_master.write_all(script.as_bytes()).context("running prefix script")?;
+
+    Ok(())
+}
+
+/// Write out an rc file which runs the prefix script after the user's
+/// usual rc files and point the shell at it. Returns false if we don't
+/// know how to do that for the shell. The command is only touched once
+/// the files have all been written.
+fn write_rcfile(
+    cmd: &mut process::Command,
+    args: &InjectArgs,
+    rc_dir: &Path,
+    script: &str,
+) -> anyhow::Result<bool> {
+    let is = |sh: &str| args.shell.ends_with(sh);
+    if !(is("bash") || is("zsh") || is("fish")) {
+        return Ok(false);
+    }
+    if is("bash") && args.norc {
+        // Some distros patch bash to read a system wide bashrc even when
+        // given --rcfile, so only --norc will really keep rc files out.
+        return Ok(false);
+    }
+
+    fs::create_dir_all(rc_dir).context("creating rc dir")?;
+    // The reader waits for a clear to dump the motd, but there is no
+    // need to shell out to `clear` and worry about whether it knows our TERM.
+    let clear = format!("\nprintf '{}'\n", octal_escape(&args.clear_code));
+
+    if is("bash") {
+        // bash ignores --rcfile for login shells, and we want to keep
+        // starting a login shell, so we have the shell source our rc
+        // file from PROMPT_COMMAND right before its first prompt, by
+        // which point it has read all of the user's startup files.
+        let rcfile = rc_dir.join("bashrc");
+        fs::write(&rcfile, format!("{}{}{}", BASH_UNSHIM, script, clear))
+            .context("writing bash rc file")?;
+
+        let prompt_command = match cmd.get_envs().find(|(var, _)| *var == "PROMPT_COMMAND") {
+            Some((_, Some(old))) => {
+                let old = old.to_str().ok_or(anyhow!("PROMPT_COMMAND is not utf8"))?;
+                format!("{}; {}", BASH_SHIM, old)
+            }
+            _ => String::from(BASH_SHIM),
+        };
+        cmd.env("PROMPT_COMMAND", prompt_command);
+        cmd.env("SHPOOL__RCFILE", &rcfile);
+    } else if is("zsh") {
+        // zsh reads its startup files from $ZDOTDIR, so we point it at a
+        // directory of shims which each source the user's real file.
+        let user_zdotdir = cmd
+            .get_envs()
+            .find(|(var, _)| *var == "ZDOTDIR")
+            .and_then(|(_, val)| val.map(|v| v.to_os_string()));
+        for name in [".zshenv", ".zprofile"] {
+            fs::write(rc_dir.join(name), zsh_shim(name, true))
+                .with_context(|| format!("writing zsh {}", name))?;
+        }
+        let zshrc = format!("{}{}{}", zsh_shim(".zshrc", false), script, clear);
+        fs::write(rc_dir.join(".zshrc"), zshrc).context("writing zsh .zshrc")?;
+
+        if let Some(user_zdotdir) = user_zdotdir {
+            cmd.env("SHPOOL__USER_ZDOTDIR", user_zdotdir);
+        }
+        cmd.env("ZDOTDIR", rc_dir);
+    } else {
+        // fish runs --init-command after it has read the user's config.
+        let rcfile = rc_dir.join("config.fish");
+        fs::write(&rcfile, format!("{}{}", script, clear)).context("writing fish rc file")?;
+
+        let rcfile = rcfile.to_str().ok_or(anyhow!("rc file path is not utf8"))?;
+        cmd.arg("--init-command").arg(format!("source {}", shell_words::quote(rcfile)));
+    }
+
+    Ok(true)
+}
+
+// The PROMPT_COMMAND which gets a bash login shell to source our rc file.
+const BASH_SHIM: &str = r#"builtin source "${SHPOOL__RCFILE}""#;
+
+// Takes the shim back out of PROMPT_COMMAND, along with whatever the
+// user's startup files used to join their own commands onto it. We
+// only exported PROMPT_COMMAND to get it to this shell, so it is
+// un-exported again to keep it from leaking into child shells.
+const BASH_UNSHIM: &str = r#"
+SHPOOL__SHIM='builtin source "${SHPOOL__RCFILE}"'
+PROMPT_COMMAND="${PROMPT_COMMAND//"${SHPOOL__SHIM}; "/}"
+PROMPT_COMMAND="${PROMPT_COMMAND//"${SHPOOL__SHIM}"/}"
+PROMPT_COMMAND="${PROMPT_COMMAND#"${PROMPT_COMMAND%%[![:space:];]*}"}"
+if [[ -z "${PROMPT_COMMAND}" && "${#PROMPT_COMMAND[@]}" -le 1 ]]; then
+    unset PROMPT_COMMAND
+else
+    export -n PROMPT_COMMAND
+fi
+unset SHPOOL__SHIM SHPOOL__RCFILE
+"#;
+
+/// The line to type into bash if it never got around to sourcing the rc
+/// file. Once the rc file has been sourced, SHPOOL__RCFILE is gone, so
+/// this just clears itself off the screen rather than injecting the
+/// prefix a second time. The leading space keeps it out of the history
+/// for users with HISTCONTROL=ignorespace.
+fn bash_fallback(clear_code: &[u8]) -> String {
+    format!(
+        r#" if [[ -n "${{SHPOOL__RCFILE+x}}" ]]; then builtin source "${{SHPOOL__RCFILE}}"; else printf '{}'; fi
+"#,
+        octal_escape(clear_code)
+    )
+}
+
+/// A zsh startup file which sources the user's version of the same file
+/// from their own $ZDOTDIR (or $HOME). If `keep_shimming` is set, ZDOTDIR
+/// gets pointed back at our shims afterwards so zsh will read the next
+/// one, otherwise it is left as the user would expect it.
+fn zsh_shim(name: &str, keep_shimming: bool) -> String {
+    let mut shim = format!(
+        r#"
+SHPOOL__ZDOTDIR="${{ZDOTDIR}}"
+if [[ -n "${{SHPOOL__USER_ZDOTDIR+x}}" ]]; then
+    ZDOTDIR="${{SHPOOL__USER_ZDOTDIR}}"
+else
+    unset ZDOTDIR
+fi
+if [[ -r "${{ZDOTDIR:-$HOME}}/{name}" ]]; then
+    builtin source "${{ZDOTDIR:-$HOME}}/{name}"
+fi
+if [[ -n "${{ZDOTDIR+x}}" ]]; then
+    SHPOOL__USER_ZDOTDIR="${{ZDOTDIR}}"
+fi
+"#
+    );
+    if keep_shimming {
+        shim.push_str("ZDOTDIR=\"${SHPOOL__ZDOTDIR}\"\n");
+    } else {
+        shim.push_str("unset SHPOOL__ZDOTDIR SHPOOL__USER_ZDOTDIR\n");
+    }
+    shim
+}
+
+/// Escape every byte so that it can be passed as a printf format string.
+fn octal_escape(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("\\{:03o}", b)).collect()
+}
+
+/// Expand the variables, conditionals and colors in a prompt prefix
+/// template. `{var}` expands to the value of a variable, and
+/// `{if var}...{end}` only includes its body when the variable
+/// has a non-empty value. Shell syntax like `${var}`, zsh's `%F{red}`,
+/// `%K{red}` and `%{...%}`, and anything in braces that we don't
+/// recognize are left alone. The legacy `$SHPOOL_SESSION_NAME` form
+/// is also supported.
+fn expand_prefix(template: &str, shell: &str, vars: &PrefixVars, use_color: bool) -> String {
+    let mut out = String::new();
+    // For each enclosing {if}, whether its body is being included.
+    let mut conds: Vec<bool> = vec![];
+
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        let active = conds.iter().all(|c| *c);
+        if active {
+            out.push_str(&rest[..open]);
+        }
+        let before = &rest[..open];
+        rest = &rest[open..];
+
+        // Braces that are part of shell syntax like ${var} or zsh's %F{red},
+        // %K{red} and %{...%} are not ours.
+        let shell_syntax = matches!(before.as_bytes(), [.., b'$' | b'%'] | [.., b'%', b'F' | b'K']);
+        if shell_syntax {
+            if active {
+                out.push('{');
+            }
+            rest = &rest[1..];
+            continue;
+        }
+
+        let close = match rest.find('}') {
+            Some(close) => close,
+            None => break,
+        };
+        let tag = &rest[1..close];
+        let verbatim = &rest[..close + 1];
+        rest = &rest[close + 1..];
+
+        if let Some(var) = tag.strip_prefix("if ") {
+            let cond = vars.get(var.trim()).map(|v| !v.is_empty()).unwrap_or(false);
+            conds.push(cond);
+        } else if tag == "end" && !conds.is_empty() {
+            conds.pop();
+        } else if !active {
+            continue;
+        } else if let Some(val) = vars.get(tag) {
+            out.push_str(&val);
+        } else if let Some(sgr) = color_sgr(tag) {
+            if use_color {
+                out.push_str(&color_code(shell, sgr));
+            }
+        } else {
+            out.push_str(verbatim);
+        }
+    }
+    if conds.iter().all(|c| *c) {
+        out.push_str(rest);
+    }
+
+    out.replace("$SHPOOL_SESSION_NAME", vars.session_name)
+}
+
+/// The SGR parameter for the named color or style.
+fn color_sgr(name: &str) -> Option<u8> {
+    match name {
+        "reset" => Some(0),
+        "bold" => Some(1),
+        "red" => Some(31),
+        "green" => Some(32),
+        "yellow" => Some(33),
+        "blue" => Some(34),
+        "magenta" => Some(35),
+        "cyan" => Some(36),
+        _ => None,
+    }
+}
+
+/// The given SGR escape code, quoted so that it can be spliced into
+/// the prefix in the built in script for the given shell. The prompt
+/// needs to know which parts of itself are zero width, so shells that
+/// need it get the code wrapped in the right markers. Shells where we
+/// don't know how to do that just don't get color.
+fn color_code(shell: &str, sgr: u8) -> String {
+    if shell.ends_with("bash") {
+        format!(r"\[\e[{}m\]", sgr)
+    } else if shell.ends_with("zsh") {
+        format!(r#"%{{"$'\e[{}m'"%}}"#, sgr)
+    } else if shell.ends_with("fish") {
+        format!(r#""(printf '\e[{}m')""#, sgr)
+    } else if shell == "nu" {
+        format!("' + (char esc) + '[{}m", sgr)
+    } else {
+        String::new()
+    }
+}
+
+/// Build the script which injects the prefix. In a user supplied template,
+/// the string '$SHPOOL_PROMPT_PREFIX' gets replaced with the prefix.
+fn prefix_script(
     shell: &str,
     prompt_prefix: &str,
-    session_name: &str,
-    needs_default_term: bool,
-) -> anyhow::Result<()> {
-    let prompt_prefix = prompt_prefix.replace("$SHPOOL_SESSION_NAME", session_name);
-    let mut script = if shell.ends_with("bash") {
+    script_template: Option<&str>,
+) -> anyhow::Result<String> {
+    // For bash, PS0 gets expanded right before a command runs but after it has
+    // been added to the history, which is the closest thing bash has to a preexec
+    // hook. For zsh, the finished mark needs to be the first precmd function so
+    // that it sees the exit status of the command.
+    let script = if let Some(template) = script_template {
+        template.replace("$SHPOOL_PROMPT_PREFIX", prompt_prefix)
+    } else if shell.ends_with("bash") {
         format!(
             r#"
+            function __shpool__command_finished() {{
+               printf '\e]133;D;%s\a' "$1"
+            }}
+            function __shpool__command_executed() {{
+               local cmd
+               cmd="$(HISTTIMEFORMAT= builtin history 1)"
+               cmd="${{cmd#*[0-9]  }}"
+               printf '\e]133;C;cmdline=%s\a' "${{cmd//[[:cntrl:]]/ }}"
+            }}
+            PS0='$(__shpool__command_executed)'"${{PS0}}"
             if [[ -z "${{PROMPT_COMMAND+x}}" ]]; then
-               PS1="{prompt_prefix}${{PS1}}"
+               PS1="\[\e]133;A\a\]{prompt_prefix}${{PS1}}\[\e]133;B\a\]"
+               PROMPT_COMMAND='__shpool__command_finished "$?"'
             else
                SHPOOL__OLD_PROMPT_COMMAND="${{PROMPT_COMMAND}}"
                SHPOOL__OLD_PS1="${{PS1}}"
+               PS1="\[\e]133;A\a\]{prompt_prefix}${{PS1}}\[\e]133;B\a\]"
                function __shpool__prompt_command() {{
+                  __shpool__command_finished "$?"
                   PS1="${{SHPOOL__OLD_PS1}}"
                   ${{SHPOOL__OLD_PROMPT_COMMAND}}
-                  PS1="{prompt_prefix}${{PS1}}"
+                  PS1="\[\e]133;A\a\]{prompt_prefix}${{PS1}}\[\e]133;B\a\]"
                }}
                PROMPT_COMMAND=__shpool__prompt_command
             fi
@@ -52,37 +424,245 @@ pub fn inject_prefix(
     } else if shell.ends_with("zsh") {
         format!(
             r#"
-            typeset -a precmd_functions
+            typeset -a precmd_functions preexec_functions
             SHPOOL__OLD_PROMPT="${{PROMPT}}"
             function __shpool__reset_rprompt() {{
                 PROMPT="${{SHPOOL__OLD_PROMPT}}"
             }}
             precmd_functions[1,0]=(__shpool__reset_rprompt)
+            function __shpool__command_finished() {{
+                printf '\e]133;D;%s\a' "$?"
+            }}
+            precmd_functions[1,0]=(__shpool__command_finished)
             function __shpool__prompt_command() {{
-               PROMPT="{prompt_prefix}${{PROMPT}}"
+               PROMPT="%{{"$'\e]133;A\a'"%}}{prompt_prefix}${{PROMPT}}%{{"$'\e]133;B\a'"%}}"
             }}
             precmd_functions+=(__shpool__prompt_command)
+            function __shpool__command_executed() {{
+                printf '\e]133;C;cmdline=%s\a' "${{1//[[:cntrl:]]/ }}"
+            }}
+            preexec_functions+=(__shpool__command_executed)
 "#
         )
     } else if shell.ends_with("fish") {
         format!(
             r#"
             functions --copy fish_prompt shpool__old_prompt
-            function fish_prompt; echo -n "{prompt_prefix}"; shpool__old_prompt; end
+            function fish_prompt
+                printf '\e]133;A\a'
+                echo -n "{prompt_prefix}"
+                shpool__old_prompt
+                printf '\e]133;B\a'
+            end
+            function __shpool__command_executed --on-event fish_preexec
+                printf '\e]133;C;cmdline=%s\a' (string replace -ra '[[:cntrl:]]' ' ' -- $argv)
+            end
+            function __shpool__command_finished --on-event fish_postexec
+                printf '\e]133;D;%s\a' $status
+            end
+"#
+        )
+    } else if shell == "nu" {
+        // The prompt may be either a closure or a plain string, and nu will
+        // fall back on its own default prompt if it is unset.
+        format!(
+            r#"
+            $env.SHPOOL__OLD_PROMPT_COMMAND = $env.PROMPT_COMMAND?
+            $env.PROMPT_COMMAND = {{||
+                let old = $env.SHPOOL__OLD_PROMPT_COMMAND
+                let old_prompt = if ($old | describe) == "closure" {{ do $old }} else {{ $old | default "" }}
+                '{prompt_prefix}' + $old_prompt
+            }}
+"#
+        )
+    } else if shell.ends_with("xonsh") {
+        // $PROMPT may be either a format string or a function returning one.
+        format!(
+            r#"
+$PROMPT = (lambda old: lambda: '{prompt_prefix}' + old())($PROMPT) if callable($PROMPT) else '{prompt_prefix}' + $PROMPT
+"#
+        )
+    } else if shell.ends_with("ksh") {
+        format!(
+            r#"
+            PS1="{prompt_prefix}${{PS1}}"
+"#
+        )
+    } else if shell.ends_with("csh") {
+        format!(
+            r#"
+            set prompt = "{prompt_prefix}${{prompt}}"
 "#
         )
     } else {
         return Err(anyhow!("don't know how to inject a prefix for shell '{}'", shell));
     };
 
-    if needs_default_term {
-        script.push_str("\nTERM=xterm clear\n");
-    } else {
-        script.push_str("\nclear\n");
+    Ok(script)
+}
+
+/// The command to clear the screen in the given shell once the typed script
+/// has run, so that it does not stick around on the screen. If we don't have a
+/// $TERM value or we have some wacky $TERM value for which there is no
+/// ClearScreen code, we set TERM to xterm for the clear so that we won't
+/// get a warning and will generate a code we can scan for.
+fn clear_command(shell: &str, needs_default_term: bool) -> &'static str {
+    let sh_like = ["bash", "zsh", "fish", "ksh"].iter().any(|sh| shell.ends_with(sh));
+    match (needs_default_term, shell) {
+        // nu has its own builtin clear which emits a different code than
+        // the terminfo one we scan for, so make sure to use the real one.
+        (true, "nu") => "\nwith-env {TERM: xterm} { ^clear }\n",
+        (false, "nu") => "\n^clear\n",
+        (true, _) if sh_like => "\nTERM=xterm clear\n",
+        (true, _) => "\nenv TERM=xterm clear\n",
+        (false, _) => "\nclear\n",
     }
+}
 
-    let mut pty_master = pty_master.is_parent().context("expected parent")?;
-    pty_master.write_all(script.as_bytes()).context("running prefix script")?;
+#[cfg(test)]
+mod test {
+    use super::*;
 
-    Ok(())
+    fn args<'a>(
+        shell: &'a str,
+        script_template: Option<&'a str>,
+        rc_dir: Option<PathBuf>,
+    ) -> InjectArgs<'a> {
+        InjectArgs {
+            shell,
+            prompt_prefix: "PFX ",
+            vars: vars(),
+            script_template,
+            needs_default_term: script_template.is_some(),
+            rc_dir,
+            norc: false,
+            clear_code: Vec::from(&b"\x1b[H\x1b[2J"[..]),
+        }
+    }
+
+    fn typed(shell: &str, script_template: Option<&str>) -> anyhow::Result<String> {
+        let mut cmd = process::Command::new(shell);
+        match prepare_injection(&mut cmd, &args(shell, script_template, None))? {
+            Injection::Typed(script) => Ok(script),
+            Injection::Rcfile { .. } => Err(anyhow!("unexpected rc file injection")),
+        }
+    }
+
+    #[test]
+    fn builtin_scripts() -> anyhow::Result<()> {
+        for shell in ["bash", "zsh", "fish", "nu", "xonsh", "ksh", "mksh", "tcsh", "csh"] {
+            let script = typed(shell, None)?;
+            assert!(script.contains("PFX "), "shell={}", shell);
+            assert!(script.ends_with("\n^clear\n") || script.ends_with("\nclear\n"));
+        }
+
+        assert!(typed("nosuchsh", None).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn rcfile() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let clear = r"printf '\033\133\110\033\133\062\112'";
+
+        for (shell, rcfile) in [("bash", "bashrc"), ("zsh", ".zshrc"), ("fish", "config.fish")] {
+            let rc_dir = tmp_dir.path().join(shell);
+            let mut cmd = process::Command::new(shell);
+            let injection = prepare_injection(&mut cmd, &args(shell, None, Some(rc_dir.clone())))?;
+            match injection {
+                Injection::Rcfile { fallback: Some(fallback) } => {
+                    assert_eq!(shell, "bash");
+                    assert!(fallback.contains(r#"builtin source "${SHPOOL__RCFILE}""#));
+                    assert!(fallback.ends_with(&format!("else {}; fi\n", clear)));
+                }
+                Injection::Rcfile { fallback: None } => assert_ne!(shell, "bash"),
+                Injection::Typed(_) => panic!("unexpected typed injection for {}", shell),
+            }
+
+            let contents = fs::read_to_string(rc_dir.join(rcfile))?;
+            assert!(contents.contains("PFX "), "shell={}", shell);
+            assert!(contents.ends_with(&format!("\n{}\n", clear)), "shell={}", shell);
+        }
+
+        // bash stays a login shell and picks the rc file up from PROMPT_COMMAND
+        let mut cmd = process::Command::new("bash");
+        cmd.env("PROMPT_COMMAND", "history -a");
+        prepare_injection(&mut cmd, &args("bash", None, Some(tmp_dir.path().join("login"))))?;
+        assert_eq!(cmd.get_args().count(), 0);
+        let prompt_command = cmd
+            .get_envs()
+            .find(|(var, _)| *var == "PROMPT_COMMAND")
+            .and_then(|(_, val)| val)
+            .and_then(|val| val.to_str());
+        assert_eq!(prompt_command, Some(format!("{}; history -a", BASH_SHIM).as_str()));
+
+        let mut cmd = process::Command::new("nu");
+        let injection =
+            prepare_injection(&mut cmd, &args("nu", None, Some(tmp_dir.path().into())))?;
+        assert!(matches!(injection, Injection::Typed(_)));
+
+        let mut cmd = process::Command::new("bash");
+        let mut bash_args = args("bash", None, Some(tmp_dir.path().join("norc")));
+        bash_args.norc = true;
+        let injection = prepare_injection(&mut cmd, &bash_args)?;
+        assert!(matches!(injection, Injection::Typed(_)));
+        assert_eq!(cmd.get_args().count(), 0);
+
+        Ok(())
+    }
+
+    fn vars() -> PrefixVars<'static> {
+        PrefixVars {
+            session_name: "main",
+            started_at: chrono::Local::now(),
+            ttl: Some(time::Duration::from_secs(2 * 60 * 60 + 30 * 60)),
+            hostname: None,
+        }
+    }
+
+    #[test]
+    fn expand() {
+        let cases = vec![
+            ("shpool:$SHPOOL_SESSION_NAME ", "shpool:main "),
+            ("{session_name} ", "main "),
+            ("[{ttl}]", "[2h30m]"),
+            ("{if ttl}ttl={ttl} {end}{if hostname}@{hostname}{end}$ ", "ttl=2h30m $ "),
+            ("{if hostname}{if ttl}nested{end}{end}x", "x"),
+            ("{if nosuchvar}gone{end}x", "x"),
+            ("%F{red}{session_name}%f", "%F{red}main%f"),
+            ("%K{blue}%{\x1b[1m%}{session_name}%k", "%K{blue}%{\x1b[1m%}main%k"),
+            ("50% {session_name} ", "50% main "),
+            ("%F {session_name}", "%F main"),
+            ("${hostname}{unknown}", "${hostname}{unknown}"),
+            ("{unterminated", "{unterminated"),
+            ("{end}", "{end}"),
+        ];
+        for (template, expanded) in cases.into_iter() {
+            assert_eq!(expand_prefix(template, "bash", &vars(), true), expanded);
+        }
+    }
+
+    #[test]
+    fn expand_colors() {
+        let template = "{red}{session_name}{reset} ";
+        assert_eq!(expand_prefix(template, "bash", &vars(), true), r"\[\e[31m\]main\[\e[0m\] ");
+        assert_eq!(expand_prefix(template, "bash", &vars(), false), "main ");
+        assert_eq!(expand_prefix(template, "ksh", &vars(), true), "main ");
+        assert_eq!(
+            expand_prefix(template, "nu", &vars(), true),
+            "' + (char esc) + '[31mmain' + (char esc) + '[0m "
+        );
+    }
+
+    #[test]
+    fn custom_template() -> anyhow::Result<()> {
+        let script = typed("nosuchsh", Some("prompt='$SHPOOL_PROMPT_PREFIX'"))?;
+        assert_eq!(script, "prompt='PFX '\nenv TERM=xterm clear\n");
+
+        let script = typed("bash", Some("PS1=\"$SHPOOL_PROMPT_PREFIX\""))?;
+        assert_eq!(script, "PS1=\"PFX \"\nTERM=xterm clear\n");
+
+        Ok(())
+    }
 }
@@ -26,13 +26,16 @@ use std::{
     },
     path::{Path, PathBuf},
     process,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
     thread, time,
     time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Context};
-use nix::unistd;
+use nix::{fcntl::OFlag, unistd};
 use tracing::{error, info, instrument, span, trace, warn, Level};
 
 use crate::{
@@ -40,8 +43,9 @@ use crate::{
     config::MotdDisplayMode,
     consts,
     daemon::{
-        etc_environment, exit_notify::ExitNotifier, hooks, pager::PagerError, prompt, shell,
-        show_motd, ttl_reaper,
+        activity, cgroup, command_log::CommandLog, control_codes, etc_environment, event_loop,
+        exit_notify, exit_notify::ExitNotifier, hook_commands, hook_worker, hooks, metrics,
+        pager::PagerError, prompt, shell, show_motd, systemd, triggers, ttl_reaper,
     },
     protocol, test_hooks, tty, user,
 };
@@ -49,6 +53,17 @@ use crate::{
 const DEFAULT_INITIAL_SHELL_PATH: &str = "/usr/bin:/bin:/usr/sbin:/sbin";
 const DEFAULT_OUTPUT_SPOOL_LINES: usize = 500;
 const DEFAULT_PROMPT_PREFIX: &str = "shpool:$SHPOOL_SESSION_NAME ";
+const DEFAULT_ACTIVITY_IDLE_SECS: u64 = 10;
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+// How long to give hook commands to finish when the daemon is stopping.
+const STOP_HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+// How long to wait for a killed shell to get reaped during a stop so
+// that its exit status can be passed along to hooks.
+const STOP_REAP_TIMEOUT: Duration = Duration::from_secs(1);
+const STOP_BANNER: &str = "the daemon is stopping";
+// Even a handful of loops can drive a great many sessions, since they
+// only wake up when there is something to do.
+const MAX_EVENT_LOOPS: usize = 4;
 
 pub struct Server {
     config: config::Config,
@@ -61,24 +76,64 @@ pub struct Server {
     shells: Arc<Mutex<HashMap<String, Box<shell::Session>>>>,
     runtime_dir: PathBuf,
     register_new_reapable_session: crossbeam_channel::Sender<(String, Instant)>,
-    hooks: Box<dyn hooks::Hooks + Send + Sync>,
+    hooks: Arc<dyn hooks::Hooks + Send + Sync>,
+    /// Runs the commands for output triggers, if any have commands.
+    command_runner: Option<hook_commands::Runner>,
+    /// Calls hooks for the readers, which must not block.
+    hook_worker: hook_worker::HookWorker,
     daily_messenger: Arc<show_motd::DailyMessenger>,
+    info: DaemonInfo,
+    metrics: Arc<metrics::Metrics>,
+    /// Set if systemd wants to hear about how we are doing.
+    notifier: Option<Arc<systemd::Notifier>>,
+    /// Set if sessions get cgroups of their own.
+    cgroups: Option<Arc<cgroup::Manager>>,
+    /// Connection ids are unique across all the sockets we listen on.
+    conn_counter: AtomicUsize,
+    /// Drives the readers and child watchers for all the sessions.
+    event_loops: event_loop::Pool,
+}
+
+/// A control socket for the daemon to accept connections on.
+pub struct Listener {
+    pub listener: UnixListener,
+    /// If set, only commands that don't change anything are allowed.
+    pub read_only: bool,
+}
+
+/// Facts about how the daemon was started, reported by `shpool status`.
+pub struct DaemonInfo {
+    pub started_at: time::SystemTime,
+    pub config_file: Option<PathBuf>,
+    pub config_loaded_at: time::SystemTime,
+    pub socket: PathBuf,
+    /// All of the control sockets we listen on, including read-only ones.
+    pub sockets: Vec<PathBuf>,
+    pub systemd_activated: bool,
+    /// The sockets we bound ourselves, which we remove on exit.
+    pub cleanup_sockets: Vec<PathBuf>,
 }
 
 impl Server {
     #[instrument(skip_all)]
     pub fn new(
         config: config::Config,
-        hooks: Box<dyn hooks::Hooks + Send + Sync>,
+        hooks: Arc<dyn hooks::Hooks + Send + Sync>,
+        command_runner: Option<hook_commands::Runner>,
         runtime_dir: PathBuf,
+        info: DaemonInfo,
+        notifier: Option<Arc<systemd::Notifier>>,
     ) -> anyhow::Result<Arc<Self>> {
         let shells = Arc::new(Mutex::new(HashMap::new()));
         // buffered so that we are unlikely to block when setting up a
         // new session
         let (new_sess_tx, new_sess_rx) = crossbeam_channel::bounded(10);
         let shells_tab = Arc::clone(&shells);
+        let reaper_hooks = Arc::clone(&hooks);
+        let metrics = Arc::new(metrics::Metrics::default());
+        let reaper_metrics = Arc::clone(&metrics);
         thread::spawn(move || {
-            if let Err(e) = ttl_reaper::run(new_sess_rx, shells_tab) {
+            if let Err(e) = ttl_reaper::run(new_sess_rx, shells_tab, reaper_hooks, reaper_metrics) {
                 warn!("ttl reaper exited with error: {:?}", e);
             }
         });
@@ -87,29 +142,79 @@ impl Server {
             config.motd.clone().unwrap_or_default(),
             config.motd_args.clone(),
         )?);
+        let cgroups = match &config.session_cgroup {
+            Some(cgroup_config) => match cgroup::Manager::new(cgroup_config) {
+                Ok(manager) => Some(Arc::new(manager)),
+                Err(err) => {
+                    warn!("not putting sessions in cgroups: {:?}", err);
+                    None
+                }
+            },
+            None => None,
+        };
+        let hook_worker =
+            hook_worker::HookWorker::new(Arc::clone(&hooks)).context("starting hook worker")?;
+        let event_loops = event_loop::Pool::new(
+            thread::available_parallelism().map(|n| n.get()).unwrap_or(1).min(MAX_EVENT_LOOPS),
+        )
+        .context("starting event loops")?;
         Ok(Arc::new(Server {
             config,
             shells,
             runtime_dir,
             register_new_reapable_session: new_sess_tx,
             hooks,
+            command_runner,
+            hook_worker,
             daily_messenger,
+            info,
+            metrics,
+            notifier,
+            cgroups,
+            conn_counter: AtomicUsize::new(0),
+            event_loops,
         }))
     }
 
     #[instrument(skip_all)]
-    pub fn serve(server: Arc<Self>, listener: UnixListener) -> anyhow::Result<()> {
+    pub fn serve(server: Arc<Self>, mut listeners: Vec<Listener>) -> anyhow::Result<()> {
         test_hooks::emit("daemon-about-to-listen");
-        let mut conn_counter = 0;
-        for stream in listener.incoming() {
+        if let Some(notifier) = &server.notifier {
+            notifier.notify(&format!("READY=1\nSTATUS={}", server.status_line()));
+            let status_server = Arc::clone(&server);
+            let alive_server = Arc::clone(&server);
+            systemd::supervise(
+                Arc::clone(notifier),
+                move || status_server.status_line(),
+                move || alive_server.check_liveness(),
+            )
+            .context("supervising with systemd")?;
+        }
+
+        // accept connections on the last socket in this thread and
+        // give every other socket a thread of its own
+        let last_listener = listeners.pop().ok_or(anyhow!("no sockets to listen on"))?;
+        for listener in listeners.into_iter() {
+            let server = Arc::clone(&server);
+            thread::Builder::new()
+                .name(String::from("listener"))
+                .spawn(move || Server::accept(server, listener))
+                .context("spawning listener thread")?;
+        }
+        Server::accept(server, last_listener);
+
+        Ok(())
+    }
+
+    fn accept(server: Arc<Self>, listener: Listener) {
+        for stream in listener.listener.incoming() {
             info!("socket got a new connection");
             match stream {
                 Ok(stream) => {
-                    conn_counter += 1;
-                    let conn_id = conn_counter;
+                    let conn_id = server.conn_counter.fetch_add(1, Ordering::Relaxed) + 1;
                     let server = Arc::clone(&server);
                     thread::spawn(move || {
-                        if let Err(err) = server.handle_conn(stream, conn_id) {
+                        if let Err(err) = server.handle_conn(stream, conn_id, listener.read_only) {
                             error!("handling new connection: {:?}", err)
                         }
                     });
@@ -119,31 +224,61 @@ impl Server {
                 }
             }
         }
-
-        Ok(())
     }
 
     #[instrument(skip_all, fields(cid = conn_id))]
-    fn handle_conn(&self, mut stream: UnixStream, conn_id: usize) -> anyhow::Result<()> {
+    fn handle_conn(
+        &self,
+        mut stream: UnixStream,
+        conn_id: usize,
+        read_only: bool,
+    ) -> anyhow::Result<()> {
         // We want to avoid timing out while blocking the main thread.
         stream
             .set_read_timeout(Some(consts::SOCK_STREAM_TIMEOUT))
             .context("setting read timout on inbound session")?;
 
         let header = parse_connect_header(&mut stream).context("parsing connect header")?;
+        let is_attach = matches!(
+            header,
+            protocol::ConnectHeader::Attach(_) | protocol::ConnectHeader::FramedAttach(_)
+        );
+        let framed = matches!(header, protocol::ConnectHeader::FramedAttach(_));
+
+        let peer = match check_peer(&stream) {
+            Ok(peer) => peer,
+            Err(err) => {
+                if is_attach {
+                    write_attach_reply(
+                        &mut stream,
+                        framed,
+                        protocol::AttachStatus::Forbidden(format!("{:?}", err)),
+                        None,
+                    )?;
+                }
+                stream.shutdown(net::Shutdown::Both).context("closing stream")?;
+                return Err(err);
+            }
+        };
 
-        if let Err(err) = check_peer(&stream) {
-            if let protocol::ConnectHeader::Attach(_) = header {
-                write_reply(
+        let changes_nothing = matches!(
+            header,
+            protocol::ConnectHeader::List
+                | protocol::ConnectHeader::ListV2
+                | protocol::ConnectHeader::Status
+        );
+        if read_only && !changes_nothing {
+            if is_attach {
+                write_attach_reply(
                     &mut stream,
-                    protocol::AttachReplyHeader {
-                        status: protocol::AttachStatus::Forbidden(format!("{:?}", err)),
-                    },
+                    framed,
+                    protocol::AttachStatus::Forbidden(String::from("this socket is read-only")),
+                    None,
                 )?;
             }
             stream.shutdown(net::Shutdown::Both).context("closing stream")?;
-            return Err(err);
-        };
+            return Err(anyhow!("refusing a command that needs a read-write socket"));
+        }
 
         // Unset the read timeout before we pass things off to a
         // worker thread because it is perfectly fine for there to
@@ -152,12 +287,21 @@ impl Server {
         stream.set_read_timeout(None).context("unsetting read timout on inbound session")?;
 
         match header {
-            protocol::ConnectHeader::Attach(h) => self.handle_attach(stream, conn_id, h),
+            protocol::ConnectHeader::Attach(h) => {
+                self.handle_attach(stream, conn_id, h, None, &peer)
+            }
+            protocol::ConnectHeader::FramedAttach(h) => {
+                self.handle_attach(stream, conn_id, h.attach, Some(h.compression), &peer)
+            }
             protocol::ConnectHeader::Detach(r) => self.handle_detach(stream, r),
-            protocol::ConnectHeader::Kill(r) => self.handle_kill(stream, r),
-            protocol::ConnectHeader::List => self.handle_list(stream),
+            protocol::ConnectHeader::Kill(r) => self.handle_kill(stream, r, &peer),
+            protocol::ConnectHeader::List => self.handle_list(stream, false),
+            protocol::ConnectHeader::ListV2 => self.handle_list(stream, true),
+            protocol::ConnectHeader::Status => self.handle_status(stream),
+            protocol::ConnectHeader::Broadcast(r) => self.handle_broadcast(stream, r),
+            protocol::ConnectHeader::Stop(r) => self.handle_stop(stream, r),
             protocol::ConnectHeader::SessionMessage(header) => {
-                self.handle_session_message(stream, header)
+                self.handle_session_message(stream, header, &peer)
             }
         }
     }
@@ -168,12 +312,17 @@ impl Server {
         mut stream: UnixStream,
         conn_id: usize,
         header: protocol::AttachHeader,
+        // Only set for a FramedAttach, which is the only kind of attach
+        // that sends chunked input and can have its output compressed.
+        offered_compression: Option<Vec<protocol::Compression>>,
+        peer: &Peer,
     ) -> anyhow::Result<()> {
+        let framed = offered_compression.is_some();
         // We don't currently populate any warnings, but we used to and we might
         // want to in the future, so it is not worth breaking the protocol over.
         let warnings = vec![];
 
-        let (child_exit_notifier, inner_to_stream, pager_ctl_slot, status) = {
+        let (child_exit_notifier, inner_to_stream, pager_ctl_slot, attached, hook_ctx, status) = {
             // we unwrap to propagate the poison as an unwind
             let mut shells = self.shells.lock().unwrap();
             info!("locked shells table");
@@ -181,7 +330,8 @@ impl Server {
             let mut status = protocol::AttachStatus::Attached { warnings: warnings.clone() };
             if let Some(session) = shells.get(&header.name) {
                 info!("found entry for '{}'", header.name);
-                if let Ok(mut inner) = session.inner.try_lock() {
+                if !session.attached.load(Ordering::Relaxed) {
+                    let mut inner = session.inner.lock().unwrap();
                     info!("session '{}': locked inner", header.name);
                     // We have an existing session in our table, but the subshell
                     // proc might have exited in the meantime, for example if the
@@ -206,7 +356,7 @@ impl Server {
                                 .unwrap_or(false)
                             {
                                 warn!(
-                                    "child_exited chan unclosed, but reader thread has exited, clobbering with new subshell"
+                                    "child_exited chan unclosed, but reader has exited, clobbering with new subshell"
                                 );
                                 status = protocol::AttachStatus::Created { warnings };
                             }
@@ -224,11 +374,9 @@ impl Server {
                     }
 
                     if inner.reader_join_h.as_ref().map(|h| h.is_finished()).unwrap_or(false) {
-                        info!("reader thread finished, joining");
+                        info!("reader finished, joining");
                         if let Some(h) = inner.reader_join_h.take() {
-                            h.join()
-                                .map_err(|e| anyhow!("joining reader on reattach: {:?}", e))?
-                                .context("within reader thread on reattach")?;
+                            h.join().context("within reader on reattach")?;
                         }
                         assert!(matches!(status, protocol::AttachStatus::Created { .. }));
                     }
@@ -236,13 +384,12 @@ impl Server {
                     // fallthrough to bidi streaming
                 } else {
                     info!("busy shell session, doing nothing");
+                    self.metrics.busy_rejections.inc();
                     // The stream is busy, so we just inform the client and close the stream.
-                    write_reply(
-                        &mut stream,
-                        protocol::AttachReplyHeader { status: protocol::AttachStatus::Busy },
-                    )?;
+                    write_attach_reply(&mut stream, framed, protocol::AttachStatus::Busy, None)?;
                     stream.shutdown(net::Shutdown::Both).context("closing stream")?;
-                    if let Err(err) = self.hooks.on_busy(&header.name) {
+                    let ctx = peer.hook_context(session, &header);
+                    if let Err(err) = self.hooks.on_busy_with_context(&ctx) {
                         warn!("busy hook: {:?}", err);
                     }
                     return Ok(());
@@ -256,9 +403,6 @@ impl Server {
                 use config::MotdDisplayMode;
 
                 info!("creating new subshell");
-                if let Err(err) = self.hooks.on_new_session(&header.name) {
-                    warn!("new_session hook: {:?}", err);
-                }
                 let motd = self.config.motd.clone().unwrap_or_default();
                 let session = self.spawn_subshell(
                     conn_id,
@@ -267,113 +411,149 @@ impl Server {
                     matches!(motd, MotdDisplayMode::Dump),
                 )?;
 
+                let ctx = peer.hook_context(&session, &header);
                 shells.insert(header.name.clone(), Box::new(session));
+                self.metrics.sessions_created.inc();
+                if let Err(err) = self.hooks.on_new_session_with_context(&ctx) {
+                    warn!("new_session hook: {:?}", err);
+                }
                 // fallthrough to bidi streaming
-            } else if let Err(err) = self.hooks.on_reattach(&header.name) {
-                warn!("reattach hook: {:?}", err);
+            } else if let Some(session) = shells.get(&header.name) {
+                let ctx = peer.hook_context(session, &header);
+                if let Err(err) = self.hooks.on_reattach_with_context(&ctx) {
+                    warn!("reattach hook: {:?}", err);
+                }
             }
 
-            // return a reference to the inner session so that
-            // we can work with it without the global session
-            // table lock held
+            // Claim the session while we still hold the table lock, then
+            // return a reference to the inner session so that we can work
+            // with it without the global session table lock held.
             if let Some(session) = shells.get(&header.name) {
+                session.attached.store(true, Ordering::Relaxed);
                 (
                     Some(Arc::clone(&session.child_exit_notifier)),
                     Some(Arc::clone(&session.inner)),
                     Some(Arc::clone(&session.pager_ctl)),
+                    Some(Arc::clone(&session.attached)),
+                    peer.hook_context(session, &header),
                     status,
                 )
             } else {
-                (None, None, None, status)
+                (None, None, None, None, hooks::HookContext::new(&header.name), status)
             }
         };
         info!("released lock on shells table");
 
-        self.link_ssh_auth_sock(&header).context("linking SSH_AUTH_SOCK")?;
-
-        if let (Some(child_exit_notifier), Some(inner), Some(pager_ctl_slot)) =
-            (child_exit_notifier, inner_to_stream, pager_ctl_slot)
-        {
-            let mut child_done = false;
-            let mut inner = inner.lock().unwrap();
-            let client_stream = match inner.client_stream.as_mut() {
-                Some(s) => s,
-                None => {
-                    return Err(anyhow!("no client stream, should be impossible"));
-                }
-            };
+        let (
+            Some(child_exit_notifier),
+            Some(inner_to_stream),
+            Some(pager_ctl_slot),
+            Some(attached),
+        ) = (child_exit_notifier, inner_to_stream, pager_ctl_slot, attached)
+        else {
+            error!("internal error: failed to fetch just inserted session");
+            return Ok(());
+        };
 
-            let reply_status =
-                write_reply(client_stream, protocol::AttachReplyHeader { status: status.clone() });
-            if let Err(e) = reply_status {
-                error!("error writing reply status: {:?}", e);
-            }
-
-            // If in pager motd mode, launch the pager and block until it is
-            // done, picking up any tty size change that happened while the
-            // user was examining the motd.
-            let motd_mode = self.config.motd.clone().unwrap_or_default();
-            let init_tty_size = if matches!(motd_mode, MotdDisplayMode::Pager { .. }) {
-                match self.daily_messenger.display_in_pager(
-                    client_stream,
-                    pager_ctl_slot,
-                    header.local_tty_size.clone(),
-                ) {
-                    Ok(new_size) => {
-                        info!("motd pager finished, reporting new tty size: {:?}", new_size);
-                        new_size
-                    }
-                    Err(e) => match e.downcast::<PagerError>() {
-                        Ok(PagerError::ClientHangup) => {
-                            info!("client hung up while talking to pager, bailing");
-                            return Ok(());
+        // Once the reader is done with the connection, or if we bail
+        // before handing it over, wrap things up and let go of the
+        // session. This runs on its own thread, since the reader lets
+        // go of the connection on an event loop.
+        let done = {
+            let shells = Arc::clone(&self.shells);
+            let hooks = Arc::clone(&self.hooks);
+            let name = header.name.clone();
+            let inner = Arc::clone(&inner_to_stream);
+            let child_exit = Arc::clone(&child_exit_notifier);
+            let mut hook_ctx = hook_ctx.clone();
+            shell::ConnDone::new(move || {
+                thread::spawn(move || {
+                    let _s = span!(Level::INFO, "attach_done", s = name, cid = conn_id).entered();
+                    hook_ctx.timestamp = time::SystemTime::now();
+                    hook_ctx.exit_status = child_exit.wait(Some(time::Duration::from_millis(0)));
+                    if hook_ctx.exit_status.is_some() {
+                        info!("'{}' exited, removing from session table", name);
+                        if let Err(err) = hooks.on_shell_disconnect_with_context(&hook_ctx) {
+                            warn!("shell_disconnect hook: {:?}", err);
                         }
-                        Err(e) => {
-                            return Err(e).context("showing motd in pager")?;
+                        shells.lock().unwrap().remove(&name);
+
+                        // The child shell has exited, so the reader gets woken
+                        // up to wrap up and finish. That means we should be
+                        // safe to join. We take the handle in a separate
+                        // statement to avoid holding the inner lock while we
+                        // join the old reader.
+                        let reader_join_h = inner.lock().unwrap().reader_join_h.take();
+                        if let Some(h) = reader_join_h {
+                            if let Err(err) = h.join() {
+                                warn!("within reader after child exit: {:?}", err);
+                            }
                         }
-                    },
-                }
-            } else {
-                header.local_tty_size.clone()
-            };
+                    } else if let Err(err) = hooks.on_client_disconnect_with_context(&hook_ctx) {
+                        warn!("client_disconnect hook: {:?}", err);
+                    }
 
-            info!("starting bidi stream loop");
-            match inner.bidi_stream(conn_id, init_tty_size, child_exit_notifier) {
-                Ok(done) => {
-                    child_done = done;
-                }
-                Err(e) => {
-                    error!("error shuffling bytes: {:?}", e);
-                }
+                    attached.store(false, Ordering::Relaxed);
+                    info!("finished attach streaming section");
+                    test_hooks::emit("daemon-bidi-stream-done");
+                });
+            })
+        };
+
+        self.link_ssh_auth_sock(&header).context("linking SSH_AUTH_SOCK")?;
+
+        self.metrics.attaches.inc();
+        let mut inner = inner_to_stream.lock().unwrap();
+        let client_stream = match inner.client_stream.as_mut() {
+            Some(s) => s,
+            None => {
+                return Err(anyhow!("no client stream, should be impossible"));
             }
-            info!("bidi stream loop finished");
+        };
 
-            if child_done {
-                info!("'{}' exited, removing from session table", header.name);
-                if let Err(err) = self.hooks.on_shell_disconnect(&header.name) {
-                    warn!("shell_disconnect hook: {:?}", err);
-                }
-                let mut shells = self.shells.lock().unwrap();
-                shells.remove(&header.name);
-
-                // The child shell has exited, so the reader thread should
-                // attempt to read from its stdout and get an error, causing
-                // it to exit. That means we should be safe to join. We use
-                // a separate if statement to avoid holding the shells lock
-                // while we join the old thread.
-                if let Some(h) = inner.reader_join_h.take() {
-                    h.join()
-                        .map_err(|e| anyhow!("joining reader after child exit: {:?}", e))?
-                        .context("within reader thread after child exit")?;
+        // Every algorithm is built in, so we can just go with the
+        // client's favorite.
+        let compression = offered_compression.and_then(|offered| offered.first().copied());
+        let reply_status = write_attach_reply(client_stream, framed, status.clone(), compression);
+        if let Err(e) = reply_status {
+            error!("error writing reply status: {:?}", e);
+        }
+
+        // If in pager motd mode, launch the pager and block until it is
+        // done, picking up any tty size change that happened while the
+        // user was examining the motd.
+        let motd_mode = self.config.motd.clone().unwrap_or_default();
+        let mut client_input =
+            if framed { protocol::ChunkDecoder::default() } else { protocol::ChunkDecoder::raw() };
+        let init_tty_size = if matches!(motd_mode, MotdDisplayMode::Pager { .. }) {
+            match self.daily_messenger.display_in_pager(
+                client_stream,
+                &mut client_input,
+                pager_ctl_slot,
+                header.local_tty_size.clone(),
+            ) {
+                Ok(new_size) => {
+                    info!("motd pager finished, reporting new tty size: {:?}", new_size);
+                    new_size
                 }
-            } else if let Err(err) = self.hooks.on_client_disconnect(&header.name) {
-                warn!("client_disconnect hook: {:?}", err);
+                Err(e) => match e.downcast::<PagerError>() {
+                    Ok(PagerError::ClientHangup) => {
+                        info!("client hung up while talking to pager, bailing");
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        return Err(e).context("showing motd in pager")?;
+                    }
+                },
             }
-
-            info!("finished attach streaming section");
         } else {
-            error!("internal error: failed to fetch just inserted session");
-        }
+            header.local_tty_size.clone()
+        };
+
+        info!("handing the client connection to the reader");
+        inner
+            .bidi_stream(conn_id, init_tty_size, client_input, compression, hook_ctx, done)
+            .context("hooking the client up to the reader")?;
 
         Ok(())
     }
@@ -460,6 +640,7 @@ impl Server {
         &self,
         mut stream: UnixStream,
         request: protocol::KillRequest,
+        peer: &Peer,
     ) -> anyhow::Result<()> {
         let mut not_found_sessions = vec![];
         {
@@ -470,6 +651,16 @@ impl Server {
                 if let Some(s) = shells.get(&session) {
                     s.kill().context("killing shell proc")?;
 
+                    let mut ctx = s.hook_context(&session);
+                    ctx.client_pid = Some(peer.pid.as_raw());
+                    ctx.client_exe = Some(peer.exe.clone());
+                    ctx.exit_status =
+                        s.child_exit_notifier.wait(Some(time::Duration::from_millis(0)));
+                    self.metrics.sessions_killed.inc();
+                    if let Err(err) = self.hooks.on_session_killed(&ctx) {
+                        warn!("session_killed hook: {:?}", err);
+                    }
+
                     // we don't need to wait since the dedicated reaping thread is active
                     // even when a tty is not attached
                     to_remove.push(session);
@@ -493,28 +684,300 @@ impl Server {
     }
 
     #[instrument(skip_all)]
-    fn handle_list(&self, mut stream: UnixStream) -> anyhow::Result<()> {
+    fn handle_broadcast(
+        &self,
+        mut stream: UnixStream,
+        request: protocol::BroadcastRequest,
+    ) -> anyhow::Result<()> {
+        let mut sessions = vec![];
+        {
+            let shells = self.shells.lock().unwrap();
+            for (name, session) in shells.iter() {
+                if !session.attached.load(Ordering::Relaxed) {
+                    // nobody is attached to see the message
+                    continue;
+                }
+
+                let delivered = match request.mode {
+                    protocol::BroadcastMode::Banner => {
+                        let reader_ctl = session.reader_ctl.lock().unwrap();
+                        reader_ctl
+                            .banner
+                            .send(request.message.clone())
+                            .context("sending banner to reader")?;
+                        reader_ctl.banner_ack.recv().context("getting banner ack")?
+                    }
+                    // The pager blocks for as long as the user is looking
+                    // at it, so it gets a thread of its own.
+                    protocol::BroadcastMode::Pager => {
+                        let pager = session.pager.clone();
+                        let msg = request.message.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = pager.message(&msg) {
+                                warn!("showing message: {:?}", e);
+                            }
+                        });
+                        true
+                    }
+                };
+                info!("broadcast to session({}), delivered={}", name, delivered);
+                if delivered {
+                    sessions.push(name.clone());
+                }
+            }
+        }
+        sessions.sort();
+
+        write_reply(&mut stream, protocol::BroadcastReply { sessions })
+            .context("writing broadcast reply")?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_stop(
+        &self,
+        mut stream: UnixStream,
+        request: protocol::StopRequest,
+    ) -> anyhow::Result<()> {
+        let pid = process::id() as i32;
+        // Hold the lock for the rest of the daemon's life so that nobody
+        // can sneak in a new session while we are shutting down.
+        let shells = self.shells.lock().unwrap();
+        let mut sessions: Vec<String> = shells.keys().cloned().collect();
+        sessions.sort();
+
+        if request.refuse_if_sessions && !sessions.is_empty() {
+            info!("refusing to stop with sessions: {:?}", sessions);
+            write_reply(
+                &mut stream,
+                protocol::StopReply {
+                    pid,
+                    status: protocol::StopStatus::SessionsRunning { sessions },
+                },
+            )
+            .context("writing stop reply")?;
+            return Ok(());
+        }
+
+        info!("stopping daemon, kill_sessions={}", request.kill_sessions);
+        write_reply(
+            &mut stream,
+            protocol::StopReply { pid, status: protocol::StopStatus::Stopping { sessions } },
+        )
+        .context("writing stop reply")?;
+
+        // Let anyone who is attached know what is going on, then send
+        // them on their way before the sessions go away out from under
+        // them.
+        for (name, session) in shells.iter() {
+            if !session.attached.load(Ordering::Relaxed) {
+                continue;
+            }
+            let reader_ctl = session.reader_ctl.lock().unwrap();
+            let notified = reader_ctl
+                .banner
+                .send(String::from(STOP_BANNER))
+                .map_err(|e| anyhow!("{:?}", e))
+                .and_then(|_| reader_ctl.banner_ack.recv().context("getting banner ack"));
+            if let Err(err) = notified {
+                warn!("notifying session({}) of stop: {:?}", name, err);
+            }
+            let detached = reader_ctl
+                .client_connection
+                .send(shell::ClientConnectionMsg::Disconnect)
+                .map_err(|e| anyhow!("{:?}", e))
+                .and_then(|_| {
+                    reader_ctl.client_connection_ack.recv().context("getting client conn ack")
+                });
+            info!("detached session({}) for stop: {:?}", name, detached);
+        }
+
+        if request.kill_sessions {
+            // Kill the shells in parallel since each one might take
+            // a while to give up.
+            thread::scope(|scope| {
+                for (name, session) in shells.iter() {
+                    scope.spawn(move || {
+                        if let Err(err) = session.kill() {
+                            warn!("killing session({}): {:?}", name, err);
+                        }
+                        let mut ctx = session.hook_context(name);
+                        ctx.exit_status = session.child_exit_notifier.wait(Some(STOP_REAP_TIMEOUT));
+                        self.metrics.sessions_killed.inc();
+                        // exit flushes the worker, so these still get called
+                        self.hook_worker.call("on_shell_disconnect", move |h| {
+                            h.on_shell_disconnect_with_context(&ctx)
+                        });
+                    });
+                }
+            });
+        }
+
+        self.exit()
+    }
+
+    /// A one line summary of the daemon for systemd to show.
+    fn status_line(&self) -> String {
+        let shells = self.shells.lock().unwrap();
+        let attached = shells.values().filter(|s| s.attached.load(Ordering::Relaxed)).count();
+        format!("{} sessions ({} attached)", shells.len(), attached)
+    }
+
+    /// Make sure that we are still accepting and serving connections
+    /// by making a request to ourselves.
+    fn check_liveness(&self) -> anyhow::Result<()> {
+        let mut client = protocol::Client::new(&self.info.socket).context("dialing self")?;
+        client
+            .stream
+            .set_read_timeout(Some(consts::SOCK_STREAM_TIMEOUT))
+            .context("setting liveness timeout")?;
+        client.write_connect_header(protocol::ConnectHeader::List).context("writing header")?;
+        let _: protocol::ListReply = client.read_reply().context("reading list reply")?;
+        Ok(())
+    }
+
+    /// Clean up after the daemon and exit.
+    fn exit(&self) -> ! {
+        if let Some(notifier) = &self.notifier {
+            notifier.notify("STOPPING=1");
+        }
+        self.hook_worker.flush(STOP_HOOK_TIMEOUT);
+        if let Some(runner) = &self.command_runner {
+            runner.flush(STOP_HOOK_TIMEOUT);
+        }
+
+        if self.info.systemd_activated {
+            info!("systemd manages the sockets, so not cleaning them up");
+        }
+        for socket in self.info.cleanup_sockets.iter() {
+            if let Err(err) = fs::remove_file(socket) {
+                warn!("cleaning up socket: {:?}", err);
+            }
+        }
+        match fs::remove_dir_all(self.runtime_dir.join("sessions")) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                warn!("cleaning up session runtime dirs: {:?}", err);
+            }
+            _ => {}
+        }
+        // only succeeds if nothing else is in there
+        let _ = fs::remove_dir(&self.runtime_dir);
+
+        if let Err(err) = self.hooks.on_daemon_stop() {
+            warn!("daemon_stop hook: {:?}", err);
+        }
+
+        info!("exiting");
+        process::exit(0);
+    }
+
+    /// Render the current metrics for the metrics endpoint.
+    pub fn render_metrics(&self) -> String {
+        let shells = self.shells.lock().unwrap();
+        let mut sessions: Vec<metrics::SessionSample> = shells
+            .iter()
+            .map(|(name, s)| metrics::SessionSample {
+                name,
+                attached: s.attached.load(Ordering::Relaxed),
+                metrics: &s.metrics,
+            })
+            .collect();
+        sessions.sort_by_key(|s| s.name);
+        self.metrics.render(&sessions)
+    }
+
+    #[instrument(skip_all)]
+    fn handle_status(&self, mut stream: UnixStream) -> anyhow::Result<()> {
+        let (sessions, attached_sessions, mut ttl_queue) = {
+            let shells = self.shells.lock().unwrap();
+            let attached_sessions =
+                shells.values().filter(|s| s.attached.load(Ordering::Relaxed)).count();
+            let now = time::SystemTime::now();
+            let ttl_queue: Vec<protocol::TtlQueueEntry> = shells
+                .iter()
+                .filter_map(|(name, s)| {
+                    let expires_in =
+                        (s.started_at + s.ttl?).duration_since(now).unwrap_or_default();
+                    Some(protocol::TtlQueueEntry {
+                        session: name.clone(),
+                        expires_in_ms: expires_in.as_millis() as u64,
+                    })
+                })
+                .collect();
+            (shells.len(), attached_sessions, ttl_queue)
+        };
+        ttl_queue.sort_by_key(|entry| entry.expires_in_ms);
+
+        let reply = protocol::StatusReply {
+            pid: process::id() as i32,
+            version: String::from(VERSION),
+            started_at_unix_ms: self.info.started_at.duration_since(time::UNIX_EPOCH)?.as_millis()
+                as i64,
+            config_file: self.info.config_file.as_ref().map(|p| p.to_string_lossy().into_owned()),
+            config_loaded_at_unix_ms: self
+                .info
+                .config_loaded_at
+                .duration_since(time::UNIX_EPOCH)?
+                .as_millis() as i64,
+            sockets: self.info.sockets.clone(),
+            systemd_activated: self.info.systemd_activated,
+            sessions,
+            attached_sessions,
+            // procfs has a directory for each thread of the process
+            threads: fs::read_dir("/proc/self/task").ok().map(|tasks| tasks.count()),
+            ttl_queue,
+        };
+        write_reply(&mut stream, reply).context("writing status reply")?;
+
+        Ok(())
+    }
+
+    /// Reply to a List, or to a ListV2 if v2 is set.
+    #[instrument(skip_all)]
+    fn handle_list(&self, mut stream: UnixStream, v2: bool) -> anyhow::Result<()> {
         let shells = self.shells.lock().unwrap();
 
-        let sessions: anyhow::Result<Vec<protocol::Session>> = shells
+        let sessions: anyhow::Result<Vec<protocol::SessionV2>> = shells
             .iter()
             .map(|(k, v)| {
-                let status = match v.inner.try_lock() {
-                    Ok(_) => protocol::SessionStatus::Disconnected,
-                    Err(_) => protocol::SessionStatus::Attached,
+                let status = if v.attached.load(Ordering::Relaxed) {
+                    protocol::SessionStatus::Attached
+                } else {
+                    protocol::SessionStatus::Disconnected
                 };
-
-                Ok(protocol::Session {
-                    name: k.to_string(),
-                    started_at_unix_ms: v.started_at.duration_since(time::UNIX_EPOCH)?.as_millis()
-                        as i64,
-                    status,
+                let command_log = v.command_log.lock().unwrap();
+                let activity = *v.activity.lock().unwrap();
+                let usage = self.cgroups.as_ref().and_then(|cgroups| cgroups.usage(v.child_pid));
+
+                Ok(protocol::SessionV2 {
+                    session: protocol::Session {
+                        name: k.to_string(),
+                        started_at_unix_ms: v
+                            .started_at
+                            .duration_since(time::UNIX_EPOCH)?
+                            .as_millis() as i64,
+                        status,
+                    },
+                    current_command: command_log
+                        .running()
+                        .map(|cmd| cmd.cmdline.clone().unwrap_or_default()),
+                    last_exit_status: command_log.last_exit_status(),
+                    bell: activity.bell,
+                    activity: activity.activity,
+                    memory_bytes: usage.as_ref().and_then(|u| u.memory_bytes),
+                    cpu_usage_usec: usage.as_ref().and_then(|u| u.cpu_usage_usec),
                 })
             })
             .collect();
         let sessions = sessions.context("collecting running session metadata")?;
 
-        write_reply(&mut stream, protocol::ListReply { sessions })?;
+        if v2 {
+            write_reply(&mut stream, protocol::ListV2Reply { sessions })?;
+        } else {
+            let sessions = sessions.into_iter().map(|s| s.session).collect();
+            write_reply(&mut stream, protocol::ListReply { sessions })?;
+        }
 
         Ok(())
     }
@@ -524,14 +987,24 @@ impl Server {
         &self,
         mut stream: UnixStream,
         header: protocol::SessionMessageRequest,
+        peer: &Peer,
     ) -> anyhow::Result<()> {
         // create a slot to store our reply so we can do
         // our IO without the lock held.
+        let mut scrollback = None;
         let reply = {
             let shells = self.shells.lock().unwrap();
             if let Some(session) = shells.get(&header.session_name) {
                 match header.payload {
                     protocol::SessionMessageRequestPayload::Resize(resize_request) => {
+                        let mut ctx = session.hook_context(&header.session_name);
+                        ctx.client_pid = Some(peer.pid.as_raw());
+                        ctx.client_exe = Some(peer.exe.clone());
+                        ctx.tty_size = Some(hooks::TtySize {
+                            rows: resize_request.tty_size.rows,
+                            cols: resize_request.tty_size.cols,
+                        });
+
                         let pager_ctl = session.pager_ctl.lock().unwrap();
                         if let Some(pager_ctl) = pager_ctl.as_ref() {
                             pager_ctl
@@ -554,6 +1027,10 @@ impl Server {
                                 .context("recving tty size ack")?;
                         }
 
+                        if let Err(err) = self.hooks.on_resize(&ctx) {
+                            warn!("resize hook: {:?}", err);
+                        }
+
                         protocol::SessionMessageReply::Resize(protocol::ResizeReply::Ok)
                     }
                     protocol::SessionMessageRequestPayload::Detach => {
@@ -571,6 +1048,17 @@ impl Server {
                             protocol::SessionMessageDetachReply::Ok,
                         )
                     }
+                    protocol::SessionMessageRequestPayload::Scrollback(req) => {
+                        if !session.attached.load(Ordering::Relaxed) {
+                            // there is no client to display the pager on
+                            protocol::SessionMessageReply::NotAttached
+                        } else {
+                            // We show the pager once we have replied.
+                            info!("requested scrollback for session({})", header.session_name);
+                            scrollback = Some((session.pager.clone(), req));
+                            protocol::SessionMessageReply::Scrollback(protocol::ScrollbackReply::Ok)
+                        }
+                    }
                 }
             } else {
                 protocol::SessionMessageReply::NotFound
@@ -579,6 +1067,15 @@ impl Server {
 
         write_reply(&mut stream, reply).context("handle_session_message: writing reply")?;
 
+        // The pager blocks for as long as the user is looking at it,
+        // so it runs on this connection's thread rather than with the
+        // reader on an event loop.
+        if let Some((pager, req)) = scrollback {
+            if let Err(e) = pager.scrollback(req) {
+                warn!("showing scrollback: {:?}", e);
+            }
+        }
+
         Ok(())
     }
 
@@ -615,11 +1112,7 @@ impl Server {
             cmd.args(&cmd_parts[1..]);
             cmd
         } else {
-            let mut cmd = process::Command::new(&shell);
-            if self.config.norc.unwrap_or(false) && shell == "/bin/bash" {
-                cmd.arg("--norc").arg("--noprofile");
-            }
-            cmd
+            process::Command::new(&shell)
         };
 
         cmd.current_dir(user_info.home_dir.clone())
@@ -670,15 +1163,88 @@ impl Server {
             None
         };
 
+        let has_clear_screen =
+            term_db.raw_string_cap(termini::StringCapability::ClearScreen).is_some();
+        let needs_default_term = !has_clear_screen || term.is_none();
+
+        // Set up the prompt prefix injection, if any. This needs to happen
+        // before we spawn the shell in case it is going to read the prefix
+        // script from an rc file.
+        let prompt_prefix =
+            self.config.prompt_prefix.clone().unwrap_or(String::from(DEFAULT_PROMPT_PREFIX));
+        let norc =
+            header.cmd.is_none() && self.config.norc.unwrap_or(false) && shell == "/bin/bash";
+        let injection = match shell_basename {
+            Some(shell_basename) if !prompt_prefix.is_empty() => {
+                let rc_dir = match self.config.prompt_prefix_injection.clone().unwrap_or_default() {
+                    config::PromptPrefixInjection::Rcfile => {
+                        Some(self.runtime_dir.join("sessions").join(&header.name).join("rc"))
+                    }
+                    config::PromptPrefixInjection::Typed => None,
+                };
+                let inject_args = prompt::InjectArgs {
+                    shell: shell_basename,
+                    prompt_prefix: &prompt_prefix,
+                    vars: prompt::PrefixVars {
+                        session_name: &header.name,
+                        started_at: chrono::Local::now(),
+                        ttl: header.ttl_secs.map(Duration::from_secs),
+                        hostname: nix::unistd::gethostname()
+                            .ok()
+                            .and_then(|h| h.into_string().ok())
+                            .map(|h| String::from(h.split('.').next().unwrap_or(""))),
+                    },
+                    script_template: self
+                        .config
+                        .prompt_prefix_scripts
+                        .as_ref()
+                        .and_then(|scripts| scripts.get(shell_basename))
+                        .map(|s| s.as_str()),
+                    needs_default_term,
+                    rc_dir,
+                    norc,
+                    clear_code: control_codes::clear_code(&term_db)?,
+                };
+                match prompt::prepare_injection(&mut cmd, &inject_args) {
+                    Ok(injection) => Some(injection),
+                    Err(err) => {
+                        warn!("issue injecting prefix: {:?}", err);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        if norc && !matches!(injection, Some(prompt::Injection::Rcfile { .. })) {
+            cmd.arg("--norc").arg("--noprofile");
+        }
+
         let noecho = self.config.noecho.unwrap_or(false);
         info!("about to fork subshell noecho={}", noecho);
+        // Hold the shell back until it is in its cgroup, so that
+        // everything it runs ends up there too.
+        let cgroup_gate = match &self.cgroups {
+            Some(_) => Some(unistd::pipe2(OFlag::O_CLOEXEC).context("creating cgroup gate")?),
+            None => None,
+        };
         let mut fork = shpool_pty::fork::Fork::from_ptmx().context("forking pty")?;
         if let Ok(slave) = fork.is_child() {
+            // turn off echo first thing, since the parent may start
+            // typing into the pty at any moment
             if noecho {
                 if let Some(fd) = slave.borrow_fd() {
                     tty::disable_echo(fd).context("disabling echo on pty")?;
                 }
             }
+            if let Some(fd) = slave.borrow_fd() {
+                if let Err(err) = tty::make_controlling_terminal(fd) {
+                    eprintln!("shpool: warn: no job control: {:?}", err);
+                }
+            }
+            if let Some((gate_rx, gate_tx)) = cgroup_gate {
+                drop(gate_tx);
+                cgroup::wait_for_placement(gate_rx);
+            }
             for fd in consts::STDERR_FD + 1..(nix::unistd::SysconfVar::OPEN_MAX as i32) {
                 let _ = nix::unistd::close(fd);
             }
@@ -687,78 +1253,99 @@ impl Server {
             std::process::exit(1);
         }
 
-        // spawn a background thread to reap the shell when it exits
-        // and notify about the exit by closing a channel.
-        let child_exit_notifier = Arc::new(ExitNotifier::new());
-        let waitable_child = fork.clone();
-        let session_name = header.name.clone();
-        let notifiable_child_exit_notifier = Arc::clone(&child_exit_notifier);
-        thread::spawn(move || {
-            let _s = span!(Level::INFO, "child_watcher", s = session_name, cid = conn_id).entered();
+        // The child turns off echo too, but we may well start typing
+        // into the pty before it gets a chance to run.
+        if noecho {
+            let pty_master = fork.is_parent().context("expected parent")?;
+            if let Some(fd) = pty_master.borrow_fd() {
+                tty::disable_echo(fd).context("disabling echo on pty master")?;
+            }
+        }
 
-            match waitable_child.wait_for_exit() {
-                Ok((_, Some(exit_status))) => {
-                    info!("child exited with status {}", exit_status);
-                    notifiable_child_exit_notifier.notify_exit(exit_status);
-                }
-                Ok((_, None)) => {
-                    info!("child exited without status, using 1");
-                    notifiable_child_exit_notifier.notify_exit(1);
-                }
-                Err(e) => {
-                    info!("error waiting on child, using exit status 1: {:?}", e);
-                    notifiable_child_exit_notifier.notify_exit(1);
+        if let (Some(cgroups), Some((gate_rx, gate_tx))) = (&self.cgroups, cgroup_gate) {
+            drop(gate_rx);
+            match fork.child_pid() {
+                Some(pid) => cgroups.place_and_release(header.name.clone(), pid, gate_tx)?,
+                None => {
+                    warn!("no child pid to put in a cgroup");
+                    cgroup::release(gate_tx);
                 }
             }
-            info!("reaped child shell: {:?}", waitable_child);
-        });
+        }
 
-        let has_clear_screen =
-            term_db.raw_string_cap(termini::StringCapability::ClearScreen).is_some();
-        let needs_default_term = !has_clear_screen || term.is_none();
+        // The reader lives on the same event loop as the child watcher
+        // no matter which one of them starts first, so it needs to
+        // exist before the child watcher can wake it up.
+        let reader_waker = self.event_loops.waker();
 
-        // inject the prompt prefix, if any
-        info!("injecting prompt prefix");
-        let prompt_prefix =
-            self.config.prompt_prefix.clone().unwrap_or(String::from(DEFAULT_PROMPT_PREFIX));
-        if let Some(shell_basename) = shell_basename {
-            if !prompt_prefix.is_empty() {
-                if let Err(err) = prompt::inject_prefix(
-                    &mut fork,
-                    shell_basename,
-                    &prompt_prefix,
-                    &header.name,
-                    needs_default_term,
-                ) {
-                    warn!("issue injecting prefix: {:?}", err);
+        // reap the shell when it exits and notify about the exit
+        let child_exit_notifier = Arc::new(ExitNotifier::new());
+        let child_pid = fork.child_pid().ok_or(anyhow!("no child pid"))?;
+        let session_name = header.name.clone();
+        let child_cgroups = self.cgroups.clone();
+        let exit_waker = reader_waker.clone();
+        exit_notify::watch_child(
+            &self.event_loops,
+            header.name.clone(),
+            conn_id,
+            child_pid,
+            Arc::clone(&child_exit_notifier),
+            Box::new(move || {
+                if let Some(cgroups) = child_cgroups {
+                    cgroups.remove(&session_name, child_pid);
                 }
-            } else {
-                // issue a clear even if we don't have a prompt to inject for consistency
-                // and to simplify motd handling
-                let script = if needs_default_term {
-                    // If we don't have a $TERM value or we have some wacky $TERM value for which
-                    // there is no ClearScreen code, set TERM to xterm so that we won't get a
-                    // warning and will generate a code we can scan for.
-                    "TERM=xterm clear\n"
-                } else {
-                    "clear\n"
-                };
-                let mut pty_master = fork.is_parent().context("expected parent")?;
-                pty_master.write_all(script.as_bytes()).context("running initial clear")?;
+                // let the reader know so it can wrap up
+                exit_waker.wake();
+            }),
+        )?;
+
+        if let Some(prompt::Injection::Typed(script)) = &injection {
+            info!("injecting prompt prefix");
+            if let Err(err) = prompt::type_script(&mut fork, script) {
+                warn!("issue injecting prefix: {:?}", err);
             }
+        } else if shell_basename.is_some() && prompt_prefix.is_empty() {
+            // issue a clear even if we don't have a prompt to inject for consistency
+            // and to simplify motd handling
+            let script = if needs_default_term {
+                // If we don't have a $TERM value or we have some wacky $TERM value for which
+                // there is no ClearScreen code, set TERM to xterm so that we won't get a
+                // warning and will generate a code we can scan for.
+                "TERM=xterm clear\n"
+            } else {
+                "clear\n"
+            };
+            let mut pty_master = fork.is_parent().context("expected parent")?;
+            pty_master.write_all(script.as_bytes()).context("running initial clear")?;
         }
 
-        let (client_connection_tx, client_connection_rx) = crossbeam_channel::bounded(0);
-        let (client_connection_ack_tx, client_connection_ack_rx) = crossbeam_channel::bounded(0);
-        let (tty_size_change_tx, tty_size_change_rx) = crossbeam_channel::bounded(0);
-        let (tty_size_change_ack_tx, tty_size_change_ack_rx) = crossbeam_channel::bounded(0);
+        // The acks have room for one message so that the reader never
+        // blocks the event loop sending them. The reader_ctl lock keeps
+        // more than one request from being in flight at a time.
+        let (client_connection_tx, client_connection_rx) = event_loop::channel(&reader_waker);
+        let (client_connection_ack_tx, client_connection_ack_rx) = crossbeam_channel::bounded(1);
+        let (tty_size_change_tx, tty_size_change_rx) = event_loop::channel(&reader_waker);
+        let (tty_size_change_ack_tx, tty_size_change_ack_rx) = crossbeam_channel::bounded(1);
+        let (pager_handoff_tx, pager_handoff_rx) = event_loop::channel(&reader_waker);
+        let (pager_handoff_ack_tx, pager_handoff_ack_rx) = crossbeam_channel::bounded(1);
+        let (banner_tx, banner_rx) = event_loop::channel(&reader_waker);
+        let (banner_ack_tx, banner_ack_rx) = crossbeam_channel::bounded(1);
 
         let reader_ctl = Arc::new(Mutex::new(shell::ReaderCtl {
             client_connection: client_connection_tx,
             client_connection_ack: client_connection_ack_rx,
             tty_size_change: tty_size_change_tx,
             tty_size_change_ack: tty_size_change_ack_rx,
+            pager_handoff: pager_handoff_tx,
+            pager_handoff_ack: pager_handoff_ack_rx,
+            banner: banner_tx,
+            banner_ack: banner_ack_rx,
         }));
+        let pager_ctl = Arc::new(Mutex::new(None));
+        let pager = shell::PagerDisplay::new(&reader_ctl, Arc::clone(&pager_ctl), &self.config);
+        let command_log = Arc::new(Mutex::new(CommandLog::default()));
+        let activity_flags = Arc::new(Mutex::new(activity::Flags::default()));
+        let session_metrics = Arc::new(metrics::SessionMetrics::default());
         let mut session_inner = shell::SessionInner {
             name: header.name.clone(),
             reader_ctl: Arc::clone(&reader_ctl),
@@ -769,25 +1356,56 @@ impl Server {
             term_db,
             daily_messenger: Arc::clone(&self.daily_messenger),
             needs_initial_motd_dump: dump_motd_on_new_session,
+            metrics: Arc::clone(&self.metrics),
         };
-        let child_pid = session_inner.pty_master.child_pid().ok_or(anyhow!("no child pid"))?;
-        session_inner.reader_join_h = Some(session_inner.spawn_reader(shell::ReaderArgs {
-            conn_id,
-            tty_size: header.local_tty_size.clone(),
-            scrollback_lines: match (
-                self.config.output_spool_lines,
-                &self.config.session_restore_mode,
-            ) {
-                (Some(l), _) => l,
-                (None, Some(config::SessionRestoreMode::Lines(l))) => *l as usize,
-                (None, _) => DEFAULT_OUTPUT_SPOOL_LINES,
+        let triggers = triggers::Triggers::new(
+            &header.name,
+            Some(child_pid),
+            self.config.trigger.as_deref().unwrap_or_default(),
+            self.hook_worker.clone(),
+            self.command_runner.clone(),
+        )
+        .context("compiling output triggers")?;
+        session_inner.reader_join_h = Some(session_inner.spawn_reader(
+            &reader_waker,
+            shell::ReaderArgs {
+                conn_id,
+                tty_size: header.local_tty_size.clone(),
+                scrollback_lines: match (
+                    self.config.output_spool_lines,
+                    &self.config.session_restore_mode,
+                ) {
+                    (Some(l), _) => l,
+                    (None, Some(config::SessionRestoreMode::Lines(l))) => *l as usize,
+                    (None, _) => DEFAULT_OUTPUT_SPOOL_LINES,
+                },
+                session_restore_mode: self.config.session_restore_mode.clone().unwrap_or_default(),
+                child_exit_notifier: Arc::clone(&child_exit_notifier),
+                client_connection: client_connection_rx,
+                client_connection_ack: client_connection_ack_tx,
+                tty_size_change: tty_size_change_rx,
+                tty_size_change_ack: tty_size_change_ack_tx,
+                pager_handoff: pager_handoff_rx,
+                pager_handoff_ack: pager_handoff_ack_tx,
+                banner: banner_rx,
+                banner_ack: banner_ack_tx,
+                command_log: Arc::clone(&command_log),
+                session_metrics: Arc::clone(&session_metrics),
+                triggers,
+                hook_worker: self.hook_worker.clone(),
+                pager: pager.clone(),
+                activity: activity::Monitor::new(
+                    Arc::clone(&activity_flags),
+                    Duration::from_secs(
+                        self.config.activity_idle_secs.unwrap_or(DEFAULT_ACTIVITY_IDLE_SECS),
+                    ),
+                ),
+                rcfile_fallback: match injection {
+                    Some(prompt::Injection::Rcfile { fallback }) => fallback,
+                    _ => None,
+                },
             },
-            session_restore_mode: self.config.session_restore_mode.clone().unwrap_or_default(),
-            client_connection: client_connection_rx,
-            client_connection_ack: client_connection_ack_tx,
-            tty_size_change: tty_size_change_rx,
-            tty_size_change_ack: tty_size_change_ack_tx,
-        })?);
+        )?);
 
         if let Some(ttl_secs) = header.ttl_secs {
             info!("registering session with ttl with the reaper");
@@ -798,10 +1416,16 @@ impl Server {
 
         Ok(shell::Session {
             reader_ctl,
-            pager_ctl: Arc::new(Mutex::new(None)),
+            pager_ctl,
+            pager,
+            command_log,
+            activity: activity_flags,
+            metrics: session_metrics,
             child_pid,
             child_exit_notifier,
             started_at: time::SystemTime::now(),
+            ttl: header.ttl_secs.map(Duration::from_secs),
+            attached: Arc::new(AtomicBool::new(false)),
             inner: Arc::new(Mutex::new(session_inner)),
         })
     }
@@ -927,10 +1551,50 @@ where
     Ok(())
 }
 
+/// Reply to an attach with the header that matches the way the client
+/// attached. Plain attaches never get their output compressed.
+fn write_attach_reply(
+    stream: &mut UnixStream,
+    framed: bool,
+    status: protocol::AttachStatus,
+    compression: Option<protocol::Compression>,
+) -> anyhow::Result<()> {
+    if framed {
+        write_reply(stream, protocol::FramedAttachReplyHeader { status, compression })
+    } else {
+        write_reply(stream, protocol::AttachReplyHeader { status })
+    }
+}
+
+/// The process on the other end of a connection to the daemon.
+struct Peer {
+    pid: unistd::Pid,
+    exe: PathBuf,
+}
+
+impl Peer {
+    /// Build the context for a hook event where this peer is
+    /// attaching to the given session.
+    fn hook_context(
+        &self,
+        session: &shell::Session,
+        header: &protocol::AttachHeader,
+    ) -> hooks::HookContext {
+        let mut ctx = session.hook_context(&header.name);
+        ctx.client_pid = Some(self.pid.as_raw());
+        ctx.client_exe = Some(self.exe.clone());
+        ctx.tty_size = Some(hooks::TtySize {
+            rows: header.local_tty_size.rows,
+            cols: header.local_tty_size.cols,
+        });
+        ctx
+    }
+}
+
 /// check_peer makes sure that a process dialing in on the shpool
 /// control socket has the same UID as the current user and that
 /// both have the same executable path.
-fn check_peer(sock: &UnixStream) -> anyhow::Result<()> {
+fn check_peer(sock: &UnixStream) -> anyhow::Result<Peer> {
     use nix::sys::socket;
 
     let peer_creds = socket::getsockopt(sock, socket::sockopt::PeerCredentials)
@@ -949,7 +1613,7 @@ fn check_peer(sock: &UnixStream) -> anyhow::Result<()> {
         warn!("attach binary differs from daemon binary");
     }
 
-    Ok(())
+    Ok(Peer { pid: peer_pid, exe: peer_exe })
 }
 
 fn exe_for_pid(pid: unistd::Pid) -> anyhow::Result<PathBuf> {
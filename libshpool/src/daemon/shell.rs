@@ -13,29 +13,43 @@
 // limitations under the License.
 
 use std::{
-    io,
-    io::{Read, Write},
     net,
     ops::Add,
-    os::unix::net::UnixStream,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc, Mutex,
+    os::{
+        fd::{AsFd, AsRawFd, OwnedFd},
+        unix::net::UnixStream,
     },
+    sync::{atomic::AtomicBool, Arc, Mutex, Weak},
     thread, time,
     time::Duration,
 };
 
 use anyhow::{anyhow, Context};
-use nix::{sys::signal, unistd::Pid};
+use nix::{
+    errno::Errno,
+    fcntl::{self, OFlag},
+    sys::{
+        signal,
+        socket::{self, MsgFlags},
+    },
+    unistd::{self, Pid},
+};
 use tracing::{debug, error, info, instrument, span, trace, warn, Level};
 
 use crate::{
-    consts,
+    compression, consts,
     daemon::{
-        config, control_codes, exit_notify::ExitNotifier, keybindings, pager::PagerCtl, show_motd,
+        activity,
+        command_log::{CommandLog, LastCommandOutput},
+        config, control_codes,
+        control_codes::{Code, SemanticPromptMark},
+        event_loop,
+        exit_notify::ExitNotifier,
+        hook_worker, keybindings, metrics,
+        pager::{Pager, PagerCtl, PagerError},
+        show_motd, triggers,
     },
-    protocol, test_hooks, tty,
+    hooks, protocol, test_hooks, tty,
 };
 
 // To prevent data getting dropped, we set this to be large, but we don't want
@@ -46,30 +60,82 @@ const VTERM_WIDTH: u16 = 1024 * 10;
 
 const SHELL_KILL_TIMEOUT: time::Duration = time::Duration::from_millis(500);
 
-const SUPERVISOR_POLL_DUR: time::Duration = time::Duration::from_millis(300);
-
 // Chosen experimentally. This value is small enough that no human will likely
 // recognize it, and it seems to be large enough that emacs consistently picks
 // up the "jiggle" trick where we oversize the pty then put it back to the right
 // size.
 const REATTACH_RESIZE_DELAY: time::Duration = time::Duration::from_millis(50);
 
-// The reader thread should wake up relatively frequently so it can detect
-// reattach, but we don't need to go crazy since reattach is not part of
-// the inner loop.
-const READER_POLL_MS: u16 = 100;
+// How long to give the shell to get through the user's startup files
+// and source the prompt prefix rc file before we type in the fallback.
+// Typing it in late is harmless, since it won't inject the prefix twice.
+const RCFILE_FALLBACK_DELAY: time::Duration = time::Duration::from_secs(3);
+
+// Tokens for the fds that the reader watches.
+const PTY_TOKEN: event_loop::Token = 0;
+const CLIENT_TOKEN: event_loop::Token = 1;
+
+// Stop reading the shell's output once this much of it has piled up
+// waiting on a slow client or on the output spool, and stop reading the
+// client's input once this much of it has piled up waiting on a busy
+// shell, so that whoever is producing the data has to wait rather than
+// us buffering it without bound.
+const MAX_BACKLOG: usize = consts::BUF_SIZE * 4;
+
+// Output gets framed into a chunk once this much of it has built up,
+// leaving room for compression to make things a little bigger in the
+// worst case without going over what the client will read in one chunk.
+const MAX_COALESCE: usize = consts::BUF_SIZE / 2;
+
+// How long to keep reading from the pty before sending what we have to
+// a client that asked for compression. Bursty output comes in lots of
+// small reads, and sending them as one chunk compresses better. Plain
+// output goes out after every read.
+const COALESCE_WINDOW: time::Duration = time::Duration::from_millis(2);
+
+// How long to spend catching the output spool up with the shell's
+// output in one go. The spool is slow enough that a burst of output
+// would otherwise hold up every other session on the same event loop.
+const SPOOL_BUDGET: time::Duration = time::Duration::from_millis(5);
+
+// How much output to feed the spool at a time while catching it up.
+const SPOOL_BLOCK: usize = 256;
+
+// How long to keep trying to get the exit status of the shell to the
+// client once the shell has exited.
+const EXIT_STATUS_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+
+// The most chunks of output to forward from the pty once the shell has
+// exited.
+const EXIT_DRAIN_READS: usize = 16;
+
+const DEFAULT_SCROLLBACK_PAGER: &str = "less";
 
 /// Session represent a shell session
 #[derive(Debug)]
 pub struct Session {
     pub started_at: time::SystemTime,
+    pub ttl: Option<time::Duration>,
     pub child_pid: libc::pid_t,
     pub child_exit_notifier: Arc<ExitNotifier>,
     pub reader_ctl: Arc<Mutex<ReaderCtl>>,
     pub pager_ctl: Arc<Mutex<Option<PagerCtl>>>,
-    /// Mutable state with the lock held by the servicing handle_attach thread
-    /// while a tty is attached to the session. Probing the mutex can be used
-    /// to determine if someone is currently attached to the session.
+    /// Used to display the scrollback or a message in a pager on the
+    /// attached client.
+    pub pager: PagerDisplay,
+    /// The commands run in the session, kept up to date by the reader.
+    pub command_log: Arc<Mutex<CommandLog>>,
+    /// Bells and activity since a client was last attached, kept up
+    /// to date by the reader.
+    pub activity: Arc<Mutex<activity::Flags>>,
+    pub metrics: Arc<metrics::SessionMetrics>,
+    /// Set while a client is attached to the session, from when
+    /// handle_attach claims the session with the shells table lock held
+    /// until the attach has been wrapped up after the reader is done
+    /// with the client connection.
+    pub attached: Arc<AtomicBool>,
+    /// Mutable state, locked by handle_attach while it hooks a new
+    /// client up to the reader.
     pub inner: Arc<Mutex<SessionInner>>,
 }
 
@@ -93,11 +159,19 @@ impl Session {
 
         Ok(())
     }
+
+    /// Build the context for a hook event concerning this session.
+    pub fn hook_context(&self, name: &str) -> hooks::HookContext {
+        let mut ctx = hooks::HookContext::new(name);
+        ctx.child_pid = Some(self.child_pid);
+        ctx.session_started_at = Some(self.started_at);
+        ctx.ttl = self.ttl;
+        ctx
+    }
 }
 
 /// ShellSessionInner contains values that the pipe thread needs to be
 /// able to mutate and fully control.
-#[derive(Debug)]
 pub struct SessionInner {
     pub name: String, // to improve logging
     pub reader_ctl: Arc<Mutex<ReaderCtl>>,
@@ -107,26 +181,68 @@ pub struct SessionInner {
     pub term_db: Arc<termini::TermInfo>,
     pub daily_messenger: Arc<show_motd::DailyMessenger>,
     pub needs_initial_motd_dump: bool,
+    pub metrics: Arc<metrics::Metrics>,
+
+    /// The join handle for the always-on reader. Only wrapped in an
+    /// option so we can start the reader after constructing the
+    /// SessionInner.
+    pub reader_join_h: Option<event_loop::JoinHandle>,
+}
 
-    /// The join handle for the always-on background reader thread.
-    /// Only wrapped in an option so we can spawn the thread after
-    /// constructing the SessionInner.
-    pub reader_join_h: Option<thread::JoinHandle<anyhow::Result<()>>>,
+// Written out by hand because the hooks are not Debug.
+impl std::fmt::Debug for SessionInner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionInner")
+            .field("name", &self.name)
+            .field("pty_master", &self.pty_master)
+            .field("client_stream", &self.client_stream)
+            .field("needs_initial_motd_dump", &self.needs_initial_motd_dump)
+            .field("reader_join_h", &self.reader_join_h)
+            .finish_non_exhaustive()
+    }
 }
 
 /// A notification that a new client has connected, sent to the
-/// reader thread.
+/// reader.
 pub struct ClientConnection {
-    /// All output data should be written to this sink rather than
-    /// directly to the unix stream. The mutex makes sure that we don't
-    /// accidentally interleave with heartbeat frames.
-    sink: Arc<Mutex<io::BufWriter<UnixStream>>>,
+    /// The client's unix socket stream.
+    stream: UnixStream,
     /// The size of the client tty.
     size: tty::Size,
-    /// The raw unix socket stream. The reader should never write
-    /// to this directly, just use it for control operations like
-    /// shutdown.
-    stream: UnixStream,
+    /// Whatever the client has sent that has been read off the stream
+    /// but not handled yet.
+    input: protocol::ChunkDecoder,
+    /// Compresses the client's output, if it asked for that.
+    compressor: Option<compression::Compressor>,
+    /// The context for hooks about the client, like resizes.
+    hook_ctx: hooks::HookContext,
+    /// The keybindings to watch the client's input for.
+    bindings: keybindings::Bindings,
+    /// Wraps up the attach once the reader is done with the connection.
+    done: ConnDone,
+}
+
+/// Calls a function once dropped, which happens when the reader is
+/// done with a client connection, whether because the client hung up,
+/// detached or got replaced, or because the shell exited. It can get
+/// dropped on an event loop, so the function must not block.
+pub struct ConnDone(Option<Box<dyn FnOnce() + Send + Sync>>);
+
+impl ConnDone {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: FnOnce() + Send + Sync + 'static,
+    {
+        ConnDone(Some(Box::new(f)))
+    }
+}
+
+impl std::ops::Drop for ConnDone {
+    fn drop(&mut self) {
+        if let Some(f) = self.0.take() {
+            f();
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -151,6 +267,13 @@ struct ResizeCmd {
     when: time::Instant,
 }
 
+/// A script to type into the shell if the clear at the end of the
+/// prompt prefix rc file has not shown up by `when`.
+struct RcfileFallback {
+    script: String,
+    when: time::Instant,
+}
+
 fn log_if_error<T, E>(ctx: &str, res: Result<T, E>) -> Result<T, E>
 where
     E: std::fmt::Debug,
@@ -161,805 +284,1656 @@ where
     })
 }
 
-/// Messages to the reader thread to add or remove a client connection.
+/// Messages to the reader to add or remove a client connection.
 pub enum ClientConnectionMsg {
     /// Accept a newly connected client
-    New(ClientConnection),
-    /// Disconnect the client and exit the reader loop since
-    /// the client shell has exited with the given exit status.
-    DisconnectExit(i32),
+    New(Box<ClientConnection>),
     /// Disconnect the client, but stay around and be ready for
     /// reconnects.
     Disconnect,
 }
 
+/// What a pager is about to display, which tells the reader
+/// what it needs to send back when handing off the connection.
+#[derive(Debug, Clone, Copy)]
+pub enum PagerContent {
+    /// The whole scrollback.
+    Scrollback,
+    /// Only the output of the most recent command.
+    LastCommand,
+    /// Text that did not come from the session, so no scrollback
+    /// is needed.
+    Message,
+}
+
+/// Messages to the reader to hand the client connection over
+/// to a pager and back again.
+pub enum PagerHandoffMsg {
+    /// Stop forwarding shell output to the client, but keep feeding
+    /// the output spool.
+    Start { content: PagerContent },
+    /// The pager has exited, so resize to the given size, redraw the
+    /// screen and resume forwarding output. The client's pending
+    /// input comes back along with the connection.
+    Finish { size: tty::Size, input: protocol::ChunkDecoder },
+}
+
+/// Acks for PagerHandoffMsgs.
+pub enum PagerHandoffAck {
+    /// The reader has stopped forwarding output. Contains a handle on
+    /// the client connection for the pager to display on, the scrollback
+    /// as plain text, or None if there is nothing to show or no scrollback
+    /// was asked for, along with the current size of the client tty and
+    /// whatever the client has sent that the reader has not handled.
+    Started {
+        stream: UnixStream,
+        scrollback: Option<String>,
+        tty_size: tty::Size,
+        input: protocol::ChunkDecoder,
+    },
+    /// There is no client attached, or it is already looking at
+    /// another pager, so the reader has kept the connection.
+    Refused,
+    /// The reader has resumed forwarding output.
+    Finished,
+}
+
 pub struct ReaderArgs {
     pub conn_id: usize,
     pub tty_size: tty::Size,
     pub scrollback_lines: usize,
     pub session_restore_mode: config::SessionRestoreMode,
+    pub child_exit_notifier: Arc<ExitNotifier>,
     pub client_connection: crossbeam_channel::Receiver<ClientConnectionMsg>,
     pub client_connection_ack: crossbeam_channel::Sender<ClientConnectionStatus>,
     pub tty_size_change: crossbeam_channel::Receiver<tty::Size>,
     pub tty_size_change_ack: crossbeam_channel::Sender<()>,
+    pub pager_handoff: crossbeam_channel::Receiver<PagerHandoffMsg>,
+    pub pager_handoff_ack: crossbeam_channel::Sender<PagerHandoffAck>,
+    pub banner: crossbeam_channel::Receiver<String>,
+    pub banner_ack: crossbeam_channel::Sender<bool>,
+    pub command_log: Arc<Mutex<CommandLog>>,
+    pub session_metrics: Arc<metrics::SessionMetrics>,
+    pub triggers: triggers::Triggers,
+    pub hook_worker: hook_worker::HookWorker,
+    /// Shows the scrollback when the keybinding for it fires.
+    pub pager: PagerDisplay,
+    pub activity: activity::Monitor,
+    /// What to type into the shell if its rc file never gets sourced.
+    pub rcfile_fallback: Option<String>,
 }
 
 impl SessionInner {
-    /// Spawn the reader thread which continually reads from the pty
-    /// and sends data both to the output spool and to the client,
-    /// if one is attached.
+    /// Start the reader on the event loop that the waker belongs to.
+    /// It continually reads from the pty and sends data both to the
+    /// output spool and to the client, if one is attached, and feeds
+    /// input from the client to the shell.
     #[instrument(skip_all, fields(s = self.name))]
     pub fn spawn_reader(
         &self,
-        args: ReaderArgs,
-    ) -> anyhow::Result<thread::JoinHandle<anyhow::Result<()>>> {
-        use nix::poll;
-
-        let term_db = Arc::clone(&self.term_db);
-        let mut control_code_matcher =
-            control_codes::Matcher::new(&self.term_db).context("building control code matcher")?;
-
-        let daily_messenger = Arc::clone(&self.daily_messenger);
-        let mut needs_initial_motd_dump = self.needs_initial_motd_dump;
-
-        let mut pty_master = self.pty_master.is_parent()?;
-        let watchable_master = pty_master;
-        let name = self.name.clone();
-        let mut closure = move || {
-            let _s = span!(Level::INFO, "reader", s = name, cid = args.conn_id).entered();
-
-            let mut output_spool =
-                if matches!(args.session_restore_mode, config::SessionRestoreMode::Simple) {
-                    None
-                } else {
-                    Some(shpool_vt100::Parser::new(
-                        args.tty_size.rows,
-                        VTERM_WIDTH,
-                        args.scrollback_lines,
-                    ))
-                };
-            let mut buf: Vec<u8> = vec![0; consts::BUF_SIZE];
-            let mut poll_fds = [poll::PollFd::new(
-                watchable_master.borrow_fd().ok_or(anyhow!("no master fd"))?,
-                poll::PollFlags::POLLIN,
-            )];
-
-            // block until we get the first connection attached so that we don't drop
-            // the initial prompt on the floor
-            info!("waiting for initial client connection");
-            let mut client_conn: ClientConnectionMsg =
-                args.client_connection.recv().context("waiting for initial client connection")?;
-            args.client_connection_ack
-                .send(ClientConnectionStatus::New)
-                .context("sending initial client connection ack")?;
-            info!("got initial client connection");
+        waker: &event_loop::Waker,
+        mut args: ReaderArgs,
+    ) -> anyhow::Result<event_loop::JoinHandle> {
+        let pty_master = self.pty_master.is_parent()?;
+        let pty = pty_master
+            .borrow_fd()
+            .ok_or(anyhow!("no master fd"))?
+            .try_clone_to_owned()
+            .context("duping pty master fd")?;
+        let flags = OFlag::from_bits_truncate(
+            fcntl::fcntl(pty.as_raw_fd(), fcntl::FcntlArg::F_GETFL)
+                .context("getting pty master flags")?,
+        );
+        fcntl::fcntl(pty.as_raw_fd(), fcntl::FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK))
+            .context("making pty master non-blocking")?;
 
-            let mut resize_cmd = if let ClientConnectionMsg::New(conn) = &client_conn {
-                Some(ResizeCmd { size: conn.size.clone(), when: time::Instant::now() })
-            } else {
+        let output_spool =
+            if matches!(args.session_restore_mode, config::SessionRestoreMode::Simple) {
                 None
+            } else {
+                Some(shpool_vt100::Parser::new(
+                    args.tty_size.rows,
+                    VTERM_WIDTH,
+                    args.scrollback_lines,
+                ))
             };
 
-            loop {
-                let mut do_reattach = false;
-                crossbeam_channel::select! {
-                    recv(args.client_connection) -> new_connection => {
-                        match new_connection {
-                            Ok(ClientConnectionMsg::New(conn)) => {
-                                info!("got new connection (rows={}, cols={})", conn.size.rows, conn.size.cols);
-                                do_reattach = true;
-                                let ack = if let ClientConnectionMsg::New(old_conn) = client_conn {
-                                    old_conn.stream.shutdown(net::Shutdown::Both)?;
-                                    ClientConnectionStatus::Replaced
-                                } else {
-                                    ClientConnectionStatus::New
-                                };
-                                // Resize the pty to be bigger than it needs to be,
-                                // we do this immediately so that the extra size
-                                // can "bake" for a little bit, which emacs seems
-                                // to require in order to pick up the jiggle.
-                                let oversize = tty::Size {
-                                    rows: conn.size.rows + 1,
-                                    cols: conn.size.cols + 1,
-                                };
-                                oversize.set_fd(pty_master.raw_fd().ok_or(anyhow!("no master fd"))?)?;
-
-                                // Always instantly resize the spool, since we don't
-                                // need to inject a delay into that.
-                                if let Some(s) = output_spool.as_mut() {
-                                    s.screen_mut().set_size(conn.size.rows, u16::MAX);
-                                }
-                                resize_cmd = Some(ResizeCmd {
-                                    size: conn.size.clone(),
-                                    when: time::Instant::now().add(REATTACH_RESIZE_DELAY),
-                                });
-                                client_conn = ClientConnectionMsg::New(conn);
-
-                                args.client_connection_ack.send(ack)
-                                    .context("sending client connection ack")?;
-                            }
-                            Ok(ClientConnectionMsg::Disconnect) => {
-                                let ack = if let ClientConnectionMsg::New(old_conn) = client_conn {
-                                    info!("disconnect, shutting down client stream");
-                                    old_conn.stream.shutdown(net::Shutdown::Both)?;
-                                    ClientConnectionStatus::Detached
-                                } else {
-                                    info!("disconnect, no client stream to shut down");
-                                    ClientConnectionStatus::DetachNone
-                                };
-                                client_conn = ClientConnectionMsg::Disconnect;
-
-                                args.client_connection_ack.send(ack)
-                                    .context("sending client connection ack")?;
-                            }
-                            Ok(ClientConnectionMsg::DisconnectExit(exit_status)) => {
-                                let ack = if let ClientConnectionMsg::New(mut old_conn) = client_conn {
-                                    info!("disconnectexit({}), shutting down client stream",
-                                           exit_status);
-
-                                    // write an exit status frame so the attach process
-                                    // can exit with the same exit code as the child shell
-                                    let status_buf: [u8; 4] = exit_status.to_le_bytes();
-                                    let chunk = protocol::Chunk {
-                                        kind: protocol::ChunkKind::ExitStatus,
-                                        buf: status_buf.as_slice(),
-                                    };
-                                    match chunk.write_to(&mut old_conn.stream).and_then(|_| old_conn.stream.flush()) {
-                                        Ok(_) => {
-                                            trace!("wrote exit status chunk");
-                                        }
-                                        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
-                                            trace!("client hangup: {:?}", e);
-                                        }
-                                        Err(e) => {
-                                            error!("writing exit status chunk: {:?}", e);
-                                        }
-                                    };
-
-                                    old_conn.stream.shutdown(net::Shutdown::Both)?;
-
-                                    ClientConnectionStatus::Detached
-                                } else {
-                                    info!(
-                                        "disconnectexit({}), no client stream to shut down",
-                                          exit_status);
-                                    ClientConnectionStatus::DetachNone
-                                };
-                                args.client_connection_ack.send(ack)
-                                    .context("sending client connection ack")?;
-
-                                return Ok(());
-                            }
+        let reader = Reader {
+            name: self.name.clone(),
+            child_pid: self.pty_master.child_pid(),
+            pty,
+            pty_open: true,
+            term_db: Arc::clone(&self.term_db),
+            control_code_matcher: control_codes::Matcher::new(&self.term_db)
+                .context("building control code matcher")?,
+            daily_messenger: Arc::clone(&self.daily_messenger),
+            needs_initial_motd_dump: self.needs_initial_motd_dump,
+            reattach_notify: self.config.reattach_notify.clone().unwrap_or_default(),
+            flow_control: self.config.output_flow_control.clone().unwrap_or_default(),
+            metrics: Arc::clone(&self.metrics),
+            output_spool,
+            spool_backlog: vec![],
+            buf: vec![0; consts::BUF_SIZE],
+            client_buf: vec![0; consts::BUF_SIZE],
+            snip_sections: vec![],
+            keep_sections: vec![],
+            started: false,
+            conn: None,
+            retired: vec![],
+            input: vec![],
+            tty_size: args.tty_size.clone(),
+            resize_cmd: None,
+            handed_off: false,
+            handoff_ack: None,
+            last_command_output: LastCommandOutput::default(),
+            spool_scrollback_lines: 0,
+            exited: None,
+            focus_reporting: false,
+            rcfile_fallback: args.rcfile_fallback.take().map(|script| RcfileFallback {
+                script,
+                when: time::Instant::now() + RCFILE_FALLBACK_DELAY,
+            }),
+            args,
+        };
+        waker.start(Box::new(reader)).context("starting reader")
+    }
 
-                            // SessionInner getting dropped, so this thread should go away.
-                            Err(crossbeam_channel::RecvError) => {
-                                info!("client conn: bailing due to RecvError");
-                                return Ok(())
-                            },
-                        }
-                    }
-                    recv(args.tty_size_change) -> new_size => {
-                        match new_size {
-                            Ok(size) => {
-                                info!("resize size={:?}", size);
-                                if let Some(s) = output_spool.as_mut() {
-                                    s.screen_mut().set_size(size.rows, u16::MAX);
-                                }
-                                resize_cmd = Some(ResizeCmd {
-                                    size,
-                                    // No delay needed for ordinary resizes, just
-                                    // for reconnects.
-                                    when: time::Instant::now(),
-                                });
-                                args.tty_size_change_ack.send(())
-                                    .context("sending size change ack")?;
-                            }
-                            Err(err) => {
-                                warn!("size change: bailing due to: {:?}", err);
-                                return Ok(());
-                            }
-                        }
-                    }
+    /// bidi_stream hooks the client connection up to the reader, which
+    /// shuffles bytes between the subprocess and the client from then on.
+    /// It returns as soon as the reader has the connection, and `done`
+    /// gets dropped once the reader is done with it.
+    #[instrument(skip_all, fields(s = self.name, cid = conn_id))]
+    pub fn bidi_stream(
+        &mut self,
+        conn_id: usize,
+        init_tty_size: tty::Size,
+        input: protocol::ChunkDecoder,
+        compression: Option<protocol::Compression>,
+        hook_ctx: hooks::HookContext,
+        done: ConnDone,
+    ) -> anyhow::Result<()> {
+        test_hooks::emit("daemon-bidi-stream-enter");
+
+        let client_stream = match self.client_stream.take() {
+            Some(s) => s,
+            None => return Err(anyhow!("no client stream to take for bidi streaming")),
+        };
+
+        let empty_bindings = vec![config::Keybinding {
+            binding: String::from("Ctrl-Space Ctrl-q"),
+            action: keybindings::Action::Detach,
+        }];
+        let bindings = keybindings::Bindings::new(
+            self.config
+                .keybinding
+                .as_ref()
+                .unwrap_or(&empty_bindings)
+                .iter()
+                .map(|binding| (binding.binding.as_str(), binding.action)),
+        )
+        .context("compiling keybindings engine")?;
+        let compressor = compression
+            .map(compression::Compressor::new)
+            .transpose()
+            .context("creating output compressor")?;
+
+        let reader_ctl = self.reader_ctl.lock().unwrap();
+        reader_ctl
+            .client_connection
+            .send(ClientConnectionMsg::New(Box::new(ClientConnection {
+                stream: client_stream,
+                size: init_tty_size,
+                input,
+                compressor,
+                hook_ctx,
+                bindings,
+                done,
+            })))
+            .context("attaching new client stream to reader")?;
+        let status =
+            reader_ctl.client_connection_ack.recv().context("waiting for client connection ack")?;
+        info!("client connection status={:?}", status);
+
+        Ok(())
+    }
+}
+
+/// A handle for displaying things in a pager on the client attached
+/// to a session. Pagers block for as long as the user is looking at
+/// them, so they never run on an event loop, but rather on the thread
+/// of the connection that asked for them or on a thread of their own.
+#[derive(Clone, Debug)]
+pub struct PagerDisplay {
+    /// Weak because the reader holds on to one of these, and the
+    /// reader goes away once everyone else has let go of its controls.
+    reader_ctl: Weak<Mutex<ReaderCtl>>,
+    pager_ctl: Arc<Mutex<Option<PagerCtl>>>,
+    /// The name of the pager program to use.
+    pager_bin: String,
+}
+
+impl PagerDisplay {
+    pub fn new(
+        reader_ctl: &Arc<Mutex<ReaderCtl>>,
+        pager_ctl: Arc<Mutex<Option<PagerCtl>>>,
+        config: &config::Config,
+    ) -> Self {
+        PagerDisplay {
+            reader_ctl: Arc::downgrade(reader_ctl),
+            pager_ctl,
+            pager_bin: config
+                .scrollback_pager
+                .clone()
+                .unwrap_or(String::from(DEFAULT_SCROLLBACK_PAGER)),
+        }
+    }
+
+    /// Display the scrollback in the configured pager, blocking until the
+    /// user quits out of it.
+    #[instrument(skip_all)]
+    pub fn scrollback(&self, req: protocol::ScrollbackRequest) -> anyhow::Result<()> {
+        let content =
+            if req.last_command { PagerContent::LastCommand } else { PagerContent::Scrollback };
+        self.display(content, |scrollback| match scrollback {
+            Some(scrollback) => Ok(scrollback),
+            None if req.last_command => Err(anyhow!("no command output has been recorded")),
+            None => Err(anyhow!("no output spool to show scrollback from (simple restore mode)")),
+        })
+        .context("displaying scrollback in pager")
+    }
+
+    /// Display a broadcast message in the configured pager, blocking until
+    /// the user quits out of it.
+    #[instrument(skip_all)]
+    pub fn message(&self, msg: &str) -> anyhow::Result<()> {
+        let text = format!("Broadcast message from shpool:\n\n{}\n", msg);
+        self.display(PagerContent::Message, |_| Ok(text)).context("displaying message in pager")
+    }
+
+    /// Take the client connection from the reader and display the text
+    /// that `text` builds from the scrollback the reader sends back. The
+    /// reader holds off on writing shell output to the client while the
+    /// pager is up, and redraws the screen once it is done.
+    fn display<F>(&self, content: PagerContent, text: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(Option<String>) -> anyhow::Result<String>,
+    {
+        let reader_ctl = self.reader_ctl.upgrade().ok_or(anyhow!("session is gone"))?;
+        let (mut client_stream, scrollback, tty_size, mut input) = {
+            let reader_ctl = reader_ctl.lock().unwrap();
+            reader_ctl
+                .pager_handoff
+                .send(PagerHandoffMsg::Start { content })
+                .context("signaling pager handoff to reader")?;
+            match reader_ctl.pager_handoff_ack.recv().context("waiting for pager handoff ack")? {
+                PagerHandoffAck::Started { stream, scrollback, tty_size, input } => {
+                    (stream, scrollback, tty_size, input)
+                }
+                PagerHandoffAck::Refused => {
+                    info!("no client connection to show the pager on");
+                    return Ok(());
+                }
+                PagerHandoffAck::Finished => return Err(anyhow!("unexpected pager handoff ack")),
+            }
+        };
+
+        let display_res = text(scrollback).and_then(|text| {
+            // The reader has written out everything it queued up for the
+            // client before acking the handoff, and it writes nothing more
+            // until the pager is done, so nothing can get interleaved with
+            // the pager output. That includes heartbeats, so the pager
+            // sends its own.
+            Pager::new(self.pager_bin.clone()).display(
+                &mut client_stream,
+                &mut input,
+                Arc::clone(&self.pager_ctl),
+                tty_size.clone(),
+                &text,
+            )
+        });
+        let (final_size, display_err) = match display_res {
+            Ok(size) => (size, None),
+            Err(e) => (tty_size, Some(e)),
+        };
 
-                    // make this select non-blocking so we spend most of our time parked
-                    // in poll
-                    default => {}
+        {
+            let reader_ctl = reader_ctl.lock().unwrap();
+            reader_ctl
+                .pager_handoff
+                .send(PagerHandoffMsg::Finish { size: final_size, input })
+                .context("signaling pager finish to reader")?;
+            reader_ctl.pager_handoff_ack.recv().context("waiting for pager finish ack")?;
+        }
+
+        match display_err {
+            None => Ok(()),
+            Some(e) => match e.downcast::<PagerError>() {
+                Ok(PagerError::ClientHangup) => {
+                    info!("client hung up while the pager was up");
+                    Ok(())
                 }
+                Err(e) => Err(e),
+            },
+        }
+    }
+}
 
-                let mut executed_resize = false;
-                if let Some(resize_cmd) = resize_cmd.as_ref() {
-                    if resize_cmd.when.saturating_duration_since(time::Instant::now())
-                        == time::Duration::ZERO
-                    {
-                        resize_cmd
-                            .size
-                            .set_fd(pty_master.raw_fd().ok_or(anyhow!("no master fd"))?)?;
-                        executed_resize = true;
-                        info!(
-                            "resized fd (rows={}, cols={})",
-                            resize_cmd.size.rows, resize_cmd.size.cols
-                        );
+/// The reader for a session, which runs on an event loop. It reads
+/// the shell's output to keep the output spool up to date and forwards
+/// it to the attached client, if there is one, and it feeds input from
+/// the client to the shell.
+struct Reader {
+    name: String,
+    child_pid: Option<libc::pid_t>,
+    args: ReaderArgs,
+    /// Our own non-blocking handle on the pty master.
+    pty: OwnedFd,
+    /// Cleared once the shell side of the pty has gone away.
+    pty_open: bool,
+    term_db: Arc<termini::TermInfo>,
+    control_code_matcher: control_codes::Matcher,
+    daily_messenger: Arc<show_motd::DailyMessenger>,
+    needs_initial_motd_dump: bool,
+    reattach_notify: config::ReattachNotify,
+    /// What to do when the client falls behind the shell's output.
+    flow_control: config::OutputFlowControl,
+    metrics: Arc<metrics::Metrics>,
+    output_spool: Option<shpool_vt100::Parser>,
+    /// Output which has been sent on to the client but not fed to the
+    /// spool yet. The spool catches up a little at a time so that it
+    /// never holds up the client's output.
+    spool_backlog: Vec<u8>,
+    buf: Vec<u8>,
+    client_buf: Vec<u8>,
+    snip_sections: Vec<(usize, usize)>, // (<len>, <end offset>)
+    keep_sections: Vec<(usize, usize)>, // (<start offset>, <end offset>)
+    /// Set once the first client has connected. We hold off on
+    /// reading from the shell until then so that we don't drop the
+    /// initial prompt on the floor.
+    started: bool,
+    conn: Option<Conn>,
+    /// The streams of connections we are done with, which have to stay
+    /// open until we have stopped watching them.
+    retired: Vec<UnixStream>,
+    /// Input from the client waiting to be written to the shell.
+    input: Vec<u8>,
+    tty_size: tty::Size,
+    resize_cmd: Option<ResizeCmd>,
+    /// Set while a pager is displaying on the client connection, so
+    /// we must not write any output to it.
+    handed_off: bool,
+    /// The ack for a pager handoff, held back until everything we
+    /// have queued up for the client has been written out.
+    handoff_ack: Option<PagerHandoffAck>,
+    last_command_output: LastCommandOutput,
+    /// The number of lines which have scrolled off the top of the
+    /// output spool's screen, up to the scrollback limit.
+    spool_scrollback_lines: usize,
+    /// Set once the shell has exited to when we give up on getting
+    /// its exit status to the client.
+    exited: Option<time::Instant>,
+    /// Set while the shell wants to hear about focus changes.
+    focus_reporting: bool,
+    /// Set until we have seen the prompt prefix rc file run.
+    rcfile_fallback: Option<RcfileFallback>,
+}
+
+/// The reader's end of the attached client connection.
+struct Conn {
+    stream: UnixStream,
+    /// What the client has sent that we have not handled yet.
+    input: protocol::ChunkDecoder,
+    hook_ctx: hooks::HookContext,
+    bindings: keybindings::Bindings,
+    /// Only held on to so that it gets dropped along with the connection.
+    _done: ConnDone,
+    /// Compresses the client's output, if it asked for that.
+    compressor: Option<compression::Compressor>,
+    /// Output that has not been framed as a chunk yet, so that small
+    /// reads from the shell can go out (and get compressed) together.
+    pending: Vec<u8>,
+    /// Chunks waiting to be written to the client.
+    outbox: Vec<u8>,
+    /// When we last queued anything for the client. We only need to
+    /// send heartbeats once the output has gone quiet.
+    last_write: time::Instant,
+    /// The start of what might turn out to be a keybinding.
+    partial_keybinding: Vec<u8>,
+    /// Set from when the scrollback keybinding fires until the pager
+    /// is done, so that we leave the input for the pager.
+    paused: bool,
+    /// Set while we are dropping output because the client fell behind,
+    /// until it catches up and gets a fresh render of the screen.
+    skipping: bool,
+}
+
+impl event_loop::Driver for Reader {
+    fn drive(
+        &mut self,
+        fds: &mut event_loop::Fds,
+        ready: &[event_loop::Ready],
+    ) -> anyhow::Result<event_loop::Next> {
+        let _s = span!(Level::INFO, "reader", s = self.name, cid = self.args.conn_id).entered();
+
+        let res = self.step(fds, ready);
+        if res.is_err() {
+            self.metrics.reader_errors.inc();
+        }
+        log_if_error("error in reader", res)
+    }
+}
+
+impl Reader {
+    fn step(
+        &mut self,
+        fds: &mut event_loop::Fds,
+        ready: &[event_loop::Ready],
+    ) -> anyhow::Result<event_loop::Next> {
+        if !self.handle_ctl()? {
+            // SessionInner getting dropped, so the reader should go away.
+            return Ok(event_loop::Next::Done);
+        }
+
+        for r in ready {
+            match r.token {
+                PTY_TOKEN => {
+                    if r.writable {
+                        self.flush_input();
+                    }
+                    if r.readable || r.hangup {
+                        self.pump_pty()?;
                     }
                 }
-                if executed_resize {
-                    resize_cmd = None;
+                CLIENT_TOKEN => {
+                    if r.writable || r.hangup {
+                        self.flush_output();
+                    }
+                    if r.readable || r.hangup {
+                        self.read_client()?;
+                    }
                 }
+                _ => {}
+            }
+        }
 
-                if do_reattach {
-                    use config::SessionRestoreMode::*;
-
-                    info!("executing reattach protocol (mode={:?})", args.session_restore_mode);
-                    let restore_buf = match (output_spool.as_mut(), &args.session_restore_mode) {
-                        (Some(spool), Screen) => {
-                            let (rows, cols) = spool.screen().size();
-                            info!(
-                                "computing screen restore buf with (rows={}, cols={})",
-                                rows, cols
-                            );
-                            spool.screen().contents_formatted()
-                        }
-                        (Some(spool), Lines(nlines)) => {
-                            let (rows, cols) = spool.screen().size();
-                            info!(
-                                "computing lines({}) restore buf with (rows={}, cols={})",
-                                nlines, rows, cols
-                            );
-                            spool.screen().last_n_rows_contents_formatted(*nlines)
-                        }
-                        (_, _) => vec![],
-                    };
-                    if let (true, ClientConnectionMsg::New(conn)) =
-                        (!restore_buf.is_empty(), &client_conn)
-                    {
-                        trace!("restore chunk='{}'", String::from_utf8_lossy(&restore_buf[..]));
-                        // send the restore buffer, broken up into chunks so that we don't make
-                        // the client allocate too much
-                        let mut s = conn.sink.lock().unwrap();
-                        for block in restore_buf.as_slice().chunks(consts::BUF_SIZE) {
-                            let chunk =
-                                protocol::Chunk { kind: protocol::ChunkKind::Data, buf: block };
-
-                            if let Err(err) = chunk.write_to(&mut *s) {
-                                warn!("err writing session-restore buf: {:?}", err);
-                            }
-                        }
-                        if let Err(err) = s.flush() {
-                            warn!("err flushing session-restore: {:?}", err);
-                        }
-                    }
+        if !self.started {
+            // Even if the shell has already exited, the client that is
+            // about to connect still needs to get its output and exit
+            // status.
+            return Ok(event_loop::Next::Wait(None));
+        }
+
+        if self.exited.is_none() {
+            if let Some(exit_status) = self.args.child_exit_notifier.wait(Some(Duration::ZERO)) {
+                self.shell_exited(exit_status)?;
+            }
+        }
+        if let Some(give_up_at) = self.exited {
+            let drained = self.conn.as_ref().map(|c| c.outbox.is_empty()).unwrap_or(true);
+            if drained || time::Instant::now() >= give_up_at {
+                if let Some(conn) = self.conn.take() {
+                    trace!("wrote exit status chunk (drained={})", drained);
+                    conn.stream.shutdown(net::Shutdown::Both)?;
+                    self.retired.push(conn.stream);
                 }
+                return Ok(event_loop::Next::Done);
+            }
+        }
 
-                // Block until the shell has some data for us so we can be sure our reads
-                // always succeed. We don't want to end up blocked forever on a read while
-                // a client is trying to attach.
-                let nready = match poll::poll(&mut poll_fds, READER_POLL_MS) {
-                    Ok(n) => n,
-                    Err(e) => {
-                        error!("polling pty master: {:?}", e);
-                        return Err(e)?;
-                    }
-                };
-                if nready == 0 {
-                    // if timeout
-                    continue;
+        if let Some(resize_cmd) = self.resize_cmd.as_ref() {
+            if resize_cmd.when <= time::Instant::now() {
+                resize_cmd.size.set_fd(self.pty.as_raw_fd())?;
+                info!("resized fd (rows={}, cols={})", resize_cmd.size.rows, resize_cmd.size.cols);
+                self.resize_cmd = None;
+            }
+        }
+
+        if self.rcfile_fallback.as_ref().map(|f| f.when <= time::Instant::now()).unwrap_or(false) {
+            if let Some(fallback) = self.rcfile_fallback.take() {
+                warn!("prompt prefix rc file never ran, typing the fallback into the shell");
+                self.input.extend_from_slice(fallback.script.as_bytes());
+                self.flush_input();
+            }
+        }
+
+        if !self.handed_off && self.conn.is_some() {
+            let notifications = self.args.triggers.take_notifications();
+            if !notifications.is_empty() {
+                info!("sending trigger notifications to client");
+                self.write_client(protocol::ChunkKind::Data, &notifications);
+            }
+        }
+
+        self.catch_up_spool(Some(SPOOL_BUDGET));
+        self.resync();
+        self.heartbeat();
+
+        let drained = self.conn.as_ref().map(|c| c.outbox.is_empty()).unwrap_or(true);
+        if drained {
+            if let Some(ack) = self.handoff_ack.take() {
+                self.args.pager_handoff_ack.send(ack).context("sending pager handoff start ack")?;
+            }
+        }
+
+        self.watch(fds)?;
+
+        let mut deadline = self.resize_cmd.as_ref().map(|r| r.when);
+        if let Some(fallback) = &self.rcfile_fallback {
+            deadline = Some(deadline.map_or(fallback.when, |d| d.min(fallback.when)));
+        }
+        if let (false, Some(conn)) = (self.handed_off, &self.conn) {
+            if conn.outbox.is_empty() {
+                let heartbeat_at = conn.last_write + consts::HEARTBEAT_DURATION;
+                deadline = Some(deadline.map_or(heartbeat_at, |d| d.min(heartbeat_at)));
+            }
+        }
+        if let Some(give_up_at) = self.exited {
+            deadline = Some(deadline.map_or(give_up_at, |d| d.min(give_up_at)));
+        }
+        if !self.spool_backlog.is_empty() {
+            // come right back to feed the spool the rest
+            deadline = Some(time::Instant::now());
+        }
+        Ok(event_loop::Next::Wait(deadline))
+    }
+
+    /// Update which fds we are watching to match our state.
+    fn watch(&mut self, fds: &mut event_loop::Fds) -> anyhow::Result<()> {
+        // If the client can't keep up with the shell's output, stop
+        // reading it so that the shell has to wait rather than us piling
+        // up output without bound.
+        let backlogged = self.backlogged();
+        fds.watch(
+            PTY_TOKEN,
+            self.pty.as_fd(),
+            event_loop::Interest {
+                read: self.started && self.pty_open && !backlogged,
+                write: self.pty_open && !self.input.is_empty(),
+            },
+        )?;
+        match &self.conn {
+            Some(conn) => fds.watch(
+                CLIENT_TOKEN,
+                conn.stream.as_fd(),
+                event_loop::Interest {
+                    read: !conn.paused && !self.handed_off && self.input.len() < MAX_BACKLOG,
+                    write: !conn.outbox.is_empty(),
+                },
+            )?,
+            None => fds.unwatch(CLIENT_TOKEN)?,
+        }
+        self.retired.clear();
+        Ok(())
+    }
+
+    /// Handle any control messages, returning false if the session is
+    /// going away.
+    fn handle_ctl(&mut self) -> anyhow::Result<bool> {
+        use crossbeam_channel::TryRecvError;
+
+        loop {
+            let mut handled = false;
+
+            match self.args.client_connection.try_recv() {
+                Ok(ClientConnectionMsg::New(conn)) => {
+                    handled = true;
+                    self.new_connection(*conn)?;
                 }
-                if nready != 1 {
-                    return Err(anyhow!("reader thread: expected exactly 1 ready fd"));
+                Ok(ClientConnectionMsg::Disconnect) => {
+                    handled = true;
+                    let ack = if self.disconnect() {
+                        info!("disconnect, shutting down client stream");
+                        ClientConnectionStatus::Detached
+                    } else {
+                        info!("disconnect, no client stream to shut down");
+                        ClientConnectionStatus::DetachNone
+                    };
+                    self.args
+                        .client_connection_ack
+                        .send(ack)
+                        .context("sending client connection ack")?;
                 }
-                let len = match pty_master.read(&mut buf) {
-                    Ok(l) => l,
-                    Err(e) => {
-                        test_hooks::emit("daemon-reader-read-error");
-                        error!("reading chunk from pty master: {:?}", e);
-                        return Err(e).context("reading pty master chunk")?;
-                    }
-                };
-                if len == 0 {
-                    continue;
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => {
+                    info!("client conn: bailing due to RecvError");
+                    return Ok(false);
                 }
-                trace!("read pty master len={} '{}'", len, String::from_utf8_lossy(&buf[..len]));
+            }
 
-                if !matches!(args.session_restore_mode, config::SessionRestoreMode::Simple) {
-                    if let Some(s) = output_spool.as_mut() {
-                        s.process(&buf[..len]);
-                    }
+            match self.args.tty_size_change.try_recv() {
+                Ok(size) => {
+                    handled = true;
+                    self.resize(size);
+                    self.args.tty_size_change_ack.send(()).context("sending size change ack")?;
                 }
+                Err(TryRecvError::Empty) => {}
+                Err(err) => {
+                    warn!("size change: bailing due to: {:?}", err);
+                    return Ok(false);
+                }
+            }
 
-                // scan for control codes we need to handle
-                let mut reset_client_conn = false;
-                let mut snip_buf_to = None;
-                if needs_initial_motd_dump {
-                    for (i, byte) in buf[..len].iter().enumerate() {
-                        match control_code_matcher.transition(*byte) {
-                            Some(control_codes::Code::ClearScreen) if needs_initial_motd_dump => {
-                                debug!("detected initial ClearScreen code");
-                                if let ClientConnectionMsg::New(conn) = &client_conn {
-                                    let mut s = conn.sink.lock().unwrap();
-
-                                    // write the clear code ahead of time so we don't
-                                    // immediately clobber ourselves
-                                    let write_to = i + 1;
-                                    let chunk = protocol::Chunk {
-                                        kind: protocol::ChunkKind::Data,
-                                        buf: &buf[..write_to],
-                                    };
-                                    let write_result =
-                                        chunk.write_to(&mut *s).and_then(|_| s.flush());
-                                    if let Err(err) = write_result {
-                                        info!(
-                                            "while writing ClearScreen: client_stream write err, assuming hangup: {:?}",
-                                            err
-                                        );
-                                        reset_client_conn = true;
-                                    } else {
-                                        test_hooks::emit("daemon-wrote-s2c-chunk");
-                                    }
-                                    snip_buf_to = Some(write_to);
-
-                                    if let Err(e) = daily_messenger.dump(&mut *s, &term_db) {
-                                        warn!("Error handling clear: {:?}", e);
-                                    }
+            match self.args.pager_handoff.try_recv() {
+                Ok(PagerHandoffMsg::Start { content }) => {
+                    handled = true;
+                    // Only one pager gets the connection at a time.
+                    let stream = match &self.conn {
+                        Some(conn) if !self.handed_off => match conn.stream.try_clone() {
+                            Ok(stream) => Some(stream),
+                            Err(e) => {
+                                warn!("cloning client stream for pager: {:?}", e);
+                                None
+                            }
+                        },
+                        _ => None,
+                    };
+                    match stream {
+                        Some(stream) => {
+                            info!("handing client connection off to pager (content={:?})", content);
+                            self.handed_off = true;
+                            // The ack waits until whatever output we have
+                            // queued up has been written, so the pager has
+                            // the connection to itself.
+                            self.handoff_ack = Some(PagerHandoffAck::Started {
+                                stream,
+                                scrollback: self.scrollback(content),
+                                tty_size: self.tty_size.clone(),
+                                input: self
+                                    .conn
+                                    .as_mut()
+                                    .map(|c| std::mem::take(&mut c.input))
+                                    .unwrap_or_default(),
+                            });
+                        }
+                        None => {
+                            info!("refusing pager handoff (handed_off={})", self.handed_off);
+                            self.args
+                                .pager_handoff_ack
+                                .send(PagerHandoffAck::Refused)
+                                .context("sending pager handoff refusal")?;
+                            // If the scrollback keybinding asked for this
+                            // pager, nobody is going to hand the input back.
+                            if !self.handed_off {
+                                if let Some(conn) = self.conn.as_mut() {
+                                    conn.paused = false;
                                 }
-                                needs_initial_motd_dump = false;
+                                self.handle_client_input()?;
                             }
-                            _ => {}
                         }
                     }
                 }
-                if let Some(snip_to) = snip_buf_to {
-                    if snip_to < buf.len() {
-                        buf = Vec::from(&buf[snip_to..]);
-                    } else {
-                        buf.clear();
+                Ok(PagerHandoffMsg::Finish { size, input }) => {
+                    handled = true;
+                    if let Some(conn) = self.conn.as_mut() {
+                        conn.paused = false;
+                    }
+                    // If the client got replaced while the pager was
+                    // up, the size and input are stale and the new client
+                    // has already gotten a fresh screen.
+                    if self.handed_off {
+                        info!("pager finished (rows={}, cols={})", size.rows, size.cols);
+                        self.handed_off = false;
+                        if let Some(conn) = self.conn.as_mut() {
+                            conn.input = input;
+                        }
+                        let resized =
+                            size.rows != self.tty_size.rows || size.cols != self.tty_size.cols;
+                        self.resize(size);
+                        if resized {
+                            // The pager took care of the resize itself,
+                            // but the hook has not heard about it.
+                            self.resize_hook();
+                        }
+
+                        // The pager clobbered the client's screen, so put
+                        // back what the shell has drawn in the meantime,
+                        // regardless of the restore mode.
+                        info!("redrawing screen after pager");
+                        self.catch_up_spool(None);
+                        let restore_buf = self
+                            .output_spool
+                            .as_ref()
+                            .map(|spool| spool.screen().contents_formatted())
+                            .unwrap_or_default();
+                        self.write_restore_buf(&restore_buf);
                     }
+                    self.args
+                        .pager_handoff_ack
+                        .send(PagerHandoffAck::Finished)
+                        .context("sending pager handoff finish ack")?;
+                    // Pick up anything the client sent that the pager
+                    // did not get to.
+                    self.handle_client_input()?;
+                }
+                Err(TryRecvError::Empty) => {}
+                Err(err) => {
+                    warn!("pager handoff: bailing due to: {:?}", err);
+                    return Ok(false);
                 }
+            }
 
-                if let ClientConnectionMsg::New(conn) = &client_conn {
-                    let chunk =
-                        protocol::Chunk { kind: protocol::ChunkKind::Data, buf: &buf[..len] };
-                    let mut s = conn.sink.lock().unwrap();
-                    let write_result = chunk.write_to(&mut *s).and_then(|_| s.flush());
-                    if let Err(err) = write_result {
-                        info!("client_stream write err, assuming hangup: {:?}", err);
-                        reset_client_conn = true;
+            match self.args.banner.try_recv() {
+                Ok(msg) => {
+                    handled = true;
+                    // Banners go straight to the client and never
+                    // through the pty, so the shell and the output
+                    // spool never see them.
+                    let delivered = if !self.handed_off && self.conn.is_some() {
+                        info!("drawing broadcast banner");
+                        let codes = banner_codes(&msg, self.tty_size.cols);
+                        self.write_client(protocol::ChunkKind::Data, &codes)
                     } else {
-                        test_hooks::emit("daemon-wrote-s2c-chunk");
-                    }
+                        false
+                    };
+                    self.args.banner_ack.send(delivered).context("sending banner ack")?;
                 }
-                if reset_client_conn {
-                    client_conn = ClientConnectionMsg::Disconnect;
+                Err(TryRecvError::Empty) => {}
+                Err(err) => {
+                    warn!("banner: bailing due to: {:?}", err);
+                    return Ok(false);
                 }
             }
-        };
 
-        Ok(thread::Builder::new()
-            .name(format!("reader({})", self.name))
-            .spawn(move || log_if_error("error in reader", closure()))?)
+            if !handled {
+                return Ok(true);
+            }
+        }
     }
 
-    /// bidi_stream shuffles bytes between the subprocess and
-    /// the client connection. It returns true if the subprocess
-    /// has exited, and false if it is still running.
-    #[instrument(skip_all, fields(s = self.name))]
-    pub fn bidi_stream(
-        &mut self,
-        conn_id: usize,
-        init_tty_size: tty::Size,
-        child_exit_notifier: Arc<ExitNotifier>,
-    ) -> anyhow::Result<bool> {
-        test_hooks::emit("daemon-bidi-stream-enter");
-        #[allow(clippy::let_unit_value)]
-        let _bidi_stream_test_guard = test_hooks::scoped("daemon-bidi-stream-done");
+    fn new_connection(&mut self, conn: ClientConnection) -> anyhow::Result<()> {
+        if !self.started {
+            self.started = true;
+            info!("got initial client connection");
+            self.resize_cmd =
+                Some(ResizeCmd { size: conn.size.clone(), when: time::Instant::now() });
+            // we don't see any of the shell's output until now
+            if let Some(fallback) = self.rcfile_fallback.as_mut() {
+                fallback.when = time::Instant::now() + RCFILE_FALLBACK_DELAY;
+            }
+            self.tty_size = conn.size.clone();
+            self.conn = Some(Conn::new(conn));
+            self.args
+                .client_connection_ack
+                .send(ClientConnectionStatus::New)
+                .context("sending initial client connection ack")?;
+            return self.handle_client_input();
+        }
 
-        // we take the client stream so that it gets closed when this routine
-        // returns
-        let client_stream = match self.client_stream.take() {
-            Some(s) => s,
-            None => return Err(anyhow!("no client stream to take for bidi streaming")),
+        info!("got new connection (rows={}, cols={})", conn.size.rows, conn.size.cols);
+        let ack = if let Some(old_conn) = self.conn.take() {
+            old_conn.stream.shutdown(net::Shutdown::Both)?;
+            self.retired.push(old_conn.stream);
+            ClientConnectionStatus::Replaced
+        } else {
+            ClientConnectionStatus::New
         };
-
-        let mut client_to_shell_client_stream =
-            client_stream.try_clone().context("creating reader client stream")?;
-        let reader_client_stream =
-            client_stream.try_clone().context("creating reader client stream handle")?;
-        let client_stream_m = Arc::new(Mutex::new(io::BufWriter::new(
-            client_stream.try_clone().context("wrapping stream in bufwriter")?,
-        )));
-
-        {
-            let reader_ctl = self.reader_ctl.lock().unwrap();
-            reader_ctl
-                .client_connection
-                .send(ClientConnectionMsg::New(ClientConnection {
-                    sink: Arc::clone(&client_stream_m),
-                    size: init_tty_size,
-                    stream: reader_client_stream,
-                }))
-                .context("attaching new client stream to reader thread")?;
-            let status = reader_ctl
-                .client_connection_ack
-                .recv()
-                .context("waiting for client connection ack")?;
-            info!("client connection status={:?}", status);
+        // Resize the pty to be bigger than it needs to be, we do this
+        // immediately so that the extra size can "bake" for a little
+        // bit, which emacs seems to require in order to pick up the
+        // jiggle.
+        let oversize = tty::Size { rows: conn.size.rows + 1, cols: conn.size.cols + 1 };
+        oversize.set_fd(self.pty.as_raw_fd())?;
+
+        // Always instantly resize the spool, since we don't need to
+        // inject a delay into that.
+        if let Some(s) = self.output_spool.as_mut() {
+            s.screen_mut().set_size(conn.size.rows, u16::MAX);
         }
+        self.resize_cmd = Some(ResizeCmd {
+            size: conn.size.clone(),
+            when: time::Instant::now().add(REATTACH_RESIZE_DELAY),
+        });
+        self.tty_size = conn.size.clone();
+        self.conn = Some(Conn::new(conn));
+        // any pager was running on the old connection
+        self.handed_off = false;
+
+        self.args.client_connection_ack.send(ack).context("sending client connection ack")?;
+
+        let mut restore_buf = self.reattach_buf();
+        restore_buf.extend(self.args.activity.attached(&self.name, &self.reattach_notify));
+        self.write_restore_buf(&restore_buf);
+        self.handle_client_input()
+    }
 
-        let pty_master =
-            self.pty_master.is_parent().context("internal error: executing in child fork")?;
-
-        // A flag to indicate that outstanding threads should stop
-        let stop = AtomicBool::new(false);
-        // A flag to indicate if the child shell has exited
-        let child_done = AtomicBool::new(false);
-
-        thread::scope(|s| -> anyhow::Result<()> {
-            // Spawn the main data transport threads
-            let client_to_shell_h = self.spawn_client_to_shell(
-                s, conn_id, &stop, &pty_master, &mut client_to_shell_client_stream)?;
-
-            // Send a steady stream of heartbeats to the client
-            // so that if the connection unexpectedly goes
-            // down, we detect it immediately.
-            let heartbeat_h = self.spawn_heartbeat(
-                s, conn_id, &stop, &client_stream_m)?;
-
-            // poll the pty master fd to see if the child
-            // shell has exited.
-            let supervisor_h = self.spawn_supervisor(
-                s, conn_id, &stop, &child_done, &pty_master,
-                Arc::clone(&child_exit_notifier))?;
-
-            loop {
-                let c_done = child_done.load(Ordering::Acquire);
-                if client_to_shell_h.is_finished()
-                    || heartbeat_h.is_finished() || supervisor_h.is_finished() || c_done {
-                    debug!("signaling for threads to stop: client_to_shell_finished={} heartbeat_finished={} supervisor_finished={} child_done={}",
-                        client_to_shell_h.is_finished(),
-                        heartbeat_h.is_finished(),
-                        supervisor_h.is_finished(),
-                        c_done,
-                    );
-                    stop.store(true, Ordering::Relaxed);
-                    break;
+    /// Let go of the client connection, if there is one, returning
+    /// true if there was.
+    fn disconnect(&mut self) -> bool {
+        self.handed_off = false;
+        match self.conn.take() {
+            Some(conn) => {
+                if let Err(e) = conn.stream.shutdown(net::Shutdown::Both) {
+                    trace!("shutting down client stream: {:?}", e);
                 }
-                thread::sleep(consts::JOIN_POLL_DURATION);
+                self.retired.push(conn.stream);
+                true
             }
+            None => false,
+        }
+    }
 
-            // disconnect the reader thread first since that will shutdown the
-            // client stream, and the client->shell thread hangs out blocked on
-            // that stream, so we need to close it in order to get all our
-            // cows to come home.
-            let c_done = child_done.load(Ordering::Acquire);
-            {
-                let reader_ctl = self.reader_ctl.lock().unwrap();
-                let send_res = reader_ctl.client_connection.send_timeout(if c_done {
-                    let exit_status = child_exit_notifier
-                        .wait(Some(Duration::from_secs(0)))
-                        .unwrap_or(1);
-                    info!("telling reader to disconnect with exit status {}", exit_status);
-                    ClientConnectionMsg::DisconnectExit(exit_status)
-                } else {
-                    info!("telling reader to disconnect without reaping");
-                    ClientConnectionMsg::Disconnect
-                }, Duration::from_millis((READER_POLL_MS + (READER_POLL_MS >> 1)) as u64));
-
-                if let Err(send_timeout_err) = send_res {
-                    info!("failed to tell reader to disconnect: {:?}", send_timeout_err);
-
-                    // the reader didn't close the client stream for us, so we'll need
-                    // to handle that ourselves
-                    client_stream.shutdown(net::Shutdown::Both)?;
-                } else {
-                    let status = reader_ctl.client_connection_ack.recv()
-                        .context("waiting for client connection ack")?;
-                    info!("detached from reader, status = {:?}", status);
-                }
-            }
+    fn reattach_buf(&mut self) -> Vec<u8> {
+        use config::SessionRestoreMode::*;
 
-            debug!("joining client_to_shell_h");
-            match client_to_shell_h.join() {
-                Ok(v) => v.context("joining client_to_shell_h")?,
-                Err(panic_err) => {
-                    debug!("client_to_shell panic_err = {:?}", panic_err);
-                    std::panic::resume_unwind(panic_err)
-                },
+        info!("executing reattach protocol (mode={:?})", self.args.session_restore_mode);
+        let mut restore_buf = match (self.output_spool.as_mut(), &self.args.session_restore_mode) {
+            (Some(spool), Screen) => {
+                let (rows, cols) = spool.screen().size();
+                info!("computing screen restore buf with (rows={}, cols={})", rows, cols);
+                spool.screen().contents_formatted()
             }
-            debug!("joining heartbeat_h");
-            match heartbeat_h.join() {
-                Ok(v) => v.context("joining heartbeat_h")?,
-                Err(panic_err) => std::panic::resume_unwind(panic_err),
+            (Some(spool), Lines(nlines)) => {
+                let (rows, cols) = spool.screen().size();
+                info!(
+                    "computing lines({}) restore buf with (rows={}, cols={})",
+                    nlines, rows, cols
+                );
+                spool.screen().last_n_rows_contents_formatted(*nlines)
             }
-            debug!("joining supervisor_h");
-            match supervisor_h.join() {
-                Ok(v) => v.context("joining supervisor_h")?,
-                Err(panic_err) => std::panic::resume_unwind(panic_err),
-            }
-            debug!("joined all threads");
+            (Some(spool), LastCommand) => match self.last_command_output.since_start() {
+                Some(output) => {
+                    info!(
+                        "computing last command restore buf with (rows={}, cols={})",
+                        self.tty_size.rows, self.tty_size.cols
+                    );
+                    let mut parser =
+                        shpool_vt100::Parser::new(self.tty_size.rows, self.tty_size.cols, 0);
+                    parser.process(output);
+                    return parser.screen().contents_formatted();
+                }
+                None => {
+                    info!("no commands yet, falling back to screen restore");
+                    spool.screen().contents_formatted()
+                }
+            },
+            (_, _) => return vec![],
+        };
+        // Rather than make the client wait for the spool to catch up,
+        // replay the output it has not seen yet on top of what it has.
+        restore_buf.extend_from_slice(&self.spool_backlog);
+        restore_buf
+    }
 
+    fn write_restore_buf(&mut self, restore_buf: &[u8]) {
+        if restore_buf.is_empty() {
+            return;
+        }
+        let Some(conn) = self.conn.as_mut() else {
+            return;
+        };
+        trace!("restore chunk='{}'", String::from_utf8_lossy(restore_buf));
+        // send the restore buffer, broken up into chunks so that we don't
+        // make the client allocate too much
+        for block in restore_buf.chunks(consts::BUF_SIZE) {
+            conn.queue(protocol::ChunkKind::Data, block);
+        }
+        self.flush_output();
+    }
 
-            Ok(())
-        }).context("outer thread scope")?;
+    /// The text that a pager needs to display the given content.
+    fn scrollback(&mut self, content: PagerContent) -> Option<String> {
+        match content {
+            PagerContent::LastCommand => self.last_command_output.output().map(|output| {
+                let mut parser = shpool_vt100::Parser::new(
+                    self.tty_size.rows,
+                    self.tty_size.cols,
+                    self.args.scrollback_lines,
+                );
+                parser.process(output);
+                let text = formatted_to_plain_text(
+                    &parser.screen().last_n_rows_contents_formatted(u16::MAX),
+                );
+                format!("{}\n", text.trim_end())
+            }),
+            PagerContent::Scrollback => {
+                self.catch_up_spool(None);
+                self.output_spool.as_ref().map(|s| {
+                    formatted_to_plain_text(&s.screen().last_n_rows_contents_formatted(u16::MAX))
+                })
+            }
+            PagerContent::Message => None,
+        }
+    }
 
-        let c_done = child_done.load(Ordering::Acquire);
-        if c_done {
-            client_stream
-                .shutdown(std::net::Shutdown::Both)
-                .context("shutting down client stream")?;
+    /// Queue up a chunk for the client and try to send it right away.
+    /// Returns false if there is no client to send it to, or if it hung
+    /// up.
+    fn write_client(&mut self, kind: protocol::ChunkKind, buf: &[u8]) -> bool {
+        match self.conn.as_mut() {
+            Some(conn) => conn.queue(kind, buf),
+            None => return false,
         }
+        self.flush_output()
+    }
 
-        info!("bidi_stream: done child_done={}", c_done);
-        Ok(c_done)
+    /// Write as much of the outbox to the client as it will take without
+    /// blocking. Returns false if the client has hung up.
+    fn flush_output(&mut self) -> bool {
+        let Some(conn) = self.conn.as_mut() else {
+            return false;
+        };
+        conn.frame_pending();
+        while !conn.outbox.is_empty() {
+            match socket::send(
+                conn.stream.as_raw_fd(),
+                &conn.outbox,
+                MsgFlags::MSG_DONTWAIT | MsgFlags::MSG_NOSIGNAL,
+            ) {
+                Ok(n) => {
+                    conn.outbox.drain(..n);
+                }
+                Err(Errno::EAGAIN) => break,
+                Err(Errno::EINTR) => {}
+                Err(e) => {
+                    info!("client_stream write err, assuming hangup: {:?}", e);
+                    self.disconnect();
+                    return false;
+                }
+            }
+        }
+        true
     }
 
-    #[instrument(skip_all)]
-    fn spawn_client_to_shell<'scope>(
-        &'scope self,
-        scope: &'scope thread::Scope<'scope, '_>,
-        conn_id: usize,
-        stop: &'scope AtomicBool,
-        pty_master: &'scope shpool_pty::fork::Master,
-        reader_client_stream: &'scope mut UnixStream,
-    ) -> anyhow::Result<thread::ScopedJoinHandle<anyhow::Result<()>>> {
-        let empty_bindings = vec![config::Keybinding {
-            binding: String::from("Ctrl-Space Ctrl-q"),
-            action: keybindings::Action::Detach,
-        }];
-        let bindings = keybindings::Bindings::new(
-            self.config
-                .keybinding
-                .as_ref()
-                .unwrap_or(&empty_bindings)
-                .iter()
-                .map(|binding| (binding.binding.as_str(), binding.action)),
-        );
+    /// Write as much of the client's input to the shell as it will take
+    /// without blocking.
+    fn flush_input(&mut self) {
+        let mut flushed = 0;
+        while flushed < self.input.len() {
+            match unistd::write(&self.pty, &self.input[flushed..]) {
+                Ok(n) => flushed += n,
+                Err(Errno::EAGAIN) => break,
+                Err(Errno::EINTR) => {}
+                Err(e) => {
+                    // There is no one to take the input anymore, so there
+                    // is nothing to do but drop it.
+                    info!("writing client input to shell, dropping it: {:?}", e);
+                    flushed = self.input.len();
+                }
+            }
+        }
+        self.input.drain(..flushed);
+        self.args.session_metrics.bytes_in.add(flushed as u64);
+        debug!("flushed input of len {}", flushed);
+    }
 
-        thread::Builder::new()
-            .name(format!("client->shell({})", self.name))
-            .spawn_scoped(scope, move || -> anyhow::Result<()> {
-                let _s =
-                    span!(Level::INFO, "client->shell", s = self.name, cid = conn_id).entered();
-                let mut bindings = bindings.context("compiling keybindings engine")?;
+    /// Read whatever the client has sent and handle all the chunks
+    /// that have come in whole.
+    fn read_client(&mut self) -> anyhow::Result<()> {
+        let Some(conn) = self.conn.as_mut() else {
+            return Ok(());
+        };
+        if conn.paused || self.handed_off {
+            return Ok(());
+        }
 
-                let mut master_writer = *pty_master;
+        let buf = &mut self.client_buf;
+        let len = match socket::recv(conn.stream.as_raw_fd(), &mut buf[..], MsgFlags::MSG_DONTWAIT)
+        {
+            Ok(0) => {
+                info!("client hung up");
+                self.disconnect();
+                return Ok(());
+            }
+            Ok(n) => n,
+            Err(Errno::EAGAIN) | Err(Errno::EINTR) => return Ok(()),
+            Err(e) => {
+                info!("client_stream read err, assuming hangup: {:?}", e);
+                self.disconnect();
+                return Ok(());
+            }
+        };
+        conn.input.feed(&buf[..len]);
+        self.handle_client_input()
+    }
 
-                let mut snip_sections = vec![]; // (<len>, <end offset>)
-                let mut keep_sections = vec![]; // (<start offset>, <end offset>)
-                let mut buf: Vec<u8> = vec![0; consts::BUF_SIZE];
-                let mut partial_keybinding = vec![];
+    /// Handle the chunks that the client has sent, up until we run out
+    /// or something else needs to take over the connection.
+    fn handle_client_input(&mut self) -> anyhow::Result<()> {
+        while let Some(conn) = self.conn.as_mut() {
+            if conn.paused || self.handed_off {
+                break;
+            }
+            let (kind, len) = match conn.input.next_chunk() {
+                Ok(Some(chunk)) => {
+                    let len = chunk.buf.len();
+                    self.client_buf[..len].copy_from_slice(chunk.buf);
+                    (chunk.kind, len)
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("bad chunk from client, hanging up: {:?}", e);
+                    self.disconnect();
+                    break;
+                }
+            };
 
-                loop {
-                    if stop.load(Ordering::Relaxed) {
-                        info!("recvd stop msg (1)");
-                        return Ok(());
+            use protocol::ChunkKind::*;
+            match kind {
+                Data => self.client_data(len),
+                Resize => match protocol::decode_size(&self.client_buf[..len]) {
+                    Ok(size) => {
+                        self.resize(size);
+                        self.resize_hook();
                     }
-
-                    // N.B. we don't need to muck about with chunking or anything
-                    // in this direction, because there is only one input stream
-                    // to the shell subprocess and we don't need to worry about
-                    // heartbeating to detect hangup or anything like that.
-                    //
-                    // Also, note that we don't access through the mutex because reads
-                    // don't need to be excluded from trampling on writes.
-                    let mut len =
-                        reader_client_stream.read(&mut buf).context("reading client chunk")?;
-                    if len == 0 {
-                        continue;
+                    Err(e) => warn!("bad resize from client: {:?}", e),
+                },
+                Detach => {
+                    info!("client asked to detach, detached={}", self.disconnect());
+                }
+                Ping => {
+                    let data = Vec::from(&self.client_buf[..len]);
+                    self.write_client(Pong, &data);
+                }
+                Clipboard => {
+                    debug!("pasting {} bytes from client", len);
+                    // whether the shell wants bracketed paste depends on
+                    // everything it has written so far
+                    self.catch_up_spool(None);
+                    let bracketed =
+                        self.output_spool.as_ref().is_some_and(|s| s.screen().bracketed_paste());
+                    if bracketed {
+                        self.input.extend_from_slice(b"\x1b[200~");
                     }
-                    test_hooks::emit("daemon-read-c2s-chunk");
-                    trace!("read client len={}: '{}'", len, String::from_utf8_lossy(&buf[..len]),);
-
-                    // We might be able to gain some perf by doing this scanning in
-                    // a background thread (though maybe not given the need to copy
-                    // the data), but just doing it inline doesn't seem have have
-                    // a major perf impact, and this way is simpler.
-                    snip_sections.clear();
-                    for (i, byte) in buf[0..len].iter().enumerate() {
-                        use keybindings::BindingResult::*;
-                        match bindings.transition(*byte) {
-                            NoMatch
-                                if !partial_keybinding.is_empty()
-                                    && i < partial_keybinding.len() =>
-                            {
-                                // it turned out the partial keybinding match was not
-                                // a real match, so flush it to the output stream
-                                debug!(
-                                    "flushing partial keybinding_len={} i={}",
-                                    partial_keybinding.len(),
-                                    i
-                                );
-                                master_writer
-                                    .write_all(&partial_keybinding)
-                                    .context("writing partial keybinding")?;
-                                if i > 0 {
-                                    // snip the leading part of the input chunk that
-                                    // was part of this keybinding
-                                    snip_sections.push((i, i - 1));
-                                }
-                                partial_keybinding.clear()
-                            }
-                            NoMatch => {
-                                partial_keybinding.clear();
-                            }
-                            Partial => {
-                                partial_keybinding.push(*byte);
-                            }
-                            Match(action) => {
-                                info!("{:?} keybinding action fired", action);
-                                let keybinding_len = partial_keybinding.len() + 1;
-                                if keybinding_len < i {
-                                    // this keybinding is wholly contained in buf
-                                    debug!("snipping keybinding_len={} i={}", keybinding_len, i);
-                                    snip_sections.push((keybinding_len, i));
-                                } else {
-                                    // this keybinding was split across multiple
-                                    // input buffers, just snip the last bit
-                                    debug!("snipping split keybinding i={}", i);
-                                    snip_sections.push((i + 1, i));
-                                }
-                                partial_keybinding.clear();
+                    self.input.extend_from_slice(&self.client_buf[..len]);
+                    if bracketed {
+                        self.input.extend_from_slice(b"\x1b[201~");
+                    }
+                }
+                Focus => {
+                    let focused = self.client_buf[..len].first().is_some_and(|b| *b != 0);
+                    debug!("client focused={} focus_reporting={}", focused, self.focus_reporting);
+                    if self.focus_reporting {
+                        self.input.extend_from_slice(if focused { b"\x1b[I" } else { b"\x1b[O" });
+                    }
+                }
+                Heartbeat | ExitStatus | Pong | CompressedData => {
+                    warn!("unexpected chunk kind from client: {:?}", kind);
+                }
+            }
+        }
+        self.flush_input();
+        Ok(())
+    }
 
-                                use keybindings::Action::*;
-                                match action {
-                                    Detach => self.action_detach()?,
-                                    NoOp => {}
+    /// Feed a chunk of user input from client_buf to the shell, minus
+    /// any keybindings.
+    fn client_data(&mut self, mut len: usize) {
+        let Some(conn) = self.conn.as_mut() else {
+            return;
+        };
+        let buf = &mut self.client_buf;
+        test_hooks::emit("daemon-read-c2s-chunk");
+        trace!("read client len={}: '{}'", len, String::from_utf8_lossy(&buf[..len]));
+
+        // Doing this scanning inline doesn't seem to have a major perf
+        // impact, and it keeps things simple.
+        self.snip_sections.clear();
+        let mut detach = false;
+        for (i, byte) in buf[0..len].iter().enumerate() {
+            use keybindings::BindingResult::*;
+            match conn.bindings.transition(*byte) {
+                NoMatch
+                    if !conn.partial_keybinding.is_empty() && i < conn.partial_keybinding.len() =>
+                {
+                    // it turned out the partial keybinding match was not
+                    // a real match, so flush it to the shell
+                    debug!(
+                        "flushing partial keybinding_len={} i={}",
+                        conn.partial_keybinding.len(),
+                        i
+                    );
+                    self.input.extend_from_slice(&conn.partial_keybinding);
+                    if i > 0 {
+                        // snip the leading part of the input chunk that
+                        // was part of this keybinding
+                        self.snip_sections.push((i, i - 1));
+                    }
+                    conn.partial_keybinding.clear()
+                }
+                NoMatch => {
+                    conn.partial_keybinding.clear();
+                }
+                Partial => {
+                    conn.partial_keybinding.push(*byte);
+                }
+                Match(action) => {
+                    info!("{:?} keybinding action fired", action);
+                    let mut ctx = hooks::HookContext::new(&self.name);
+                    ctx.child_pid = self.child_pid;
+                    let action_name = action.name();
+                    self.args
+                        .hook_worker
+                        .call("on_keybinding", move |h| h.on_keybinding(&ctx, action_name));
+                    let keybinding_len = conn.partial_keybinding.len() + 1;
+                    if keybinding_len < i {
+                        // this keybinding is wholly contained in buf
+                        debug!("snipping keybinding_len={} i={}", keybinding_len, i);
+                        self.snip_sections.push((keybinding_len, i));
+                    } else {
+                        // this keybinding was split across multiple
+                        // input buffers, just snip the last bit
+                        debug!("snipping split keybinding i={}", i);
+                        self.snip_sections.push((i + 1, i));
+                    }
+                    conn.partial_keybinding.clear();
+
+                    use keybindings::Action::*;
+                    match action {
+                        Detach => detach = true,
+                        // The pager gets a thread of its own, since it
+                        // blocks for as long as the user is looking
+                        // at it. Leave the input for the pager.
+                        Scrollback => {
+                            conn.paused = true;
+                            let pager = self.args.pager.clone();
+                            thread::spawn(move || {
+                                let req = protocol::ScrollbackRequest::default();
+                                if let Err(e) = pager.scrollback(req) {
+                                    warn!("showing scrollback: {:?}", e);
                                 }
-                            }
+                            });
                         }
+                        NoOp => {}
                     }
-                    if !partial_keybinding.is_empty() {
-                        // we have a partial keybinding pending, so don't write
-                        // it to the output stream immediately
-                        let snip_chunk_len = if partial_keybinding.len() > len {
-                            len
-                        } else {
-                            partial_keybinding.len()
-                        };
-                        debug!(
-                            "end of buf w/ partial keybinding_len={} snip_chunk_len={} buf_len={}",
-                            partial_keybinding.len(),
-                            snip_chunk_len,
-                            len
-                        );
-                        snip_sections.push((snip_chunk_len, len - 1));
-                    }
-                    len = snip_buf(&mut buf[..], len, &snip_sections[..], &mut keep_sections);
+                }
+            }
+        }
+        if !conn.partial_keybinding.is_empty() {
+            // we have a partial keybinding pending, so don't write
+            // it to the shell immediately
+            let snip_chunk_len = conn.partial_keybinding.len().min(len);
+            debug!(
+                "end of buf w/ partial keybinding_len={} snip_chunk_len={} buf_len={}",
+                conn.partial_keybinding.len(),
+                snip_chunk_len,
+                len
+            );
+            self.snip_sections.push((snip_chunk_len, len - 1));
+        }
+        len = snip_buf(&mut buf[..], len, &self.snip_sections[..], &mut self.keep_sections);
+        self.input.extend_from_slice(&buf[..len]);
 
-                    master_writer.write_all(&buf[0..len]).context("writing client chunk")?;
+        if detach {
+            info!("action detach, detached={}", self.disconnect());
+        }
+    }
 
-                    master_writer.flush().context("flushing input from client to shell")?;
+    /// Resize the pty and the output spool to the client's new size.
+    fn resize(&mut self, size: tty::Size) {
+        info!("resize size={:?}", size);
+        if let Some(s) = self.output_spool.as_mut() {
+            s.screen_mut().set_size(size.rows, u16::MAX);
+        }
+        self.tty_size = size.clone();
+        self.resize_cmd = Some(ResizeCmd {
+            size,
+            // No delay needed for ordinary resizes, just
+            // for reconnects.
+            when: time::Instant::now(),
+        });
+    }
 
-                    debug!("flushed chunk of len {}", len);
-                }
-            })
-            .map_err(|e| anyhow!("{:?}", e))
+    /// Let the hooks know that the attached client changed size.
+    fn resize_hook(&self) {
+        let Some(conn) = self.conn.as_ref() else {
+            return;
+        };
+        let mut ctx = conn.hook_ctx.clone();
+        ctx.tty_size = Some(hooks::TtySize { rows: self.tty_size.rows, cols: self.tty_size.cols });
+        ctx.timestamp = time::SystemTime::now();
+        self.args.hook_worker.call("on_resize", move |h| h.on_resize(&ctx));
     }
 
-    #[instrument(skip_all)]
-    fn spawn_heartbeat<'scope>(
-        &'scope self,
-        scope: &'scope thread::Scope<'scope, '_>,
-        conn_id: usize,
-        stop: &'scope AtomicBool,
-        client_stream_m: &'scope Arc<Mutex<io::BufWriter<UnixStream>>>,
-    ) -> anyhow::Result<thread::ScopedJoinHandle<anyhow::Result<()>>> {
-        thread::Builder::new()
-            .name(format!("heartbeat({})", self.name))
-            .spawn_scoped(scope, move || -> anyhow::Result<()> {
-                let _s1 = span!(Level::INFO, "heartbeat", s = self.name, cid = conn_id).entered();
-
-                loop {
-                    trace!("checking stop_rx");
-                    if stop.load(Ordering::Relaxed) {
-                        info!("recvd stop msg");
-                        return Ok(());
-                    }
+    /// Read a chunk from the shell. Returns how much was read, which is
+    /// zero if there was nothing to read.
+    fn read_pty(&mut self) -> anyhow::Result<usize> {
+        let len = match unistd::read(self.pty.as_raw_fd(), &mut self.buf) {
+            // EIO means that the shell side of the pty has been closed
+            Ok(0) | Err(Errno::EIO) => {
+                info!("pty closed");
+                self.pty_open = false;
+                return Ok(0);
+            }
+            Ok(n) => n,
+            Err(Errno::EAGAIN) | Err(Errno::EINTR) => return Ok(0),
+            Err(e) => {
+                test_hooks::emit("daemon-reader-read-error");
+                error!("reading chunk from pty master: {:?}", e);
+                return Err(e).context("reading pty master chunk");
+            }
+        };
+        let buf = &self.buf[..len];
+        trace!("read pty master len={} '{}'", len, String::from_utf8_lossy(buf));
+        self.args.session_metrics.bytes_out.add(len as u64);
 
-                    thread::sleep(consts::HEARTBEAT_DURATION);
-                    let chunk = protocol::Chunk { kind: protocol::ChunkKind::Heartbeat, buf: &[] };
-                    {
-                        let mut s = client_stream_m.lock().unwrap();
-                        match chunk.write_to(&mut *s).and_then(|_| s.flush()) {
-                            Ok(_) => {
-                                trace!("wrote heartbeat");
-                            }
-                            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
-                                trace!("client hangup: {:?}", e);
-                                return Ok(());
-                            }
-                            Err(e) => {
-                                return Err(e).context("writing heartbeat")?;
+        if self.output_spool.is_some() {
+            self.spool_backlog.extend_from_slice(buf);
+        }
+
+        // scan for control codes we need to handle
+        let mut snip_buf_to = 0;
+        // The offset up to which we have recorded output as part
+        // of the last command's output.
+        let mut captured_to = 0;
+        for (i, byte) in buf.iter().enumerate() {
+            match self.control_code_matcher.transition(*byte) {
+                Some(Code::ClearScreen)
+                    if self.needs_initial_motd_dump || self.rcfile_fallback.is_some() =>
+                {
+                    // The prompt prefix rc file ends with a clear, so once we
+                    // see one there is no need to type in the fallback.
+                    if self.rcfile_fallback.take().is_some() {
+                        debug!("prompt prefix rc file ran");
+                    }
+                    if self.needs_initial_motd_dump {
+                        debug!("detected initial ClearScreen code");
+                        if let Some(conn) = self.conn.as_mut() {
+                            // write the clear code ahead of time so we don't
+                            // immediately clobber ourselves
+                            snip_buf_to = i + 1;
+                            conn.queue(protocol::ChunkKind::Data, &buf[..snip_buf_to]);
+                            conn.frame_pending();
+                            if let Err(e) =
+                                self.daily_messenger.dump(&mut conn.outbox, &self.term_db)
+                            {
+                                warn!("Error handling clear: {:?}", e);
                             }
                         }
+                        self.needs_initial_motd_dump = false;
                     }
                 }
-            })
-            .map_err(|e| anyhow!("{:?}", e))
-    }
-
-    #[instrument(skip_all)]
-    fn spawn_supervisor<'scope>(
-        &'scope self,
-        scope: &'scope thread::Scope<'scope, '_>,
-        conn_id: usize,
-        stop: &'scope AtomicBool,
-        child_done: &'scope AtomicBool,
-        pty_master: &'scope shpool_pty::fork::Master,
-        child_exit_notifier: Arc<ExitNotifier>,
-    ) -> anyhow::Result<thread::ScopedJoinHandle<anyhow::Result<()>>> {
-        thread::Builder::new()
-            .name(format!("supervisor({})", self.name))
-            .spawn_scoped(scope, move || -> anyhow::Result<()> {
-                let _s1 = span!(Level::INFO, "supervisor", s = self.name, cid = conn_id).entered();
-
-                loop {
-                    trace!("checking stop_rx (pty_master={:?})", pty_master.raw_fd());
-                    if stop.load(Ordering::Relaxed) {
-                        info!("recvd stop msg");
-                        return Ok(());
-                    }
-
-                    match child_exit_notifier.wait(Some(SUPERVISOR_POLL_DUR)) {
-                        Some(exit_status) => {
-                            info!("child shell exited with status {}", exit_status);
-                            // mark child as exited so the attach routine will
-                            // cleanup correctly.
-                            child_done.store(true, Ordering::Release);
-
-                            // we don't need to worry about the ExitStatus frame
-                            // because the reader thread cleanup should handle
-                            // that.
-                            return Ok(());
+                Some(Code::FocusReporting(on)) => {
+                    debug!("focus reporting={}", on);
+                    self.focus_reporting = on;
+                }
+                Some(Code::SemanticPrompt(mark)) => {
+                    self.last_command_output.push(&buf[captured_to..i + 1]);
+                    captured_to = i + 1;
+                    match mark {
+                        SemanticPromptMark::CommandExecuted { cmdline } => {
+                            debug!("command executed (cmdline={:?})", cmdline);
+                            self.args.command_log.lock().unwrap().executed(cmdline);
+                            self.last_command_output.start();
                         }
-                        None => {
-                            // shell is still running, do nothing
-                            trace!("poll timeout");
+                        SemanticPromptMark::CommandFinished { exit_status } => {
+                            self.args.command_log.lock().unwrap().finished(exit_status);
+                            self.last_command_output.finish();
                         }
+                        _ => {}
                     }
                 }
-            })
-            .map_err(|e| anyhow!("{:?}", e))
+                _ => {}
+            }
+        }
+        self.last_command_output.push(&buf[captured_to..]);
+        self.args.triggers.scan(buf);
+        self.args.activity.output(buf, self.conn.is_some());
+
+        let skips_output = self.skips_output();
+        if let (false, Some(conn)) = (self.handed_off, self.conn.as_mut()) {
+            let output = &buf[snip_buf_to..];
+            if skips_output && (conn.skipping || conn.outbox.len() >= MAX_BACKLOG) {
+                if !conn.skipping {
+                    info!("client fell behind, skipping output until it catches up");
+                    test_hooks::emit("daemon-output-skipped");
+                    conn.skipping = true;
+                }
+                self.args.session_metrics.bytes_skipped.add(output.len() as u64);
+            } else {
+                conn.queue(protocol::ChunkKind::Data, output);
+            }
+        }
+        Ok(len)
     }
 
-    //
-    // actions which can be bound to keybindings
-    //
+    /// Feed the spool the output that it has not seen yet, stopping
+    /// early once the budget runs out if there is one.
+    fn catch_up_spool(&mut self, budget: Option<time::Duration>) {
+        let Some(spool) = self.output_spool.as_mut() else {
+            return;
+        };
+        if self.spool_backlog.is_empty() {
+            return;
+        }
+        let start = time::Instant::now();
+        let mut fed = 0;
+        while fed < self.spool_backlog.len() && budget.map_or(true, |b| start.elapsed() < b) {
+            let block = &self.spool_backlog[fed..self.spool_backlog.len().min(fed + SPOOL_BLOCK)];
+            spool.process(block);
+            fed += block.len();
+
+            // vt100 does not tell us how much memory it is using, so
+            // count the newlines to guess how full the scrollback is.
+            let newlines = block.iter().filter(|b| **b == b'\n').count();
+            self.spool_scrollback_lines =
+                (self.spool_scrollback_lines + newlines).min(self.args.scrollback_lines);
+        }
+        self.spool_backlog.drain(..fed);
+        trace!("fed the spool {} bytes, {} to go", fed, self.spool_backlog.len());
+
+        let (rows, cols) = spool.screen().size();
+        let cells = (rows as usize + self.spool_scrollback_lines) * cols as usize;
+        self.args
+            .session_metrics
+            .spool_bytes
+            .set((cells * std::mem::size_of::<shpool_vt100::Cell>()) as u64);
+    }
 
-    #[instrument(skip_all)]
-    fn action_detach(&self) -> anyhow::Result<()> {
-        let reader_ctl = self.reader_ctl.lock().unwrap();
-        reader_ctl
-            .client_connection
-            .send(ClientConnectionMsg::Disconnect)
-            .context("signaling client detach to reader thread")?;
-        let status =
-            reader_ctl.client_connection_ack.recv().context("waiting for client connection ack")?;
+    /// True if so much output has piled up waiting on the client or the
+    /// spool that we should stop reading the shell's output for now.
+    fn backlogged(&self) -> bool {
+        let client_behind = !self.skips_output()
+            && self.conn.as_ref().map(|c| c.outbox.len() >= MAX_BACKLOG).unwrap_or(false);
+        client_behind || self.spool_backlog.len() >= MAX_BACKLOG
+    }
+
+    /// True if we can drop output that the client can't keep up with,
+    /// since the spool will have what it needs to redraw the screen.
+    fn skips_output(&self) -> bool {
+        matches!(self.flow_control, config::OutputFlowControl::Skip) && self.output_spool.is_some()
+    }
+
+    /// Once a client that fell behind has taken everything that was
+    /// already queued for it, send it a fresh render of the screen in
+    /// place of the output it missed.
+    fn resync(&mut self) {
+        let caught_up = self.conn.as_ref().map(|c| c.skipping && c.outbox.is_empty());
+        if self.handed_off || caught_up != Some(true) {
+            return;
+        }
+        self.catch_up_spool(None);
+        let (Some(conn), Some(spool)) = (self.conn.as_mut(), self.output_spool.as_ref()) else {
+            return;
+        };
+        conn.skipping = false;
+        info!("client caught up, redrawing the screen");
+
+        let screen = spool.screen();
+        // The output we stopped forwarding may have cut an escape
+        // sequence short, so cancel it (CAN) before drawing anything,
+        // and make sure the client is on the same screen as the shell.
+        let mut render = vec![0x18];
+        render.extend_from_slice(if screen.alternate_screen() {
+            b"\x1b[?1049h"
+        } else {
+            b"\x1b[?1049l"
+        });
+        render.extend(screen.state_formatted());
+        for block in render.chunks(consts::BUF_SIZE) {
+            conn.queue(protocol::ChunkKind::Data, block);
+        }
+        self.flush_output();
+    }
 
-        info!("action detach, status={:?}", status);
+    /// Read whatever the shell has written, up to a chunk's worth or
+    /// however much shows up in a short window, and send it on to the
+    /// client. Output for a client that asked for compression goes out
+    /// in one go at the end, everything else as soon as it is read.
+    fn pump_pty(&mut self) -> anyhow::Result<()> {
+        let start = time::Instant::now();
+        let compressing = self.conn.as_ref().map(|c| c.compressor.is_some()).unwrap_or(false);
+        let mut read = 0;
+        while self.pty_open && read < MAX_COALESCE && start.elapsed() < COALESCE_WINDOW {
+            if self.backlogged() {
+                break;
+            }
+            let len = self.read_pty()?;
+            if len == 0 {
+                break;
+            }
+            read += len;
+            if !compressing && !self.handed_off {
+                self.flush_output();
+            }
+        }
+        if read > 0 && !self.handed_off && self.flush_output() {
+            test_hooks::emit("daemon-wrote-s2c-chunk");
+        }
+        Ok(())
+    }
+
+    /// Make sure that the client knows we are still here once the
+    /// output has gone quiet, and find out if it is not.
+    fn heartbeat(&mut self) {
+        let Some(conn) = self.conn.as_ref() else {
+            return;
+        };
+        // If there is still output waiting to go out, we already know
+        // the client is there, it is just slow.
+        if self.handed_off
+            || !conn.outbox.is_empty()
+            || conn.last_write.elapsed() < consts::HEARTBEAT_DURATION
+        {
+            return;
+        }
+        if self.write_client(protocol::ChunkKind::Heartbeat, &[]) {
+            trace!("wrote heartbeat");
+        } else {
+            info!("client hangup writing heartbeat");
+            self.metrics.heartbeat_failures.inc();
+        }
+    }
+
+    /// Wrap up once the shell has exited by forwarding whatever it
+    /// wrote on the way out followed by its exit status to the client,
+    /// if there is one, so that the attach process can exit with the
+    /// same exit code as the shell.
+    fn shell_exited(&mut self, exit_status: i32) -> anyhow::Result<()> {
+        info!("child shell exited with status {}", exit_status);
+        // Bound the drain so that a background process that is still
+        // writing to the pty can't keep us from wrapping up.
+        for _ in 0..EXIT_DRAIN_READS {
+            if !self.pty_open || self.read_pty()? == 0 {
+                break;
+            }
+        }
+
+        self.exited = Some(time::Instant::now() + EXIT_STATUS_TIMEOUT);
+        if self.conn.is_some() {
+            info!("disconnectexit({}), shutting down client stream", exit_status);
+            let status_buf: [u8; 4] = exit_status.to_le_bytes();
+            self.write_client(protocol::ChunkKind::ExitStatus, &status_buf);
+        } else {
+            info!("disconnectexit({}), no client stream to shut down", exit_status);
+        }
         Ok(())
     }
 }
 
-/// A handle for poking at the always-running reader thread.
+impl Conn {
+    fn new(conn: ClientConnection) -> Self {
+        Conn {
+            stream: conn.stream,
+            input: conn.input,
+            compressor: conn.compressor,
+            pending: vec![],
+            hook_ctx: conn.hook_ctx,
+            bindings: conn.bindings,
+            _done: conn.done,
+            outbox: vec![],
+            last_write: time::Instant::now(),
+            partial_keybinding: vec![],
+            paused: false,
+            skipping: false,
+        }
+    }
+
+    /// Queue up a chunk for the client. Output gets held back in
+    /// pending to be coalesced with whatever comes after it until
+    /// flush_output calls frame_pending.
+    fn queue(&mut self, kind: protocol::ChunkKind, buf: &[u8]) {
+        self.last_write = time::Instant::now();
+        if let protocol::ChunkKind::Data = kind {
+            self.pending.extend_from_slice(buf);
+            if self.pending.len() >= MAX_COALESCE {
+                self.frame_pending();
+            }
+            return;
+        }
+
+        self.frame_pending();
+        let chunk = protocol::Chunk { kind, buf };
+        chunk.write_to(&mut self.outbox).expect("writes to a vec to succeed");
+    }
+
+    /// Move any pending output to the outbox as a chunk, compressing
+    /// it if the client asked for that.
+    fn frame_pending(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let mut pending = std::mem::take(&mut self.pending);
+        for block in pending.chunks(MAX_COALESCE) {
+            let mut compressed = vec![];
+            let res = match self.compressor.as_mut() {
+                Some(c) => c.compress(block, &mut compressed),
+                None => Ok(()),
+            };
+            let chunk = match res {
+                Ok(()) if self.compressor.is_some() => {
+                    protocol::Chunk { kind: protocol::ChunkKind::CompressedData, buf: &compressed }
+                }
+                Ok(()) => protocol::Chunk { kind: protocol::ChunkKind::Data, buf: block },
+                Err(e) => {
+                    // The client can still read plain output, so limp
+                    // along without compression.
+                    warn!("compressing output, falling back to plain data: {:?}", e);
+                    self.compressor = None;
+                    protocol::Chunk { kind: protocol::ChunkKind::Data, buf: block }
+                }
+            };
+            chunk.write_to(&mut self.outbox).expect("writes to a vec to succeed");
+        }
+        pending.clear();
+        self.pending = pending;
+    }
+}
+
+/// A handle for poking at the always-running reader.
 /// Shared between the session struct (for calls originating with the cli)
-/// and the session inner struct (for calls resulting from keybindings).
+/// and the session inner struct (for calls from the attach thread).
 #[derive(Debug)]
 pub struct ReaderCtl {
-    /// A control channel for the reader thread. Whenever a new client dials in,
+    /// A control channel for the reader. Whenever a new client dials in,
     /// the output stream for that client must be attached to the reader
-    /// thread by sending it down this channel. A disconnect is signaled by
-    /// sending None down this channel. Dropping the channel entirely causes
-    /// the reader thread to exit.
-    pub client_connection: crossbeam_channel::Sender<ClientConnectionMsg>,
-    /// A control channel for the reader thread. Acks the addition of a fresh
+    /// by sending it down this channel. Dropping the channel entirely causes
+    /// the reader to exit.
+    pub client_connection: event_loop::Sender<ClientConnectionMsg>,
+    /// A control channel for the reader. Acks the addition of a fresh
     /// client connection.
     pub client_connection_ack: crossbeam_channel::Receiver<ClientConnectionStatus>,
 
-    /// A control channel for the reader thread. Used to signal size changes so
+    /// A control channel for the reader. Used to signal size changes so
     /// that the output spool will correctly reflect the size of the user's
     /// tty.
-    pub tty_size_change: crossbeam_channel::Sender<tty::Size>,
-    /// A control channel for the reader thread. Acks the completion of a spool
+    pub tty_size_change: event_loop::Sender<tty::Size>,
+    /// A control channel for the reader. Acks the completion of a spool
     /// resize.
     pub tty_size_change_ack: crossbeam_channel::Receiver<()>,
+
+    /// A control channel for the reader. Used to hand the client
+    /// connection over to a pager and to take it back once the pager
+    /// exits.
+    pub pager_handoff: event_loop::Sender<PagerHandoffMsg>,
+    /// A control channel for the reader. Acks pager handoffs.
+    pub pager_handoff_ack: crossbeam_channel::Receiver<PagerHandoffAck>,
+
+    /// A control channel for the reader. Used to draw a broadcast
+    /// banner on the attached client.
+    pub banner: event_loop::Sender<String>,
+    /// A control channel for the reader. Acks banners, indicating
+    /// whether there was a client to draw the banner on.
+    pub banner_ack: crossbeam_channel::Receiver<bool>,
+}
+
+/// The escape codes to draw a banner with the given message across
+/// the top line of a terminal with the given width, leaving the cursor
+/// and text attributes as they were.
+fn banner_codes(msg: &str, cols: u16) -> Vec<u8> {
+    // Control chars could move the cursor or start an escape code, which
+    // would leave the terminal in a state the shell doesn't know about.
+    let text: String = format!(" shpool: {} ", msg)
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .take(cols as usize)
+        .collect();
+    let pad = (cols as usize).saturating_sub(text.chars().count());
+    format!("\x1b7\x1b[1;1H\x1b[0;7m{}{}\x1b8", text, " ".repeat(pad)).into_bytes()
+}
+
+/// Render the output of one of the vt100 `*_contents_formatted` routines
+/// as plain text. We only need to understand the cursor motion that the
+/// formatter emits, all other escape codes are just dropped. Trailing
+/// whitespace is trimmed from each line.
+fn formatted_to_plain_text(formatted: &[u8]) -> String {
+    let formatted = String::from_utf8_lossy(formatted);
+    let mut lines: Vec<Vec<char>> = vec![];
+    let (mut row, mut col) = (0, 0);
+
+    let mut chars = formatted.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => match chars.next() {
+                Some('[') => {
+                    let mut params = String::new();
+                    let mut final_byte = None;
+                    for c in chars.by_ref() {
+                        if ('\x40'..='\x7e').contains(&c) {
+                            final_byte = Some(c);
+                            break;
+                        }
+                        params.push(c);
+                    }
+                    let mut params = params.split(';').map(|p| p.parse::<usize>().unwrap_or(0));
+                    match final_byte {
+                        // MoveRight
+                        Some('C') => col += params.next().unwrap_or(1).max(1),
+                        // MoveTo, 1 indexed
+                        Some('H') => {
+                            row = params.next().unwrap_or(1).max(1) - 1;
+                            col = params.next().unwrap_or(1).max(1) - 1;
+                        }
+                        _ => {}
+                    }
+                }
+                Some(']') => {
+                    // OSC, terminated by either BEL or ST
+                    while let Some(c) = chars.next() {
+                        if c == '\x07' {
+                            break;
+                        } else if c == '\x1b' {
+                            chars.next();
+                            break;
+                        }
+                    }
+                }
+                _ => {}
+            },
+            '\r' => col = 0,
+            '\n' => row += 1,
+            '\x08' => col = col.saturating_sub(1),
+            c if c.is_control() => {}
+            c => {
+                if lines.len() <= row {
+                    lines.resize(row + 1, vec![]);
+                }
+                let line = &mut lines[row];
+                if line.len() <= col {
+                    line.resize(col + 1, ' ');
+                }
+                line[col] = c;
+                col += 1;
+            }
+        }
+    }
+
+    let mut text = String::new();
+    for line in lines.iter() {
+        let line: String = line.iter().collect();
+        text.push_str(line.trim_end());
+        text.push('\n');
+    }
+    text
 }
 
 /// Given a buffer, a length after which the data is not valid, a list of
@@ -1038,4 +2012,42 @@ mod test {
             assert_eq!(&buf[..got_len], &want_buf[..]);
         }
     }
+
+    #[test]
+    fn test_formatted_to_plain_text() {
+        let cases = vec![
+            ("", ""),
+            ("\x1b[m\x1b[H\x1b[Jfoo\r\nbar", "foo\nbar\n"),
+            ("\x1b[m\x1b[H\x1b[J\x1b[31mred\x1b[m\x1b[3Cx", "red   x\n"),
+            ("\x1b[H\x1b[Ja\x1b[3;3Hb", "a\n\n  b\n"),
+            ("\x1b[?25l\x1b]0;title\x07ab\x08c   ", "ac\n"),
+        ];
+
+        for (formatted, want) in cases.into_iter() {
+            assert_eq!(formatted_to_plain_text(formatted.as_bytes()), want);
+        }
+    }
+
+    #[test]
+    fn test_spool_to_plain_text() {
+        let mut spool = shpool_vt100::Parser::new(2, 20, 100);
+        spool.process(b"one\r\n  two\r\nthree\r\n\x1b[32mfour\x1b[m");
+        let text =
+            formatted_to_plain_text(&spool.screen().last_n_rows_contents_formatted(u16::MAX));
+        assert_eq!(text, "one\n  two\nthree\nfour\n");
+    }
+
+    #[test]
+    fn test_banner_codes() {
+        let cases = vec![
+            ("hi", 12, "\x1b7\x1b[1;1H\x1b[0;7m shpool: hi \x1b8"),
+            ("hi", 14, "\x1b7\x1b[1;1H\x1b[0;7m shpool: hi   \x1b8"),
+            ("hello world", 10, "\x1b7\x1b[1;1H\x1b[0;7m shpool: h\x1b8"),
+            ("a\nb\x1b[2J", 20, "\x1b7\x1b[1;1H\x1b[0;7m shpool: a b [2J    \x1b8"),
+        ];
+
+        for (msg, cols, want) in cases.into_iter() {
+            assert_eq!(String::from_utf8(banner_codes(msg, cols)).unwrap(), want);
+        }
+    }
 }
@@ -68,6 +68,8 @@ impl DailyMessenger {
         &self,
         // The client connection on which to display the pager.
         client_stream: &mut UnixStream,
+        // Whatever the client has sent which has not been handled yet.
+        input: &mut protocol::ChunkDecoder,
         // The session to associate this pager with for SIGWINCH purposes.
         ctl_slot: Arc<Mutex<Option<PagerCtl>>>,
         // The size of the tty to start off with
@@ -85,7 +87,7 @@ impl DailyMessenger {
 
         let pager = Pager::new(pager_bin.to_string());
 
-        pager.display(client_stream, ctl_slot, init_tty_size, motd_value.as_str())
+        pager.display(client_stream, input, ctl_slot, init_tty_size, motd_value.as_str())
     }
 
     fn motd_value(&self) -> anyhow::Result<String> {
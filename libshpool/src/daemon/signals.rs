@@ -20,14 +20,22 @@ use std::{
 
 use anyhow::Context;
 use signal_hook::{consts::TERM_SIGNALS, flag, iterator::Signals};
-use tracing::{error, info};
+use tracing::{error, info, warn};
+
+use crate::{daemon::systemd, hooks};
 
 pub struct Handler {
-    sock: Option<PathBuf>,
+    socks: Vec<PathBuf>,
+    hooks: Arc<dyn hooks::Hooks + Send + Sync>,
+    notifier: Option<Arc<systemd::Notifier>>,
 }
 impl Handler {
-    pub fn new(sock: Option<PathBuf>) -> Self {
-        Handler { sock }
+    pub fn new(
+        socks: Vec<PathBuf>,
+        hooks: Arc<dyn hooks::Hooks + Send + Sync>,
+        notifier: Option<Arc<systemd::Notifier>>,
+    ) -> Self {
+        Handler { socks, hooks, notifier }
     }
 
     pub fn spawn(self) -> anyhow::Result<()> {
@@ -57,13 +65,21 @@ impl Handler {
             for signal in &mut signals {
                 assert!(TERM_SIGNALS.contains(&signal));
 
-                info!("term sig handler: cleaning up socket");
-                if let Some(sock) = self.sock {
+                if let Some(notifier) = &self.notifier {
+                    notifier.notify("STOPPING=1");
+                }
+
+                info!("term sig handler: cleaning up sockets");
+                for sock in self.socks.iter() {
                     if let Err(e) = std::fs::remove_file(sock).context("cleaning up socket") {
                         error!("error cleaning up socket file: {}", e);
                     }
                 }
 
+                if let Err(err) = self.hooks.on_daemon_stop() {
+                    warn!("daemon_stop hook: {:?}", err);
+                }
+
                 info!("term sig handler: exiting");
                 std::process::exit(0);
             }
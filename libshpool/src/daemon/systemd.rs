@@ -14,33 +14,229 @@
 
 use std::{
     env,
-    os::unix::{io::FromRawFd, net::UnixListener},
+    ffi::OsString,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            io::FromRawFd,
+            net::{SocketAddr, UnixDatagram, UnixListener},
+        },
+    },
+    process,
+    sync::Arc,
+    thread, time,
 };
 
 use anyhow::{anyhow, Context};
 use nix::sys::stat;
+use tracing::{info, warn};
 
 // the fd that systemd uses for the first activation socket
 // (0 through 2 are for the std streams)
 const FIRST_ACTIVATION_SOCKET_FD: i32 = 3;
+// How often to let systemd know how many sessions we have.
+const STATUS_INTERVAL: time::Duration = time::Duration::from_secs(5);
 
-/// activation_socket converts the systemd activation socket
-/// to a usable UnixStream.
-pub fn activation_socket() -> anyhow::Result<UnixListener> {
+/// A socket that systemd passed to us, along with the name it got
+/// with `FileDescriptorName=` in the socket unit.
+pub struct ActivationSocket {
+    pub name: String,
+    pub listener: UnixListener,
+}
+
+/// activation_sockets converts the systemd activation sockets
+/// to usable UnixListeners, in the order systemd passed them.
+pub fn activation_sockets() -> anyhow::Result<Vec<ActivationSocket>> {
     let num_activation_socks = env::var("LISTEN_FDS")
         .context("fetching LISTEN_FDS env var")?
-        .parse::<isize>()
+        .parse::<usize>()
         .context("parsing LISTEN_FDS as int")?;
-    if num_activation_socks != 1 {
-        return Err(anyhow!("expected exactly 1 activation fd, got {}", num_activation_socks));
+    if num_activation_socks == 0 {
+        return Err(anyhow!("expected at least 1 activation fd"));
+    }
+    // systemd names sockets after the socket unit unless told otherwise
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+
+    let mut socks = vec![];
+    for fd in FIRST_ACTIVATION_SOCKET_FD..FIRST_ACTIVATION_SOCKET_FD + num_activation_socks as i32 {
+        let sock_stat = stat::fstat(fd).context("stating activation sock")?;
+        if !stat::SFlag::from_bits_truncate(sock_stat.st_mode).contains(stat::SFlag::S_IFSOCK) {
+            return Err(anyhow!("expected to be passed a unix socket as fd {}", fd));
+        }
+
+        socks.push(ActivationSocket {
+            name: String::from(names.next().unwrap_or("")),
+            // Safety: we have just verified that this is a unix socket.
+            listener: unsafe { UnixListener::from_raw_fd(fd) },
+        });
     }
 
-    let fd = FIRST_ACTIVATION_SOCKET_FD;
-    let sock_stat = stat::fstat(fd).context("stating activation sock")?;
-    if !stat::SFlag::from_bits_truncate(sock_stat.st_mode).contains(stat::SFlag::S_IFSOCK) {
-        return Err(anyhow!("expected to be passed a unix socket"));
+    Ok(socks)
+}
+
+/// A handle for telling systemd about the state of the daemon
+/// over the socket in $NOTIFY_SOCKET, as described in sd_notify(3).
+/// This is what lets the unit use `Type=notify` and `WatchdogSec`.
+#[derive(Debug)]
+pub struct Notifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+    /// How often systemd expects to hear from us, if it has
+    /// the watchdog enabled.
+    watchdog: Option<time::Duration>,
+}
+
+impl Notifier {
+    /// Connect to the notification socket systemd gave us, if any.
+    /// The variables get scrubbed from the environment so that the
+    /// processes we spawn can't go telling systemd things on our behalf.
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let notifier = Self::from_vars(
+            env::var_os("NOTIFY_SOCKET"),
+            env::var("WATCHDOG_USEC").ok(),
+            env::var("WATCHDOG_PID").ok(),
+        );
+        for var in ["NOTIFY_SOCKET", "WATCHDOG_USEC", "WATCHDOG_PID"] {
+            env::remove_var(var);
+        }
+        notifier
     }
 
-    // Safety: we have just verified that this is a unix socket.
-    unsafe { Ok(UnixListener::from_raw_fd(fd)) }
+    fn from_vars(
+        notify_socket: Option<OsString>,
+        watchdog_usec: Option<String>,
+        watchdog_pid: Option<String>,
+    ) -> anyhow::Result<Option<Self>> {
+        let notify_socket = match notify_socket {
+            Some(s) if !s.is_empty() => s,
+            _ => return Ok(None),
+        };
+        let addr = match notify_socket.as_bytes().strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name),
+            None => SocketAddr::from_pathname(&notify_socket),
+        }
+        .context("parsing NOTIFY_SOCKET")?;
+
+        let watchdog = match (watchdog_usec, watchdog_pid) {
+            // the watchdog is meant for some other process
+            (_, Some(pid)) if pid.parse::<u32>().ok() != Some(process::id()) => None,
+            (Some(usec), _) => Some(time::Duration::from_micros(
+                usec.parse::<u64>().context("parsing WATCHDOG_USEC")?,
+            )),
+            (None, _) => None,
+        };
+
+        let socket = UnixDatagram::unbound().context("creating notify socket")?;
+        Ok(Some(Notifier { socket, addr, watchdog }))
+    }
+
+    /// Send a newline separated list of KEY=VALUE assignments.
+    pub fn notify(&self, state: &str) {
+        if let Err(err) = self.socket.send_to_addr(state.as_bytes(), &self.addr) {
+            warn!("notifying systemd of '{}': {:?}", state, err);
+        }
+    }
+}
+
+/// Keep systemd up to date on how the daemon is doing. `status`
+/// produces a human readable description of the daemon, and `alive`
+/// checks that the daemon is still serving requests. We only ping the
+/// watchdog while `alive` succeeds, so if the daemon gets wedged systemd
+/// will notice and restart it.
+pub fn supervise<S, A>(notifier: Arc<Notifier>, status: S, alive: A) -> anyhow::Result<()>
+where
+    S: Fn() -> String + Send + 'static,
+    A: Fn() -> anyhow::Result<()> + Send + 'static,
+{
+    // systemd recommends pinging at twice the rate it expects
+    let tick = notifier.watchdog.map(|w| (w / 2).min(STATUS_INTERVAL)).unwrap_or(STATUS_INTERVAL);
+    info!("supervising with tick={:?} watchdog={:?}", tick, notifier.watchdog);
+
+    thread::Builder::new().name(String::from("systemd")).spawn(move || {
+        let mut last_status = String::new();
+        loop {
+            let status = status();
+            if status != last_status {
+                notifier.notify(&format!("STATUS={}", status));
+                last_status = status;
+            }
+
+            if notifier.watchdog.is_some() {
+                match alive() {
+                    Ok(()) => notifier.notify("WATCHDOG=1"),
+                    Err(err) => warn!("liveness check failed, not pinging watchdog: {:?}", err),
+                }
+            }
+
+            thread::sleep(tick);
+        }
+    })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn vars() -> anyhow::Result<()> {
+        let pid = process::id().to_string();
+        assert!(Notifier::from_vars(None, None, None)?.is_none());
+        assert!(Notifier::from_vars(Some(OsString::new()), None, None)?.is_none());
+
+        let notifier = Notifier::from_vars(Some(OsString::from("/run/notify")), None, None)?
+            .expect("a notifier");
+        assert_eq!(notifier.addr.as_pathname(), Some(std::path::Path::new("/run/notify")));
+        assert_eq!(notifier.watchdog, None);
+
+        let notifier = Notifier::from_vars(
+            Some(OsString::from("@/org/freedesktop/systemd1/notify")),
+            Some(String::from("30000000")),
+            Some(pid),
+        )?
+        .expect("a notifier");
+        assert_eq!(
+            notifier.addr.as_abstract_name(),
+            Some(&b"/org/freedesktop/systemd1/notify"[..])
+        );
+        assert_eq!(notifier.watchdog, Some(time::Duration::from_secs(30)));
+
+        let notifier = Notifier::from_vars(
+            Some(OsString::from("/run/notify")),
+            Some(String::from("30000000")),
+            Some(String::from("1")),
+        )?
+        .expect("a notifier");
+        assert_eq!(notifier.watchdog, None);
+
+        assert!(
+            Notifier::from_vars(
+                Some(OsString::from("/run/notify")),
+                Some(String::from("soon")),
+                None
+            )
+            .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn notify() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let path = tmp_dir.path().join("notify.socket");
+        let listener = UnixDatagram::bind(&path)?;
+        let notifier =
+            Notifier::from_vars(Some(path.into_os_string()), None, None)?.expect("a notifier");
+        notifier.notify("READY=1");
+
+        let mut buf = [0; 64];
+        let len = listener.recv(&mut buf)?;
+        assert_eq!(&buf[..len], b"READY=1");
+
+        Ok(())
+    }
 }
@@ -0,0 +1,330 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Output triggers watch the output of a shell session for lines
+  matching user supplied regexes, so that users can find out about
+  things like a failed build or a password prompt while they are
+  detached. The reader feeds every chunk of shell output
+  through the session's triggers, and a trigger fires at most once
+  per line of output and at most once per `min_interval_secs`.
+
+  Lines are matched as they come in rather than when they are
+  finished, since prompts usually don't end with a newline.
+*/
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use tracing::info;
+
+use crate::{
+    config,
+    daemon::{hook_commands, hook_worker},
+    hooks,
+};
+
+const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(30);
+// The longest line we will match against. Past this, we drop the start
+// of the line so a program spewing output without newlines can't make
+// us use unbounded memory.
+const MAX_LINE_LEN: usize = 4096;
+// The most notifications we will hold on to while waiting for a client
+// to attach. Past this, we drop the oldest ones.
+const MAX_PENDING_NOTIFICATIONS: usize = 16;
+
+/// A single compiled trigger.
+struct Trigger {
+    pattern: String,
+    re: regex::Regex,
+    command: Option<String>,
+    notify: bool,
+    min_interval: Duration,
+    last_fired: Option<Instant>,
+    /// Set once the trigger has matched the current line, so that
+    /// we don't fire again as more of the line comes in.
+    matched_line: bool,
+}
+
+/// The triggers which apply to a single session, along with the
+/// state needed to match them against the session's output.
+pub struct Triggers {
+    session_name: String,
+    child_pid: Option<i32>,
+    triggers: Vec<Trigger>,
+    hook_worker: hook_worker::HookWorker,
+    runner: Option<hook_commands::Runner>,
+    /// The output of the current line so far.
+    line: Vec<u8>,
+    /// Desktop notifications waiting to be sent to a client.
+    notifications: VecDeque<String>,
+}
+
+impl Triggers {
+    /// Compile the configured triggers which apply to the given session.
+    pub fn new(
+        session_name: &str,
+        child_pid: Option<i32>,
+        config: &[config::Trigger],
+        hook_worker: hook_worker::HookWorker,
+        runner: Option<hook_commands::Runner>,
+    ) -> anyhow::Result<Self> {
+        let mut triggers = vec![];
+        for trigger in config.iter() {
+            if let Some(sessions) = &trigger.sessions {
+                if !sessions.iter().any(|s| s == session_name) {
+                    continue;
+                }
+            }
+            triggers.push(Trigger {
+                pattern: trigger.pattern.clone(),
+                re: regex::Regex::new(&trigger.pattern)
+                    .context(format!("compiling trigger pattern '{}'", trigger.pattern))?,
+                command: trigger.command.clone(),
+                notify: trigger.notify.unwrap_or(false),
+                min_interval: trigger
+                    .min_interval_secs
+                    .map(Duration::from_secs)
+                    .unwrap_or(DEFAULT_MIN_INTERVAL),
+                last_fired: None,
+                matched_line: false,
+            });
+        }
+
+        Ok(Triggers {
+            session_name: String::from(session_name),
+            child_pid,
+            triggers,
+            hook_worker,
+            runner,
+            line: vec![],
+            notifications: VecDeque::new(),
+        })
+    }
+
+    /// Match a chunk of shell output against the triggers, firing
+    /// any that match.
+    pub fn scan(&mut self, buf: &[u8]) {
+        if self.triggers.is_empty() {
+            return;
+        }
+
+        for piece in buf.split_inclusive(|b| *b == b'\n') {
+            self.line.extend_from_slice(piece);
+            if self.line.len() > MAX_LINE_LEN {
+                let excess = self.line.len() - MAX_LINE_LEN;
+                self.line.drain(..excess);
+            }
+
+            self.match_line();
+
+            if self.line.last() == Some(&b'\n') {
+                self.line.clear();
+                for trigger in self.triggers.iter_mut() {
+                    trigger.matched_line = false;
+                }
+            }
+        }
+    }
+
+    /// Take the desktop notifications which are waiting to be sent
+    /// to a client, formatted as escape codes for the client's terminal.
+    pub fn take_notifications(&mut self) -> Vec<u8> {
+        let mut codes = vec![];
+        for msg in self.notifications.drain(..) {
+            // OSC 9 is the iTerm2 notification code, which a good number
+            // of other terminals also understand.
+            codes.extend_from_slice(format!("\x1b]9;{}\x07", msg).as_bytes());
+        }
+        codes
+    }
+
+    fn match_line(&mut self) {
+        let stripped = strip_ansi_escapes::strip(&self.line);
+        let text = String::from_utf8_lossy(&stripped);
+        let text = text.trim_end_matches(['\r', '\n']);
+        let now = Instant::now();
+
+        for trigger in self.triggers.iter_mut() {
+            if trigger.matched_line || !trigger.re.is_match(text) {
+                continue;
+            }
+            trigger.matched_line = true;
+
+            if let Some(last_fired) = trigger.last_fired {
+                if now.duration_since(last_fired) < trigger.min_interval {
+                    info!("trigger '{}' matched, but fired too recently", trigger.pattern);
+                    continue;
+                }
+            }
+            trigger.last_fired = Some(now);
+            info!("trigger '{}' fired", trigger.pattern);
+
+            let mut ctx = hooks::HookContext::new(&self.session_name);
+            ctx.child_pid = self.child_pid;
+            let hook_ctx = ctx.clone();
+            let pattern = trigger.pattern.clone();
+            let line = String::from(text);
+            self.hook_worker.call("on_output_trigger", move |h| {
+                h.on_output_trigger(&hook_ctx, &pattern, &line)
+            });
+            if let (Some(cmd), Some(runner)) = (&trigger.command, &self.runner) {
+                runner.enqueue(
+                    "on_output_trigger",
+                    cmd,
+                    hook_commands::trigger_env(&ctx, &trigger.pattern, text),
+                );
+            }
+            if trigger.notify {
+                if self.notifications.len() >= MAX_PENDING_NOTIFICATIONS {
+                    self.notifications.pop_front();
+                }
+                // control chars would end the escape code early
+                let line: String = text.chars().filter(|c| !c.is_control()).collect();
+                self.notifications.push_back(format!("shpool({}): {}", self.session_name, line));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct Recorder {
+        fired: Mutex<Vec<(String, String)>>,
+    }
+
+    impl hooks::Hooks for Recorder {
+        fn on_output_trigger(
+            &self,
+            _ctx: &hooks::HookContext,
+            pattern: &str,
+            line: &str,
+        ) -> anyhow::Result<()> {
+            self.fired.lock().unwrap().push((String::from(pattern), String::from(line)));
+            Ok(())
+        }
+    }
+
+    fn trigger(pattern: &str) -> config::Trigger {
+        config::Trigger {
+            pattern: String::from(pattern),
+            sessions: None,
+            command: None,
+            notify: None,
+            min_interval_secs: Some(0),
+        }
+    }
+
+    fn triggers(config: &[config::Trigger]) -> anyhow::Result<(Triggers, Arc<Recorder>)> {
+        let recorder = Arc::new(Recorder::default());
+        let worker = hook_worker::HookWorker::new(recorder.clone())?;
+        let triggers = Triggers::new("main", None, config, worker, None)?;
+        Ok((triggers, recorder))
+    }
+
+    /// The hooks which have fired so far, once the worker has called them.
+    fn fired(triggers: &Triggers, recorder: &Recorder) -> Vec<(String, String)> {
+        triggers.hook_worker.flush(Duration::from_secs(5));
+        recorder.fired.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn matching() -> anyhow::Result<()> {
+        let (mut triggers, recorder) = triggers(&[trigger("BUILD FAILED"), trigger("assword:")])?;
+        triggers.scan(b"compiling\nBUILD \x1b[31mFAIL");
+        triggers.scan(b"ED\x1b[0m\r\nstill BUILD FAILED\nPass");
+        triggers.scan(b"word: ");
+        triggers.scan(b"\n");
+        assert_eq!(
+            fired(&triggers, &recorder),
+            vec![
+                (String::from("BUILD FAILED"), String::from("BUILD FAILED")),
+                (String::from("BUILD FAILED"), String::from("still BUILD FAILED")),
+                (String::from("assword:"), String::from("Password: ")),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn once_per_line() -> anyhow::Result<()> {
+        let (mut triggers, recorder) = triggers(&[trigger("foo")])?;
+        triggers.scan(b"foo");
+        triggers.scan(b" foo");
+        triggers.scan(b" foo\n");
+        assert_eq!(fired(&triggers, &recorder).len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rate_limit() -> anyhow::Result<()> {
+        let mut limited = trigger("foo");
+        limited.min_interval_secs = None;
+        let (mut triggers, recorder) = triggers(&[limited])?;
+        triggers.scan(b"foo\nfoo\nfoo\n");
+        assert_eq!(fired(&triggers, &recorder).len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn session_filter() -> anyhow::Result<()> {
+        let mut other = trigger("foo");
+        other.sessions = Some(vec![String::from("other")]);
+        let mut main = trigger("bar");
+        main.sessions = Some(vec![String::from("other"), String::from("main")]);
+        let (mut triggers, recorder) = triggers(&[other, main])?;
+        triggers.scan(b"foo bar\n");
+        assert_eq!(
+            fired(&triggers, &recorder),
+            vec![(String::from("bar"), String::from("foo bar"))]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn notifications() -> anyhow::Result<()> {
+        let mut notify = trigger("done");
+        notify.notify = Some(true);
+        let (mut triggers, _) = triggers(&[notify])?;
+        assert!(triggers.take_notifications().is_empty());
+
+        triggers.scan(b"\x1b[1mdone\x07\x1b[0m\n");
+        assert_eq!(triggers.take_notifications(), b"\x1b]9;shpool(main): done\x07");
+        assert!(triggers.take_notifications().is_empty());
+
+        for _ in 0..(MAX_PENDING_NOTIFICATIONS + 5) {
+            triggers.scan(b"done\n");
+        }
+        let codes = triggers.take_notifications();
+        assert_eq!(codes.iter().filter(|b| **b == 0x07).count(), MAX_PENDING_NOTIFICATIONS);
+
+        Ok(())
+    }
+
+    #[test]
+    fn bad_pattern() {
+        assert!(triggers(&[trigger("(")]).is_err());
+    }
+}
@@ -24,18 +24,21 @@ use std::{
     cmp,
     collections::{BinaryHeap, HashMap},
     sync::{Arc, Mutex},
-    time::Instant,
+    time::{self, Instant},
 };
 
 use tracing::{info, span, warn, Level};
 
-use super::shell;
+use super::{metrics, shell};
+use crate::hooks;
 
 /// Run the reaper thread loop. Should be invoked in a dedicated
 /// thread.
 pub fn run(
     new_sess: crossbeam_channel::Receiver<(String, Instant)>,
     shells: Arc<Mutex<HashMap<String, Box<shell::Session>>>>,
+    hooks: Arc<dyn hooks::Hooks + Send + Sync>,
+    metrics: Arc<metrics::Metrics>,
 ) -> anyhow::Result<()> {
     let _s = span!(Level::INFO, "ttl_reaper").entered();
 
@@ -108,6 +111,13 @@ pub fn run(
                             warn!("error trying to kill '{}': {:?}",
                                   reapable.session_name, e);
                         }
+                        metrics.sessions_ttl_expired.inc();
+                        let mut ctx = sess.hook_context(&reapable.session_name);
+                        ctx.exit_status = sess.child_exit_notifier
+                            .wait(Some(time::Duration::from_millis(0)));
+                        if let Err(err) = hooks.on_ttl_expired(&ctx) {
+                            warn!("ttl_expired hook: {:?}", err);
+                        }
                     } else {
                         warn!("tried to kill '{}' but it wasn't in the shells tab",
                               reapable.session_name);
@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-/*! A parser for the duration format supported by the
+/*! A parser and formatter for the duration format supported by the
   attach --ttl flag.
 */
 
@@ -29,6 +29,24 @@ pub fn parse(src: &str) -> anyhow::Result<time::Duration> {
     }
 }
 
+/// Formats the duration in the suffix format, combining units as needed
+/// (e.g. 2h30m). Sub-second precision is dropped.
+pub fn format(dur: time::Duration) -> String {
+    let mut secs = dur.as_secs();
+    if secs == 0 {
+        return String::from("0s");
+    }
+
+    let mut out = String::new();
+    for (unit, unit_secs) in [('d', 60 * 60 * 24), ('h', 60 * 60), ('m', 60), ('s', 1)] {
+        if secs >= unit_secs {
+            out.push_str(&format!("{}{}", secs / unit_secs, unit));
+            secs %= unit_secs;
+        }
+    }
+    out
+}
+
 /// Parses dd:hh:mm:ss or any suffix
 fn parse_colon_duration(src: &str) -> anyhow::Result<time::Duration> {
     let mut parts = src.split(':').collect::<Vec<_>>();
@@ -106,6 +124,21 @@ mod test {
         }
     }
 
+    #[test]
+    fn formatting() {
+        let cases = vec![
+            (time::Duration::from_secs(0), "0s"),
+            (time::Duration::from_millis(5500), "5s"),
+            (time::Duration::from_secs(5 * 60), "5m"),
+            (time::Duration::from_secs(2 * 60 * 60 + 30 * 60), "2h30m"),
+            (time::Duration::from_secs(60 * 60 * 24 + 3 * 60 * 60 + 10 * 60 + 30), "1d3h10m30s"),
+        ];
+
+        for (dur, src) in cases.into_iter() {
+            assert_eq!(format(dur), src);
+        }
+    }
+
     #[test]
     fn errors() {
         let cases = vec![
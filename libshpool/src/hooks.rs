@@ -12,6 +12,63 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+/// The size of a client's terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TtySize {
+    pub rows: u16,
+    pub cols: u16,
+}
+
+/// Everything the daemon knows about the session and client involved
+/// in a hook event. Not every field makes sense for every event, so
+/// fields that are unknown for a particular event are left as `None`.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct HookContext {
+    /// The name of the session the event concerns.
+    pub session_name: String,
+    /// The pid of the shell process for the session.
+    pub child_pid: Option<i32>,
+    /// The exit status of the shell, only set once it has exited.
+    pub exit_status: Option<i32>,
+    /// The pid of the `shpool` process which dialed in to the daemon.
+    pub client_pid: Option<i32>,
+    /// The executable of the `shpool` process which dialed in to the
+    /// daemon.
+    pub client_exe: Option<PathBuf>,
+    /// The size of the client's terminal.
+    pub tty_size: Option<TtySize>,
+    /// When the session was first created.
+    pub session_started_at: Option<SystemTime>,
+    /// The ttl the session was created with, if any.
+    pub ttl: Option<Duration>,
+    /// When the event happened.
+    pub timestamp: SystemTime,
+}
+
+impl HookContext {
+    /// Create a context for the given session with everything
+    /// other than the timestamp left unknown.
+    pub fn new(session_name: &str) -> Self {
+        HookContext {
+            session_name: String::from(session_name),
+            child_pid: None,
+            exit_status: None,
+            client_pid: None,
+            client_exe: None,
+            tty_size: None,
+            session_started_at: None,
+            ttl: None,
+            timestamp: SystemTime::now(),
+        }
+    }
+}
+
 /// Callbacks that the wrapping binary can implement in order to do
 /// stuff like inject telemetry into the daemon or trigger background
 /// processes based on a particular session name (for example you
@@ -31,7 +88,13 @@
 ///
 /// Any errors returned will simply be logged.
 ///
-/// All hooks do nothing by default.
+/// All hooks do nothing by default. The `*_with_context` variants
+/// of the original hooks call through to the plain versions by
+/// default, so implementations only need to override one or the other.
+///
+/// There is no hook for a session getting renamed, since the daemon
+/// has no way to rename a session yet. One should come along with the
+/// command that does the renaming.
 pub trait Hooks {
     /// Triggered when a fresh session is created.
     fn on_new_session(&self, _session_name: &str) -> anyhow::Result<()> {
@@ -59,4 +122,72 @@ pub trait Hooks {
     fn on_shell_disconnect(&self, _session_name: &str) -> anyhow::Result<()> {
         Ok(())
     }
+
+    /// Like `on_new_session`, but with the full event context.
+    fn on_new_session_with_context(&self, ctx: &HookContext) -> anyhow::Result<()> {
+        self.on_new_session(&ctx.session_name)
+    }
+
+    /// Like `on_reattach`, but with the full event context.
+    fn on_reattach_with_context(&self, ctx: &HookContext) -> anyhow::Result<()> {
+        self.on_reattach(&ctx.session_name)
+    }
+
+    /// Like `on_busy`, but with the full event context.
+    fn on_busy_with_context(&self, ctx: &HookContext) -> anyhow::Result<()> {
+        self.on_busy(&ctx.session_name)
+    }
+
+    /// Like `on_client_disconnect`, but with the full event context.
+    fn on_client_disconnect_with_context(&self, ctx: &HookContext) -> anyhow::Result<()> {
+        self.on_client_disconnect(&ctx.session_name)
+    }
+
+    /// Like `on_shell_disconnect`, but with the full event context.
+    fn on_shell_disconnect_with_context(&self, ctx: &HookContext) -> anyhow::Result<()> {
+        self.on_shell_disconnect(&ctx.session_name)
+    }
+
+    /// Triggered when a session is killed with `shpool kill`.
+    fn on_session_killed(&self, _ctx: &HookContext) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Triggered when a session is reaped because its ttl ran out.
+    fn on_ttl_expired(&self, _ctx: &HookContext) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Triggered when an attached client reports a new tty size.
+    fn on_resize(&self, _ctx: &HookContext) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Triggered when the user presses a keybinding. The action is
+    /// the name of the action as it appears in the config file.
+    fn on_keybinding(&self, _ctx: &HookContext, _action: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Triggered when a shell prints a line of output which matches
+    /// the pattern of one of the `[[trigger]]` config entries.
+    fn on_output_trigger(
+        &self,
+        _ctx: &HookContext,
+        _pattern: &str,
+        _line: &str,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Triggered once the daemon is about to start accepting connections.
+    fn on_daemon_start(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Triggered when the daemon is shutting down due to a signal.
+    /// The daemon exits as soon as this returns.
+    fn on_daemon_stop(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
 }
@@ -14,21 +14,21 @@
 
 use std::{
     collections::hash_map::DefaultHasher,
-    env, fs,
+    env,
     hash::{Hash, Hasher},
     io,
     path::PathBuf,
-    sync::Mutex,
 };
 
 use anyhow::{anyhow, Context};
 use clap::{Parser, Subcommand};
-pub use hooks::Hooks;
+pub use hooks::{HookContext, Hooks, TtySize};
 use tracing::error;
-use tracing_subscriber::fmt::format::FmtSpan;
 
 mod attach;
+mod broadcast;
 mod common;
+mod compression;
 mod config;
 mod consts;
 mod daemon;
@@ -37,7 +37,13 @@ mod duration;
 mod hooks;
 mod kill;
 mod list;
+mod logging;
+mod predict;
 mod protocol;
+mod scrollback;
+mod size;
+mod status;
+mod stop;
 mod test_hooks;
 mod tty;
 mod user;
@@ -64,6 +70,58 @@ running in daemon mode, the logs will go to stderr by default."
     )]
     pub log_file: Option<String>,
 
+    #[clap(
+        long,
+        action,
+        value_parser = ["text", "json"],
+        long_help = "The format to write logs in
+
+Either 'text' (the default) for human readable logs, or 'json'
+to write each log line as a JSON object, which is handy for
+shipping the logs off to a log aggregator."
+    )]
+    pub log_format: Option<String>,
+
+    #[clap(
+        long,
+        action,
+        long_help = "Rotate the log file once it would grow past this size
+
+The size is a number of bytes, optionally followed by K, M or G
+(i.e. '10M'). Only applies when writing logs with --log-file."
+    )]
+    pub log_max_size: Option<String>,
+
+    #[clap(
+        long,
+        action,
+        long_help = "Rotate the log file once it has been written to for this long
+
+The duration uses the same format as the --ttl flag of attach
+(i.e. '1d' or '12h'). Only applies when writing logs with --log-file."
+    )]
+    pub log_max_age: Option<String>,
+
+    #[clap(
+        long,
+        action,
+        long_help = "The number of rotated log files to keep around
+
+Rotated logs are named by adding .1, .2 and so on to the name of
+the log file, with .1 being the most recent. Defaults to 5."
+    )]
+    pub log_retain: Option<usize>,
+
+    #[clap(
+        long,
+        action,
+        long_help = "Only log events for the given session
+
+May be provided multiple times. Events which are not tied to any
+particular session, like daemon startup, are still logged."
+    )]
+    pub log_session: Vec<String>,
+
     #[clap(
         short,
         long,
@@ -95,12 +153,16 @@ the daemon is launched by systemd."
 
 /// The subcommds that shpool supports.
 #[derive(Subcommand, Debug)]
+#[non_exhaustive]
 pub enum Commands {
     #[clap(about = "Print version")]
     Version,
 
     #[clap(about = "Starts running a daemon that holds a pool of shells")]
-    Daemon,
+    Daemon {
+        #[clap(subcommand)]
+        action: Option<DaemonCommands>,
+    },
 
     #[clap(about = "Creates or attaches to an existing shell session")]
     Attach {
@@ -129,6 +191,16 @@ The command is broken up into a binary to invoke and a list of arguments to
 pass to the binary using the shell-words crate."
         )]
         cmd: Option<String>,
+        #[clap(
+            long,
+            value_parser = ["never", "adaptive", "always"],
+            long_help = "Show what you type right away, before the shell echoes it
+
+The guesses are underlined until the shell confirms them. 'adaptive'
+only shows them while the connection to the daemon is slow. Overrides
+the predictive_echo config option."
+        )]
+        predict: Option<String>,
         #[clap(help = "The name of the shell session to create or attach to")]
         name: String,
     },
@@ -156,6 +228,67 @@ will be used if it is present in the environment.")]
 
     #[clap(about = "lists all the running shell sessions")]
     List,
+
+    #[clap(about = "Report on the health of the daemon
+
+Exits with a non-zero status if the daemon can't be reached, so this
+can be used as a health check.")]
+    Status,
+
+    #[clap(about = "Open the scrollback of the given session in a pager
+
+The pager is displayed on the terminal currently attached to the
+session, so the session must be attached. If no session name is
+provided $SHPOOL_SESSION_NAME will be used if it is present in the
+environment.")]
+    Scrollback {
+        #[clap(
+            long,
+            long_help = "Only show the output of the most recent command
+
+This relies on the shell reporting command boundaries with semantic
+prompt marks, which shpool sets up along with the prompt prefix."
+        )]
+        last_command: bool,
+        #[clap(help = "session to show the scrollback of")]
+        session: Option<String>,
+    },
+
+    #[clap(about = "Show a message on every attached session
+
+By default the message is drawn as a banner over the top line of each
+attached terminal. The banner is never sent to the shell, so the
+program running in the session is not disturbed, and the banner goes
+away the next time that line of the screen gets redrawn.")]
+    Broadcast {
+        #[clap(long, help = "Show the message in the scrollback pager instead of as a banner")]
+        pager: bool,
+        #[clap(help = "the message to show")]
+        message: String,
+    },
+}
+
+/// The subcommands for managing a running daemon.
+#[derive(Subcommand, Debug)]
+#[non_exhaustive]
+pub enum DaemonCommands {
+    #[clap(about = "Gracefully shut down the running daemon
+
+Any attached terminals are told that the daemon is stopping and get
+detached. By default the shells are left to be hung up on when the
+daemon exits.")]
+    Stop {
+        #[clap(long, help = "Wait for the daemon to exit before returning")]
+        wait: bool,
+        #[clap(
+            long,
+            conflicts_with = "refuse_if_sessions",
+            help = "Kill the shells, running the shell disconnect hooks for them"
+        )]
+        kill_sessions: bool,
+        #[clap(long, help = "Don't stop the daemon if it has any sessions")]
+        refuse_if_sessions: bool,
+    },
 }
 
 impl Args {
@@ -176,23 +309,23 @@ pub fn run(args: Args, hooks: Option<Box<dyn hooks::Hooks + Send + Sync>>) -> an
     } else {
         tracing::Level::TRACE
     };
+    let log_opts = logging::Options {
+        level: trace_level.into(),
+        json: args.log_format.as_deref() == Some("json"),
+        sessions: args.log_session.clone(),
+    };
+    let rotation = logging::Rotation::parse(
+        args.log_max_size.as_deref(),
+        args.log_max_age.as_deref(),
+        args.log_retain,
+    )?;
     if let Some(log_file) = args.log_file.clone() {
-        let file = fs::File::create(log_file)?;
-        tracing_subscriber::fmt()
-            .with_max_level(trace_level)
-            .with_thread_ids(true)
-            .with_target(false)
-            .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
-            .with_writer(Mutex::new(file))
-            .init();
-    } else if let Commands::Daemon { .. } = args.command {
-        tracing_subscriber::fmt()
-            .with_max_level(trace_level)
-            .with_thread_ids(true)
-            .with_target(false)
-            .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
-            .with_writer(io::stderr)
-            .init();
+        logging::init(log_opts, logging::open_file(&log_file, rotation)?);
+    } else if let Commands::Daemon { action: None } = args.command {
+        if rotation.is_enabled() {
+            return Err(anyhow!("log rotation requires --log-file"));
+        }
+        logging::init(log_opts, io::stderr);
     }
 
     #[cfg(feature = "test_hooks")]
@@ -233,18 +366,26 @@ pub fn run(args: Args, hooks: Option<Box<dyn hooks::Hooks + Send + Sync>>) -> an
 
     let res: anyhow::Result<()> = match args.command {
         Commands::Version => return Err(anyhow!("wrapper binary must handle version")),
-        Commands::Daemon => daemon::run(
+        Commands::Daemon { action: None } => daemon::run(
             args.config_file,
             runtime_dir,
             hooks.unwrap_or(Box::new(NoopHooks {})),
             socket,
         ),
-        Commands::Attach { force, ttl, cmd, name } => {
-            attach::run(args.config_file, name, force, ttl, cmd, socket)
+        Commands::Daemon {
+            action: Some(DaemonCommands::Stop { wait, kill_sessions, refuse_if_sessions }),
+        } => stop::run(wait, kill_sessions, refuse_if_sessions, socket),
+        Commands::Attach { force, ttl, cmd, predict, name } => {
+            attach::run(args.config_file, name, force, ttl, cmd, predict, socket)
         }
         Commands::Detach { sessions } => detach::run(sessions, socket),
         Commands::Kill { sessions } => kill::run(sessions, socket),
         Commands::List => list::run(socket),
+        Commands::Status => status::run(socket),
+        Commands::Scrollback { last_command, session } => {
+            scrollback::run(session, last_command, socket)
+        }
+        Commands::Broadcast { pager, message } => broadcast::run(message, pager, socket),
     };
 
     if let Err(err) = res {
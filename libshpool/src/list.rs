@@ -15,34 +15,94 @@
 use std::{io, path::PathBuf, time};
 
 use anyhow::Context;
+use tracing::info;
 
 use super::{
-    protocol,
-    protocol::{ConnectHeader, ListReply},
+    duration, protocol,
+    protocol::{ConnectHeader, ListReply, ListV2Reply, SessionV2},
+    size,
 };
 
 pub fn run(socket: PathBuf) -> anyhow::Result<()> {
-    let mut client = match protocol::Client::new(socket) {
-        Ok(c) => c,
+    let sessions = match list_v2(&socket)? {
+        Ok(sessions) => sessions,
         Err(err) => {
-            let io_err = err.downcast::<io::Error>()?;
-            if io_err.kind() == io::ErrorKind::NotFound {
-                eprintln!("could not connect to daemon");
-            }
-            return Err(io_err).context("connecting to daemon");
+            // A daemon that predates ListV2 hangs up on it, so ask again
+            // the old way and go without the extra details.
+            info!("falling back to a plain list (err: {:?})", err);
+            let mut client = dial(&socket)?;
+            client
+                .write_connect_header(ConnectHeader::List)
+                .context("sending list connect header")?;
+            let reply: ListReply = client.read_reply().context("reading reply")?;
+            reply
+                .sessions
+                .into_iter()
+                .map(|session| SessionV2 {
+                    session,
+                    current_command: None,
+                    last_exit_status: None,
+                    bell: false,
+                    activity: false,
+                    memory_bytes: None,
+                    cpu_usage_usec: None,
+                })
+                .collect()
         }
     };
 
-    client.write_connect_header(ConnectHeader::List).context("sending list connect header")?;
-    let reply: ListReply = client.read_reply().context("reading reply")?;
-
-    println!("NAME\tSTARTED_AT\tSTATUS");
-    for session in reply.sessions.iter() {
+    println!("NAME\tSTARTED_AT\tSTATUS\tLAST_EXIT\tMEMORY\tCPU\tCOMMAND");
+    for details in sessions.iter() {
+        let session = &details.session;
         let started_at =
             time::UNIX_EPOCH + time::Duration::from_millis(session.started_at_unix_ms as u64);
         let started_at = chrono::DateTime::<chrono::Utc>::from(started_at);
-        println!("{}\t{}\t{}", session.name, started_at.to_rfc3339(), session.status);
+        let mut status = session.status.to_string();
+        if details.bell {
+            status.push_str(", bell");
+        }
+        if details.activity {
+            status.push_str(", activity");
+        }
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            session.name,
+            started_at.to_rfc3339(),
+            status,
+            details.last_exit_status.map(|s| s.to_string()).unwrap_or_default(),
+            details.memory_bytes.map(size::format).unwrap_or_default(),
+            details
+                .cpu_usage_usec
+                .map(|usec| duration::format(time::Duration::from_micros(usec)))
+                .unwrap_or_default(),
+            details.current_command.as_deref().unwrap_or(""),
+        );
     }
 
     Ok(())
 }
+
+/// Ask the daemon for a ListV2Reply. Only failing to connect is an
+/// error, anything going wrong after that is handed back for the
+/// caller to fall back on a plain list.
+fn list_v2(socket: &PathBuf) -> anyhow::Result<anyhow::Result<Vec<SessionV2>>> {
+    let mut client = dial(socket)?;
+    Ok(client
+        .write_connect_header(ConnectHeader::ListV2)
+        .context("sending list connect header")
+        .and_then(|_| client.read_reply::<ListV2Reply>().context("reading reply"))
+        .map(|reply| reply.sessions))
+}
+
+fn dial(socket: &PathBuf) -> anyhow::Result<protocol::Client> {
+    match protocol::Client::new(socket) {
+        Ok(c) => Ok(c),
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            Err(io_err).context("connecting to daemon")
+        }
+    }
+}
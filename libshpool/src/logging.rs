@@ -0,0 +1,365 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Logging setup. Logs can be written as plain text or as one JSON
+  object per line, and a log file can be rotated once it gets too
+  big or too old, with only a limited number of old logs kept around.
+
+  Most of the daemon's logs are emitted within a span which carries
+  the session name in its `s` field, which lets us filter the logs
+  down to just the sessions someone is interested in.
+*/
+
+use std::{
+    fmt, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time,
+};
+
+use anyhow::Context;
+use tracing::{
+    field::{Field, Visit},
+    level_filters::LevelFilter,
+    span, Event, Metadata, Subscriber,
+};
+use tracing_subscriber::{
+    fmt::format::FmtSpan,
+    layer::{self, Filter, SubscriberExt},
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+    Layer, Registry,
+};
+
+use crate::{duration, size};
+
+const DEFAULT_RETAIN: usize = 5;
+
+/// How to log.
+pub struct Options {
+    pub level: LevelFilter,
+    pub json: bool,
+    /// If set, only log events from these sessions, along with the
+    /// events which don't belong to any session.
+    pub sessions: Vec<String>,
+}
+
+/// When to rotate a log file and how many old logs to keep.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Rotation {
+    pub max_size: Option<u64>,
+    pub max_age: Option<time::Duration>,
+    pub retain: Option<usize>,
+}
+
+impl Rotation {
+    /// Build a rotation policy from the raw command line flags.
+    pub fn parse(
+        max_size: Option<&str>,
+        max_age: Option<&str>,
+        retain: Option<usize>,
+    ) -> anyhow::Result<Self> {
+        Ok(Rotation {
+            max_size: max_size.map(size::parse).transpose().context("parsing --log-max-size")?,
+            max_age: max_age.map(duration::parse).transpose().context("parsing --log-max-age")?,
+            retain,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_size.is_some() || self.max_age.is_some()
+    }
+}
+
+/// Install the global subscriber, writing logs to the given writer.
+pub fn init<W>(opts: Options, writer: W)
+where
+    W: for<'w> tracing_subscriber::fmt::MakeWriter<'w> + Send + Sync + 'static,
+{
+    let fmt_layer = tracing_subscriber::fmt::layer()
+        .with_thread_ids(true)
+        .with_target(false)
+        .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
+        .with_writer(writer);
+    let fmt_layer: Box<dyn Layer<Registry> + Send + Sync> =
+        if opts.json { Box::new(fmt_layer.json()) } else { Box::new(fmt_layer) };
+
+    tracing_subscriber::registry()
+        .with(fmt_layer.with_filter(SessionFilter { level: opts.level, sessions: opts.sessions }))
+        .init();
+}
+
+/// A log file which gets rotated according to a rotation policy.
+/// When the file is rotated, `log` gets renamed to `log.1`, `log.1`
+/// gets renamed to `log.2` and so on, dropping the oldest logs past
+/// the number we are supposed to retain.
+pub struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: fs::File,
+    size: u64,
+    opened_at: time::SystemTime,
+}
+
+impl RotatingFile {
+    /// Open the given log file for appending.
+    pub fn open<P: AsRef<Path>>(path: P, rotation: Rotation) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = fs::OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        // A file we are appending to has been around since it was created,
+        // not since we started, so it counts against the max age.
+        let opened_at = metadata.created().unwrap_or_else(|_| time::SystemTime::now());
+        Ok(RotatingFile { path, rotation, file, size: metadata.len(), opened_at })
+    }
+
+    fn should_rotate(&self, incoming: usize, now: time::SystemTime) -> bool {
+        // never rotate an empty file, or a single huge write could
+        // push out all the old logs
+        if self.size == 0 {
+            return false;
+        }
+        if let Some(max_size) = self.rotation.max_size {
+            if self.size + incoming as u64 > max_size {
+                return true;
+            }
+        }
+        if let Some(max_age) = self.rotation.max_age {
+            if now.duration_since(self.opened_at).unwrap_or_default() >= max_age {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let retain = self.rotation.retain.unwrap_or(DEFAULT_RETAIN);
+        if retain == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..retain).rev() {
+                match fs::rename(self.rotated_path(n), self.rotated_path(n + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.file = fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        self.opened_at = time::SystemTime::now();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.should_rotate(buf.len(), time::SystemTime::now()) {
+            if let Err(e) = self.rotate() {
+                // There is nowhere to log this, but we can at least keep
+                // writing to the old file.
+                eprintln!("shpool: rotating log file: {:?}", e);
+            }
+        }
+        let len = self.file.write(buf)?;
+        self.size += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Open the log file, wrapped up so it can be handed to `init`.
+pub fn open_file(path: &str, rotation: Rotation) -> anyhow::Result<Mutex<RotatingFile>> {
+    Ok(Mutex::new(RotatingFile::open(path, rotation).context("opening log file")?))
+}
+
+/// The session a span belongs to, stashed in the span's extensions
+/// when the span gets created.
+struct SessionName(String);
+
+/// Pulls the session name out of a span's fields.
+#[derive(Default)]
+struct SessionVisitor(Option<String>);
+
+impl Visit for SessionVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "s" {
+            self.0 = Some(String::from(value));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "s" {
+            self.0 = Some(format!("{:?}", value).trim_matches('"').to_string());
+        }
+    }
+}
+
+/// Filters out events above the configured level, and events from
+/// sessions we were not asked to log if any sessions were given.
+struct SessionFilter {
+    level: LevelFilter,
+    sessions: Vec<String>,
+}
+
+impl<S> Filter<S> for SessionFilter
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn enabled(&self, meta: &Metadata<'_>, _cx: &layer::Context<'_, S>) -> bool {
+        self.level >= *meta.level()
+    }
+
+    fn event_enabled(&self, event: &Event<'_>, cx: &layer::Context<'_, S>) -> bool {
+        if self.sessions.is_empty() {
+            return true;
+        }
+        let scope = match cx.event_scope(event) {
+            Some(scope) => scope,
+            None => return true,
+        };
+        for span in scope {
+            if let Some(name) = span.extensions().get::<SessionName>() {
+                return self.sessions.contains(&name.0);
+            }
+        }
+        true
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(self.level)
+    }
+
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, cx: layer::Context<'_, S>) {
+        if self.sessions.is_empty() {
+            return;
+        }
+        let mut visitor = SessionVisitor::default();
+        attrs.record(&mut visitor);
+        if let (Some(name), Some(span)) = (visitor.0, cx.span(id)) {
+            span.extensions_mut().insert(SessionName(name));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use tracing::{info, info_span};
+
+    #[test]
+    fn rotate_by_size() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("daemon.log");
+        fs::write(&path, "old\n")?;
+        let rotation = Rotation { max_size: Some(10), max_age: None, retain: Some(2) };
+        let mut file = RotatingFile::open(&path, rotation)?;
+
+        // appends to the existing log until it fills up
+        file.write_all(b"aaaa\n")?;
+        for line in ["bbbbbbbb\n", "cccccccc\n", "dddddddd\n"] {
+            file.write_all(line.as_bytes())?;
+        }
+        file.flush()?;
+
+        assert_eq!(fs::read_to_string(&path)?, "dddddddd\n");
+        assert_eq!(fs::read_to_string(tmp.path().join("daemon.log.1"))?, "cccccccc\n");
+        assert_eq!(fs::read_to_string(tmp.path().join("daemon.log.2"))?, "bbbbbbbb\n");
+        assert!(!tmp.path().join("daemon.log.3").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn rotate_by_age() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("daemon.log");
+        let rotation = Rotation {
+            max_size: None,
+            max_age: Some(time::Duration::from_secs(60)),
+            retain: Some(0),
+        };
+        let mut file = RotatingFile::open(&path, rotation)?;
+        file.write_all(b"first\n")?;
+        let now = time::SystemTime::now();
+        assert!(!file.should_rotate(1, now));
+        assert!(file.should_rotate(1, now + time::Duration::from_secs(61)));
+
+        file.opened_at -= time::Duration::from_secs(61);
+        file.write_all(b"second\n")?;
+        assert_eq!(fs::read_to_string(&path)?, "second\n");
+        assert!(!tmp.path().join("daemon.log.1").exists());
+
+        Ok(())
+    }
+
+    #[derive(Clone, Default)]
+    struct Buf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn session_filter() {
+        let buf = Buf::default();
+        let writer = buf.clone();
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_subscriber::fmt::layer()
+                .json()
+                .with_writer(move || writer.clone())
+                .with_filter(SessionFilter {
+                    level: LevelFilter::INFO,
+                    sessions: vec![String::from("main")],
+                }),
+        );
+
+        tracing::subscriber::with_default(subscriber, || {
+            info!("daemon event");
+            info_span!("reader", s = "main").in_scope(|| {
+                info_span!("inner").in_scope(|| info!("main event"));
+            });
+            let other = String::from("other");
+            info_span!("reader", s = other).in_scope(|| info!("other event"));
+            tracing::debug!("debug event");
+        });
+
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 2, "out={}", out);
+        assert!(lines[0].contains("\"message\":\"daemon event\""));
+        assert!(lines[1].contains("\"message\":\"main event\""));
+        assert!(lines[1].contains("\"s\":\"main\""));
+    }
+}
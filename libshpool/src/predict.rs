@@ -0,0 +1,509 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Predictive local echo for `shpool attach`, along the lines of what
+//! mosh does.
+//!
+//! The client keeps its own model of the local terminal, fed with
+//! everything the daemon sends. When the user types a printable
+//! character, we guess that the shell is going to echo it at the
+//! cursor, and draw it there underlined right away rather than waiting
+//! a round trip to find out. Once the daemon's output shows the same
+//! character in that cell, the guess is confirmed and the real output
+//! has already replaced the underlined one. If something else shows up
+//! there, or nothing does for too long, we wipe all outstanding guesses
+//! and repaint those cells from the model.
+//!
+//! Guesses are grouped into epochs. A new epoch starts whenever we see
+//! input we can't predict (enter, arrow keys, ...) or a guess turns out
+//! to be wrong, and the guesses in an epoch stay hidden until one of
+//! them is confirmed. That way we never draw anything the shell would
+//! not have echoed, like a password.
+
+use std::{
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+use byteorder::{ByteOrder, LittleEndian};
+use tracing::{debug, trace};
+
+use super::{config::PredictiveEcho, tty};
+
+/// How long a guess can go without being confirmed before we give up
+/// on it, unless the round trip time says we should wait longer.
+const MIN_PREDICTION_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// In adaptive mode, start showing guesses once the smoothed round trip
+/// time goes over this...
+const ADAPTIVE_SHOW_RTT: Duration = Duration::from_millis(30);
+
+/// ...and stop once it drops back under this.
+const ADAPTIVE_HIDE_RTT: Duration = Duration::from_millis(20);
+
+/// How often to ping the daemon to measure the round trip time in
+/// adaptive mode, at most. We only ping when the user types something.
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A character that we expect the shell to echo.
+#[derive(Debug)]
+struct Prediction {
+    row: u16,
+    col: u16,
+    ch: u8,
+    /// What the cell held when we made the guess, so we can tell
+    /// when the shell has written something else there.
+    original: String,
+    made_at: Instant,
+    /// Set once we have drawn the guess on the real terminal.
+    displayed: bool,
+}
+
+pub struct Predictor {
+    mode: PredictiveEcho,
+    /// A model of what the local terminal is showing.
+    term: shpool_vt100::Parser,
+    escape: EscapeTracker,
+    predictions: Vec<Prediction>,
+    /// Cells we drew a guess in that need to be repainted from the
+    /// model as soon as it is safe to write to the terminal.
+    stale: Vec<(u16, u16)>,
+    /// Set once a guess from the current epoch has been confirmed.
+    epoch_confirmed: bool,
+    /// Set while the real cursor is not where the model says it is,
+    /// because it is sitting after the guesses we drew.
+    cursor_moved: bool,
+    /// Set while the round trip time is bad enough to show guesses
+    /// in adaptive mode.
+    slow: bool,
+    srtt: Option<Duration>,
+    start: Instant,
+    last_ping: Option<Instant>,
+}
+
+impl Predictor {
+    pub fn new(mode: PredictiveEcho, size: &tty::Size) -> Self {
+        Predictor {
+            slow: matches!(mode, PredictiveEcho::Always),
+            mode,
+            term: shpool_vt100::Parser::new(size.rows, size.cols, 0),
+            escape: EscapeTracker::default(),
+            predictions: vec![],
+            stale: vec![],
+            epoch_confirmed: false,
+            cursor_moved: false,
+            srtt: None,
+            start: Instant::now(),
+            last_ping: None,
+        }
+    }
+
+    /// Pass along output from the daemon to the terminal, then check it
+    /// against our guesses.
+    pub fn output<W: Write>(&mut self, buf: &[u8], out: &mut W) -> io::Result<()> {
+        if self.cursor_moved {
+            // Put the real cursor back where the shell thinks it is
+            // before the shell's output gets to it.
+            out.write_all(&self.term.screen().cursor_state_formatted())?;
+            out.write_all(&self.term.screen().attributes_formatted())?;
+            self.cursor_moved = false;
+        }
+        out.write_all(buf)?;
+        self.term.process(buf);
+        self.escape.feed(buf);
+
+        self.reconcile(Instant::now());
+        self.draw(out)
+    }
+
+    /// Make guesses about how the shell is going to echo the given
+    /// input from the user, and show them if we are confident enough.
+    pub fn input<W: Write>(&mut self, buf: &[u8], out: &mut W) -> io::Result<()> {
+        for byte in buf.iter() {
+            match byte {
+                0x20..=0x7e => self.predict(*byte),
+                // backspace
+                0x7f | 0x08 => match self.predictions.pop() {
+                    Some(p) if p.displayed => self.stale.push((p.row, p.col)),
+                    Some(_) => {}
+                    None => self.new_epoch(),
+                },
+                _ => self.new_epoch(),
+            }
+        }
+        self.draw(out)
+    }
+
+    /// Forget everything about the current terminal and start over
+    /// with a new size.
+    pub fn resize<W: Write>(&mut self, size: &tty::Size, out: &mut W) -> io::Result<()> {
+        self.new_epoch();
+        self.term.screen_mut().set_size(size.rows, size.cols);
+        self.draw(out)
+    }
+
+    /// Give up on any guesses that have been outstanding for too long.
+    pub fn expire<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        self.reconcile(Instant::now());
+        self.draw(out)
+    }
+
+    /// When expire should next be called, if ever.
+    pub fn deadline(&self) -> Option<Instant> {
+        let timeout = self.timeout();
+        self.predictions.first().map(|p| p.made_at + timeout)
+    }
+
+    /// The payload for a Ping chunk, if it is time to measure the round
+    /// trip time again.
+    pub fn ping(&mut self) -> Option<[u8; 8]> {
+        if !matches!(self.mode, PredictiveEcho::Adaptive) {
+            return None;
+        }
+        let now = Instant::now();
+        if self.last_ping.is_some_and(|at| now.duration_since(at) < PING_INTERVAL) {
+            return None;
+        }
+        self.last_ping = Some(now);
+        let mut payload = [0; 8];
+        LittleEndian::write_u64(&mut payload, now.duration_since(self.start).as_micros() as u64);
+        Some(payload)
+    }
+
+    /// Take the round trip time from the daemon's answer to one of
+    /// our pings.
+    pub fn pong(&mut self, payload: &[u8]) {
+        if payload.len() != 8 {
+            return;
+        }
+        let sent = self.start + Duration::from_micros(LittleEndian::read_u64(payload));
+        let rtt = Instant::now().saturating_duration_since(sent);
+        let srtt = match self.srtt {
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+            None => rtt,
+        };
+        self.srtt = Some(srtt);
+        if !matches!(self.mode, PredictiveEcho::Adaptive) {
+            return;
+        }
+        if self.slow && srtt < ADAPTIVE_HIDE_RTT {
+            self.slow = false;
+        } else if !self.slow && srtt > ADAPTIVE_SHOW_RTT {
+            self.slow = true;
+        }
+        trace!("rtt={:?} srtt={:?} slow={}", rtt, srtt, self.slow);
+    }
+
+    fn predict(&mut self, ch: u8) {
+        let screen = self.term.screen();
+        if screen.alternate_screen() {
+            // Full screen programs do all sorts of things with input
+            // besides echoing it.
+            return self.new_epoch();
+        }
+        let (row, col) = match self.predictions.last() {
+            Some(p) => (p.row, p.col + 1),
+            None => screen.cursor_position(),
+        };
+        let (_, cols) = screen.size();
+        if col + 1 >= cols {
+            // Don't try to guess how the line is going to wrap.
+            return self.new_epoch();
+        }
+        let original = screen.cell(row, col).map(|c| c.contents()).unwrap_or_default();
+        self.predictions.push(Prediction {
+            row,
+            col,
+            ch,
+            original,
+            made_at: Instant::now(),
+            displayed: false,
+        });
+    }
+
+    /// Check our guesses against the model.
+    fn reconcile(&mut self, now: Instant) {
+        let timeout = self.timeout();
+        let screen = self.term.screen();
+        let mut failed = false;
+        self.predictions.retain(|p| {
+            let contents = screen.cell(p.row, p.col).map(|c| c.contents()).unwrap_or_default();
+            if contents.as_bytes() == [p.ch] {
+                self.epoch_confirmed = true;
+                false
+            } else {
+                failed = failed || contents != p.original || now >= p.made_at + timeout;
+                true
+            }
+        });
+        if failed {
+            debug!("prediction failed, starting a new epoch");
+            self.new_epoch();
+        }
+    }
+
+    /// Drop all outstanding guesses, and stop showing new ones until
+    /// one gets confirmed.
+    fn new_epoch(&mut self) {
+        for p in self.predictions.drain(..) {
+            if p.displayed {
+                self.stale.push((p.row, p.col));
+            }
+        }
+        self.epoch_confirmed = false;
+    }
+
+    /// Bring the real terminal up to date with our guesses, if it is
+    /// safe to write to it.
+    fn draw<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        if !self.escape.is_ground() {
+            // We would garble whatever escape sequence the shell
+            // is in the middle of.
+            return Ok(());
+        }
+        // The shell's output may well have drawn over our guesses, so
+        // we redraw all of them every time.
+        let show = self.slow && self.epoch_confirmed;
+        if self.stale.is_empty() && (!show || self.predictions.is_empty()) {
+            return Ok(());
+        }
+
+        let screen = self.term.screen();
+        let mut buf = vec![];
+        for (row, col) in self.stale.drain(..) {
+            // blank the cell, then draw whatever the model has there
+            write!(buf, "\x1b[{};{}H\x1b[0m\x1b[X", row + 1, col + 1)?;
+            if let Some(contents) = screen.rows_formatted(col, 1).nth(row as usize) {
+                buf.extend(contents);
+            }
+        }
+        let mut cursor = None;
+        if show {
+            for p in self.predictions.iter_mut() {
+                write!(buf, "\x1b[{};{}H\x1b[0;4m", p.row + 1, p.col + 1)?;
+                buf.push(p.ch);
+                p.displayed = true;
+                cursor = Some((p.row, p.col + 1));
+            }
+        }
+        buf.extend(screen.attributes_formatted());
+        match cursor {
+            Some((row, col)) => {
+                write!(buf, "\x1b[{};{}H", row + 1, col + 1)?;
+                self.cursor_moved = true;
+            }
+            None => {
+                buf.extend(screen.cursor_state_formatted());
+                buf.extend(screen.attributes_formatted());
+                self.cursor_moved = false;
+            }
+        }
+        out.write_all(&buf)?;
+        out.flush()
+    }
+
+    fn timeout(&self) -> Duration {
+        self.srtt.map(|srtt| srtt * 3).unwrap_or_default().max(MIN_PREDICTION_TIMEOUT)
+    }
+}
+
+/// Keeps track of whether a byte stream is in the middle of an escape
+/// sequence or utf8 character, so that we know when we can inject our
+/// own escape sequences into it.
+#[derive(Debug, Default)]
+struct EscapeTracker {
+    state: EscapeState,
+}
+
+#[derive(Debug, Default, PartialEq)]
+enum EscapeState {
+    #[default]
+    Ground,
+    /// In the middle of a multi-byte utf8 character, with the given
+    /// number of bytes left to go.
+    Utf8(u8),
+    /// Just saw ESC.
+    Escape,
+    /// In a CSI sequence.
+    Csi,
+    /// In a string like an OSC or DCS, which runs until BEL or ST.
+    Str,
+    /// Saw ESC in a string, which might be the start of ST.
+    StrEscape,
+}
+
+impl EscapeTracker {
+    fn feed(&mut self, buf: &[u8]) {
+        use EscapeState::*;
+
+        for byte in buf.iter() {
+            self.state = match (&self.state, byte) {
+                // CAN and SUB abort any sequence
+                (_, 0x18 | 0x1a) => Ground,
+                (Str, 0x07) => Ground,
+                (Str, 0x1b) => StrEscape,
+                (Str, _) => Str,
+                (StrEscape, b'\\') => Ground,
+                (StrEscape, 0x1b) => StrEscape,
+                (StrEscape, _) => Str,
+                (_, 0x1b) => Escape,
+                (Escape, b'[') => Csi,
+                (Escape, b']' | b'P' | b'X' | b'^' | b'_') => Str,
+                // intermediate bytes, like the `(` in a charset selection
+                (Escape, 0x20..=0x2f) => Escape,
+                (Escape, _) => Ground,
+                (Csi, 0x40..=0x7e) => Ground,
+                (Csi, _) => Csi,
+                (Utf8(n), 0x80..=0xbf) if *n > 1 => Utf8(n - 1),
+                (Utf8(_), 0x80..=0xbf) => Ground,
+                (_, 0xc0..=0xdf) => Utf8(1),
+                (_, 0xe0..=0xef) => Utf8(2),
+                (_, 0xf0..=0xf7) => Utf8(3),
+                (_, _) => Ground,
+            };
+        }
+    }
+
+    fn is_ground(&self) -> bool {
+        self.state == EscapeState::Ground
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn predictor(mode: PredictiveEcho) -> Predictor {
+        Predictor::new(mode, &tty::Size { rows: 24, cols: 80 })
+    }
+
+    #[test]
+    fn tentative_until_confirmed() -> anyhow::Result<()> {
+        let mut p = predictor(PredictiveEcho::Always);
+        let mut out = vec![];
+        p.output(b"prompt> ", &mut out)?;
+
+        // nothing gets drawn until the shell has echoed something
+        out.clear();
+        p.input(b"ab", &mut out)?;
+        assert!(out.is_empty());
+
+        p.output(b"a", &mut out)?;
+        let out = String::from_utf8_lossy(&out);
+        assert!(out.contains("\x1b[1;10H\x1b[0;4mb"), "out={:?}", out);
+        assert_eq!(p.predictions.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn confirmed_epoch_draws_right_away() -> anyhow::Result<()> {
+        let mut p = predictor(PredictiveEcho::Always);
+        let mut out = vec![];
+        p.output(b"prompt> ", &mut out)?;
+        p.input(b"a", &mut out)?;
+        p.output(b"a", &mut out)?;
+        assert!(p.epoch_confirmed);
+
+        out.clear();
+        p.input(b"c", &mut out)?;
+        assert!(String::from_utf8_lossy(&out).contains("\x1b[0;4mc"));
+        assert!(p.cursor_moved);
+
+        // the echo puts the cursor back before writing
+        out.clear();
+        p.output(b"c", &mut out)?;
+        assert!(String::from_utf8_lossy(&out).contains("\x1b[1;10H"));
+        assert!(p.predictions.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn wrong_guess_gets_repainted() -> anyhow::Result<()> {
+        let mut p = predictor(PredictiveEcho::Always);
+        let mut out = vec![];
+        p.output(b"prompt> ", &mut out)?;
+        p.input(b"a", &mut out)?;
+        p.output(b"a", &mut out)?;
+        p.input(b"b", &mut out)?;
+
+        out.clear();
+        p.output(b"*", &mut out)?;
+        assert!(p.predictions.is_empty());
+        assert!(!p.epoch_confirmed);
+        // the cell we guessed in gets blanked and redrawn
+        assert!(String::from_utf8_lossy(&out).contains("\x1b[1;10H\x1b[0m\x1b[X*"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn no_echo_never_shows() -> anyhow::Result<()> {
+        let mut p = predictor(PredictiveEcho::Always);
+        let mut out = vec![];
+        p.output(b"password: ", &mut out)?;
+
+        out.clear();
+        p.input(b"hunter2", &mut out)?;
+        p.output(b"\x1b[1m", &mut out)?;
+        assert_eq!(out, b"\x1b[1m");
+        assert_eq!(p.predictions.len(), 7);
+
+        Ok(())
+    }
+
+    #[test]
+    fn adaptive_needs_slow_link() -> anyhow::Result<()> {
+        let mut p = predictor(PredictiveEcho::Adaptive);
+        let mut out = vec![];
+        p.output(b"prompt> ", &mut out)?;
+        p.input(b"a", &mut out)?;
+        p.output(b"a", &mut out)?;
+
+        out.clear();
+        p.input(b"b", &mut out)?;
+        assert!(out.is_empty());
+
+        let payload = p.ping().expect("first ping to be sent");
+        p.start -= Duration::from_millis(100);
+        p.pong(&payload);
+        assert!(p.slow);
+        p.input(b"c", &mut out)?;
+        assert!(String::from_utf8_lossy(&out).contains("\x1b[0;4mb\x1b[1;11H\x1b[0;4mc"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn escape_tracker() {
+        let mut t = EscapeTracker::default();
+        for (input, ground) in [
+            (&b"abc"[..], true),
+            (b"\x1b[3", false),
+            (b"1m", true),
+            (b"\x1b]0;title", false),
+            (b"\x1b\\", true),
+            (b"\xe2\x94", false),
+            (b"\x80", true),
+            (b"\x1b(", false),
+            (b"B", true),
+            (b"\x1b[1", false),
+            (b"\x18", true),
+        ] {
+            t.feed(input);
+            assert_eq!(t.is_ground(), ground, "input={:?}", input);
+        }
+    }
+}
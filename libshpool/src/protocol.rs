@@ -15,26 +15,38 @@
 use std::{
     fmt,
     io::{self, Read, Write},
-    os::unix::net::UnixStream,
-    path::Path,
-    sync::atomic::{AtomicI32, Ordering},
-    thread, time,
+    os::{fd::AsFd, unix::net::UnixStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Context};
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use nix::{
+    errno::Errno,
+    poll,
+    sys::eventfd::{EfdFlags, EventFd},
+    unistd,
+};
 use serde_derive::{Deserialize, Serialize};
-use tracing::{debug, error, instrument, span, trace, warn, Level};
-
-use super::{consts, tty};
+use tracing::{debug, error, info, instrument, span, trace, warn, Level};
 
-const JOIN_POLL_DUR: time::Duration = time::Duration::from_millis(100);
-const JOIN_HANGUP_DUR: time::Duration = time::Duration::from_millis(300);
+use super::{compression, consts, predict, test_hooks, tty};
 
 /// ConnectHeader is the blob of metadata that a client transmits when it
 /// first connections. It uses an enum to allow different connection types
 /// to be initiated on the same socket. The ConnectHeader is always prefixed
 /// with a 4 byte little endian unsigned word to indicate length.
+///
+/// Variants are encoded by their index and there is no version handshake,
+/// so new variants must only ever be added at the end. Otherwise a new
+/// client talking to a daemon that is still running an old binary could
+/// have its request read as some other request entirely.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ConnectHeader {
     /// Attach to the named session indicated by the given header.
@@ -58,6 +70,88 @@ pub enum ConnectHeader {
     /// A message to request that a list of running
     /// sessions get killed.
     Kill(KillRequest),
+    /// A message to show on every attached session.
+    ///
+    /// Responds with a BroadcastReply.
+    Broadcast(BroadcastRequest),
+    /// A request for the daemon to shut down.
+    ///
+    /// Responds with a StopReply, then exits once it has finished
+    /// cleaning up, which closes the connection.
+    Stop(StopRequest),
+    /// Report on the daemon itself rather than any one session.
+    ///
+    /// Responds with a StatusReply.
+    Status,
+    /// Attach to a session like Attach, but with the client's input
+    /// sent as a stream of chunks rather than raw bytes. Plain Attach
+    /// is still served for clients that predate chunked input, and a
+    /// daemon that predates it rejects this variant outright rather
+    /// than feeding chunk headers to the shell.
+    ///
+    /// Responds with a FramedAttachReplyHeader.
+    FramedAttach(FramedAttachHeader),
+    /// List all of the currently active sessions like List, but with
+    /// more detail about each one. A daemon that predates it hangs up
+    /// rather than replying, so clients fall back to a plain List.
+    ///
+    /// Responds with a ListV2Reply.
+    ListV2,
+}
+
+/// StopRequest represents a request to shut down the daemon.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StopRequest {
+    /// Kill the shells of all the sessions rather than leaving
+    /// them to get hung up on when the daemon exits.
+    pub kill_sessions: bool,
+    /// Don't stop if there are any sessions.
+    pub refuse_if_sessions: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StopReply {
+    /// The pid of the daemon.
+    pub pid: i32,
+    pub status: StopStatus,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum StopStatus {
+    /// The daemon is shutting down, taking the given sessions
+    /// with it.
+    Stopping { sessions: Vec<String> },
+    /// The daemon refused to stop because it still has the
+    /// given sessions.
+    SessionsRunning { sessions: Vec<String> },
+}
+
+/// BroadcastRequest represents a request to show a
+/// message to everyone attached to a session.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BroadcastRequest {
+    /// The message to show
+    pub message: String,
+    /// How to show the message
+    pub mode: BroadcastMode,
+}
+
+/// BroadcastMode picks how a broadcast message gets
+/// displayed on the attached terminals.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BroadcastMode {
+    /// Draw the message as a banner over the top line of the
+    /// terminal. The shell's next redraw of that line clears it.
+    Banner,
+    /// Show the message in the scrollback pager, handing
+    /// the terminal back to the shell once it is dismissed.
+    Pager,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BroadcastReply {
+    /// The sessions the message was delivered to
+    pub sessions: Vec<String>,
 }
 
 /// KillRequest represents a request to kill
@@ -105,18 +199,28 @@ pub struct SessionMessageRequest {
 /// a running session.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum SessionMessageRequestPayload {
-    /// Resize a named session's pty. Generated when
-    /// a `shpool attach` process receives a SIGWINCH.
+    /// Resize a named session's pty.
     Resize(ResizeRequest),
     /// Detach the given session. Generated internally
     /// by the server from a batch detach request.
     Detach,
+    /// Open the session's scrollback in a pager on the
+    /// attached terminal. Generated by `shpool scrollback`.
+    Scrollback(ScrollbackRequest),
+}
+
+/// ScrollbackRequest indicates which part of a session's
+/// scrollback to display.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ScrollbackRequest {
+    /// Only show the output of the most recent command rather than
+    /// the whole scrollback.
+    pub last_command: bool,
 }
 
 /// ResizeRequest resizes the pty for a given named session.
-/// We use an out-of-band request rather than doing this
-/// in the input stream because we don't want to have to
-/// introduce a framing protocol for the input stream.
+/// `shpool attach` sends resizes in-band as Resize chunks
+/// instead, this is for resizing a session from elsewhere.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ResizeRequest {
     /// The size of the client's tty
@@ -134,6 +238,8 @@ pub enum SessionMessageReply {
     Resize(ResizeReply),
     /// The response to a detach message
     Detach(SessionMessageDetachReply),
+    /// The response to a scrollback message
+    Scrollback(ScrollbackReply),
 }
 
 /// A reply to a detach message
@@ -148,6 +254,14 @@ pub enum ResizeReply {
     Ok,
 }
 
+/// A reply to a scrollback message
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum ScrollbackReply {
+    /// The request was handed off to the attached client's
+    /// session thread, which will launch the pager.
+    Ok,
+}
+
 /// AttachHeader is the blob of metadata that a client transmits when it
 /// first dials into the shpool daemon indicating which shell it wants
 /// to attach to.
@@ -187,12 +301,84 @@ pub struct AttachReplyHeader {
     pub status: AttachStatus,
 }
 
+/// FramedAttachHeader is sent in place of an AttachHeader by clients
+/// which send their input as chunks. It can carry things that the
+/// plain AttachHeader has no room for without breaking older daemons.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct FramedAttachHeader {
+    pub attach: AttachHeader,
+    /// The compression algorithms that the client is willing to use for
+    /// the shell's output, in order of preference. Empty if the client
+    /// wants its output uncompressed.
+    pub compression: Vec<Compression>,
+}
+
+/// FramedAttachReplyHeader is the reply to a FramedAttachHeader.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FramedAttachReplyHeader {
+    pub status: AttachStatus,
+    /// The compression algorithm the daemon picked from the ones the
+    /// client offered, if any. All output after the header that would
+    /// otherwise be sent as Data chunks is sent as CompressedData chunks
+    /// instead.
+    pub compression: Option<Compression>,
+}
+
+/// Compression is an algorithm that can be used to compress the output
+/// sent to an attached client. Each connection uses a single streaming
+/// compression context, so later chunks get to refer back to earlier
+/// ones, which is where most of the win on terminal output comes from.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Zstd,
+    Deflate,
+}
+
 /// ListReply is contains a list of active sessions to be displayed to the user.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ListReply {
     pub sessions: Vec<Session>,
 }
 
+/// ListV2Reply is the reply to a ListV2. Unlike ListReply, which older
+/// clients have to keep being able to parse, it has room for details
+/// about each session that a plain Session has no field for.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListV2Reply {
+    pub sessions: Vec<SessionV2>,
+}
+
+/// StatusReply describes the daemon process.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StatusReply {
+    pub pid: i32,
+    /// The version of shpool that the daemon is running.
+    pub version: String,
+    pub started_at_unix_ms: i64,
+    /// The config file the daemon loaded, if there was one.
+    pub config_file: Option<String>,
+    pub config_loaded_at_unix_ms: i64,
+    /// The control sockets the daemon is listening on.
+    pub sockets: Vec<PathBuf>,
+    /// Set if the daemon got its socket from systemd socket activation.
+    pub systemd_activated: bool,
+    pub sessions: usize,
+    pub attached_sessions: usize,
+    /// The number of threads in the daemon process, if known.
+    pub threads: Option<usize>,
+    /// The sessions that will be killed when their ttl runs out,
+    /// soonest first.
+    pub ttl_queue: Vec<TtlQueueEntry>,
+}
+
+/// TtlQueueEntry describes a session waiting for its ttl to run out.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TtlQueueEntry {
+    pub session: String,
+    pub expires_in_ms: u64,
+}
+
 /// Session describes an active session.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Session {
@@ -201,6 +387,29 @@ pub struct Session {
     pub status: SessionStatus,
 }
 
+/// SessionV2 describes an active session in a ListV2Reply.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionV2 {
+    pub session: Session,
+    /// The command line of the command currently running in the
+    /// session. Only known if the shell emits semantic prompt marks.
+    pub current_command: Option<String>,
+    /// The exit status of the most recently finished command. Only
+    /// known if the shell emits semantic prompt marks.
+    pub last_exit_status: Option<i32>,
+    /// Set if the session rang the bell while detached.
+    pub bell: bool,
+    /// Set if the session produced output after being idle while
+    /// detached.
+    pub activity: bool,
+    /// How much memory the session's cgroup is using. Only known if
+    /// sessions get cgroups of their own.
+    pub memory_bytes: Option<u64>,
+    /// How much cpu time the session's cgroup has used. Only known if
+    /// sessions get cgroups of their own.
+    pub cpu_usage_usec: Option<u64>,
+}
+
 /// Indicates if a shpool session currently has a client attached.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum SessionStatus {
@@ -244,11 +453,14 @@ pub enum AttachStatus {
 }
 
 /// ChunkKind is a tag that indicates what type of frame is being transmitted
-/// through the socket.
+/// through the socket. Once a client has attached with a FramedAttach, both
+/// directions of the connection are a stream of chunks. A client that
+/// attached with a plain Attach sends its input as raw bytes instead.
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum ChunkKind {
     /// After the kind tag, the chunk will have a 4 byte little endian length
-    /// prefix followed by the actual data.
+    /// prefix followed by the actual data. Shell output when sent by the
+    /// daemon, user input when sent by the client.
     Data = 0,
     /// An empty chunk sent so that the daemon can check to make sure the attach
     /// process is still listening.
@@ -257,6 +469,28 @@ pub enum ChunkKind {
     /// have exactly 4 bytes of data, which will contain a little endian
     /// code indicating the child's exit status.
     ExitStatus = 2,
+    /// The client's tty has changed size. The data is the new size, see
+    /// `encode_size`. Sent by the client.
+    Resize = 3,
+    /// The client wants to detach from the session. Empty, sent by the
+    /// client.
+    Detach = 4,
+    /// Asks the daemon to send back a Pong with the same data, so that
+    /// the client can see how long a round trip takes. Sent by the client.
+    Ping = 5,
+    /// The answer to a Ping. Sent by the daemon.
+    Pong = 6,
+    /// Text to paste into the shell. Unlike Data, it is not scanned for
+    /// keybindings and is wrapped in bracketed paste markers if the shell
+    /// has asked for them. Sent by the client.
+    Clipboard = 7,
+    /// The client's terminal has gained or lost focus. The data is a single
+    /// byte, 1 for focus in and 0 for focus out. Sent by the client.
+    Focus = 8,
+    /// Shell output compressed with the algorithm negotiated in the
+    /// attach reply header. Chunks must be decompressed in order, since
+    /// they share a single compression stream. Sent by the daemon.
+    CompressedData = 9,
 }
 
 impl TryFrom<u8> for ChunkKind {
@@ -267,12 +501,20 @@ impl TryFrom<u8> for ChunkKind {
             0 => Ok(ChunkKind::Data),
             1 => Ok(ChunkKind::Heartbeat),
             2 => Ok(ChunkKind::ExitStatus),
+            3 => Ok(ChunkKind::Resize),
+            4 => Ok(ChunkKind::Detach),
+            5 => Ok(ChunkKind::Ping),
+            6 => Ok(ChunkKind::Pong),
+            7 => Ok(ChunkKind::Clipboard),
+            8 => Ok(ChunkKind::Focus),
+            9 => Ok(ChunkKind::CompressedData),
             _ => Err(anyhow!("unknown ChunkKind {}", v)),
         }
     }
 }
 
-/// Chunk represents of a chunk of data in the output stream
+/// Chunk represents of a chunk of data in either direction of an
+/// attached connection.
 ///
 /// format:
 ///
@@ -333,6 +575,95 @@ impl<'data> Chunk<'data> {
     }
 }
 
+/// ChunkDecoder pulls whole chunks out of a stream that might hand them
+/// over a piece at a time, as a non-blocking socket does. Whatever has
+/// been read but not yet decoded stays with the decoder, so it has to
+/// move along with the stream if something else takes over reading it.
+#[derive(Debug, Default)]
+pub struct ChunkDecoder {
+    buf: Vec<u8>,
+    /// The offset of the first byte in buf which has not been decoded.
+    start: usize,
+    /// Set for clients that send their input as raw bytes, in which case
+    /// whatever has been fed in comes back out as a Data chunk.
+    raw: bool,
+}
+
+impl ChunkDecoder {
+    /// A decoder for a client that attached with a plain Attach, and so
+    /// sends raw input bytes rather than chunks.
+    pub fn raw() -> Self {
+        ChunkDecoder { raw: true, ..ChunkDecoder::default() }
+    }
+
+    /// Add some bytes read off of the stream.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        if self.start > 0 {
+            self.buf.drain(..self.start);
+            self.start = 0;
+        }
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Decode the next chunk, or return None if we don't have all of it
+    /// yet.
+    pub fn next_chunk(&mut self) -> anyhow::Result<Option<Chunk<'_>>> {
+        let pending = &self.buf[self.start..];
+        let Some(&kind) = pending.first() else {
+            return Ok(None);
+        };
+        if self.raw {
+            let data_start = self.start;
+            self.start += pending.len().min(consts::BUF_SIZE);
+            let buf = &self.buf[data_start..self.start];
+            return Ok(Some(Chunk { kind: ChunkKind::Data, buf }));
+        }
+        let kind = ChunkKind::try_from(kind)?;
+        let (header_len, len) = if let ChunkKind::ExitStatus = kind {
+            (1, 4)
+        } else {
+            if pending.len() < 5 {
+                return Ok(None);
+            }
+            (5, LittleEndian::read_u32(&pending[1..5]) as usize)
+        };
+        if len > consts::BUF_SIZE {
+            return Err(anyhow!(
+                "chunk of size {} exceeds size limit of {} bytes",
+                len,
+                consts::BUF_SIZE
+            ));
+        }
+        if pending.len() < header_len + len {
+            return Ok(None);
+        }
+
+        let data_start = self.start + header_len;
+        self.start = data_start + len;
+        Ok(Some(Chunk { kind, buf: &self.buf[data_start..self.start] }))
+    }
+}
+
+/// Encode a tty size as the data for a Resize chunk: the rows then
+/// the cols, each as a little endian 2 byte word.
+pub fn encode_size(size: &tty::Size) -> [u8; 4] {
+    let mut buf = [0; 4];
+    LittleEndian::write_u16(&mut buf[..2], size.rows);
+    LittleEndian::write_u16(&mut buf[2..], size.cols);
+    buf
+}
+
+/// Decode the data of a Resize chunk.
+pub fn decode_size(buf: &[u8]) -> anyhow::Result<tty::Size> {
+    if buf.len() != 4 {
+        return Err(anyhow!("resize chunk has {} bytes, want 4", buf.len()));
+    }
+    Ok(tty::Size {
+        rows: LittleEndian::read_u16(&buf[..2]),
+        cols: LittleEndian::read_u16(&buf[2..]),
+    })
+}
+
 pub struct Client {
     pub stream: UnixStream,
 }
@@ -362,136 +693,175 @@ impl Client {
     /// socket and back again. It is the main loop of
     /// `shpool attach`.
     ///
+    /// compression is the algorithm the daemon picked in its attach
+    /// reply, if any, which it uses for the shell's output. If there
+    /// is a predictor, it gets to draw its guesses about how the shell
+    /// is going to echo the user's input.
+    ///
     /// Return value: the exit status that `shpool attach` should
     /// exit with.
     #[instrument(skip_all)]
-    pub fn pipe_bytes(self) -> anyhow::Result<i32> {
-        let tty_guard = tty::set_attach_flags()?;
+    pub fn pipe_bytes(
+        self,
+        compression: Option<Compression>,
+        predictor: Option<predict::Predictor>,
+    ) -> anyhow::Result<i32> {
+        let _tty_guard = tty::set_attach_flags()?;
 
         let mut read_client_stream = self.stream.try_clone().context("cloning read stream")?;
         let mut write_client_stream = self.stream.try_clone().context("cloning read stream")?;
 
+        // There is no way to interrupt a blocking read on stdin, so the
+        // stdin thread polls on this eventfd alongside stdin, and we arm
+        // it to tell the thread to stop once the daemon side is done.
+        let stop = EventFd::from_value_and_flags(0, EfdFlags::EFD_CLOEXEC)
+            .context("creating stdin stop eventfd")?;
+        let (done_tx, done_rx) = crossbeam_channel::bounded(2);
+
+        // Resizes get sent in-band by the stdin thread, so have SIGWINCH
+        // wake it up.
+        let (winch_rx, winch_tx) = UnixStream::pair().context("creating sigwinch pipe")?;
+        winch_rx.set_nonblocking(true).context("making sigwinch pipe non-blocking")?;
+        let winch_id =
+            signal_hook::low_level::pipe::register(signal_hook::consts::SIGWINCH, winch_tx)
+                .context("registering sigwinch handler")?;
+        let _winch_guard = SigGuard(winch_id);
+
+        // Both threads draw on the terminal through the predictor, so
+        // they always lock it before locking stdout.
+        let predictor = predictor.map(Mutex::new);
+
         let exit_status = AtomicI32::new(1);
         thread::scope(|s| {
             // stdin -> sock
-            let stdin_to_sock_h = s.spawn(|| -> anyhow::Result<()> {
-                let _s = span!(Level::INFO, "stdin->sock").entered();
-                let mut stdin = std::io::stdin().lock();
-                let mut buf = vec![0; consts::BUF_SIZE];
-
-                loop {
-                    let nread = stdin.read(&mut buf).context("reading stdin from user")?;
-                    if nread == 0 {
-                        continue;
-                    }
-                    debug!("read {} bytes", nread);
-
-                    let to_write = &buf[..nread];
-                    trace!("created to_write='{}'", String::from_utf8_lossy(to_write));
-
-                    write_client_stream.write_all(to_write)?;
-                    write_client_stream.flush().context("flushing client")?;
+            let stdin_to_sock_h = s.spawn({
+                let done_tx = done_tx.clone();
+                let stop = &stop;
+                let predictor = predictor.as_ref();
+                move || -> anyhow::Result<()> {
+                    let _s = span!(Level::INFO, "stdin->sock").entered();
+                    let res = stdin_to_sock(stop, &winch_rx, predictor, &mut write_client_stream);
+                    let _ = done_tx.send(Side::StdinToSock);
+                    res
                 }
             });
 
             // sock -> stdout
-            let sock_to_stdout_h = s.spawn(|| -> anyhow::Result<()> {
-                let _s = span!(Level::INFO, "sock->stdout").entered();
-
-                let mut stdout = std::io::stdout().lock();
-                let mut buf = vec![0; consts::BUF_SIZE];
-
-                loop {
-                    let chunk = match Chunk::read_into(&mut read_client_stream, &mut buf) {
-                        Ok(c) => c,
-                        Err(err) => {
-                            error!("reading chunk: {:?}", err);
-                            return Err(err);
-                        }
-                    };
-
-                    if !chunk.buf.is_empty() {
-                        debug!(
-                            "chunk='{}' kind={:?} len={}",
-                            String::from_utf8_lossy(chunk.buf),
-                            chunk.kind,
-                            chunk.buf.len()
-                        );
-                    }
+            let sock_to_stdout_h = s.spawn({
+                let done_tx = done_tx.clone();
+                let exit_status = &exit_status;
+                let predictor = predictor.as_ref();
+                move || -> anyhow::Result<()> {
+                    let _s = span!(Level::INFO, "sock->stdout").entered();
+                    let res = (|| -> anyhow::Result<()> {
+                        let stdout = std::io::stdout();
+                        let mut buf = vec![0; consts::BUF_SIZE];
+                        let mut decompressor =
+                            compression.map(compression::Decompressor::new).transpose()?;
+                        let mut plain = vec![];
 
-                    match chunk.kind {
-                        ChunkKind::Heartbeat => {
-                            trace!("got heartbeat chunk");
-                        }
-                        ChunkKind::Data => {
-                            stdout.write_all(chunk.buf).context("writing chunk to stdout")?;
-
-                            if let Err(e) = stdout.flush() {
-                                if e.kind() == std::io::ErrorKind::WouldBlock {
-                                    // If the fd is busy, we are likely just getting
-                                    // flooded with output and don't need to worry about
-                                    // flushing every last byte. Flushing is really
-                                    // about interactive situations where we want to
-                                    // see echoed bytes immediately.
-                                    continue;
+                        loop {
+                            let chunk = match Chunk::read_into(&mut read_client_stream, &mut buf) {
+                                Ok(c) => c,
+                                Err(err) if is_hangup(&err) => {
+                                    // The daemon closes the connection on
+                                    // detach, kill and shell exit, so this
+                                    // is the normal way for us to finish.
+                                    info!("daemon hung up");
+                                    return Ok(());
+                                }
+                                Err(err) => {
+                                    error!("reading chunk: {:?}", err);
+                                    return Err(err);
+                                }
+                            };
+
+                            if !chunk.buf.is_empty() {
+                                debug!(
+                                    "chunk='{}' kind={:?} len={}",
+                                    String::from_utf8_lossy(chunk.buf),
+                                    chunk.kind,
+                                    chunk.buf.len()
+                                );
+                            }
+
+                            match chunk.kind {
+                                ChunkKind::Heartbeat => {
+                                    trace!("got heartbeat chunk");
+                                }
+                                ChunkKind::Pong => {
+                                    trace!("got pong chunk");
+                                    if let Some(predictor) = predictor {
+                                        predictor.lock().unwrap().pong(chunk.buf);
+                                    }
+                                }
+                                ChunkKind::Data | ChunkKind::CompressedData => {
+                                    let data = if chunk.kind == ChunkKind::CompressedData {
+                                        let decompressor = decompressor.as_mut().ok_or(anyhow!(
+                                            "compressed data without negotiated compression"
+                                        ))?;
+                                        plain.clear();
+                                        decompressor
+                                            .decompress(chunk.buf, &mut plain)
+                                            .context("decompressing chunk")?;
+                                        &plain[..]
+                                    } else {
+                                        chunk.buf
+                                    };
+                                    let mut predictor = predictor.map(|p| p.lock().unwrap());
+                                    let mut stdout = stdout.lock();
+                                    match predictor.as_mut() {
+                                        Some(predictor) => predictor.output(data, &mut stdout),
+                                        None => stdout.write_all(data),
+                                    }
+                                    .context("writing chunk to stdout")?;
+
+                                    if let Err(e) = stdout.flush() {
+                                        if e.kind() == std::io::ErrorKind::WouldBlock {
+                                            // If the fd is busy, we are likely just getting
+                                            // flooded with output and don't need to worry about
+                                            // flushing every last byte. Flushing is really
+                                            // about interactive situations where we want to
+                                            // see echoed bytes immediately.
+                                            continue;
+                                        }
+                                    }
+                                    debug!("flushed stdout");
+                                }
+                                ChunkKind::ExitStatus => {
+                                    let mut status_reader = io::Cursor::new(chunk.buf);
+                                    exit_status.store(
+                                        status_reader.read_i32::<LittleEndian>().context(
+                                            "reading exit status from exit status chunk",
+                                        )?,
+                                        Ordering::Release,
+                                    );
+                                }
+                                kind => {
+                                    warn!("unexpected chunk kind from daemon: {:?}", kind);
                                 }
                             }
-                            debug!("flushed stdout");
-                        }
-                        ChunkKind::ExitStatus => {
-                            let mut status_reader = io::Cursor::new(chunk.buf);
-                            exit_status.store(
-                                status_reader
-                                    .read_i32::<LittleEndian>()
-                                    .context("reading exit status from exit status chunk")?,
-                                Ordering::Release,
-                            );
                         }
-                    }
+                    })();
+                    let _ = done_tx.send(Side::SockToStdout);
+                    res
                 }
             });
 
-            loop {
-                let mut nfinished_threads = 0;
-                if stdin_to_sock_h.is_finished() {
-                    nfinished_threads += 1;
-                }
-                if sock_to_stdout_h.is_finished() {
-                    nfinished_threads += 1;
+            // Whichever side finishes first, wake the other one up so
+            // that it can finish too. Both threads always report in,
+            // so this never blocks for longer than the connection lasts.
+            match done_rx.recv() {
+                Ok(Side::SockToStdout) => {
+                    stop.arm().context("stopping stdin thread")?;
                 }
-                if nfinished_threads > 0 {
-                    if nfinished_threads < 2 {
-                        thread::sleep(JOIN_HANGUP_DUR);
-                        nfinished_threads = 0;
-                        if stdin_to_sock_h.is_finished() {
-                            nfinished_threads += 1;
-                        }
-                        if sock_to_stdout_h.is_finished() {
-                            nfinished_threads += 1;
-                        }
-                        if nfinished_threads < 2 {
-                            // If one of the worker threads is done and the
-                            // other is not exiting, we are likely blocked on
-                            // some IO. Fortunately, since there isn't much else
-                            // going on in the client process and the thing to do
-                            // is to shut down at this point, we can resolve this
-                            // by just hard-exiting the whole process. This allows
-                            // us to use simple blocking IO.
-                            warn!(
-                                "exiting due to a stuck IO thread stdin_to_sock_finished={} sock_to_stdout_finished={}",
-                                stdin_to_sock_h.is_finished(),
-                                sock_to_stdout_h.is_finished()
-                            );
-                            // make sure that we restore the tty flags on the input
-                            // tty before exiting the process.
-                            drop(tty_guard);
-
-                            std::process::exit(exit_status.load(Ordering::Acquire));
-                        }
+                Ok(Side::StdinToSock) => {
+                    // Unblocks the read in the sock->stdout thread.
+                    if let Err(e) = self.stream.shutdown(std::net::Shutdown::Both) {
+                        warn!("shutting down client stream: {:?}", e);
                     }
-                    break;
                 }
-                thread::sleep(JOIN_POLL_DUR);
+                Err(_) => return Err(anyhow!("io threads hung up without reporting")),
             }
 
             match stdin_to_sock_h.join() {
@@ -502,12 +872,189 @@ impl Client {
                 Ok(v) => v?,
                 Err(panic_err) => std::panic::resume_unwind(panic_err),
             }
+            test_hooks::emit("attach-io-threads-joined");
 
             Ok(exit_status.load(Ordering::Acquire))
         })
     }
 }
 
+/// Forward input and resizes to the daemon until told to stop.
+fn stdin_to_sock(
+    stop: &EventFd,
+    winch: &UnixStream,
+    predictor: Option<&Mutex<predict::Predictor>>,
+    stream: &mut UnixStream,
+) -> anyhow::Result<()> {
+    let stdin = std::io::stdin();
+    let mut buf = vec![0; consts::BUF_SIZE];
+    // Each chunk gets encoded here first so that it goes out in a single
+    // write, which saves the daemon from seeing it in pieces.
+    let mut out = Vec::with_capacity(consts::BUF_SIZE + 5);
+    let mut stdin_open = true;
+
+    loop {
+        let mut poll_fds = [
+            poll::PollFd::new(stop.as_fd(), poll::PollFlags::POLLIN),
+            poll::PollFd::new(winch.as_fd(), poll::PollFlags::POLLIN),
+            poll::PollFd::new(stdin.as_fd(), poll::PollFlags::POLLIN),
+        ];
+        let nfds = if stdin_open { 3 } else { 2 };
+        // Wake up in time to give up on any guesses that the shell
+        // never confirmed.
+        let timeout = match predictor.and_then(|p| p.lock().unwrap().deadline()) {
+            Some(deadline) => {
+                let wait = deadline.saturating_duration_since(Instant::now());
+                // round up so we don't wake up just short of the deadline
+                poll::PollTimeout::try_from(wait + Duration::from_millis(1))
+                    .unwrap_or(poll::PollTimeout::MAX)
+            }
+            None => poll::PollTimeout::NONE,
+        };
+        match poll::poll(&mut poll_fds[..nfds], timeout) {
+            Ok(_) => {}
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e).context("polling stdin"),
+        }
+        if let Some(predictor) = predictor {
+            if let Err(e) = predictor.lock().unwrap().expire(&mut std::io::stdout().lock()) {
+                warn!("expiring predictions: {:?}", e);
+            }
+        }
+        if poll_fds[0].any().unwrap_or(false) {
+            debug!("told to stop");
+            return Ok(());
+        }
+
+        if poll_fds[1].any().unwrap_or(false) {
+            // Several signals might have piled up, but we only care
+            // about the current size.
+            while let Ok(n) = (&*winch).read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+            }
+            match tty::Size::from_fd(consts::STDIN_FD) {
+                Ok(tty_size) => {
+                    info!("sending resize (rows={}, cols={})", tty_size.rows, tty_size.cols);
+                    write_chunk(stream, &mut out, ChunkKind::Resize, &encode_size(&tty_size))?;
+                    if let Some(predictor) = predictor {
+                        let mut predictor = predictor.lock().unwrap();
+                        if let Err(e) = predictor.resize(&tty_size, &mut std::io::stdout().lock()) {
+                            warn!("resizing predictor: {:?}", e);
+                        }
+                    }
+                }
+                Err(e) => warn!("getting tty size, not resizing: {:?}", e),
+            }
+        }
+
+        if !stdin_open || !poll_fds[2].any().unwrap_or(false) {
+            continue;
+        }
+        let nread = match unistd::read(consts::STDIN_FD, &mut buf) {
+            Ok(n) => n,
+            Err(Errno::EINTR | Errno::EAGAIN) => continue,
+            Err(e) => return Err(e).context("reading stdin from user"),
+        };
+        if nread == 0 {
+            // Keep the connection going so that output still makes
+            // it to the user, we just have nothing more to send.
+            info!("stdin closed");
+            stdin_open = false;
+            continue;
+        }
+        debug!("read {} bytes", nread);
+
+        // Focus reports get sent as focus chunks so that the daemon can
+        // drop them if the shell has not asked for them, which happens
+        // when a program exits without turning focus reporting back off.
+        let mut rest = &buf[..nread];
+        while !rest.is_empty() {
+            let (to_write, focused) = match find_focus_report(rest) {
+                Some((i, focused)) => {
+                    let to_write = &rest[..i];
+                    rest = &rest[i + FOCUS_REPORT_LEN..];
+                    (to_write, Some(focused))
+                }
+                None => (std::mem::take(&mut rest), None),
+            };
+
+            if !to_write.is_empty() {
+                trace!("created to_write='{}'", String::from_utf8_lossy(to_write));
+                write_chunk(stream, &mut out, ChunkKind::Data, to_write)?;
+
+                if let Some(predictor) = predictor {
+                    let mut predictor = predictor.lock().unwrap();
+                    if let Some(payload) = predictor.ping() {
+                        write_chunk(stream, &mut out, ChunkKind::Ping, &payload)?;
+                    }
+                    if let Err(e) = predictor.input(to_write, &mut std::io::stdout().lock()) {
+                        warn!("drawing predictions: {:?}", e);
+                    }
+                }
+            }
+            if let Some(focused) = focused {
+                debug!("sending focus={}", focused);
+                write_chunk(stream, &mut out, ChunkKind::Focus, &[focused as u8])?;
+            }
+        }
+    }
+}
+
+/// The length of the focus in (`ESC [ I`) and out (`ESC [ O`) reports
+/// that a terminal sends when focus reporting is on.
+const FOCUS_REPORT_LEN: usize = 3;
+
+/// Find the first focus report in buf, returning where it starts and
+/// whether it reports gaining focus. Terminals write each report in
+/// one go, so we don't bother looking for reports split across reads.
+fn find_focus_report(buf: &[u8]) -> Option<(usize, bool)> {
+    buf.windows(FOCUS_REPORT_LEN).enumerate().find_map(|(i, w)| match w {
+        b"\x1b[I" => Some((i, true)),
+        b"\x1b[O" => Some((i, false)),
+        _ => None,
+    })
+}
+
+/// Write a single chunk to the daemon, using out as scratch space.
+fn write_chunk(
+    stream: &mut UnixStream,
+    out: &mut Vec<u8>,
+    kind: ChunkKind,
+    buf: &[u8],
+) -> anyhow::Result<()> {
+    out.clear();
+    Chunk { kind, buf }.write_to(out).context("encoding chunk")?;
+    stream.write_all(out).context("writing chunk to daemon")?;
+    stream.flush().context("flushing client")
+}
+
+/// Unregisters a signal handler when dropped.
+struct SigGuard(signal_hook::SigId);
+
+impl std::ops::Drop for SigGuard {
+    fn drop(&mut self) {
+        signal_hook::low_level::unregister(self.0);
+    }
+}
+
+/// The two IO threads that pipe_bytes runs.
+#[derive(Debug)]
+enum Side {
+    StdinToSock,
+    SockToStdout,
+}
+
+/// Check if an error from reading a chunk means that the daemon has
+/// closed the connection.
+fn is_hangup(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<io::Error>().map(|e| e.kind()),
+        Some(io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset)
+    )
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -531,4 +1078,131 @@ mod test {
             assert_eq!(c, round_tripped);
         }
     }
+
+    #[test]
+    fn connect_header_indices() -> anyhow::Result<()> {
+        // Older daemons must keep reading these the way they always have.
+        let cases = [
+            (ConnectHeader::List, 1),
+            (ConnectHeader::Detach(DetachRequest { sessions: vec![] }), 3),
+            (ConnectHeader::Kill(KillRequest { sessions: vec![] }), 4),
+            (ConnectHeader::Status, 7),
+            (ConnectHeader::FramedAttach(FramedAttachHeader::default()), 8),
+            (ConnectHeader::ListV2, 9),
+        ];
+        for (header, index) in cases {
+            let encoded = bincode::serialize(&header)?;
+            assert_eq!(&encoded[..4], &u32::to_le_bytes(index), "header={:?}", header);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn attach_reply_shape() -> anyhow::Result<()> {
+        // Older clients read nothing past the status, so a plain attach
+        // reply must not grow any new fields.
+        let reply = AttachReplyHeader { status: AttachStatus::Busy };
+        assert_eq!(bincode::serialize(&reply)?, bincode::serialize(&AttachStatus::Busy)?);
+        Ok(())
+    }
+
+    #[test]
+    fn list_reply_shape() -> anyhow::Result<()> {
+        // Older clients parse list replies field by field, so a Session
+        // must not grow any new fields.
+        let reply = ListReply {
+            sessions: vec![Session {
+                name: String::from("main"),
+                started_at_unix_ms: 7,
+                status: SessionStatus::Disconnected,
+            }],
+        };
+        let mut expected = vec![];
+        expected.extend(1u64.to_le_bytes());
+        expected.extend(4u64.to_le_bytes());
+        expected.extend(b"main");
+        expected.extend(7i64.to_le_bytes());
+        expected.extend(1u32.to_le_bytes());
+        assert_eq!(bincode::serialize(&reply)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn focus_reports() {
+        assert_eq!(find_focus_report(b"\x1b[I"), Some((0, true)));
+        assert_eq!(find_focus_report(b"ls\x1b[O\x1b[I"), Some((2, false)));
+        assert_eq!(find_focus_report(b"ls -l\r"), None);
+        // arrow keys and a lone escape are just input
+        assert_eq!(find_focus_report(b"\x1b[A\x1b[B\x1b"), None);
+        assert_eq!(find_focus_report(b"\x1b["), None);
+    }
+
+    #[test]
+    fn chunk_decoder_partial() -> anyhow::Result<()> {
+        let size = encode_size(&tty::Size { rows: 24, cols: 80 });
+        let cases = [
+            Chunk { kind: ChunkKind::Data, buf: b"hello" },
+            Chunk { kind: ChunkKind::Resize, buf: &size },
+            Chunk { kind: ChunkKind::Detach, buf: &[] },
+            Chunk { kind: ChunkKind::ExitStatus, buf: &[1, 0, 0, 0] },
+            Chunk { kind: ChunkKind::Focus, buf: &[1] },
+        ];
+        let mut encoded = vec![];
+        for c in cases.iter() {
+            c.write_to(&mut encoded)?;
+        }
+
+        // However the stream gets cut up, we should get the same
+        // chunks back out.
+        for piece_len in 1..encoded.len() + 1 {
+            let mut decoder = ChunkDecoder::default();
+            let mut decoded = vec![];
+            for piece in encoded.chunks(piece_len) {
+                decoder.feed(piece);
+                while let Some(chunk) = decoder.next_chunk()? {
+                    decoded.push((chunk.kind, Vec::from(chunk.buf)));
+                }
+            }
+            let want: Vec<_> = cases.iter().map(|c| (c.kind, Vec::from(c.buf))).collect();
+            assert_eq!(decoded, want, "piece_len={}", piece_len);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn chunk_decoder_bad_chunks() {
+        let mut decoder = ChunkDecoder::default();
+        decoder.feed(&[42]);
+        assert!(decoder.next_chunk().is_err());
+
+        let mut decoder = ChunkDecoder::default();
+        decoder.feed(&[ChunkKind::Data as u8]);
+        decoder.feed(&(consts::BUF_SIZE as u32 + 1).to_le_bytes());
+        assert!(decoder.next_chunk().is_err());
+    }
+
+    #[test]
+    fn chunk_decoder_raw() -> anyhow::Result<()> {
+        let mut decoder = ChunkDecoder::raw();
+        assert!(decoder.next_chunk()?.is_none());
+
+        // input that looks like a chunk header is still just input
+        decoder.feed(&[ChunkKind::Detach as u8, 0, 0]);
+        decoder.feed(b"ls\r");
+        let chunk = decoder.next_chunk()?.unwrap();
+        assert_eq!((chunk.kind, chunk.buf), (ChunkKind::Data, &b"\x04\x00\x00ls\r"[..]));
+        assert!(decoder.next_chunk()?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn size_round_trip() -> anyhow::Result<()> {
+        let size = decode_size(&encode_size(&tty::Size { rows: 300, cols: 7 }))?;
+        assert_eq!((size.rows, size.cols), (300, 7));
+        assert!(decode_size(&[1, 2, 3]).is_err());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,66 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{io, path::Path};
+
+use anyhow::{anyhow, Context};
+
+use super::{
+    common, protocol,
+    protocol::{
+        ConnectHeader, ScrollbackReply, ScrollbackRequest, SessionMessageReply,
+        SessionMessageRequest, SessionMessageRequestPayload,
+    },
+};
+
+pub fn run<P>(session: Option<String>, last_command: bool, socket: P) -> anyhow::Result<()>
+where
+    P: AsRef<Path>,
+{
+    let mut client = match protocol::Client::new(socket) {
+        Ok(c) => c,
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    let mut sessions: Vec<String> = session.into_iter().collect();
+    common::resolve_sessions(&mut sessions, "show scrollback for")?;
+    let session_name = sessions.pop().ok_or(anyhow!("no session"))?;
+
+    client
+        .write_connect_header(ConnectHeader::SessionMessage(SessionMessageRequest {
+            session_name: session_name.clone(),
+            payload: SessionMessageRequestPayload::Scrollback(ScrollbackRequest { last_command }),
+        }))
+        .context("writing scrollback request header")?;
+
+    let reply: SessionMessageReply = client.read_reply().context("reading reply")?;
+    match reply {
+        SessionMessageReply::Scrollback(ScrollbackReply::Ok) => Ok(()),
+        SessionMessageReply::NotFound => {
+            eprintln!("not found: {}", session_name);
+            Err(anyhow!("not found: {}", session_name))
+        }
+        SessionMessageReply::NotAttached => {
+            eprintln!("not attached: {}", session_name);
+            Err(anyhow!("not attached: {}", session_name))
+        }
+        reply => Err(anyhow!("unexpected reply: {:?}", reply)),
+    }
+}
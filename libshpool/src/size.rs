@@ -0,0 +1,86 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! A parser and formatter for byte sizes like 10M, as used by the
+  --log-max-size flag and the session cgroup memory limits.
+*/
+
+use anyhow::{anyhow, bail, Context};
+
+/// Parses a number of bytes with an optional K, M or G suffix
+/// (powers of 1024).
+pub fn parse(src: &str) -> anyhow::Result<u64> {
+    let src = src.trim();
+    let (digits, unit) = match src.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&src[..i], c.to_ascii_uppercase()),
+        _ => (src, 'B'),
+    };
+    let n = digits.parse::<u64>().context(format!("could not parse '{}' as a size", src))?;
+    let multiplier = match unit {
+        'B' => 1,
+        'K' => 1024,
+        'M' => 1024 * 1024,
+        'G' => 1024 * 1024 * 1024,
+        _ => bail!("unknown size unit '{}', expected one of K, M or G", unit),
+    };
+    n.checked_mul(multiplier).ok_or(anyhow!("size '{}' is too big", src))
+}
+
+/// Formats the size using the biggest unit that keeps it at or above
+/// one, with a single decimal place (e.g. 1.5G).
+pub fn format(bytes: u64) -> String {
+    for (unit, unit_bytes) in [('G', 1 << 30), ('M', 1 << 20), ('K', 1 << 10)] {
+        if bytes >= unit_bytes {
+            return format!("{:.1}{}", bytes as f64 / unit_bytes as f64, unit);
+        }
+    }
+    format!("{}B", bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parsing() {
+        let cases = vec![
+            ("1024", Some(1024)),
+            ("1k", Some(1024)),
+            ("10M", Some(10 * 1024 * 1024)),
+            ("2G", Some(2 * 1024 * 1024 * 1024)),
+            ("3b", Some(3)),
+            ("", None),
+            ("M", None),
+            ("10X", None),
+            ("-1K", None),
+        ];
+        for (src, want) in cases.into_iter() {
+            assert_eq!(parse(src).ok(), want, "src={:?}", src);
+        }
+    }
+
+    #[test]
+    fn formatting() {
+        let cases = vec![
+            (0, "0B"),
+            (1023, "1023B"),
+            (1024, "1.0K"),
+            (1536 * 1024, "1.5M"),
+            (4 * 1024 * 1024 * 1024, "4.0G"),
+        ];
+        for (bytes, want) in cases.into_iter() {
+            assert_eq!(format(bytes), want);
+        }
+    }
+}
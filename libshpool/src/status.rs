@@ -0,0 +1,85 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{io, path::PathBuf, time};
+
+use anyhow::Context;
+
+use super::{
+    duration, protocol,
+    protocol::{ConnectHeader, StatusReply},
+};
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+pub fn run(socket: PathBuf) -> anyhow::Result<()> {
+    let mut client = match protocol::Client::new(socket) {
+        Ok(c) => c,
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.write_connect_header(ConnectHeader::Status).context("sending status connect header")?;
+    let reply: StatusReply = client.read_reply().context("reading reply")?;
+
+    let started_at = from_unix_ms(reply.started_at_unix_ms);
+    let uptime = time::SystemTime::now().duration_since(started_at).unwrap_or_default();
+    let config_loaded_at = from_unix_ms(reply.config_loaded_at_unix_ms);
+
+    println!("pid:              {}", reply.pid);
+    if reply.version == VERSION {
+        println!("version:          {}", reply.version);
+    } else {
+        println!("version:          {} (client is {})", reply.version, VERSION);
+    }
+    println!(
+        "started at:       {} (up {})",
+        chrono::DateTime::<chrono::Utc>::from(started_at).to_rfc3339(),
+        duration::format(uptime)
+    );
+    println!(
+        "config file:      {} (loaded {})",
+        reply.config_file.as_deref().unwrap_or("none"),
+        chrono::DateTime::<chrono::Utc>::from(config_loaded_at).to_rfc3339(),
+    );
+    println!("sockets:          {}", reply.sockets.len());
+    for socket in reply.sockets.iter() {
+        println!("    {}", socket.display());
+    }
+    println!("systemd socket:   {}", if reply.systemd_activated { "yes" } else { "no" });
+    println!("sessions:         {} ({} attached)", reply.sessions, reply.attached_sessions);
+    println!(
+        "threads:          {}",
+        reply.threads.map(|n| n.to_string()).unwrap_or(String::from("unknown"))
+    );
+    println!("ttl queue:        {}", reply.ttl_queue.len());
+    for entry in reply.ttl_queue.iter() {
+        println!(
+            "    {}\texpires in {}",
+            entry.session,
+            duration::format(time::Duration::from_millis(entry.expires_in_ms))
+        );
+    }
+
+    Ok(())
+}
+
+fn from_unix_ms(ms: i64) -> time::SystemTime {
+    time::UNIX_EPOCH + time::Duration::from_millis(ms as u64)
+}
@@ -0,0 +1,81 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    io::{self, Read},
+    path::PathBuf,
+    time,
+};
+
+use anyhow::{anyhow, Context};
+
+use super::{
+    protocol,
+    protocol::{ConnectHeader, StopReply, StopRequest, StopStatus},
+};
+
+// How long to wait for the daemon to exit with --wait. Killing
+// sessions and running hook commands can take a little while.
+const WAIT_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+
+pub fn run(
+    wait: bool,
+    kill_sessions: bool,
+    refuse_if_sessions: bool,
+    socket: PathBuf,
+) -> anyhow::Result<()> {
+    let mut client = match protocol::Client::new(socket) {
+        Ok(c) => c,
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client
+        .write_connect_header(ConnectHeader::Stop(StopRequest {
+            kill_sessions,
+            refuse_if_sessions,
+        }))
+        .context("writing stop request header")?;
+    let reply: StopReply = client.read_reply().context("reading reply")?;
+
+    match reply.status {
+        StopStatus::SessionsRunning { sessions } => {
+            eprintln!("not stopping, the daemon still has sessions: {}", sessions.join(" "));
+            return Err(anyhow!("daemon has sessions: {}", sessions.join(" ")));
+        }
+        StopStatus::Stopping { sessions } => {
+            println!("stopping daemon (pid {})", reply.pid);
+            if !sessions.is_empty() {
+                let fate = if kill_sessions { "killing" } else { "abandoning" };
+                println!("{} sessions: {}", fate, sessions.join(" "));
+            }
+        }
+    }
+
+    if wait {
+        // The daemon never writes anything else, so the connection
+        // closing means the daemon has exited.
+        client.stream.set_read_timeout(Some(WAIT_TIMEOUT)).context("setting wait timeout")?;
+        let mut rest = vec![];
+        client.stream.read_to_end(&mut rest).context("waiting for the daemon to exit")?;
+        println!("daemon stopped");
+    }
+
+    Ok(())
+}
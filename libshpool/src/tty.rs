@@ -26,6 +26,7 @@ use nix::{
         termios,
         termios::{ControlFlags, InputFlags, LocalFlags, OutputFlags, SetArg},
     },
+    unistd,
     unistd::isatty,
 };
 use serde_derive::{Deserialize, Serialize};
@@ -36,6 +37,7 @@ use crate::consts;
 // see `man ioctl_tty` for info on these ioctl commands
 nix::ioctl_read_bad!(tiocgwinsz, libc::TIOCGWINSZ, libc::winsize);
 nix::ioctl_write_ptr_bad!(tiocswinsz, libc::TIOCSWINSZ, libc::winsize);
+nix::ioctl_write_int_bad!(tiocsctty, libc::TIOCSCTTY);
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Size {
@@ -80,6 +82,28 @@ pub fn disable_echo(fd: BorrowedFd<'_>) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Make the calling process, which must be freshly forked with the given
+/// pty as its stdio, lead a session of its own with the pty as its
+/// controlling terminal and itself in the foreground. Without this, job
+/// control does not work and tools like sudo which cache credentials per
+/// terminal session would share them between all the shells we spawn.
+pub fn make_controlling_terminal(fd: BorrowedFd<'_>) -> anyhow::Result<()> {
+    let pid = unistd::getpid();
+    // the pty crate already calls setsid() for us, but everything
+    // else here depends on it, so make sure
+    if unistd::getsid(None).context("getting session id")? != pid {
+        unistd::setsid().context("creating new session")?;
+    }
+
+    // Safety: the fd is borrowed, so it is live for the whole call.
+    unsafe {
+        tiocsctty(fd.as_raw_fd(), 0).context("setting controlling terminal")?;
+    }
+    unistd::tcsetpgrp(fd, pid).context("setting foreground process group")?;
+
+    Ok(())
+}
+
 pub fn set_attach_flags() -> anyhow::Result<AttachFlagsGuard<'static>> {
     // Safety: stdin is live for the whole program duration
     let fd = unsafe { BorrowedFd::borrow_raw(consts::STDIN_FD) };
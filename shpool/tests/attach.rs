@@ -417,6 +417,125 @@ fn output_flood() -> anyhow::Result<()> {
     })
 }
 
+#[test]
+#[timeout(30000)]
+fn flow_control_skip() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("flow_control_skip.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let mut waiter = daemon_proc.events.take().unwrap().waiter(["daemon-output-skipped"]);
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+
+        // Way more output than fits in the buffers between the daemon
+        // and us, all written while we are not reading any of it.
+        let done_file = daemon_proc.tmp_dir.join("flood-done");
+        attach_proc.run_cmd(&format!(
+            "for i in $(seq 1 5000); do printf '%05d%01000d\\n' $i 0; done && touch {} && echo fin$((1+1))",
+            done_file.display()
+        ))?;
+        waiter.wait_event("daemon-output-skipped")?;
+        while !done_file.exists() {
+            std::thread::sleep(time::Duration::from_millis(50));
+        }
+
+        let mut stdout = attach_proc.proc.stdout.take().unwrap();
+        let mut output = vec![];
+        let mut buf = vec![0; 1024 * 64];
+        while !String::from_utf8_lossy(&output).contains("fin2") {
+            let len = stdout.read(&mut buf).context("reading output")?;
+            if len == 0 {
+                return Err(anyhow!("attach proc hung up"));
+            }
+            output.extend_from_slice(&buf[..len]);
+        }
+
+        // The middle of the output got skipped, but the redrawn screen
+        // shows the end of it.
+        let output = String::from_utf8_lossy(&output);
+        assert!(!output.contains("\n02500"));
+        assert!(output.contains("05000"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn focus_reports() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        // the shell hears about focus changes once it asks for them
+        attach_proc.run_cmd("printf '\\e[?1004h'; echo on")?;
+        line_matcher.scan_until_re("on$")?;
+        attach_proc.run_cmd("read -rsn3 -t5 x; echo \"got=[${x:1}]\"")?;
+        attach_proc.run_raw(b"\x1b[I".to_vec())?;
+        line_matcher.scan_until_re("got=\\[\\[I\\]$")?;
+
+        // and not once it has turned them back off
+        attach_proc.run_cmd("printf '\\e[?1004l'; echo off")?;
+        line_matcher.scan_until_re("off$")?;
+        attach_proc.run_cmd("read -rsn3 -t1 x; echo \"got=[${x:1}]\"")?;
+        attach_proc.run_raw(b"\x1b[O".to_vec())?;
+        line_matcher.scan_until_re("got=\\[\\]$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn predictive_echo() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("predictive_echo.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let mut attach_proc = daemon_proc
+            .attach(
+                "sh1",
+                AttachArgs {
+                    config: Some(String::from("predictive_echo.toml")),
+                    ..Default::default()
+                },
+            )
+            .context("starting attach proc")?;
+        let mut stdout = attach_proc.proc.stdout.take().unwrap();
+        let mut output = vec![];
+        let mut read_until = |pat: &str| -> anyhow::Result<()> {
+            let mut buf = vec![0; 1024 * 16];
+            while !String::from_utf8_lossy(&output).contains(pat) {
+                let len = stdout.read(&mut buf).context("reading output")?;
+                if len == 0 {
+                    return Err(anyhow!("attach proc hung up"));
+                }
+                output.extend_from_slice(&buf[..len]);
+            }
+            Ok(())
+        };
+
+        attach_proc.run_cmd("echo ready")?;
+        read_until("\nready")?;
+
+        // Once the shell has echoed the first key, the rest get drawn
+        // underlined before the shell gets around to echoing them.
+        for key in "echo hi".bytes() {
+            attach_proc.run_raw(vec![key])?;
+            std::thread::sleep(time::Duration::from_millis(100));
+        }
+        attach_proc.run_raw(b"\n".to_vec())?;
+        read_until("\nhi")?;
+        assert!(String::from_utf8_lossy(&output).contains("\x1b[0;4mh"));
+
+        Ok(())
+    })
+}
+
 #[test]
 #[timeout(30000)]
 fn force_attach() -> anyhow::Result<()> {
@@ -843,6 +962,44 @@ fn screen_wide_restore() -> anyhow::Result<()> {
     })
 }
 
+#[test]
+#[timeout(30000)]
+fn last_command_restore() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("restore_last_command.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let bidi_done_w = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
+
+        {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+
+            attach_proc.run_cmd("echo first")?;
+            line_matcher.scan_until_re("first$")?;
+            attach_proc.run_cmd("echo second")?;
+            line_matcher.scan_until_re("second$")?;
+        }
+
+        // wait until the daemon has noticed that the connection
+        // has dropped before we attempt to open the connection again
+        daemon_proc.events = Some(bidi_done_w.wait_final_event("daemon-bidi-stream-done")?);
+
+        {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+
+            // the re-attach should redraw starting from the output of the
+            // last command, so the first line we see is its output.
+            line_matcher.match_re("second$")?;
+        }
+
+        Ok(())
+    })
+}
+
 #[test]
 #[timeout(30000)]
 fn lines_restore() -> anyhow::Result<()> {
@@ -931,6 +1088,54 @@ fn lines_big_chunk_restore() -> anyhow::Result<()> {
     })
 }
 
+#[test]
+#[timeout(30000)]
+fn compressed_output_zstd() -> anyhow::Result<()> {
+    support::dump_err(|| compressed_output("compression_zstd.toml"))
+}
+
+#[test]
+#[timeout(30000)]
+fn compressed_output_deflate() -> anyhow::Result<()> {
+    support::dump_err(|| compressed_output("compression_deflate.toml"))
+}
+
+fn compressed_output(config: &str) -> anyhow::Result<()> {
+    let mut daemon_proc = support::daemon::Proc::new(config, DaemonArgs::default())
+        .context("starting daemon proc")?;
+    let bidi_done_w = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
+    let attach_args = || AttachArgs { config: Some(String::from(config)), ..Default::default() };
+
+    {
+        let mut attach_proc =
+            daemon_proc.attach("sh1", attach_args()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd("echo foo")?;
+        line_matcher.scan_until_re("foo$")?;
+
+        // enough output to take more than one chunk
+        attach_proc.run_cmd("seq 1 5000")?;
+        line_matcher.scan_until_re("^5000$")?;
+    }
+
+    daemon_proc.events = Some(bidi_done_w.wait_final_event("daemon-bidi-stream-done")?);
+
+    // the restore buffer goes through the same compression stream as
+    // the rest of the output
+    {
+        let mut attach_proc =
+            daemon_proc.attach("sh1", attach_args()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        line_matcher.scan_until_re("^5000$")?;
+        attach_proc.run_cmd("echo bar")?;
+        line_matcher.scan_until_re("bar$")?;
+    }
+
+    Ok(())
+}
+
 #[test]
 #[timeout(30000)]
 fn exits_with_same_status_as_shell() -> anyhow::Result<()> {
@@ -1053,6 +1258,239 @@ fn prompt_prefix_bash() -> anyhow::Result<()> {
     })
 }
 
+#[test]
+#[timeout(30000)]
+fn prompt_prefix_hidden() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let daemon_proc =
+            support::daemon::Proc::new("prompt_prefix_hidden.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+
+        // we have to manually spawn the child proc rather than using the support
+        // util because the line matcher gets bound only after the process gets
+        // spawned, so there will be a race between the prompt printing and
+        // binding the line matcher if we use the wrapper util.
+        let mut child = Command::new(support::shpool_bin()?)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .arg("--socket")
+            .arg(&daemon_proc.socket_path)
+            .arg("--config-file")
+            .arg(support::testdata_file("prompt_prefix_hidden.toml"))
+            .arg("attach")
+            .arg("sh1")
+            .spawn()
+            .context("spawning attach process")?;
+
+        // The attach shell should be spawned and have read the
+        // initial prompt after half a second.
+        std::thread::sleep(time::Duration::from_millis(500));
+        child.kill().context("killing child")?;
+
+        let mut stderr = child.stderr.take().context("missing stderr")?;
+        let mut stderr_str = String::from("");
+        stderr.read_to_string(&mut stderr_str).context("slurping stderr")?;
+        assert!(stderr_str.is_empty());
+
+        let mut stdout = child.stdout.take().context("missing stdout")?;
+        let mut stdout_str = String::from("");
+        stdout.read_to_string(&mut stdout_str).context("slurping stdout")?;
+        // without norc, /etc/profile might set its own PS1
+        let stdout_re = Regex::new(".*session_name=sh1 .*")?;
+        assert!(stdout_re.is_match(&stdout_str));
+        // echo is on, so the script would show up if it got typed in
+        assert!(!stdout_str.contains("__shpool__"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn prompt_prefix_login_shell() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("prompt_prefix_hidden.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        // injecting the prefix must not cost bash its login shell status
+        attach_proc.run_cmd("shopt -q login_shell && echo login=yes$((1-1))")?;
+        line_matcher.scan_until_re("login=yes0$")?;
+
+        // and the rc file shim must not leak into child processes
+        attach_proc.run_cmd("echo \"pc=[$(printenv PROMPT_COMMAND)]$((1-1))\"")?;
+        line_matcher.scan_until_re("pc=\\[\\]0$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn prompt_prefix_clobbered_prompt_command() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::tempdir().context("creating tmp dir")?;
+        fs::write(tmp_dir.path().join(".bash_profile"), "PROMPT_COMMAND='history -a'\n")?;
+        let config_tmpl =
+            fs::read_to_string(support::testdata_file("prompt_prefix_clobbered.toml.tmpl"))?;
+        let config_file = tmp_dir.path().join("prompt_prefix_clobbered.toml");
+        fs::write(&config_file, config_tmpl.replace("TMP_HOME", tmp_dir.path().to_str().unwrap()))?;
+
+        let mut daemon_proc = support::daemon::Proc::new(&config_file, DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        // The rc file never runs, so the daemon has to fall back on
+        // typing in the script once it gives up waiting for it.
+        std::thread::sleep(time::Duration::from_secs(5));
+        attach_proc.run_cmd("echo \"ps1=[$PS1]$((1-1))\"")?;
+        // a login shell picks up whatever PS1 /etc/profile sets, so only
+        // look for the prefix
+        line_matcher.scan_until_re("ps1=\\[.*session_name=sh1 .*\\]0$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn prompt_prefix_typed() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let daemon_proc =
+            support::daemon::Proc::new("prompt_prefix_typed.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+
+        // we have to manually spawn the child proc rather than using the support
+        // util because the line matcher gets bound only after the process gets
+        // spawned, so there will be a race between the prompt printing and
+        // binding the line matcher if we use the wrapper util.
+        let mut child = Command::new(support::shpool_bin()?)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .arg("--socket")
+            .arg(&daemon_proc.socket_path)
+            .arg("--config-file")
+            .arg(support::testdata_file("prompt_prefix_typed.toml"))
+            .arg("attach")
+            .arg("sh1")
+            .spawn()
+            .context("spawning attach process")?;
+
+        // The attach shell should be spawned and have read the
+        // initial prompt after half a second.
+        std::thread::sleep(time::Duration::from_millis(500));
+        child.kill().context("killing child")?;
+
+        let mut stderr = child.stderr.take().context("missing stderr")?;
+        let mut stderr_str = String::from("");
+        stderr.read_to_string(&mut stderr_str).context("slurping stderr")?;
+        assert!(stderr_str.is_empty());
+
+        let mut stdout = child.stdout.take().context("missing stdout")?;
+        let mut stdout_str = String::from("");
+        stdout.read_to_string(&mut stdout_str).context("slurping stdout")?;
+        let stdout_re = Regex::new(".*session_name=sh1 prompt>.*")?;
+        assert!(stdout_re.is_match(&stdout_str));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn prompt_prefix_custom() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let daemon_proc =
+            support::daemon::Proc::new("prompt_prefix_custom.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+
+        // we have to manually spawn the child proc rather than using the support
+        // util because the line matcher gets bound only after the process gets
+        // spawned, so there will be a race between the prompt printing and
+        // binding the line matcher if we use the wrapper util.
+        let mut child = Command::new(support::shpool_bin()?)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .arg("--socket")
+            .arg(&daemon_proc.socket_path)
+            .arg("--config-file")
+            .arg(support::testdata_file("prompt_prefix_custom.toml"))
+            .arg("attach")
+            .arg("sh1")
+            .spawn()
+            .context("spawning attach process")?;
+
+        // The attach shell should be spawned and have read the
+        // initial prompt after half a second.
+        std::thread::sleep(time::Duration::from_millis(500));
+        child.kill().context("killing child")?;
+
+        let mut stderr = child.stderr.take().context("missing stderr")?;
+        let mut stderr_str = String::from("");
+        stderr.read_to_string(&mut stderr_str).context("slurping stderr")?;
+        assert!(stderr_str.is_empty());
+
+        let mut stdout = child.stdout.take().context("missing stdout")?;
+        let mut stdout_str = String::from("");
+        stdout.read_to_string(&mut stdout_str).context("slurping stdout")?;
+        let stdout_re = Regex::new(".*custom session_name=sh1 prompt>.*")?;
+        assert!(stdout_re.is_match(&stdout_str));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn prompt_prefix_template() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let daemon_proc =
+            support::daemon::Proc::new("prompt_prefix_template.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+
+        // we have to manually spawn the child proc rather than using the support
+        // util because the line matcher gets bound only after the process gets
+        // spawned, so there will be a race between the prompt printing and
+        // binding the line matcher if we use the wrapper util.
+        let mut child = Command::new(support::shpool_bin()?)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .arg("--socket")
+            .arg(&daemon_proc.socket_path)
+            .arg("--config-file")
+            .arg(support::testdata_file("prompt_prefix_template.toml"))
+            .arg("attach")
+            .arg("--ttl")
+            .arg("1h")
+            .arg("sh1")
+            .spawn()
+            .context("spawning attach process")?;
+
+        // The attach shell should be spawned and have read the
+        // initial prompt after half a second.
+        std::thread::sleep(time::Duration::from_millis(500));
+        child.kill().context("killing child")?;
+
+        let mut stderr = child.stderr.take().context("missing stderr")?;
+        let mut stderr_str = String::from("");
+        stderr.read_to_string(&mut stderr_str).context("slurping stderr")?;
+        assert!(stderr_str.is_empty());
+
+        let mut stdout = child.stdout.take().context("missing stdout")?;
+        let mut stdout_str = String::from("");
+        stdout.read_to_string(&mut stdout_str).context("slurping stdout")?;
+        let stdout_re = Regex::new(".*sh1 ttl=1h prompt>.*")?;
+        assert!(stdout_re.is_match(&stdout_str));
+
+        Ok(())
+    })
+}
+
 #[test]
 #[timeout(30000)]
 fn prompt_prefix_zsh() -> anyhow::Result<()> {
@@ -1300,3 +1738,146 @@ fn up_arrow_no_crash() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+#[timeout(30000)]
+fn output_trigger() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-triggers")?;
+        let hook_log = tmp_dir.path().join("hook.log");
+        let daemon_proc = support::daemon::Proc::new(
+            "output_trigger.toml",
+            DaemonArgs {
+                extra_env: vec![(
+                    String::from("HOOK_LOG"),
+                    String::from(hook_log.to_str().unwrap()),
+                )],
+                ..DaemonArgs::default()
+            },
+        )
+        .context("starting daemon proc")?;
+
+        // We slurp all of stdout rather than using a line matcher because
+        // the notification escape code does not end with a newline.
+        let mut child = Command::new(support::shpool_bin()?)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .arg("--socket")
+            .arg(&daemon_proc.socket_path)
+            .arg("--config-file")
+            .arg(support::testdata_file("output_trigger.toml"))
+            .arg("attach")
+            .arg("sh1")
+            .spawn()
+            .context("spawning attach process")?;
+
+        let stdin = child.stdin.as_mut().context("missing stdin")?;
+        stdin.write_all(b"echo unwatched; echo BUILD' 'FAILED\n")?;
+        stdin.flush()?;
+
+        support::wait_until(|| {
+            Ok(fs::read_to_string(&hook_log).unwrap_or_default().contains("BUILD FAILED"))
+        })?;
+        std::thread::sleep(time::Duration::from_millis(500));
+        child.kill().context("killing child")?;
+
+        assert_eq!(fs::read_to_string(&hook_log)?, "sh1 BUILD FAILED\n");
+
+        let mut stdout = child.stdout.take().context("missing stdout")?;
+        let mut stdout_str = String::from("");
+        stdout.read_to_string(&mut stdout_str).context("slurping stdout")?;
+        assert!(stdout_str.contains("\x1b]9;shpool(sh1): BUILD FAILED\x07"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn session_per_shell() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let mut session_ids = vec![];
+        let mut attach_procs = vec![];
+        for name in ["sh1", "sh2"] {
+            let mut attach_proc =
+                daemon_proc.attach(name, Default::default()).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+            // skip past any noise from the shell starting up
+            attach_proc.run_cmd("echo ready$((1-1))")?;
+            line_matcher.scan_until_re("ready0$")?;
+
+            // fields 5 through 8 of /proc/<pid>/stat are the process group,
+            // the session, the controlling tty and the foreground process
+            // group of the controlling tty
+            attach_proc.run_cmd(r#"echo "ids $$ $(cut -d' ' -f5-8 /proc/$$/stat)""#)?;
+            let ids = line_matcher.capture_re(r"ids (\d+) (\d+) (\d+) (\d+) (-?\d+)$")?;
+            let ids: Vec<i64> =
+                ids[1..].iter().map(|id| id.as_ref().unwrap().parse().unwrap()).collect();
+            let (pid, pgrp, sid, tty, tpgid) = (ids[0], ids[1], ids[2], ids[3], ids[4]);
+            assert_eq!(pgrp, pid, "shell should lead its own process group");
+            assert_eq!(sid, pid, "shell should lead its own session");
+            assert_ne!(tty, 0, "shell should have a controlling tty");
+            assert_eq!(tpgid, pid, "shell should be in the foreground");
+            session_ids.push(sid);
+
+            // with job control, background jobs get process groups of their own
+            attach_proc.run_cmd("sleep 30 &")?;
+            line_matcher.match_re(r"\[1\] \d+$")?;
+            attach_proc.run_cmd(r#"echo "job $! $(cut -d' ' -f5 /proc/$!/stat)""#)?;
+            let job = line_matcher.capture_re(r"job (\d+) (\d+)$")?;
+            assert_eq!(job[1], job[2], "background job should have its own process group");
+            attach_proc.run_cmd("kill %1")?;
+
+            attach_procs.push(attach_proc);
+        }
+        assert_ne!(session_ids[0], session_ids[1]);
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn idle_session_heartbeats() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let daemon_pid = daemon_proc.proc.as_ref().unwrap().id();
+
+        let mut attach_procs = vec![];
+        for name in ["sh1", "sh2", "sh3"] {
+            let mut attach_proc =
+                daemon_proc.attach(name, Default::default()).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+            attach_proc.run_cmd("echo ready$((1-1))")?;
+            line_matcher.scan_until_re("ready0$")?;
+            attach_procs.push((attach_proc, line_matcher));
+        }
+
+        // heartbeats come from the reader, so attached sessions
+        // should not cost us any extra threads
+        let mut thread_names = vec![];
+        for task in fs::read_dir(format!("/proc/{}/task", daemon_pid))? {
+            thread_names.push(fs::read_to_string(task?.path().join("comm"))?);
+        }
+        assert!(
+            !thread_names.iter().any(|name| name.starts_with("heartbeat")),
+            "unexpected heartbeat threads: {:?}",
+            thread_names
+        );
+
+        // sit idle for a few heartbeat periods, the connections should
+        // still be up
+        thread::sleep(time::Duration::from_secs(2));
+        for (attach_proc, line_matcher) in attach_procs.iter_mut() {
+            attach_proc.run_cmd("echo still here")?;
+            line_matcher.scan_until_re("still here$")?;
+        }
+
+        Ok(())
+    })
+}
@@ -0,0 +1,154 @@
+use std::{
+    fs,
+    io::{Read, Write},
+    path::PathBuf,
+    process::{Command, Stdio},
+    time,
+};
+
+use anyhow::Context;
+use ntest::timeout;
+use regex::Regex;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+// Write out a config which uses a fake pager that just dumps the
+// file it is given surrounded by some markers we can scan for.
+fn write_config(tmp_dir: &tempfile::TempDir) -> anyhow::Result<PathBuf> {
+    let pager = support::testdata_file("scrollback_pager.sh");
+    let config_tmpl = fs::read_to_string(support::testdata_file("scrollback.toml.tmpl"))?;
+    let config_contents = config_tmpl.replace("TMP_SCROLLBACK_PAGER", pager.to_str().unwrap());
+    let config_file = tmp_dir.path().join("scrollback.toml");
+    let mut f = fs::File::create(&config_file)?;
+    f.write_all(config_contents.as_bytes())?;
+    Ok(config_file)
+}
+
+#[test]
+#[timeout(30000)]
+fn banner() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        {
+            let mut detached_proc =
+                daemon_proc.attach("sh2", Default::default()).context("starting attach proc")?;
+            let mut line_matcher = detached_proc.line_matcher()?;
+            detached_proc.run_cmd("echo started")?;
+            line_matcher.scan_until_re("started$")?;
+        }
+        let detached_re = Regex::new("sh2\t[^\t]*\tdisconnected")?;
+        daemon_proc.wait_until_list_matches(|out| detached_re.is_match(out))?;
+
+        // We slurp all of stdout rather than using a line matcher because
+        // the banner does not end with a newline.
+        let mut child = Command::new(support::shpool_bin()?)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .arg("--socket")
+            .arg(&daemon_proc.socket_path)
+            .arg("--config-file")
+            .arg(support::testdata_file("norc.toml"))
+            .arg("attach")
+            .arg("sh1")
+            .spawn()
+            .context("spawning attach process")?;
+        let attached_re = Regex::new("sh1\t[^\t]*\tattached")?;
+        daemon_proc.wait_until_list_matches(|out| attached_re.is_match(out))?;
+
+        let out = daemon_proc.broadcast("going down\nfor maintenance", false)?;
+        assert!(out.status.success(), "broadcast proc did not exit successfully");
+        assert_eq!(String::from_utf8_lossy(&out.stdout[..]), "sh1\n");
+
+        // make sure the shell never saw the banner
+        let stdin = child.stdin.as_mut().context("missing stdin")?;
+        stdin.write_all(b"echo after$((6*7))\n")?;
+        stdin.flush()?;
+        std::thread::sleep(time::Duration::from_millis(500));
+        child.kill().context("killing child")?;
+
+        let mut stdout = child.stdout.take().context("missing stdout")?;
+        let mut stdout_str = String::from("");
+        stdout.read_to_string(&mut stdout_str).context("slurping stdout")?;
+        let banner_re = Regex::new(
+            "\x1b7\x1b\\[1;1H\x1b\\[0;7m shpool: going down for maintenance +\x1b8(prompt> )?after42\r\n",
+        )?;
+        assert!(banner_re.is_match(&stdout_str), "stdout={:?}", stdout_str);
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn pager() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-config")?;
+        let config_file = write_config(&tmp_dir)?;
+        let mut daemon_proc = support::daemon::Proc::new(config_file, DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd("echo before$((6*7))")?;
+        line_matcher.scan_until_re("before42$")?;
+
+        let out = daemon_proc.broadcast("going down for maintenance", true)?;
+        assert!(out.status.success(), "broadcast proc did not exit successfully");
+        assert_eq!(String::from_utf8_lossy(&out.stdout[..]), "sh1\n");
+
+        line_matcher.scan_until_re("PAGER_START$")?;
+        line_matcher.scan_until_re("Broadcast message from shpool:$")?;
+        line_matcher.scan_until_re("^going down for maintenance$")?;
+        line_matcher.scan_until_re("PAGER_END$")?;
+
+        // make sure we are back to talking to the shell
+        attach_proc.run_cmd("echo after$((6*7))")?;
+        line_matcher.scan_until_re("after42$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn nobody_attached() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = daemon_proc.broadcast("hello", false)?;
+        assert!(!out.status.success(), "broadcast proc exited successfully");
+
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("no attached sessions"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn no_daemon() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let out = Command::new(support::shpool_bin()?)
+            .arg("--socket")
+            .arg("/fake/does/not/exist/shpool.socket")
+            .arg("broadcast")
+            .arg("hello")
+            .output()
+            .context("spawning broadcast proc")?;
+
+        assert!(!out.status.success(), "broadcast proc exited successfully");
+
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("could not connect to daemon"));
+
+        Ok(())
+    })
+}
@@ -1,9 +1,10 @@
 use std::{
     fmt::Write,
-    io::Read,
+    fs,
+    io::{Read, Write as _},
     os::unix::{
-        io::{AsRawFd, FromRawFd},
-        net::UnixListener,
+        io::{AsRawFd, FromRawFd, RawFd},
+        net::{UnixDatagram, UnixListener, UnixStream},
         process::CommandExt,
     },
     path,
@@ -78,34 +79,103 @@ fn systemd_activation() -> anyhow::Result<()> {
         let sock_path = tmp_dir.path().join("shpool.socket");
         let activation_sock = UnixListener::bind(&sock_path)?;
 
-        let (parent_stderr, child_stderr) =
-            nix::unistd::pipe().context("creating pipe to collect stderr")?;
-        // Safety: this is a test
-        let child_stderr_pipe = unsafe { Stdio::from_raw_fd(child_stderr) };
-        let mut cmd = Command::new(support::shpool_bin()?);
-        cmd.stdout(Stdio::piped())
-            .stderr(child_stderr_pipe)
-            .env("LISTEN_FDS", "1")
-            .env("LISTEN_FDNAMES", sock_path)
-            .arg("daemon");
-
-        let mut pid_buf = String::with_capacity(128);
-
-        // We use fork both so we can correctly set LISTEN_PID and so
-        // that the daemon will inherit the socket fd the way that we
-        // want.
-        //
-        // We have to manually fork rather than using pre_exec because
-        // there does not appear to be a way to set an environment
-        // variable the child will inherit in the pre_exec callback.
-        //
-        // Safety: it's a test, get off my back. I try to avoid allocating.
-        let child_pid = match unsafe { nix::unistd::fork() } {
-            Ok(ForkResult::Parent { child, .. }) => child,
-            Ok(ForkResult::Child) => {
-                // place the unix socket file descriptor in the right
-                // place
-                let fdarg = match nix::unistd::dup2(activation_sock.as_raw_fd(), 3) {
+        let (child_pid, parent_stderr) =
+            spawn_activated(&[&activation_sock], sock_path.to_str().unwrap())?;
+
+        // The server should start up and run without incident for
+        // half a second.
+        std::thread::sleep(time::Duration::from_millis(500));
+
+        let stderr = kill_activated(child_pid, parent_stderr)?;
+        assert!(stderr.contains("using systemd activation socket"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn systemd_activation_multiple_sockets() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("shpool-test")
+            .rand_bytes(20)
+            .tempdir()
+            .context("creating tmp dir")?;
+        let sock_path = tmp_dir.path().join("shpool.socket");
+        let read_only_sock_path = tmp_dir.path().join("readonly.socket");
+        let metrics_sock_path = tmp_dir.path().join("metrics.socket");
+        let activation_sock = UnixListener::bind(&sock_path)?;
+        let read_only_sock = UnixListener::bind(&read_only_sock_path)?;
+        let metrics_sock = UnixListener::bind(&metrics_sock_path)?;
+
+        let (child_pid, parent_stderr) = spawn_activated(
+            &[&activation_sock, &read_only_sock, &metrics_sock],
+            "shpool.socket:readonly:metrics",
+        )?;
+
+        // the sockets are already bound, so we can talk to the daemon
+        // right away even if it is still getting going
+        let out = run_with_socket(&sock_path, &["list"])?;
+        assert!(out.status.success(), "list on the main socket failed: {:?}", out);
+        let out = run_with_socket(&read_only_sock_path, &["list"])?;
+        assert!(out.status.success(), "list on the read-only socket failed: {:?}", out);
+        let out = run_with_socket(&read_only_sock_path, &["kill", "sh1"])?;
+        assert!(!out.status.success(), "kill on the read-only socket succeeded");
+
+        let mut stream =
+            UnixStream::connect(&metrics_sock_path).context("dialing metrics socket")?;
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "response={}", response);
+
+        let stderr = kill_activated(child_pid, parent_stderr)?;
+        assert!(stderr.contains("using systemd activation sockets"));
+
+        Ok(())
+    })
+}
+
+/// Spawn a daemon the way systemd would, with the given sockets passed
+/// as fds starting at 3. Returns the pid of the daemon and the read end
+/// of a pipe collecting its stderr.
+fn spawn_activated(socks: &[&UnixListener], fd_names: &str) -> anyhow::Result<(Pid, RawFd)> {
+    let (parent_stderr, child_stderr) =
+        nix::unistd::pipe().context("creating pipe to collect stderr")?;
+    // Safety: this is a test
+    let child_stderr_pipe = unsafe { Stdio::from_raw_fd(child_stderr) };
+    let mut cmd = Command::new(support::shpool_bin()?);
+    cmd.stdout(Stdio::piped())
+        .stderr(child_stderr_pipe)
+        .env("LISTEN_FDS", socks.len().to_string())
+        .env("LISTEN_FDNAMES", fd_names)
+        .arg("daemon");
+
+    let mut pid_buf = String::with_capacity(128);
+
+    // We use fork both so we can correctly set LISTEN_PID and so
+    // that the daemon will inherit the socket fds the way that we
+    // want.
+    //
+    // We have to manually fork rather than using pre_exec because
+    // there does not appear to be a way to set an environment
+    // variable the child will inherit in the pre_exec callback.
+    //
+    // Safety: it's a test, get off my back. I try to avoid allocating.
+    match unsafe { nix::unistd::fork() } {
+        Ok(ForkResult::Parent { child, .. }) => Ok((child, parent_stderr)),
+        Ok(ForkResult::Child) => {
+            // place the unix socket file descriptors in the right
+            // places, going by way of fds well out of the way so
+            // that we don't clobber one socket with another
+            for (i, sock) in socks.iter().enumerate() {
+                let fdarg = nix::fcntl::fcntl(
+                    sock.as_raw_fd(),
+                    nix::fcntl::FcntlArg::F_DUPFD(100 + i as i32),
+                )
+                .and_then(|high_fd| nix::unistd::dup2(high_fd, 3 + i as i32));
+                let fdarg = match fdarg {
                     Ok(newfd) => newfd,
                     Err(e) => {
                         eprintln!("dup err: {}", e);
@@ -121,39 +191,41 @@ fn systemd_activation() -> anyhow::Result<()> {
                 newflags.remove(nix::fcntl::FdFlag::FD_CLOEXEC);
                 nix::fcntl::fcntl(fdarg, nix::fcntl::FcntlArg::F_SETFD(newflags))
                     .expect("FD_CLOEXEC to be unset");
-
-                // set the LISTEN_PID environment variable without
-                // allocating
-                write!(&mut pid_buf, "{}", std::process::id())
-                    .expect("to be able to format the pid");
-                cmd.env("LISTEN_PID", pid_buf);
-
-                let err = cmd.exec();
-                eprintln!("exec err: {:?}", err);
-                std::process::exit(1);
             }
-            Err(e) => {
-                return Err(e).context("forking daemon proc");
-            }
-        };
 
-        // The server should start up and run without incident for
-        // half a second.
-        std::thread::sleep(time::Duration::from_millis(500));
+            // set the LISTEN_PID environment variable without
+            // allocating
+            write!(&mut pid_buf, "{}", std::process::id()).expect("to be able to format the pid");
+            cmd.env("LISTEN_PID", pid_buf);
 
-        // kill the daemon proc and reap the return code
-        nix::sys::signal::kill(child_pid, Some(nix::sys::signal::Signal::SIGKILL))
-            .context("killing daemon")?;
-        nix::sys::wait::waitpid(child_pid, None).context("reaping daemon")?;
+            let err = cmd.exec();
+            eprintln!("exec err: {:?}", err);
+            std::process::exit(1);
+        }
+        Err(e) => Err(e).context("forking daemon proc"),
+    }
+}
 
-        let mut stderr_buf: Vec<u8> = vec![0; 1024 * 8];
-        let len =
-            nix::unistd::read(parent_stderr, &mut stderr_buf[..]).context("reading stderr")?;
-        let stderr = String::from_utf8_lossy(&stderr_buf[..len]);
-        assert!(stderr.contains("using systemd activation socket"));
+/// Kill a daemon started with spawn_activated and return its stderr.
+fn kill_activated(child_pid: Pid, parent_stderr: RawFd) -> anyhow::Result<String> {
+    // kill the daemon proc and reap the return code
+    nix::sys::signal::kill(child_pid, Some(nix::sys::signal::Signal::SIGKILL))
+        .context("killing daemon")?;
+    nix::sys::wait::waitpid(child_pid, None).context("reaping daemon")?;
 
-        Ok(())
-    })
+    let mut stderr_buf: Vec<u8> = vec![0; 1024 * 8];
+    let len = nix::unistd::read(parent_stderr, &mut stderr_buf[..]).context("reading stderr")?;
+    Ok(String::from_utf8_lossy(&stderr_buf[..len]).into_owned())
+}
+
+/// Run a shpool subcommand against the daemon on the given socket.
+fn run_with_socket(socket: &path::Path, args: &[&str]) -> anyhow::Result<std::process::Output> {
+    Command::new(support::shpool_bin()?)
+        .arg("--socket")
+        .arg(socket)
+        .args(args)
+        .output()
+        .context("running shpool")
 }
 
 #[test]
@@ -249,6 +321,95 @@ fn hooks() -> anyhow::Result<()> {
     })
 }
 
+#[test]
+#[timeout(30000)]
+fn hook_commands() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-hooks")?;
+        let hook_log = tmp_dir.path().join("hook.log");
+        let mut daemon_proc = support::daemon::Proc::new(
+            "hook_commands.toml",
+            DaemonArgs {
+                extra_env: vec![(
+                    String::from("HOOK_LOG"),
+                    String::from(hook_log.to_str().unwrap()),
+                )],
+                ..DaemonArgs::default()
+            },
+        )
+        .context("starting daemon proc")?;
+        let sh1_detached_re = Regex::new("sh1.*disconnected")?;
+
+        {
+            let mut sh1_proc =
+                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+            let mut sh1_matcher = sh1_proc.line_matcher()?;
+            sh1_proc.run_cmd("echo hi")?;
+            sh1_matcher.scan_until_re("hi$")?;
+        }
+        daemon_proc.wait_until_list_matches(|listout| sh1_detached_re.is_match(listout))?;
+
+        let mut sh1_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        sh1_proc.run_cmd("exit")?;
+
+        support::wait_until(|| {
+            Ok(fs::read_to_string(&hook_log).unwrap_or_default().contains("on_shell_disconnect"))
+        })?;
+
+        assert_eq!(
+            fs::read_to_string(&hook_log)?,
+            "on_new_session sh1\non_client_disconnect sh1\non_reattach sh1\non_shell_disconnect sh1\n"
+        );
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn hook_commands_context() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-hooks")?;
+        let hook_log = tmp_dir.path().join("hook.log");
+        let mut daemon_proc = support::daemon::Proc::new(
+            "hook_commands_context.toml",
+            DaemonArgs {
+                extra_env: vec![(
+                    String::from("HOOK_LOG"),
+                    String::from(hook_log.to_str().unwrap()),
+                )],
+                ..DaemonArgs::default()
+            },
+        )
+        .context("starting daemon proc")?;
+        let sh1_detached_re = Regex::new("sh1.*disconnected")?;
+
+        {
+            let mut sh1_proc =
+                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+            let mut sh1_matcher = sh1_proc.line_matcher()?;
+            sh1_proc.run_cmd("echo hi")?;
+            sh1_matcher.scan_until_re("hi$")?;
+        }
+        daemon_proc.wait_until_list_matches(|listout| sh1_detached_re.is_match(listout))?;
+
+        let out = daemon_proc.kill(vec![String::from("sh1")])?;
+        assert!(out.status.success());
+
+        support::wait_until(|| {
+            Ok(fs::read_to_string(&hook_log).unwrap_or_default().contains("on_session_killed"))
+        })?;
+
+        assert_eq!(
+            fs::read_to_string(&hook_log)?,
+            "on_daemon_start\non_new_session sh1 client tty\non_session_killed sh1 child client\n"
+        );
+
+        Ok(())
+    })
+}
+
 #[test]
 #[timeout(30000)]
 fn cleanup_socket() -> anyhow::Result<()> {
@@ -270,3 +431,368 @@ fn cleanup_socket() -> anyhow::Result<()> {
         Ok(())
     })
 }
+
+#[test]
+#[timeout(30000)]
+fn listen_sockets() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-listen")?;
+        let extra_socket = tmp_dir.path().join("extra.socket");
+        let read_only_socket = tmp_dir.path().join("readonly.socket");
+        let config_tmpl = fs::read_to_string(support::testdata_file("listen.toml.tmpl"))?;
+        let config_file = tmp_dir.path().join("listen.toml");
+        fs::write(
+            &config_file,
+            config_tmpl
+                .replace("TMP_EXTRA_SOCKET", extra_socket.to_str().unwrap())
+                .replace("TMP_READ_ONLY_SOCKET", read_only_socket.to_str().unwrap()),
+        )?;
+        let mut daemon_proc = support::daemon::Proc::new(&config_file, DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo hi")?;
+        line_matcher.scan_until_re("hi$")?;
+
+        for socket in [&extra_socket, &read_only_socket] {
+            let out = run_with_socket(socket, &["list"])?;
+            assert!(out.status.success(), "list on {:?} failed: {:?}", socket, out);
+            assert!(String::from_utf8_lossy(&out.stdout).contains("sh1\t"));
+        }
+
+        let out = run_with_socket(&read_only_socket, &["attach", "sh2"])?;
+        assert!(!out.status.success(), "attach on the read-only socket succeeded");
+        assert!(String::from_utf8_lossy(&out.stderr).contains("this socket is read-only"));
+        let out = run_with_socket(&read_only_socket, &["kill", "sh1"])?;
+        assert!(!out.status.success(), "kill on the read-only socket succeeded");
+
+        // the session survived the attempt to kill it
+        let out = run_with_socket(&extra_socket, &["list"])?;
+        assert!(String::from_utf8_lossy(&out.stdout).contains("sh1\t"));
+
+        let out = daemon_proc.status()?;
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        assert!(stdout.contains("sockets:          3\n"), "{}", stdout);
+        for socket in [&daemon_proc.socket_path, &extra_socket, &read_only_socket] {
+            let line = format!("\n    {}\n", socket.display());
+            assert!(stdout.contains(&line), "{:?} missing from {}", socket, stdout);
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn listen_path_in_use() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-listen-in-use")?;
+        let extra_socket = tmp_dir.path().join("extra.socket");
+        let read_only_socket = tmp_dir.path().join("readonly.socket");
+        let config_tmpl = fs::read_to_string(support::testdata_file("listen.toml.tmpl"))?;
+        let config_file = tmp_dir.path().join("listen.toml");
+        fs::write(
+            &config_file,
+            config_tmpl
+                .replace("TMP_EXTRA_SOCKET", extra_socket.to_str().unwrap())
+                .replace("TMP_READ_ONLY_SOCKET", read_only_socket.to_str().unwrap()),
+        )?;
+        let main_socket = tmp_dir.path().join("shpool.socket");
+        let run_daemon = || {
+            Command::new(support::shpool_bin()?)
+                .arg("--socket")
+                .arg(&main_socket)
+                .arg("--config-file")
+                .arg(&config_file)
+                .arg("daemon")
+                .output()
+                .context("running daemon")
+        };
+
+        // a file that just happens to be at a listen path must survive
+        fs::write(&extra_socket, "precious")?;
+        let out = run_daemon()?;
+        assert!(!out.status.success(), "daemon started over a regular file");
+        assert!(String::from_utf8_lossy(&out.stderr).contains("is not a socket"), "{:?}", out);
+        assert_eq!(fs::read_to_string(&extra_socket)?, "precious");
+        assert!(!main_socket.exists());
+
+        // as must a socket that someone is still listening on
+        fs::remove_file(&extra_socket)?;
+        let _listener = UnixListener::bind(&extra_socket)?;
+        let out = run_daemon()?;
+        assert!(!out.status.success(), "daemon started over a live socket");
+        assert!(String::from_utf8_lossy(&out.stderr).contains("already in use"), "{:?}", out);
+        assert!(extra_socket.exists());
+        assert!(!main_socket.exists());
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn metrics() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-metrics")?;
+        let metrics_socket = tmp_dir.path().join("metrics.socket");
+        let config_tmpl = fs::read_to_string(support::testdata_file("metrics.toml.tmpl"))?;
+        let config_file = tmp_dir.path().join("metrics.toml");
+        fs::write(
+            &config_file,
+            config_tmpl.replace("TMP_METRICS_SOCKET", metrics_socket.to_str().unwrap()),
+        )?;
+        let mut daemon_proc = support::daemon::Proc::new(&config_file, DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo hi")?;
+        line_matcher.scan_until_re("hi$")?;
+
+        let mut stream = UnixStream::connect(&metrics_socket).context("dialing metrics socket")?;
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "response={}", response);
+        for re in [
+            "(?m)^shpool_sessions_created_total 1$",
+            "(?m)^shpool_attaches_total 1$",
+            "(?m)^shpool_sessions\\{status=\"attached\"\\} 1$",
+            "(?m)^shpool_session_bytes_in_total\\{session=\"sh1\"\\} [1-9][0-9]*$",
+            "(?m)^shpool_session_bytes_out_total\\{session=\"sh1\"\\} [1-9][0-9]*$",
+            "(?m)^shpool_session_spool_bytes\\{session=\"sh1\"\\} [1-9][0-9]*$",
+        ] {
+            assert!(Regex::new(re)?.is_match(&response), "/{}/ does not match {}", re, response);
+        }
+
+        signal::kill(
+            Pid::from_raw(daemon_proc.proc.as_ref().unwrap().id() as i32),
+            Signal::SIGINT,
+        )?;
+        daemon_proc.proc_wait()?;
+        assert!(!metrics_socket.exists());
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn metrics_socket_path_taken() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-metrics-taken")?;
+        let metrics_socket = tmp_dir.path().join("metrics.socket");
+        let config_tmpl = fs::read_to_string(support::testdata_file("metrics.toml.tmpl"))?;
+        let config_file = tmp_dir.path().join("metrics.toml");
+        fs::write(
+            &config_file,
+            config_tmpl.replace("TMP_METRICS_SOCKET", metrics_socket.to_str().unwrap()),
+        )?;
+        fs::write(&metrics_socket, "precious")?;
+        let mut daemon_proc = support::daemon::Proc::new(
+            &config_file,
+            DaemonArgs { listen_events: false, ..DaemonArgs::default() },
+        )
+        .context("starting daemon proc")?;
+
+        // the daemon carries on without metrics, and leaves the file be
+        let out = daemon_proc.list()?;
+        assert!(out.status.success(), "list failed: {:?}", out);
+        signal::kill(
+            Pid::from_raw(daemon_proc.proc.as_ref().unwrap().id() as i32),
+            Signal::SIGINT,
+        )?;
+        daemon_proc.proc_wait()?;
+        assert_eq!(fs::read_to_string(&metrics_socket)?, "precious");
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn json_logs() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new(
+            "norc.toml",
+            DaemonArgs {
+                extra_args: vec![
+                    String::from("--log-format"),
+                    String::from("json"),
+                    String::from("--log-session"),
+                    String::from("sh1"),
+                ],
+                ..DaemonArgs::default()
+            },
+        )
+        .context("starting daemon proc")?;
+
+        for name in ["sh1", "sh2"] {
+            let mut attach_proc =
+                daemon_proc.attach(name, Default::default()).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+            attach_proc.run_cmd("echo hi")?;
+            line_matcher.scan_until_re("hi$")?;
+        }
+
+        let log = fs::read_to_string(&daemon_proc.log_file)?;
+        let mut sessions = vec![];
+        for line in log.lines() {
+            let entry: serde_json::Value = serde_json::from_str(line)
+                .with_context(|| format!("parsing log line '{}'", line))?;
+            let msg = entry["fields"]["message"].as_str().unwrap_or("");
+            // the new and close lines for a span are not events within it
+            if msg == "new" || msg == "close" {
+                continue;
+            }
+            if let Some(spans) = entry["spans"].as_array() {
+                sessions.extend(spans.iter().filter_map(|s| s["s"].as_str()).map(String::from));
+            }
+        }
+        assert!(sessions.iter().any(|s| s == "sh1"), "no sh1 events in {}", log);
+        assert!(!sessions.iter().any(|s| s == "sh2"), "sh2 events in {}", log);
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn sd_notify() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-sd-notify")?;
+        let notify_path = tmp_dir.path().join("notify.socket");
+        let notify_sock = UnixDatagram::bind(&notify_path)?;
+        notify_sock.set_read_timeout(Some(time::Duration::from_secs(10)))?;
+        let mut daemon_proc = support::daemon::Proc::new(
+            "norc.toml",
+            DaemonArgs {
+                extra_env: vec![
+                    (String::from("NOTIFY_SOCKET"), String::from(notify_path.to_str().unwrap())),
+                    (String::from("WATCHDOG_USEC"), String::from("200000")),
+                ],
+                ..DaemonArgs::default()
+            },
+        )
+        .context("starting daemon proc")?;
+
+        let recv_until = |want: &str| -> anyhow::Result<()> {
+            let mut buf = [0; 1024];
+            loop {
+                let len = notify_sock.recv(&mut buf).context("waiting for notification")?;
+                let msg = String::from_utf8_lossy(&buf[..len]);
+                eprintln!("notification: {:?}", msg);
+                if msg.lines().any(|l| l == want) {
+                    return Ok(());
+                }
+            }
+        };
+
+        recv_until("READY=1")?;
+        recv_until("WATCHDOG=1")?;
+
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo hi")?;
+        line_matcher.scan_until_re("hi$")?;
+        recv_until("STATUS=1 sessions (1 attached)")?;
+
+        let out = daemon_proc.stop(&["--kill-sessions"])?;
+        assert!(out.status.success(), "stop proc did not exit successfully");
+        recv_until("STOPPING=1")?;
+
+        Ok(())
+    })
+}
+
+// The threads the daemon has running, along with how many times they
+// have gone to sleep waiting for something to happen.
+fn daemon_threads(pid: u32) -> anyhow::Result<(usize, u64)> {
+    let (mut threads, mut switches) = (0, 0);
+    for task in fs::read_dir(format!("/proc/{}/task", pid))? {
+        let status = fs::read_to_string(task?.path().join("status"))?;
+        threads += 1;
+        switches += status
+            .lines()
+            .find_map(|l| l.strip_prefix("voluntary_ctxt_switches:"))
+            .ok_or(anyhow!("no context switches in {:?}", status))?
+            .trim()
+            .parse::<u64>()?;
+    }
+    Ok((threads, switches))
+}
+
+#[test]
+#[timeout(30000)]
+fn sessions_share_threads() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let daemon_pid = daemon_proc.proc.as_ref().unwrap().id();
+
+        let mut attach_procs = vec![];
+        let mut start_session = |name: &str| -> anyhow::Result<()> {
+            let mut attach_proc =
+                daemon_proc.attach(name, Default::default()).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+            attach_proc.run_cmd("echo ready$((1-1))")?;
+            line_matcher.scan_until_re("ready0$")?;
+            attach_procs.push(attach_proc);
+            Ok(())
+        };
+
+        start_session("sh0")?;
+        // give the daemon a moment to settle down after the attach
+        std::thread::sleep(time::Duration::from_millis(500));
+        let (threads_before, _) = daemon_threads(daemon_pid)?;
+
+        for i in 1..=8 {
+            start_session(&format!("sh{}", i))?;
+        }
+        std::thread::sleep(time::Duration::from_millis(500));
+        let (threads_attached, _) = daemon_threads(daemon_pid)?;
+        assert!(
+            threads_attached <= threads_before + 1,
+            "{} threads with 1 client attached, but {} threads with 9 clients attached",
+            threads_before,
+            threads_attached
+        );
+
+        for mut attach_proc in attach_procs.into_iter() {
+            attach_proc.proc.kill()?;
+            attach_proc.proc.wait()?;
+        }
+        // the daemon wraps up each detach on a short-lived thread, so
+        // wait for those to finish
+        let mut threads_after = 0;
+        let settled = support::wait_until(|| {
+            threads_after = daemon_threads(daemon_pid)?.0;
+            Ok(threads_after <= threads_before + 1)
+        });
+        assert!(
+            settled.is_ok(),
+            "{} threads for 1 session, but {} threads for 9 sessions",
+            threads_before,
+            threads_after
+        );
+        let (_, switches_before) = daemon_threads(daemon_pid)?;
+
+        // Nothing is going on in any of the sessions, so the daemon
+        // should be asleep rather than polling.
+        std::thread::sleep(time::Duration::from_secs(1));
+        let (_, switches_after) = daemon_threads(daemon_pid)?;
+        assert!(
+            switches_after - switches_before < 10,
+            "daemon woke up {} times while idle",
+            switches_after - switches_before
+        );
+
+        Ok(())
+    })
+}
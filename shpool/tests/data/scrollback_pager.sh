@@ -0,0 +1,5 @@
+#!/bin/sh
+
+echo "PAGER_START"
+cat "$1"
+echo "PAGER_END"
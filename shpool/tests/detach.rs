@@ -38,6 +38,33 @@ fn single_running() -> anyhow::Result<()> {
     })
 }
 
+#[test]
+#[timeout(30000)]
+fn attach_exits_promptly() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let mut waiter = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-enter"]);
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        waiter.wait_event("daemon-bidi-stream-enter")?;
+        let attach_waiter = attach_proc.events.take().unwrap().waiter(["attach-io-threads-joined"]);
+
+        let out = daemon_proc.detach(vec![String::from("sh1")])?;
+        assert!(out.status.success(), "not successful");
+
+        // Both IO threads should wind down on their own once the daemon
+        // hangs up, rather than one of them being left stuck in a read
+        // for the process to hard-exit out from under.
+        attach_waiter.wait_final_event("attach-io-threads-joined")?;
+        let status = attach_proc.proc.wait().context("waiting for attach proc")?;
+        assert!(status.code().is_some(), "attach exited with {:?}", status);
+
+        Ok(())
+    })
+}
+
 #[test]
 #[timeout(30000)]
 fn single_not_running() -> anyhow::Result<()> {
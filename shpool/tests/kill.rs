@@ -114,11 +114,14 @@ fn reattach_after_kill() -> anyhow::Result<()> {
         let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
             .context("starting daemon proc")?;
 
+        // The attach side of things wraps up as soon as the shell exits,
+        // which can be before or after the shell gets removed from the
+        // table, so we wait for both in whatever order they come.
         let mut waiter = daemon_proc
             .events
             .take()
             .unwrap()
-            .waiter(["daemon-handle-kill-removed-shells", "daemon-bidi-stream-done"]);
+            .unordered_waiter(["daemon-handle-kill-removed-shells", "daemon-bidi-stream-done"]);
 
         let mut sess1 =
             daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
@@ -136,8 +139,7 @@ fn reattach_after_kill() -> anyhow::Result<()> {
         let stderr = String::from_utf8_lossy(&out.stderr[..]);
         assert!(stderr.len() == 0);
 
-        waiter.wait_event("daemon-handle-kill-removed-shells")?;
-        waiter.wait_event("daemon-bidi-stream-done")?;
+        waiter.wait_events(&["daemon-handle-kill-removed-shells", "daemon-bidi-stream-done"])?;
 
         let mut sess2 =
             daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
@@ -1,4 +1,9 @@
-use std::process::Command;
+use std::{
+    io::{Read, Write},
+    os::unix::net::UnixListener,
+    process::{Command, Stdio},
+    thread, time,
+};
 
 use anyhow::Context;
 use ntest::timeout;
@@ -51,6 +56,56 @@ fn no_daemon() -> anyhow::Result<()> {
     })
 }
 
+#[test]
+#[timeout(30000)]
+fn old_daemon() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-old-daemon")?;
+        let socket = tmp_dir.path().join("shpool.socket");
+        let listener = UnixListener::bind(&socket)?;
+
+        // Pretend to be a daemon from before ListV2, which hangs up on
+        // headers it does not know about and answers a plain List with
+        // name, start time and status.
+        let daemon = thread::spawn(move || -> anyhow::Result<Vec<u32>> {
+            let mut headers = vec![];
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept()?;
+                let mut variant = [0; 4];
+                stream.read_exact(&mut variant)?;
+                headers.push(u32::from_le_bytes(variant));
+                if headers.last() == Some(&1) {
+                    let mut reply = vec![];
+                    reply.extend(1u64.to_le_bytes());
+                    reply.extend(3u64.to_le_bytes());
+                    reply.extend(b"old");
+                    reply.extend(0i64.to_le_bytes());
+                    reply.extend(1u32.to_le_bytes());
+                    stream.write_all(&reply)?;
+                }
+            }
+            Ok(headers)
+        });
+
+        let out = Command::new(support::shpool_bin()?)
+            .arg("--socket")
+            .arg(&socket)
+            .arg("list")
+            .output()
+            .context("spawning list proc")?;
+        assert!(out.status.success(), "list proc did not exit successfully: {:?}", out);
+
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert!(Regex::new("old\t[^\t]*\tdisconnected\t")?.is_match(&stdout), "{}", stdout);
+
+        // a ListV2, then a List once the daemon hung up
+        let headers = daemon.join().unwrap()?;
+        assert_eq!(headers, vec![9, 1]);
+
+        Ok(())
+    })
+}
+
 #[test]
 #[timeout(30000)]
 fn one_session() -> anyhow::Result<()> {
@@ -124,3 +179,98 @@ fn two_sessions() -> anyhow::Result<()> {
         Ok(())
     })
 }
+
+#[test]
+#[timeout(30000)]
+fn current_command() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("prompt_prefix_bash.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+
+        attach_proc.run_cmd("(exit 7)")?;
+        let exit_re = Regex::new("sh1.*\t7\t")?;
+        daemon_proc.wait_until_list_matches(|out| exit_re.is_match(out))?;
+
+        attach_proc.run_cmd("sleep 30")?;
+        let cmd_re = Regex::new("sh1.*\tsleep 30\n")?;
+        daemon_proc.wait_until_list_matches(|out| cmd_re.is_match(out))?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn bell_and_activity() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("activity.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+            attach_proc.run_cmd("echo started; sleep 1; printf '\\a'")?;
+            line_matcher.scan_until_re("started$")?;
+        }
+
+        let flagged_re = Regex::new("sh1\t[^\t]*\tdisconnected, bell, activity\t")?;
+        daemon_proc.wait_until_list_matches(|out| flagged_re.is_match(out))?;
+
+        // We slurp all of stdout rather than using a line matcher because
+        // the notification escape code does not end with a newline.
+        let mut child = Command::new(support::shpool_bin()?)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .arg("--socket")
+            .arg(&daemon_proc.socket_path)
+            .arg("--config-file")
+            .arg(support::testdata_file("activity.toml"))
+            .arg("attach")
+            .arg("sh1")
+            .spawn()
+            .context("spawning attach process")?;
+        std::thread::sleep(time::Duration::from_millis(500));
+        child.kill().context("killing child")?;
+
+        let mut stdout = child.stdout.take().context("missing stdout")?;
+        let mut stdout_str = String::from("");
+        stdout.read_to_string(&mut stdout_str).context("slurping stdout")?;
+        assert!(stdout_str.contains("\x1b]9;shpool(sh1): bell, activity\x07"));
+
+        let cleared_re = Regex::new("sh1\t[^\t]*\tdisconnected\t")?;
+        daemon_proc.wait_until_list_matches(|out| cleared_re.is_match(out))?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn session_cgroup() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        // Whether or not there is a systemd around to give us a scope,
+        // the shell should come up and list should have usage columns.
+        let mut daemon_proc =
+            support::daemon::Proc::new("session_cgroup.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd("echo hi")?;
+        line_matcher.match_re("hi$")?;
+
+        let out = daemon_proc.list()?;
+        assert!(out.status.success(), "list proc did not exit successfully");
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert!(stdout.starts_with("NAME\tSTARTED_AT\tSTATUS\tLAST_EXIT\tMEMORY\tCPU\tCOMMAND\n"));
+        assert!(stdout.contains("sh1\t"));
+
+        Ok(())
+    })
+}
@@ -0,0 +1,158 @@
+use std::{fs, io::Write, path::PathBuf};
+
+use anyhow::Context;
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+// Write out a config which uses a fake pager that just dumps the
+// scrollback file surrounded by some markers we can scan for.
+fn write_config(tmp_dir: &tempfile::TempDir) -> anyhow::Result<PathBuf> {
+    let pager = support::testdata_file("scrollback_pager.sh");
+    let config_tmpl = fs::read_to_string(support::testdata_file("scrollback.toml.tmpl"))?;
+    let config_contents = config_tmpl.replace("TMP_SCROLLBACK_PAGER", pager.to_str().unwrap());
+    let config_file = tmp_dir.path().join("scrollback.toml");
+    let mut f = fs::File::create(&config_file)?;
+    f.write_all(config_contents.as_bytes())?;
+    Ok(config_file)
+}
+
+#[test]
+#[timeout(30000)]
+fn keybinding() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-config")?;
+        let config_file = write_config(&tmp_dir)?;
+        let mut daemon_proc = support::daemon::Proc::new(config_file, DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd("echo scroll$((6*7))")?;
+        line_matcher.scan_until_re("scroll42$")?;
+
+        attach_proc.run_raw(vec![17, b's'])?; // Ctrl-q s
+        line_matcher.scan_until_re("PAGER_START$")?;
+        line_matcher.scan_until_re("scroll42$")?;
+        line_matcher.scan_until_re("PAGER_END$")?;
+
+        // make sure we are back to talking to the shell
+        attach_proc.run_cmd("echo after$((6*7))")?;
+        line_matcher.scan_until_re("after42$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn subcommand() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-config")?;
+        let config_file = write_config(&tmp_dir)?;
+        let mut daemon_proc = support::daemon::Proc::new(config_file, DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd("echo scroll$((6*7))")?;
+        line_matcher.scan_until_re("scroll42$")?;
+
+        let out = daemon_proc.scrollback("sh1", false)?;
+        assert!(out.status.success(), "not successful");
+
+        line_matcher.scan_until_re("PAGER_START$")?;
+        line_matcher.scan_until_re("scroll42$")?;
+        line_matcher.scan_until_re("PAGER_END$")?;
+
+        attach_proc.run_cmd("echo after$((6*7))")?;
+        line_matcher.scan_until_re("after42$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn last_command() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-config")?;
+        let config_file = write_config(&tmp_dir)?;
+        let mut daemon_proc = support::daemon::Proc::new(config_file, DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd("echo first$((6*7))")?;
+        line_matcher.scan_until_re("first42$")?;
+        attach_proc.run_cmd("echo second$((6*7))")?;
+        line_matcher.scan_until_re("second42$")?;
+
+        let out = daemon_proc.scrollback("sh1", true)?;
+        assert!(out.status.success(), "not successful");
+
+        // only the output of the last command should be shown
+        line_matcher.scan_until_re("PAGER_START$")?;
+        line_matcher.match_re("^second42$")?;
+        line_matcher.match_re("^PAGER_END$")?;
+
+        attach_proc.run_cmd("echo after$((6*7))")?;
+        line_matcher.scan_until_re("after42$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn not_attached() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut waiter = daemon_proc
+            .events
+            .take()
+            .unwrap()
+            .waiter(["daemon-bidi-stream-enter", "daemon-bidi-stream-done"]);
+        let _attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        waiter.wait_event("daemon-bidi-stream-enter")?;
+
+        let out = daemon_proc.detach(vec![String::from("sh1")])?;
+        assert!(out.status.success(), "not successful");
+        daemon_proc.events = Some(waiter.wait_final_event("daemon-bidi-stream-done")?);
+
+        let out = daemon_proc.scrollback("sh1", false)?;
+        assert!(!out.status.success(), "successful");
+
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("not attached: sh1"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn not_found() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new(
+            "norc.toml",
+            DaemonArgs { listen_events: false, ..DaemonArgs::default() },
+        )
+        .context("starting daemon proc")?;
+
+        let out = daemon_proc.scrollback("sh1", false)?;
+        assert!(!out.status.success(), "successful");
+
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("not found: sh1"));
+
+        Ok(())
+    })
+}
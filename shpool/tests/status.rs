@@ -0,0 +1,71 @@
+use std::{process::Command, time};
+
+use anyhow::Context;
+use ntest::timeout;
+use regex::Regex;
+
+mod support;
+
+use crate::support::daemon::{AttachArgs, DaemonArgs};
+
+#[test]
+#[timeout(30000)]
+fn basic() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let mut attach_proc = daemon_proc
+            .attach(
+                "sh1",
+                AttachArgs { ttl: Some(time::Duration::from_secs(3600)), ..Default::default() },
+            )
+            .context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo started")?;
+        line_matcher.scan_until_re("started$")?;
+
+        let out = daemon_proc.status()?;
+        assert!(out.status.success(), "status proc did not exit successfully");
+
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        for re in [
+            "(?m)^pid: +[0-9]+$",
+            "(?m)^version: +[0-9.]+$",
+            "(?m)^started at: +.* \\(up [0-9dhms]+\\)$",
+            "(?m)^config file: +.*norc.toml \\(loaded .*\\)$",
+            &format!(
+                "(?m)^sockets: +1\n +{}$",
+                regex::escape(&daemon_proc.socket_path.to_string_lossy())
+            ),
+            "(?m)^systemd socket: +no$",
+            "(?m)^sessions: +1 \\(1 attached\\)$",
+            "(?m)^threads: +[0-9]+$",
+            "(?m)^ttl queue: +1\n +sh1\texpires in (59m|1h)",
+        ] {
+            assert!(Regex::new(re)?.is_match(&stdout), "/{}/ does not match {:?}", re, stdout);
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn no_daemon() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let out = Command::new(support::shpool_bin()?)
+            .arg("--socket")
+            .arg("/fake/does/not/exist/shpool.socket")
+            .arg("status")
+            .output()
+            .context("spawning status proc")?;
+
+        assert!(!out.status.success(), "status proc exited successfully");
+
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("could not connect to daemon"));
+
+        Ok(())
+    })
+}
@@ -0,0 +1,111 @@
+use std::{fs, process::Command};
+
+use anyhow::Context;
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+#[test]
+#[timeout(30000)]
+fn kill_sessions() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::TempDir::with_prefix("shpool-test-stop")?;
+        let hook_log = tmp_dir.path().join("hook.log");
+        let mut daemon_proc = support::daemon::Proc::new(
+            "hook_commands.toml",
+            DaemonArgs {
+                extra_env: vec![(
+                    String::from("HOOK_LOG"),
+                    String::from(hook_log.to_str().unwrap()),
+                )],
+                ..DaemonArgs::default()
+            },
+        )
+        .context("starting daemon proc")?;
+
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo hi")?;
+        line_matcher.scan_until_re("hi$")?;
+
+        let out = daemon_proc.stop(&["--wait", "--kill-sessions"])?;
+        assert!(out.status.success(), "stop proc did not exit successfully");
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert!(stdout.contains("killing sessions: sh1\n"), "stdout={:?}", stdout);
+        assert!(stdout.ends_with("daemon stopped\n"), "stdout={:?}", stdout);
+
+        assert!(daemon_proc.proc_wait()?.success(), "daemon did not exit cleanly");
+        attach_proc.proc.wait()?;
+        assert!(!daemon_proc.socket_path.exists(), "socket not cleaned up");
+
+        let hook_log = fs::read_to_string(&hook_log)?;
+        assert!(hook_log.contains("on_shell_disconnect sh1\n"), "hook_log={:?}", hook_log);
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn refuse_if_sessions() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo hi")?;
+        line_matcher.scan_until_re("hi$")?;
+
+        let out = daemon_proc.stop(&["--refuse-if-sessions"])?;
+        assert!(!out.status.success(), "stop proc exited successfully");
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("daemon still has sessions: sh1"), "stderr={:?}", stderr);
+
+        // still up and running
+        assert!(daemon_proc.list()?.status.success());
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn no_sessions() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = daemon_proc.stop(&["--wait", "--refuse-if-sessions"])?;
+        assert!(out.status.success(), "stop proc did not exit successfully");
+        assert!(daemon_proc.proc_wait()?.success(), "daemon did not exit cleanly");
+        assert!(!daemon_proc.socket_path.exists(), "socket not cleaned up");
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn no_daemon() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let out = Command::new(support::shpool_bin()?)
+            .arg("--socket")
+            .arg("/fake/does/not/exist/shpool.socket")
+            .arg("daemon")
+            .arg("stop")
+            .output()
+            .context("spawning stop proc")?;
+
+        assert!(!out.status.success(), "stop proc exited successfully");
+
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("could not connect to daemon"));
+
+        Ok(())
+    })
+}
@@ -19,7 +19,7 @@ use super::{attach, events::Events, shpool_bin, testdata_file, wait_until};
 pub struct Proc {
     pub proc: Option<process::Child>,
     subproc_counter: usize,
-    log_file: PathBuf,
+    pub log_file: PathBuf,
     local_tmp_dir: Option<TempDir>,
     pub tmp_dir: PathBuf,
     pub events: Option<Events>,
@@ -31,11 +31,12 @@ pub struct Proc {
 pub struct DaemonArgs {
     pub listen_events: bool,
     pub extra_env: Vec<(String, String)>,
+    pub extra_args: Vec<String>,
 }
 
 impl std::default::Default for DaemonArgs {
     fn default() -> Self {
-        DaemonArgs { listen_events: true, extra_env: vec![] }
+        DaemonArgs { listen_events: true, extra_env: vec![], extra_args: vec![] }
     }
 }
 
@@ -129,6 +130,7 @@ impl Proc {
             .arg(&socket_path)
             .arg("--config-file")
             .arg(resolved_config)
+            .args(args.extra_args)
             .arg("daemon");
         if args.listen_events {
             cmd.env("SHPOOL_TEST_HOOK_SOCKET_PATH", &test_hook_socket_path);
@@ -187,6 +189,11 @@ impl Proc {
                     .into_string()
                     .map_err(|e| anyhow!("conversion error: {:?}", e))?,
             ),
+            log_format: None,
+            log_max_size: None,
+            log_max_age: None,
+            log_retain: None,
+            log_session: vec![],
             verbose: 2,
             socket: Some(
                 socket_path
@@ -201,7 +208,7 @@ impl Proc {
                     .into_string()
                     .map_err(|e| anyhow!("conversion error: {:?}", e))?,
             ),
-            command: libshpool::Commands::Daemon,
+            command: libshpool::Commands::Daemon { action: None },
         };
         let hooks_recorder = Box::new(HooksRecorder {
             records: Arc::new(Mutex::new(HookRecords {
@@ -334,6 +341,46 @@ impl Proc {
         cmd.output().context("spawning kill proc")
     }
 
+    pub fn scrollback(
+        &mut self,
+        session: &str,
+        last_command: bool,
+    ) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("scrollback_{}.log", self.subproc_counter));
+        eprintln!("spawning scrollback proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        let mut cmd = Command::new(shpool_bin()?);
+        cmd.arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("scrollback");
+        if last_command {
+            cmd.arg("--last-command");
+        }
+        cmd.arg(session).output().context("spawning scrollback proc")
+    }
+
+    pub fn broadcast(&mut self, message: &str, pager: bool) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("broadcast_{}.log", self.subproc_counter));
+        eprintln!("spawning broadcast proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        let mut cmd = Command::new(shpool_bin()?);
+        cmd.arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("broadcast");
+        if pager {
+            cmd.arg("--pager");
+        }
+        cmd.arg(message).output().context("spawning broadcast proc")
+    }
+
     pub fn wait_until_list_matches<F>(&mut self, pred: F) -> anyhow::Result<()>
     where
         F: Fn(&str) -> bool,
@@ -350,6 +397,40 @@ impl Proc {
         })
     }
 
+    pub fn status(&mut self) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("status_{}.log", self.subproc_counter));
+        eprintln!("spawning status proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("status")
+            .output()
+            .context("spawning status proc")
+    }
+
+    pub fn stop(&mut self, args: &[&str]) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("stop_{}.log", self.subproc_counter));
+        eprintln!("spawning stop proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("daemon")
+            .arg("stop")
+            .args(args)
+            .output()
+            .context("spawning stop proc")
+    }
+
     /// list launches a `shpool list` process, collects the
     /// output and returns it as a string
     pub fn list(&mut self) -> anyhow::Result<process::Output> {
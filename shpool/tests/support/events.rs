@@ -76,6 +76,41 @@ impl Events {
         waiter
     }
 
+    /// unordered_waiter is like `waiter`, except that the events may
+    /// show up in any order. Each event is matched once, and the events
+    /// should be waited for all together with `wait_events`.
+    pub fn unordered_waiter<S, SI>(mut self, events: SI) -> EventWaiter
+    where
+        S: Into<String>,
+        SI: IntoIterator<Item = S>,
+    {
+        let mut events: Vec<String> = events.into_iter().map(|s| s.into()).collect();
+        assert!(!events.is_empty());
+
+        let (tx, rx) = crossbeam_channel::bounded(events.len());
+        let waiter = EventWaiter { matched: rx };
+        std::thread::spawn(move || {
+            for line in &mut self.lines {
+                match line {
+                    Ok(l) => {
+                        if let Some(i) = events.iter().position(|e| *e == l) {
+                            events.remove(i);
+                            tx.send(WaiterEvent::Event(l)).unwrap();
+                            if events.is_empty() {
+                                break;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("error scanning for events {:?}: {:?}", events, e);
+                    }
+                }
+            }
+        });
+
+        waiter
+    }
+
     /// await_events waits for a given event on the stream.
     /// Prefer `waiter` since it is less prone to race conditions.
     /// `await_event` might be approriate for startup events where
@@ -125,6 +160,26 @@ impl EventWaiter {
         }
     }
 
+    /// wait_events waits for all of the given events, in whatever
+    /// order they show up.
+    pub fn wait_events(&mut self, events: &[&str]) -> anyhow::Result<()> {
+        eprintln!("waiting for events {:?}", events);
+        let mut want: Vec<&str> = events.to_vec();
+        while !want.is_empty() {
+            let got = match self.matched.recv()? {
+                WaiterEvent::Event(e) => e,
+                WaiterEvent::Done((e, _)) => e,
+            };
+            match want.iter().position(|e| *e == got) {
+                Some(i) => {
+                    want.remove(i);
+                }
+                None => return Err(anyhow!("Got '{}' event, want one of {:?}", got, want)),
+            }
+        }
+        Ok(())
+    }
+
     pub fn wait_final_event(self, event: &str) -> anyhow::Result<Events> {
         eprintln!("waiting for final event '{}'", event);
         match self.matched.recv()? {